@@ -0,0 +1,9 @@
+-- Per-tenant usage counters written by the proxy (binding `USAGE_DB`).
+CREATE TABLE IF NOT EXISTS usage (
+    day TEXT NOT NULL,
+    tenant TEXT NOT NULL,
+    host TEXT NOT NULL,
+    requests INTEGER NOT NULL DEFAULT 0,
+    errors INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (day, tenant, host)
+);
@@ -0,0 +1,104 @@
+//! Cloudflare Access assertions.
+//!
+//! When the worker sits behind Cloudflare Access, every request carries a
+//! `Cf-Access-Jwt-Assertion` header signed by the team's Access keys. Setting
+//! `CF_ACCESS_TEAM_DOMAIN` (e.g. `myteam.cloudflareaccess.com`) and
+//! `CF_ACCESS_AUD` (the application's AUD tag, comma-separated for several)
+//! makes the worker verify it itself instead of trusting the network path.
+//! Service tokens are covered too: Access exchanges the
+//! `CF-Access-Client-Id`/`CF-Access-Client-Secret` pair for the same assertion.
+
+use worker::*;
+
+use crate::{config, jwt};
+
+pub const ASSERTION_HEADER: &str = "Cf-Access-Jwt-Assertion";
+
+/// Access rotates its signing keys rarely; an hour keeps refetches cheap.
+const CERTS_TTL_MS: u64 = 3_600_000;
+
+pub struct AccessConfig {
+    pub team_origin: String,
+    pub audiences: Vec<String>,
+}
+
+/// `myteam.cloudflareaccess.com` or a full URL -> `https://myteam.cloudflareaccess.com`.
+pub fn team_origin(domain: &str) -> String {
+    let host = domain
+        .trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+    format!("https://{host}")
+}
+
+pub fn config(env: &Env) -> Option<AccessConfig> {
+    let team = config::var(env, "CF_ACCESS_TEAM_DOMAIN")?;
+    let audiences = config::var_list(env, "CF_ACCESS_AUD");
+    if audiences.is_empty() {
+        console_error!("CF_ACCESS_TEAM_DOMAIN is set without CF_ACCESS_AUD; ignoring Access");
+        return None;
+    }
+    Some(AccessConfig {
+        team_origin: team_origin(&team),
+        audiences,
+    })
+}
+
+/// Human-readable identity for logs: user email, else service token id.
+pub fn identity(claims: &jwt::Claims) -> String {
+    claims
+        .email
+        .clone()
+        .or_else(|| {
+            claims
+                .common_name
+                .as_ref()
+                .map(|cn| format!("service-token:{cn}"))
+        })
+        .or_else(|| claims.sub.clone())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Verify an Access assertion and return the caller's identity.
+pub async fn verify(
+    env: &Env,
+    access: &AccessConfig,
+    assertion: &str,
+) -> std::result::Result<String, jwt::JwtError> {
+    let rules = jwt::Validation {
+        audiences: access.audiences.clone(),
+        issuer: Some(access.team_origin.clone()),
+        leeway_secs: jwt::leeway_secs(env),
+    };
+    let certs = format!("{}/cdn-cgi/access/certs", access.team_origin);
+    let claims = jwt::verify(assertion, &certs, CERTS_TTL_MS, &rules).await?;
+    Ok(identity(&claims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_team_origin() {
+        assert_eq!(
+            team_origin("myteam.cloudflareaccess.com"),
+            "https://myteam.cloudflareaccess.com"
+        );
+        assert_eq!(
+            team_origin("https://myteam.cloudflareaccess.com/"),
+            "https://myteam.cloudflareaccess.com"
+        );
+    }
+
+    #[test]
+    fn test_identity_prefers_email_then_service_token() {
+        let claims: jwt::Claims =
+            serde_json::from_str(r#"{"email":"a@example.com","sub":"123"}"#).unwrap();
+        assert_eq!(identity(&claims), "a@example.com");
+        let claims: jwt::Claims =
+            serde_json::from_str(r#"{"common_name":"abc.access","sub":""}"#).unwrap();
+        assert_eq!(identity(&claims), "service-token:abc.access");
+    }
+}
@@ -0,0 +1,93 @@
+//! Operator endpoints under `/admin/` (and the `DELETE /cache` purge),
+//! protected by the `ADMIN_KEY` secret.
+//!
+//! The key is accepted as `Authorization: Bearer <key>` or `X-Admin-Key`.
+//! Without `ADMIN_KEY` the whole admin API is disabled.
+//!
+//! Operator traffic (these endpoints, `GET /health` and `GET /version`) is
+//! routed before origin and country checks, rate limits, quotas and any
+//! Durable Object work, so it never shares a budget with proxy traffic and a
+//! deployment that is being hammered can still be inspected and
+//! reconfigured.
+
+use worker::*;
+
+use crate::{
+    bundle, cache, certs, config, diagnostics, health, keys, monitor, responses, schema, signing,
+    slo, utils, watermark,
+};
+
+pub const PREFIX: &str = "/admin/";
+
+/// Whether the request is for an operator endpoint rather than the proxy.
+/// Preflights are left to the regular CORS handling.
+pub fn is_operator_request(method: &Method, path: &str) -> bool {
+    match method {
+        Method::Options => false,
+        Method::Get if path == health::PATH || path == diagnostics::PATH => true,
+        Method::Delete if path == cache::PURGE_PATH => true,
+        _ => path.starts_with(PREFIX),
+    }
+}
+
+/// Return an error response unless the request carries the admin key.
+pub fn authorize(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let Some(admin_key) = config::var(env, "ADMIN_KEY") else {
+        return responses::error(404, "admin_disabled", "Admin API is not enabled").map(Some);
+    };
+    let headers = req.headers();
+    let presented = match headers.get("X-Admin-Key")? {
+        Some(key) => Some(key),
+        None => headers
+            .get("Authorization")?
+            .and_then(|v| v.strip_prefix("Bearer ").map(str::to_string)),
+    };
+    match presented {
+        Some(key) if utils::constant_time_eq(key.trim().as_bytes(), admin_key.as_bytes()) => {
+            Ok(None)
+        }
+        _ => responses::error(401, "unauthorized", "A valid admin key is required").map(Some),
+    }
+}
+
+pub async fn handle(req: Request, env: &Env) -> Result<Response> {
+    if let Some(denied) = authorize(&req, env)? {
+        return Ok(denied);
+    }
+    match (req.method(), req.path().as_str()) {
+        (Method::Delete, cache::PURGE_PATH) => cache::admin_purge(&req, env).await,
+        (Method::Get, "/admin/slo") => slo::admin_summary(&req, env).await,
+        (Method::Get, "/admin/probes") => monitor::admin_results(env).await,
+        (Method::Get, "/admin/certs") => certs::admin_certs(env).await,
+        (Method::Get, "/admin/schemas") => schema::admin_schemas(&req, env).await,
+        (Method::Get, "/admin/sign") => signing::admin_sign(&req, env),
+        (Method::Get, "/admin/config/export") => bundle::admin_export(env).await,
+        (Method::Post, "/admin/config/import") => bundle::admin_import(req, env).await,
+        (Method::Get, "/admin/keys") => keys::admin_list(env).await,
+        (Method::Post, "/admin/keys") => keys::admin_update(req, env, "add").await,
+        (Method::Post, "/admin/keys/promote") => keys::admin_update(req, env, "promote").await,
+        (Method::Post, "/admin/keys/retire") => keys::admin_update(req, env, "retire").await,
+        (Method::Post, "/admin/watermark/trace") => watermark::admin_trace(req, env).await,
+        _ => responses::error(404, "not_found", "Unknown admin endpoint"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_operator_request() {
+        assert!(is_operator_request(&Method::Get, "/health"));
+        assert!(is_operator_request(&Method::Get, "/version"));
+        assert!(is_operator_request(&Method::Post, "/admin/keys"));
+        assert!(!is_operator_request(&Method::Post, "/health"));
+        assert!(is_operator_request(&Method::Delete, "/cache"));
+        assert!(!is_operator_request(&Method::Get, "/cache"));
+        assert!(!is_operator_request(&Method::Options, "/admin/keys"));
+        assert!(!is_operator_request(
+            &Method::Get,
+            "/https://example.com/admin/"
+        ));
+    }
+}
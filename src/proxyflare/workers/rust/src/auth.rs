@@ -0,0 +1,196 @@
+//! Optional API-key authentication for proxy access.
+//!
+//! Enabled when `API_KEYS` (comma-separated keys) is set or an `API_KEYS_KV`
+//! namespace is bound. KV entries are stored under `key:<sha256-hex of key>`
+//! with a JSON value like `{"name": "team-a"}`, so raw keys never sit in KV.
+//! Keys in `API_KEYS_SECONDARY` and KV records in the `secondary` slot are
+//! accepted alongside the primary ones during a rotation (see [`crate::keys`]).
+//!
+//! Clients present the key as `Authorization: Bearer <key>` or `X-Api-Key`.
+//! With `JWT_JWKS_URL` set, a bearer JWT is accepted as well (see [`jwt`]).
+//! With Cloudflare Access configured (see [`access`]), a valid Access
+//! assertion is required and identifies the caller on its own.
+//! The credential is stripped before the request is forwarded upstream.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::{access, config, jwt, responses, utils};
+
+const KEYS_VAR: &str = "API_KEYS";
+const SECONDARY_KEYS_VAR: &str = "API_KEYS_SECONDARY";
+pub const KEYS_KV: &str = "API_KEYS_KV";
+pub const KV_PREFIX: &str = "key:";
+
+/// The authenticated caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    /// Stable, non-secret identifier used for logs and per-key accounting.
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+    #[default]
+    Primary,
+    /// Still accepted, but on its way out (or not yet promoted).
+    Secondary,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub slot: Slot,
+}
+
+/// Where the key was presented; decides which header gets stripped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeySource {
+    Authorization,
+    ApiKeyHeader,
+    AccessAssertion,
+}
+
+#[derive(Debug)]
+pub enum AuthOutcome {
+    /// Authentication is not configured.
+    Disabled,
+    Authenticated(ApiKey, KeySource),
+    Rejected(Response),
+}
+
+pub fn sha256_hex(value: &str) -> String {
+    utils::hex(&Sha256::digest(value.as_bytes()))
+}
+
+/// Id for keys configured via env: a short hash prefix, safe to log.
+pub fn key_id(key: &str) -> String {
+    format!("key-{}", &sha256_hex(key)[..12])
+}
+
+/// Extract the presented key from `Authorization: Bearer` or `X-Api-Key`.
+pub fn presented_key(req: &Request) -> Result<Option<(String, KeySource)>> {
+    let headers = req.headers();
+    if let Some(key) = headers.get("X-Api-Key")? {
+        return Ok(Some((key.trim().to_string(), KeySource::ApiKeyHeader)));
+    }
+    Ok(headers.get("Authorization")?.and_then(|value| {
+        value
+            .strip_prefix("Bearer ")
+            .map(|key| (key.trim().to_string(), KeySource::Authorization))
+    }))
+}
+
+fn unauthorized(message: &str) -> Result<AuthOutcome> {
+    let mut response = responses::error(401, "unauthorized", message)?;
+    response
+        .headers_mut()
+        .set("WWW-Authenticate", "Bearer realm=\"proxyflare\"")?;
+    Ok(AuthOutcome::Rejected(response))
+}
+
+pub async fn authenticate(req: &Request, env: &Env) -> Result<AuthOutcome> {
+    if let Some(access) = access::config(env) {
+        let Some(assertion) = req.headers().get(access::ASSERTION_HEADER)? else {
+            return unauthorized("A Cloudflare Access assertion is required");
+        };
+        return match access::verify(env, &access, assertion.trim()).await {
+            Ok(identity) => Ok(AuthOutcome::Authenticated(
+                ApiKey {
+                    id: format!("access:{identity}"),
+                },
+                KeySource::AccessAssertion,
+            )),
+            Err(e) => unauthorized(&format!("Invalid Access assertion: {e}")),
+        };
+    }
+
+    let env_keys = config::var_list(env, KEYS_VAR);
+    let secondary_keys = config::var_list(env, SECONDARY_KEYS_VAR);
+    let kv = env.kv(KEYS_KV).ok();
+    let jwt_enabled = jwt::is_enabled(env);
+    if env_keys.is_empty() && secondary_keys.is_empty() && kv.is_none() && !jwt_enabled {
+        return Ok(AuthOutcome::Disabled);
+    }
+
+    let Some((key, source)) = presented_key(req)? else {
+        return unauthorized("An API key is required");
+    };
+    if key.is_empty() {
+        return unauthorized("An API key is required");
+    }
+
+    if jwt_enabled && source == KeySource::Authorization && jwt::looks_like_jwt(&key) {
+        return match jwt::validate(env, &key).await {
+            Ok(subject) => Ok(AuthOutcome::Authenticated(
+                ApiKey {
+                    id: format!("jwt:{subject}"),
+                },
+                source,
+            )),
+            Err(e) => unauthorized(&format!("Invalid token: {e}")),
+        };
+    }
+
+    let listed = |keys: &[String]| {
+        keys.iter()
+            .any(|k| utils::constant_time_eq(k.as_bytes(), key.as_bytes()))
+    };
+    let slot = if listed(&env_keys) {
+        Some(Slot::Primary)
+    } else if listed(&secondary_keys) {
+        Some(Slot::Secondary)
+    } else {
+        None
+    };
+    if let Some(slot) = slot {
+        let id = key_id(&key);
+        if slot == Slot::Secondary {
+            console_log!("Caller {} used a secondary key", id);
+        }
+        return Ok(AuthOutcome::Authenticated(ApiKey { id }, source));
+    }
+
+    if let Some(kv) = kv {
+        let digest = sha256_hex(&key);
+        let stored = kv
+            .get(&format!("{KV_PREFIX}{digest}"))
+            .json::<KeyRecord>()
+            .await?;
+        if let Some(record) = stored {
+            let id = record.name.unwrap_or_else(|| key_id(&key));
+            if record.slot == Slot::Secondary {
+                console_log!("Caller {} used a secondary key", id);
+            }
+            return Ok(AuthOutcome::Authenticated(ApiKey { id }, source));
+        }
+    }
+
+    unauthorized("Invalid API key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_key_id_is_stable_and_does_not_leak_key() {
+        let id = key_id("super-secret");
+        assert_eq!(id, key_id("super-secret"));
+        assert!(id.starts_with("key-"));
+        assert!(!id.contains("secret"));
+        assert_eq!(id.len(), 16);
+    }
+}
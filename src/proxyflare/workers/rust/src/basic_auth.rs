@@ -0,0 +1,78 @@
+//! Optional HTTP Basic auth gate in front of the whole proxy.
+//!
+//! Enabled by the `BASIC_AUTH_CREDENTIALS` secret: comma-separated
+//! `user:password` pairs. Requests without matching credentials get a 401
+//! with `WWW-Authenticate: Basic`, so browsers show their login prompt.
+//! Signed links bypass the gate; admin endpoints keep their own key.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use worker::*;
+
+use crate::{config, responses, utils};
+
+const CREDENTIALS_VAR: &str = "BASIC_AUTH_CREDENTIALS";
+
+pub fn is_enabled(env: &Env) -> bool {
+    !config::var_list(env, CREDENTIALS_VAR).is_empty()
+}
+
+/// Decode an `Authorization: Basic` value into `user:password`.
+pub fn decode(header: &str) -> Option<String> {
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()
+}
+
+pub fn matches(presented: &str, credentials: &[String]) -> bool {
+    // Check every entry so timing doesn't reveal which user exists.
+    credentials.iter().fold(false, |found, c| {
+        utils::constant_time_eq(c.as_bytes(), presented.as_bytes()) | found
+    })
+}
+
+/// Return a 401 unless the request carries valid Basic credentials.
+pub fn authorize(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let credentials = config::var_list(env, CREDENTIALS_VAR);
+    if credentials.is_empty() {
+        return Ok(None);
+    }
+    let presented = req.headers().get("Authorization")?.and_then(|h| decode(&h));
+    if presented.is_some_and(|p| matches(&p, &credentials)) {
+        return Ok(None);
+    }
+    let mut response = responses::error(401, "unauthorized", "Authentication required")?;
+    response.headers_mut().set(
+        "WWW-Authenticate",
+        "Basic realm=\"proxyflare\", charset=\"UTF-8\"",
+    )?;
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode("Basic YWxpY2U6czNjcmV0").as_deref(),
+            Some("alice:s3cret")
+        );
+        assert_eq!(
+            decode("basic YWxpY2U6czNjcmV0").as_deref(),
+            Some("alice:s3cret")
+        );
+        assert_eq!(decode("Bearer YWxpY2U6czNjcmV0"), None);
+        assert_eq!(decode("Basic !!!"), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        let creds = vec!["alice:s3cret".to_string(), "bob:hunter2".to_string()];
+        assert!(matches("bob:hunter2", &creds));
+        assert!(!matches("bob:hunter3", &creds));
+        assert!(!matches("alice", &creds));
+    }
+}
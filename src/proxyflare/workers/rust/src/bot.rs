@@ -0,0 +1,160 @@
+//! Bot Management score gate for anonymous callers.
+//!
+//! On zones with Bot Management, `request.cf.botManagement.score` runs from 1
+//! (automated) to 99 (human). Scores below `BOT_SCORE_BLOCK` are rejected
+//! with 403; scores below `BOT_SCORE_THROTTLE` are limited to
+//! `BOT_THROTTLE_PER_MINUTE` requests (default 10) per client IP through the
+//! `QUOTA_COUNTER` object. Verified bots (search crawlers etc.) pass unless
+//! `BOT_ALLOW_VERIFIED=false`. Without score data the gate does nothing.
+
+use worker::js_sys::Reflect;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, quota, responses};
+
+const DEFAULT_THROTTLE_PER_MINUTE: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BotSignal {
+    pub score: u64,
+    pub verified_bot: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BotPolicy {
+    pub block_below: Option<u64>,
+    pub throttle_below: Option<u64>,
+    pub allow_verified: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BotDecision {
+    Allow,
+    Throttle,
+    Block,
+}
+
+impl BotPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            block_below: config::var_u64(env, "BOT_SCORE_BLOCK"),
+            throttle_below: config::var_u64(env, "BOT_SCORE_THROTTLE"),
+            allow_verified: config::var(env, "BOT_ALLOW_VERIFIED").as_deref() != Some("false"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.block_below.is_some() || self.throttle_below.is_some()
+    }
+
+    pub fn decide(&self, signal: BotSignal) -> BotDecision {
+        if signal.verified_bot && self.allow_verified {
+            return BotDecision::Allow;
+        }
+        if self.block_below.is_some_and(|t| signal.score < t) {
+            BotDecision::Block
+        } else if self.throttle_below.is_some_and(|t| signal.score < t) {
+            BotDecision::Throttle
+        } else {
+            BotDecision::Allow
+        }
+    }
+}
+
+/// Read `cf.botManagement` from the incoming request, if the zone has it.
+pub fn signal(req: &Request) -> Option<BotSignal> {
+    let get = |target: &JsValue, key: &str| Reflect::get(target, &JsValue::from_str(key)).ok();
+    let cf = get(req.inner().as_ref(), "cf")?;
+    let bot = get(&cf, "botManagement").filter(|b| b.is_object())?;
+    let score = get(&bot, "score")?.as_f64()?;
+    Some(BotSignal {
+        score: score as u64,
+        verified_bot: get(&bot, "verifiedBot")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    })
+}
+
+/// Return a rejection for likely-automated traffic, if any.
+pub async fn check(req: &Request, env: &Env, rctx: &RequestCtx) -> Result<Option<Response>> {
+    let policy = BotPolicy::from_env(env);
+    if !policy.is_enabled() {
+        return Ok(None);
+    }
+    let Some(signal) = signal(req) else {
+        return Ok(None);
+    };
+    match policy.decide(signal) {
+        BotDecision::Allow => Ok(None),
+        BotDecision::Block => {
+            console_log!("Blocked request with bot score {}", signal.score);
+            responses::error(
+                403,
+                "bot_traffic_blocked",
+                "Automated traffic is not allowed",
+            )
+            .map(Some)
+        }
+        BotDecision::Throttle => {
+            let ip = rctx.client_ip.as_deref().unwrap_or_default();
+            let limit = config::var_u64(env, "BOT_THROTTLE_PER_MINUTE")
+                .unwrap_or(DEFAULT_THROTTLE_PER_MINUTE);
+            let request = quota::ConsumeRequest {
+                bucket: "bot-throttle".into(),
+                amount: 1,
+                limit,
+                window_secs: 60,
+                sliding: false,
+                force: false,
+            };
+            match quota::consume(env, &format!("bot:{ip}"), &request).await {
+                Some(result) if !result.allowed => quota::too_many_requests(
+                    &result,
+                    "bot_traffic_throttled",
+                    "Too many requests from likely-automated traffic",
+                )
+                .map(Some),
+                _ => Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> BotPolicy {
+        BotPolicy {
+            block_below: Some(10),
+            throttle_below: Some(30),
+            allow_verified: true,
+        }
+    }
+
+    fn signal(score: u64, verified_bot: bool) -> BotSignal {
+        BotSignal {
+            score,
+            verified_bot,
+        }
+    }
+
+    #[test]
+    fn test_decide_thresholds() {
+        let policy = policy();
+        assert_eq!(policy.decide(signal(2, false)), BotDecision::Block);
+        assert_eq!(policy.decide(signal(10, false)), BotDecision::Throttle);
+        assert_eq!(policy.decide(signal(29, false)), BotDecision::Throttle);
+        assert_eq!(policy.decide(signal(30, false)), BotDecision::Allow);
+    }
+
+    #[test]
+    fn test_verified_bots() {
+        let mut policy = policy();
+        assert_eq!(policy.decide(signal(1, true)), BotDecision::Allow);
+        policy.allow_verified = false;
+        assert_eq!(policy.decide(signal(1, true)), BotDecision::Block);
+    }
+}
@@ -0,0 +1,436 @@
+//! Signed export/import of a deployment's state, for cloning an environment
+//! (dev → prod) and disaster recovery.
+//!
+//! `GET /admin/config/export` returns one JSON bundle with the non-secret
+//! settings, the API key records from `API_KEYS_KV` (hashes and metadata
+//! only) and the rules in `COMPLIANCE_KV`, signed with
+//! `HMAC-SHA256(CONFIG_BUNDLE_SECRET, "<bundle>\n<created_at>")`. Share the
+//! secret between the environments involved.
+//!
+//! `POST /admin/config/import` verifies the signature, rejects bundles older
+//! than `CONFIG_BUNDLE_MAX_AGE_SECS` (default a week) and writes the
+//! compliance rules into the bound namespace. The bundle's key records
+//! replace the ones in `API_KEYS_KV`: keys missing from the bundle are
+//! deleted and listed in `removed_keys`, and keys it brings back (e.g. ones
+//! retired since the export) are listed in `added_keys`; the age limit
+//! bounds how stale that key set can be (`?dry_run=1` only reports).
+//! Settings are plain environment variables the worker cannot change, so
+//! they are compared instead and the differing names returned for the
+//! operator to apply with wrangler.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::auth::{self, KeyRecord};
+use crate::compliance::{self, ComplianceRule};
+use crate::{config, keys, responses, signing, utils};
+
+pub const FORMAT_VERSION: u32 = 1;
+const SECRET_VAR: &str = "CONFIG_BUNDLE_SECRET";
+const DEFAULT_MAX_AGE_SECS: u64 = 7 * 86_400;
+
+/// Settings carried in a bundle. Secrets (`ADMIN_KEY`, `API_KEYS*`,
+/// `URL_SIGNING_SECRET`, `TURNSTILE_SECRET`, `BASIC_AUTH_CREDENTIALS`,
+/// `LOOP_GUARD_SECRET`, `WATERMARK_SECRET`, `XFF_PSEUDONYM_SECRET`,
+/// `XFF_STICKY_SALT`, webhook URLs) are deliberately absent.
+pub const SETTINGS: &[&str] = &[
+    "ALLOWED_CONTENT_TYPES",
+    "ALLOWED_COUNTRIES",
+    "ALLOWED_ORIGINS",
+    "ALLOWED_RESPONSE_HEADERS",
+    "ANONYMIZE",
+    "ANONYMOUS_QUOTA",
+    "BLOCKED_COUNTRIES",
+    "BOT_ALLOW_VERIFIED",
+    "BOT_SCORE_BLOCK",
+    "BOT_SCORE_THROTTLE",
+    "BOT_THROTTLE_PER_MINUTE",
+    "CACHE_COALESCE_WAIT_MS",
+    "CACHE_HONOR_CLIENT_NO_CACHE",
+    "CACHE_IGNORED_PARAMS",
+    "CACHE_KEY_HEADERS",
+    "CACHE_KV_MAX_BYTES",
+    "CACHE_NEGATIVE_TTL",
+    "CACHE_R2_MIN_BYTES",
+    "CACHE_REVALIDATE_SECS",
+    "CACHE_STALE_SECS",
+    "CACHE_TTL",
+    "CACHE_TTL_RULES",
+    "CERT_ALERT_DAYS",
+    "CERT_WATCH_HOSTS",
+    "CF_ACCESS_AUD",
+    "CF_ACCESS_TEAM_DOMAIN",
+    "CF_FETCH_OPTIONS",
+    "CF_FETCH_OPTION_HEADERS",
+    "COMPLIANCE_BLOCKLIST",
+    "CONFIG_BUNDLE_MAX_AGE_SECS",
+    "CORS_ALLOWED_ORIGINS",
+    "CORS_ALLOW_CREDENTIALS",
+    "CORS_EXPOSE_HEADERS",
+    "CORS_MAX_AGE",
+    "CORS_PASSTHROUGH",
+    "CORS_PREFLIGHT_CACHE",
+    "CORS_RULES",
+    "DEPRECATIONS",
+    "DIAGNOSTIC_HEADERS",
+    "EGRESS_BUDGETS",
+    "EGRESS_WINDOW_SECS",
+    "FORWARDED_HEADER",
+    "HOST_CONCURRENCY",
+    "HOST_CONCURRENCY_QUEUE_MS",
+    "HOST_OVERRIDES",
+    "IDEMPOTENCY_MAX_BODY_BYTES",
+    "IDEMPOTENCY_MAX_REQUEST_BYTES",
+    "IDEMPOTENCY_TTL_SECS",
+    "JSONP_ENABLED",
+    "JWT_AUDIENCE",
+    "JWT_ISSUER",
+    "JWT_JWKS_TTL_SECS",
+    "JWT_JWKS_URL",
+    "JWT_LEEWAY_SECS",
+    "KEY_QUOTAS",
+    "LINKCHECK_CONCURRENCY",
+    "LINKCHECK_MAX_LINKS",
+    "LOOP_MAX_HOPS",
+    "MAX_HEADER_BYTES",
+    "MAX_REQUEST_BYTES",
+    "MAX_REQUEST_HEADER_BYTES",
+    "MAX_RESPONSE_BYTES",
+    "MONITOR_PROBES",
+    "PARALLEL_RANGES_MAX",
+    "PARALLEL_RANGE_MAX_BYTES",
+    "PARALLEL_RANGE_MIN_BYTES",
+    "QR_SIGNED_MAX_TTL_SECS",
+    "RATE_LIMIT_BACKEND",
+    "RATE_LIMIT_COUNTRIES",
+    "RATE_LIMIT_REQUESTS",
+    "RATE_LIMIT_WINDOW_SECS",
+    "REPORT_RATE_LIMIT",
+    "REQUEST_DEADLINE_MS",
+    "REQUEST_HEADER_RULES",
+    "RETENTION_DAYS",
+    "ROOT_INFO",
+    "ROOT_REDIRECT_URL",
+    "SCHEMA_REDACT_FIELDS",
+    "SCHEMA_SAMPLE_MAX_BYTES",
+    "SCHEMA_SAMPLE_RATE",
+    "SECURITY_HEADERS",
+    "SECURITY_HEADER_OVERRIDES",
+    "SESSION_KV_MAX_BYTES",
+    "SESSION_KV_MAX_TTL_SECS",
+    "SISTER_DEPLOYMENTS",
+    "SLO_MIN_REQUESTS",
+    "SLO_TARGET",
+    "SPEND_CAPS",
+    "SPEND_RULES",
+    "STRICT_RESPONSE_HEADERS",
+    "STRIP_IMAGE_METADATA",
+    "STRIP_REQUEST_HEADERS",
+    "STRIP_RESPONSE_HEADERS",
+    "TIMING_FLOORS",
+    "TURNSTILE_PASS_TTL_SECS",
+    "TURNSTILE_SITE_KEY",
+    "UPSTREAM_ACCEPT_ENCODING",
+    "URL_SIGNATURE_SKEW_SECS",
+    "USER_AGENT",
+    "USER_AGENT_POOL",
+    "VIA_HEADER",
+    "VIA_PSEUDONYM",
+    "WATERMARK_MAX_BYTES",
+    "XFF_MODE",
+    "XFF_RANDOM_IPV6",
+    "X_FORWARDED_HOST_PROTO",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub created_at: u64,
+    #[serde(default)]
+    pub config: BTreeMap<String, String>,
+    /// API key records keyed by key hash.
+    #[serde(default)]
+    pub keys: BTreeMap<String, KeyRecord>,
+    /// Compliance rules keyed by their `COMPLIANCE_KV` key.
+    #[serde(default)]
+    pub compliance: BTreeMap<String, ComplianceRule>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Bundle {
+    /// The signed content: the bundle serialized without its signature.
+    fn payload(&self) -> String {
+        let unsigned = Bundle {
+            signature: String::new(),
+            ..self.clone()
+        };
+        serde_json::to_string(&unsigned).expect("bundle serializes")
+    }
+
+    pub fn sign(&mut self, secret: &str) {
+        self.signature = signing::sign(secret, &self.payload(), self.created_at);
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        let expected = signing::sign(secret, &self.payload(), self.created_at);
+        utils::constant_time_eq(expected.as_bytes(), self.signature.as_bytes())
+    }
+}
+
+/// Names whose values differ between `bundle` and `current` (either side
+/// may be unset).
+pub fn config_differences(
+    bundle: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let names: BTreeSet<&String> = bundle.keys().chain(current.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| bundle.get(*name) != current.get(*name))
+        .cloned()
+        .collect()
+}
+
+/// Whether a bundle created at `created_at` is too old to import at `now`.
+pub fn expired(created_at: u64, now: u64, max_age_secs: u64) -> bool {
+    now.saturating_sub(created_at) > max_age_secs
+}
+
+/// Digests only in `bundle` (added) and only in `current` (removed).
+pub fn key_changes(
+    bundle: &BTreeMap<String, KeyRecord>,
+    current: &[(String, KeyRecord)],
+) -> (Vec<String>, Vec<String>) {
+    let existing: BTreeSet<&String> = current.iter().map(|(digest, _)| digest).collect();
+    let added = bundle
+        .keys()
+        .filter(|digest| !existing.contains(digest))
+        .cloned()
+        .collect();
+    let removed = current
+        .iter()
+        .filter(|(digest, _)| !bundle.contains_key(digest))
+        .map(|(digest, _)| digest.clone())
+        .collect();
+    (added, removed)
+}
+
+fn current_config(env: &Env) -> BTreeMap<String, String> {
+    SETTINGS
+        .iter()
+        .filter_map(|name| config::var(env, name).map(|v| (name.to_string(), v)))
+        .collect()
+}
+
+fn secret(env: &Env) -> std::result::Result<String, Result<Response>> {
+    config::var(env, SECRET_VAR)
+        .ok_or_else(|| responses::error(503, "bundles_disabled", "CONFIG_BUNDLE_SECRET is not set"))
+}
+
+/// `GET /admin/config/export`
+pub async fn admin_export(env: &Env) -> Result<Response> {
+    let secret = match secret(env) {
+        Ok(secret) => secret,
+        Err(response) => return response,
+    };
+    let mut bundle = Bundle {
+        version: FORMAT_VERSION,
+        created_at: Date::now().as_millis() / 1000,
+        config: current_config(env),
+        keys: BTreeMap::new(),
+        compliance: BTreeMap::new(),
+        signature: String::new(),
+    };
+    if let Ok(kv) = env.kv(auth::KEYS_KV) {
+        bundle.keys = keys::records(&kv).await?.into_iter().collect();
+    }
+    if let Ok(kv) = env.kv(compliance::BLOCKLIST_KV) {
+        bundle.compliance = compliance::kv_rules(&kv).await?;
+    }
+    bundle.sign(&secret);
+
+    let mut response = responses::json(200, &serde_json::to_value(&bundle)?)?;
+    response.headers_mut().set(
+        "Content-Disposition",
+        &format!(
+            "attachment; filename=\"proxyflare-{}.json\"",
+            utils::iso_date(bundle.created_at * 1000)
+        ),
+    )?;
+    Ok(response)
+}
+
+/// `POST /admin/config/import[?dry_run=1]`
+pub async fn admin_import(mut req: Request, env: &Env) -> Result<Response> {
+    let secret = match secret(env) {
+        Ok(secret) => secret,
+        Err(response) => return response,
+    };
+    let bundle: Bundle = match req.json().await {
+        Ok(bundle) => bundle,
+        Err(_) => return responses::error(400, "invalid_bundle", "Expected a bundle JSON body"),
+    };
+    if bundle.version != FORMAT_VERSION {
+        return responses::error(
+            400,
+            "invalid_bundle",
+            &format!("Unsupported bundle version {}", bundle.version),
+        );
+    }
+    if !bundle.verify(&secret) {
+        return responses::error(403, "invalid_signature", "Bundle signature does not match");
+    }
+    let max_age =
+        config::var_u64(env, "CONFIG_BUNDLE_MAX_AGE_SECS").unwrap_or(DEFAULT_MAX_AGE_SECS);
+    if expired(bundle.created_at, Date::now().as_millis() / 1000, max_age) {
+        return responses::error(
+            400,
+            "bundle_expired",
+            "Bundle is older than CONFIG_BUNDLE_MAX_AGE_SECS",
+        );
+    }
+    let dry_run = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "dry_run" && v != "0");
+
+    let mut skipped = Vec::new();
+    let mut applied = json!({ "keys": 0, "compliance": 0 });
+    let (mut added, mut removed) = (Vec::new(), Vec::new());
+    match env.kv(auth::KEYS_KV) {
+        Ok(kv) => {
+            (added, removed) = key_changes(&bundle.keys, &keys::records(&kv).await?);
+            if !dry_run {
+                for digest in &removed {
+                    kv.delete(&format!("{}{digest}", auth::KV_PREFIX)).await?;
+                }
+                for (digest, record) in &bundle.keys {
+                    keys::store(&kv, digest, record).await?;
+                }
+                applied["keys"] = bundle.keys.len().into();
+            }
+        }
+        Err(_) if !bundle.keys.is_empty() => skipped.push(auth::KEYS_KV),
+        Err(_) => {}
+    }
+    match env.kv(compliance::BLOCKLIST_KV) {
+        Ok(kv) if !dry_run => {
+            for (key, rule) in &bundle.compliance {
+                kv.put(key, rule)?.execute().await?;
+            }
+            applied["compliance"] = bundle.compliance.len().into();
+        }
+        Ok(_) => {}
+        Err(_) if !bundle.compliance.is_empty() => skipped.push(compliance::BLOCKLIST_KV),
+        Err(_) => {}
+    }
+    console_log!(
+        "Config bundle from {} imported (dry run: {})",
+        bundle.created_at,
+        dry_run
+    );
+    responses::json(
+        200,
+        &json!({
+            "dry_run": dry_run,
+            "applied": applied,
+            "added_keys": added,
+            "removed_keys": removed,
+            "skipped": skipped,
+            "config_differences": config_differences(&bundle.config, &current_config(env)),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> Bundle {
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "ab".repeat(32),
+            KeyRecord {
+                name: Some("team-a".into()),
+                slot: auth::Slot::Primary,
+            },
+        );
+        Bundle {
+            version: FORMAT_VERSION,
+            created_at: 1_700_000_000,
+            config: BTreeMap::from([("SLO_TARGET".to_string(), "0.999".to_string())]),
+            keys,
+            compliance: BTreeMap::new(),
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_roundtrip() {
+        let mut original = bundle();
+        original.sign("shared");
+        let parsed: Bundle =
+            serde_json::from_str(&serde_json::to_string(&original).unwrap()).unwrap();
+        assert!(parsed.verify("shared"));
+        assert!(!parsed.verify("other"));
+
+        let mut tampered = parsed;
+        tampered.config.insert("SLO_TARGET".into(), "0.5".into());
+        assert!(!tampered.verify("shared"));
+    }
+
+    #[test]
+    fn test_expired() {
+        let week = DEFAULT_MAX_AGE_SECS;
+        assert!(!expired(1_000, 1_000 + week, week));
+        assert!(expired(1_000, 1_001 + week, week));
+        assert!(!expired(2_000, 1_000, 0));
+    }
+
+    #[test]
+    fn test_key_changes() {
+        let bundle = bundle();
+        let kept = bundle.keys.iter().next().unwrap();
+        let newer = KeyRecord {
+            name: Some("created-later".into()),
+            slot: auth::Slot::Secondary,
+        };
+        let current = vec![(kept.0.clone(), kept.1.clone()), ("cd".repeat(32), newer)];
+        let (added, removed) = key_changes(&bundle.keys, &current);
+        assert!(added.is_empty());
+        assert_eq!(removed, vec!["cd".repeat(32)]);
+        let (added, removed) = key_changes(&bundle.keys, &[]);
+        assert_eq!(added, vec![kept.0.clone()]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn test_config_differences() {
+        let bundle = BTreeMap::from([
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2".to_string()),
+        ]);
+        let current = BTreeMap::from([
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "3".to_string()),
+            ("C".to_string(), "x".to_string()),
+        ]);
+        assert_eq!(config_differences(&bundle, &current), vec!["B", "C"]);
+    }
+
+    #[test]
+    fn test_settings_exclude_secrets() {
+        for secret in [
+            "ADMIN_KEY",
+            "API_KEYS",
+            "URL_SIGNING_SECRET",
+            "TURNSTILE_SECRET",
+        ] {
+            assert!(!SETTINGS.contains(&secret));
+        }
+    }
+}
@@ -0,0 +1,1035 @@
+//! Edge cache for repeated GETs (Cache API).
+//!
+//! With `CACHE_TTL` set (seconds) successful GET responses are kept in the
+//! colo's Cache API under the target URL, and later requests for the same
+//! target are answered from there without an upstream fetch.
+//! `X-Proxyflare-Cache-TTL` overrides the TTL for one request; `0` skips
+//! the cache altogether. The upstream response is stored as received, so
+//! per-request processing (CORS, watermarks, date normalization...) still
+//! runs on hits, and `Age` counts the time the entry spent in storage.
+//!
+//! Keys come from the normalized target: query parameters sorted, tracking
+//! parameters ([`TRACKING_PARAMS`] plus `CACHE_IGNORED_PARAMS`, a
+//! comma-separated list where a trailing `*` matches a prefix) dropped, the
+//! fragment removed. Client headers are not part of the key, except those
+//! named in `CACHE_KEY_HEADERS` (`accept-language`, say) for upstreams that
+//! negotiate on them.
+//!
+//! `CACHE_TTL_RULES` sets TTLs by upstream response, first match wins:
+//! `[{"content_type": "image/*", "ttl": 86400}, {"content_type":
+//! "application/json", "ttl": 60}, {"content_type": "text/html", "ttl": 0},
+//! {"status": 404, "ttl": 30}]`. A rule matches on `status`, on the media
+//! type (`type/*` for a whole family) or both; `ttl: 0` keeps matching
+//! responses out of the cache. Responses no rule matches use `CACHE_TTL`
+//! when they are 200s. The control header applies to 200s and overrides
+//! the rules.
+//!
+//! With `CACHE_STALE_SECS` an entry stays that much longer past its TTL:
+//! a request in that window is answered from the stale copy at once while
+//! the entry is refreshed in the background (stale-while-revalidate), so
+//! upstream latency spikes and short outages don't reach clients.
+//!
+//! Entries past both windows are revalidated rather than refetched: the
+//! proxy sends the stored `ETag`/`Last-Modified` upstream as
+//! `If-None-Match`/`If-Modified-Since`, and a `304` renews the entry's TTL
+//! without transferring the body again (background refreshes do the same).
+//! `CACHE_REVALIDATE_SECS` keeps entries that carry a validator that much
+//! longer in the Cache API so there is something left to revalidate.
+//!
+//! `CACHE_NEGATIVE_TTL` (seconds) caches 404s and 410s no rule covers, and
+//! upstream connection failures as a `502 upstream_unreachable`, so a burst
+//! of requests for a dead URL reaches the upstream once per TTL. Keep it
+//! short. `X-Proxyflare-No-Negative-Cache: true` ignores cached error
+//! responses (any status of 400 or above) for one request.
+//!
+//! `X-Proxyflare-No-Cache: true` skips the lookup and fetches from the
+//! upstream, still storing the result; with `CACHE_HONOR_CLIENT_NO_CACHE=true`
+//! a client `Cache-Control: no-cache` (or `Pragma: no-cache`) does the same.
+//!
+//! `DELETE /cache?url=<target>` (admin key required) evicts a target's
+//! entry. The Cache API is per data center, so this only reaches the colo
+//! that serves the purge; entries elsewhere expire with their TTL. Targets
+//! keyed on `CACHE_KEY_HEADERS` are evicted for the header values sent
+//! with the purge.
+//!
+//! With a `CACHE_KV` namespace bound, entries whose body is at most
+//! `CACHE_KV_MAX_BYTES` (default 64 KiB) are also written to KV (status,
+//! headers and body in one value), which is consulted when the colo's Cache
+//! API misses. KV entries survive isolate restarts and are visible from every
+//! colo, at KV's eventual consistency: a purge can take up to a minute to
+//! reach other locations.
+//!
+//! With an R2 bucket bound as `CACHE_BUCKET`, responses declaring a
+//! `Content-Length` of at least `CACHE_R2_MIN_BYTES` (default 10 MiB) are
+//! streamed into R2 instead, under a hash of the cache key, and streamed
+//! back out on hits: videos and archives too big for the Cache API or KV.
+//! R2 has no expiry of its own, so entries past their retention are ignored
+//! until overwritten; a bucket lifecycle rule cleans up the rest.
+//!
+//! Once caching is configured every response says what the cache did in
+//! `X-Proxyflare-Cache` ([`Status`]), and responses served from a stored
+//! entry carry its age in seconds in `X-Proxyflare-Cache-Age`.
+//!
+//! Nothing is cached for requests forwarding credentials (`Authorization`,
+//! `Cookie`) or a `Range` upstream, nor responses that set cookies, say
+//! `no-store` or `private`, or carry `Vary: *`. Stored copies are cut off at
+//! `MAX_RESPONSE_BYTES` like the client's body, and the write is abandoned.
+
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use serde_json::json;
+
+use crate::{
+    auth, coalesce, config, context::RequestCtx, header_rules, responses, streams, target,
+};
+
+pub const PURGE_PATH: &str = "/cache";
+pub const STATUS_HEADER: &str = "X-Proxyflare-Cache";
+pub const AGE_HEADER: &str = "X-Proxyflare-Cache-Age";
+
+/// Epoch milliseconds the entry was stored, on stored copies only.
+const STORED_AT_HEADER: &str = "X-Proxyflare-Stored-At";
+/// The upstream's own `Cache-Control`, replaced by the TTL while stored.
+const UPSTREAM_CACHE_CONTROL_HEADER: &str = "X-Proxyflare-Upstream-Cache-Control";
+/// Epoch milliseconds the entry's TTL ends.
+const EXPIRES_AT_HEADER: &str = "X-Proxyflare-Expires-At";
+const KEY_BASE: &str = "https://cache.proxyflare.internal/";
+const KV_BINDING: &str = "CACHE_KV";
+const DEFAULT_KV_MAX_BYTES: u64 = 64 * 1024;
+/// Shortest expiration KV accepts.
+const MIN_KV_TTL_SECS: u64 = 60;
+pub const R2_BINDING: &str = "CACHE_BUCKET";
+pub const R2_PREFIX: &str = "cache/";
+const DEFAULT_R2_MIN_BYTES: u64 = 10 * 1024 * 1024;
+/// R2 limits custom metadata to 2 KiB.
+const R2_MAX_METADATA_BYTES: usize = 2048;
+
+/// Query parameters that only identify campaigns, dropped from cache keys.
+pub const TRACKING_PARAMS: &[&str] = &[
+    "utm_*", "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "_ga", "_gl", "yclid",
+];
+
+/// How a response relates to the cache, for [`STATUS_HEADER`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// Served from a fresh entry.
+    Hit,
+    /// Fetched from the upstream (and stored, if cacheable).
+    Miss,
+    /// Served from an entry past its TTL while it is refreshed.
+    Stale,
+    /// The cache wasn't consulted: a no-cache request, or one that can't be
+    /// cached (credentials, `Range`, non-GET...).
+    Bypass,
+    /// An expired entry the upstream confirmed unchanged with a 304.
+    Revalidated,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Hit => "HIT",
+            Status::Miss => "MISS",
+            Status::Stale => "STALE",
+            Status::Bypass => "BYPASS",
+            Status::Revalidated => "REVALIDATED",
+        }
+    }
+}
+
+/// Whether any caching is configured.
+pub fn is_enabled(env: &Env) -> bool {
+    ["CACHE_TTL", "CACHE_TTL_RULES", "CACHE_NEGATIVE_TTL"]
+        .iter()
+        .any(|name| config::var(env, name).is_some())
+}
+
+/// A `CACHE_TTL_RULES` entry.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TtlRule {
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub ttl: u64,
+}
+
+impl TtlRule {
+    pub fn matches(&self, status: u16, content_type: Option<&str>) -> bool {
+        let essence = content_type
+            .and_then(|ct| ct.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.status.is_none_or(|s| s == status)
+            && self.content_type.as_deref().is_none_or(|pattern| {
+                let pattern = pattern.trim().to_ascii_lowercase();
+                match pattern.strip_suffix('*') {
+                    Some(prefix) => essence.starts_with(prefix),
+                    None => essence == pattern,
+                }
+            })
+    }
+}
+
+/// Where and for how long a request's response is cached.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub key: String,
+    /// TTL from the control header, overriding the rules.
+    pub override_ttl: Option<u64>,
+    /// `CACHE_TTL`.
+    pub default_ttl: Option<u64>,
+    pub rules: Vec<TtlRule>,
+    /// How long past its TTL an entry may still be served while refreshing.
+    pub stale_secs: u64,
+    /// How long past the stale window an entry with validators is kept
+    /// for revalidation.
+    pub revalidate_secs: u64,
+    /// `CACHE_NEGATIVE_TTL`, for 404s, 410s and upstream failures.
+    pub negative_ttl: Option<u64>,
+    /// Fetch from the upstream without looking in the cache.
+    pub bypass: bool,
+    /// Ignore cached error responses.
+    pub skip_negative: bool,
+    /// `MAX_RESPONSE_BYTES`: a stored copy growing past it is abandoned,
+    /// like the client's.
+    pub max_body_bytes: Option<u64>,
+}
+
+impl Plan {
+    /// Seconds to keep a response with `status` and `content_type`; `None`
+    /// when it isn't cached.
+    pub fn ttl_for(&self, status: u16, content_type: Option<&str>) -> Option<u64> {
+        let ttl = match self.override_ttl {
+            Some(ttl) => (status == 200).then_some(ttl),
+            None => match self.rules.iter().find(|r| r.matches(status, content_type)) {
+                Some(rule) => Some(rule.ttl),
+                None => self.default_ttl.filter(|_| status == 200),
+            },
+        };
+        ttl.or_else(|| self.negative_ttl.filter(|_| matches!(status, 404 | 410)))
+            .filter(|ttl| *ttl > 0)
+    }
+}
+
+/// A response served from the cache.
+pub struct Hit {
+    pub response: Response,
+    /// When the upstream response was fetched (epoch ms).
+    pub stored_at_ms: u64,
+    /// Past its TTL; the caller should refresh it.
+    pub stale: bool,
+    /// Past the stale window too; the caller must revalidate it before use.
+    pub expired: bool,
+}
+
+/// Status and headers of a stored response outside the Cache API: the head
+/// of a `CACHE_KV` value (the body follows after a newline), or R2 metadata.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Entry {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// The storage tiers besides the Cache API that are bound.
+struct Tiers {
+    /// `CACHE_KV` and the largest body it takes.
+    kv: Option<(KvStore, u64)>,
+    /// `CACHE_BUCKET` and the smallest body it takes.
+    r2: Option<(Bucket, u64)>,
+}
+
+fn tiers(env: &Env) -> Tiers {
+    Tiers {
+        kv: env.kv(KV_BINDING).ok().map(|kv| {
+            let max_bytes =
+                config::var_u64(env, "CACHE_KV_MAX_BYTES").unwrap_or(DEFAULT_KV_MAX_BYTES);
+            (kv, max_bytes)
+        }),
+        r2: env.bucket(R2_BINDING).ok().map(|bucket| {
+            let min_bytes =
+                config::var_u64(env, "CACHE_R2_MIN_BYTES").unwrap_or(DEFAULT_R2_MIN_BYTES);
+            (bucket, min_bytes)
+        }),
+    }
+}
+
+/// KV keys are limited to 512 bytes, so entries go under a hash of the key.
+fn kv_key(key: &str) -> String {
+    format!("cache:{}", auth::sha256_hex(key))
+}
+
+fn r2_key(key: &str) -> String {
+    format!("{R2_PREFIX}{}", auth::sha256_hex(key))
+}
+
+/// R2 custom metadata for `entry`, kept until `retain_until_ms`; `None` if
+/// the headers don't fit.
+fn r2_metadata(entry: &Entry, retain_until_ms: u64) -> Option<HashMap<String, String>> {
+    let entry = serde_json::to_string(entry).ok()?;
+    (entry.len() < R2_MAX_METADATA_BYTES - 64).then(|| {
+        HashMap::from([
+            ("entry".to_string(), entry),
+            ("retain_until".to_string(), retain_until_ms.to_string()),
+        ])
+    })
+}
+
+/// Whether an R2 object with `metadata` is past its retention at `now_ms`
+/// (or was never given one) and will only ever miss.
+pub fn r2_stale(metadata: &HashMap<String, String>, now_ms: u64) -> bool {
+    metadata
+        .get("retain_until")
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_none_or(|retain_until| now_ms >= retain_until)
+}
+
+/// The entry in R2 `metadata`, unless it is past its retention at `now_ms`.
+fn r2_entry(metadata: &HashMap<String, String>, now_ms: u64) -> Option<Entry> {
+    if r2_stale(metadata, now_ms) {
+        return None;
+    }
+    serde_json::from_str(metadata.get("entry")?).ok()
+}
+
+fn headers_of(entry: &Entry) -> Result<Headers> {
+    let headers = Headers::new();
+    for (name, value) in &entry.headers {
+        headers.append(name, value)?;
+    }
+    Ok(headers)
+}
+
+fn encode(entry: &Entry, body: &[u8]) -> Vec<u8> {
+    // Compact JSON escapes newlines, so the first one ends the head.
+    let mut value = serde_json::to_vec(entry).unwrap_or_default();
+    value.push(b'\n');
+    value.extend_from_slice(body);
+    value
+}
+
+fn decode(value: &[u8]) -> Option<(Entry, &[u8])> {
+    let split = value.iter().position(|b| *b == b'\n')?;
+    let entry = serde_json::from_slice(&value[..split]).ok()?;
+    Some((entry, &value[split + 1..]))
+}
+
+/// The body of `response`, or `None` once it grows past `max_bytes`.
+async fn read_limited(response: &mut Response, max_bytes: u64) -> Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    let mut stream = response.stream()?;
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() as u64 > max_bytes {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body))
+}
+
+async fn kv_lookup(kv: &KvStore, key: &str) -> Result<Option<Response>> {
+    let Some(value) = kv.get(&kv_key(key)).bytes().await? else {
+        return Ok(None);
+    };
+    let Some((entry, body)) = decode(&value) else {
+        return Ok(None);
+    };
+    Ok(Some(
+        Response::from_bytes(body.to_vec())?
+            .with_status(entry.status)
+            .with_headers(headers_of(&entry)?),
+    ))
+}
+
+async fn r2_lookup(bucket: &Bucket, key: &str) -> Result<Option<Response>> {
+    let Some(object) = bucket.get(r2_key(key)).execute().await? else {
+        return Ok(None);
+    };
+    let (Some(entry), Some(body)) = (
+        r2_entry(&object.custom_metadata()?, Date::now().as_millis()),
+        object.body(),
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some(
+        Response::from_body(body.response_body()?)?
+            .with_status(entry.status)
+            .with_headers(headers_of(&entry)?),
+    ))
+}
+
+async fn r2_put(
+    bucket: &Bucket,
+    key: &str,
+    mut stored: Response,
+    length: u64,
+    retain_secs: u64,
+) -> Result<()> {
+    let entry = Entry {
+        status: stored.status_code(),
+        headers: stored.headers().entries().collect(),
+    };
+    let retain_until_ms = Date::now().as_millis() + retain_secs * 1000;
+    let Some(metadata) = r2_metadata(&entry, retain_until_ms) else {
+        return Ok(());
+    };
+    bucket
+        .put(
+            r2_key(key),
+            FixedLengthStream::wrap(stored.stream()?, length),
+        )
+        .custom_metadata(metadata)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+async fn kv_put(
+    kv: &KvStore,
+    max_bytes: u64,
+    key: &str,
+    stored: &mut Response,
+    retain_secs: u64,
+) -> Result<()> {
+    let Some(body) = read_limited(stored, max_bytes).await? else {
+        return Ok(());
+    };
+    let entry = Entry {
+        status: stored.status_code(),
+        headers: stored.headers().entries().collect(),
+    };
+    kv.put_bytes(&kv_key(key), &encode(&entry, &body))?
+        .expiration_ttl(retain_secs.max(MIN_KV_TTL_SECS))
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Headers not taken over from a `304` when updating a stored response
+/// (RFC 9111 §3.2): they describe the body, which the 304 doesn't carry.
+const KEPT_ON_REVALIDATION: &[&str] = &[
+    "content-encoding",
+    "content-length",
+    "content-range",
+    "content-type",
+    "transfer-encoding",
+];
+
+/// Conditional request headers that revalidate a stored response with
+/// `etag`/`last_modified`.
+pub fn validators(
+    etag: Option<String>,
+    last_modified: Option<String>,
+) -> Vec<(&'static str, String)> {
+    let mut validators = Vec::new();
+    if let Some(etag) = etag {
+        validators.push(("If-None-Match", etag));
+    }
+    if let Some(last_modified) = last_modified {
+        validators.push(("If-Modified-Since", last_modified));
+    }
+    validators
+}
+
+/// Replace the conditional headers in `request_headers` with the validators
+/// of `stored`, if it has any. Returns whether it did.
+pub fn condition(request_headers: &Headers, stored: &Headers) -> Result<bool> {
+    let validators = validators(stored.get("ETag")?, stored.get("Last-Modified")?);
+    if validators.is_empty() {
+        return Ok(false);
+    }
+    request_headers.delete("If-None-Match")?;
+    request_headers.delete("If-Modified-Since")?;
+    for (name, value) in &validators {
+        request_headers.set(name, value)?;
+    }
+    Ok(true)
+}
+
+/// `stored` updated with the headers of the `304` that revalidated it.
+pub fn revalidated(stored: Response, not_modified: &Response) -> Result<Response> {
+    let headers = stored.headers().clone();
+    for (name, value) in not_modified.headers() {
+        if !KEPT_ON_REVALIDATION.contains(&name.as_str()) {
+            headers.set(&name, &value)?;
+        }
+    }
+    Ok(stored.with_headers(headers))
+}
+
+/// `target` with sorted query parameters and without `ignored` ones or the
+/// fragment. The host is already lowercase and default ports dropped.
+pub fn normalize(target: &Url, ignored: &[String]) -> Url {
+    let mut normalized = target.clone();
+    normalized.set_fragment(None);
+    let mut pairs: Vec<(String, String)> = target
+        .query_pairs()
+        .filter(|(k, _)| !header_rules::listed(&k.to_ascii_lowercase(), ignored))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    pairs.sort();
+    if pairs.is_empty() {
+        normalized.set_query(None);
+    } else {
+        normalized.query_pairs_mut().clear().extend_pairs(&pairs);
+    }
+    normalized
+}
+
+/// Cache API key for the normalized target and the values of the
+/// `CACHE_KEY_HEADERS` (`key_headers`, lowercase names).
+pub fn key(normalized: &Url, key_headers: &[(String, String)]) -> String {
+    let mut key = Url::parse(KEY_BASE).expect("valid URL");
+    key.query_pairs_mut()
+        .append_pair("url", normalized.as_str())
+        .extend_pairs(key_headers.iter().map(|(k, v)| (format!("h.{k}"), v)));
+    key.into()
+}
+
+/// Whether a request may be answered from and stored in the cache, given
+/// the names of the headers sent upstream. Credentials the proxy consumed
+/// itself (API keys, the Basic gate, JWTs) aren't among them, so
+/// authenticated callers still share cached responses.
+pub fn request_cacheable(
+    method: &Method,
+    upstream_headers: impl IntoIterator<Item = impl AsRef<str>>,
+) -> bool {
+    *method == Method::Get
+        && !upstream_headers.into_iter().any(|name| {
+            ["authorization", "cookie", "range"]
+                .iter()
+                .any(|h| name.as_ref().eq_ignore_ascii_case(h))
+        })
+}
+
+/// Whether the client's `Cache-Control`/`Pragma` ask for a fresh response.
+pub fn client_no_cache(cache_control: Option<&str>, pragma: Option<&str>) -> bool {
+    let has = |value: Option<&str>, directive: &str| {
+        value.is_some_and(|v| {
+            v.split(',')
+                .any(|d| d.trim().eq_ignore_ascii_case(directive))
+        })
+    };
+    has(cache_control, "no-cache") || has(cache_control, "max-age=0") || has(pragma, "no-cache")
+}
+
+/// Whether an upstream response's headers allow storing it.
+pub fn storable(cache_control: Option<&str>, vary: Option<&str>, sets_cookie: bool) -> bool {
+    let cache_control = cache_control.unwrap_or_default().to_ascii_lowercase();
+    !sets_cookie
+        && !cache_control
+            .split(',')
+            .any(|d| matches!(d.trim(), "no-store" | "private"))
+        && !vary.is_some_and(|v| v.split(',').any(|m| m.trim() == "*"))
+}
+
+/// The key for `target` requested with the client `headers`.
+pub fn key_for(env: &Env, headers: &Headers, target: &Url) -> Result<String> {
+    let mut ignored: Vec<String> = TRACKING_PARAMS.iter().map(|p| p.to_string()).collect();
+    ignored.extend(
+        config::var_list(env, "CACHE_IGNORED_PARAMS")
+            .into_iter()
+            .map(|p| p.to_ascii_lowercase()),
+    );
+    let mut key_headers = Vec::new();
+    for name in config::var_list(env, "CACHE_KEY_HEADERS") {
+        let name = name.to_ascii_lowercase();
+        let value = headers.get(&name)?.unwrap_or_default();
+        key_headers.push((name, value));
+    }
+    Ok(key(&normalize(target, &ignored), &key_headers))
+}
+
+/// The cache plan for this request, if it uses the cache. `headers` are the
+/// client's request headers, `upstream` the ones forwarded to the target.
+pub fn plan(
+    env: &Env,
+    rctx: &RequestCtx,
+    headers: &Headers,
+    upstream: &Headers,
+    target: &Url,
+) -> Result<Option<Plan>> {
+    let override_ttl = rctx.flags.controls.cache_ttl.map(|ttl| ttl.max(0) as u64);
+    if override_ttl == Some(0) {
+        return Ok(None);
+    }
+    let default_ttl = config::var_u64(env, "CACHE_TTL").filter(|ttl| *ttl > 0);
+    let rules: Vec<TtlRule> = config::var_json(env, "CACHE_TTL_RULES").unwrap_or_default();
+    let negative_ttl = config::var_u64(env, "CACHE_NEGATIVE_TTL").filter(|ttl| *ttl > 0);
+    if override_ttl.is_none() && default_ttl.is_none() && rules.is_empty() && negative_ttl.is_none()
+    {
+        return Ok(None);
+    }
+    // Parallel ranges and language fallbacks fetch differently shaped bodies.
+    if rctx.flags.parallel.is_some()
+        || !rctx.flags.languages.is_empty()
+        || !request_cacheable(
+            &rctx.method,
+            upstream
+                .keys()
+                .collect::<Vec<_>>()
+                .iter()
+                .map(String::as_str),
+        )
+    {
+        return Ok(None);
+    }
+    let bypass = rctx.flags.controls.no_cache
+        || (config::var(env, "CACHE_HONOR_CLIENT_NO_CACHE").as_deref() == Some("true")
+            && client_no_cache(
+                headers.get("Cache-Control")?.as_deref(),
+                headers.get("Pragma")?.as_deref(),
+            ));
+    Ok(Some(Plan {
+        key: key_for(env, headers, target)?,
+        override_ttl,
+        default_ttl,
+        rules,
+        stale_secs: config::var_u64(env, "CACHE_STALE_SECS").unwrap_or(0),
+        revalidate_secs: config::var_u64(env, "CACHE_REVALIDATE_SECS").unwrap_or(0),
+        negative_ttl,
+        bypass,
+        skip_negative: rctx.flags.controls.no_negative_cache,
+        max_body_bytes: config::var_u64(env, "MAX_RESPONSE_BYTES"),
+    }))
+}
+
+/// The stored response for `plan`, with the upstream's headers restored.
+pub async fn lookup(env: &Env, plan: &Plan) -> Result<Option<Hit>> {
+    let tiers = tiers(env);
+    let mut stored = Cache::default().get(&plan.key, false).await?;
+    if let (None, Some((kv, _))) = (&stored, &tiers.kv) {
+        stored = kv_lookup(kv, &plan.key).await?;
+    }
+    if let (None, Some((bucket, _))) = (&stored, &tiers.r2) {
+        stored = r2_lookup(bucket, &plan.key).await?;
+    }
+    let Some(stored) = stored.filter(|s| !(plan.skip_negative && s.status_code() >= 400)) else {
+        return Ok(None);
+    };
+    let headers = Headers::new();
+    let mut stored_at_ms = None;
+    let mut expires_at_ms = None;
+    let mut upstream_cache_control = None;
+    for (name, value) in stored.headers() {
+        match name.as_str() {
+            "x-proxyflare-stored-at" => stored_at_ms = value.parse().ok(),
+            "x-proxyflare-expires-at" => expires_at_ms = value.parse().ok(),
+            "x-proxyflare-upstream-cache-control" => upstream_cache_control = Some(value),
+            "cache-control" | "cf-cache-status" => {}
+            _ => headers.append(&name, &value)?,
+        }
+    }
+    if let Some(cache_control) = upstream_cache_control {
+        headers.set("Cache-Control", &cache_control)?;
+    }
+    let now_ms = Date::now().as_millis();
+    Ok(Some(Hit {
+        // The body is handed on as is, so R2 bodies stream without passing
+        // through the worker.
+        response: stored.with_headers(headers),
+        stored_at_ms: stored_at_ms.unwrap_or(now_ms),
+        stale: is_stale(expires_at_ms, now_ms),
+        expired: is_stale(
+            expires_at_ms.map(|expires| expires + plan.stale_secs * 1000),
+            now_ms,
+        ),
+    }))
+}
+
+/// The copy of a fresh upstream `response` to store and how many seconds
+/// to keep it, if it is cacheable.
+fn prepare(plan: &Plan, response: &mut Response, now_ms: u64) -> Result<Option<(Response, u64)>> {
+    let headers = response.headers();
+    let Some(ttl_secs) = plan.ttl_for(
+        response.status_code(),
+        headers.get("Content-Type")?.as_deref(),
+    ) else {
+        return Ok(None);
+    };
+    if !storable(
+        headers.get("Cache-Control")?.as_deref(),
+        headers.get("Vary")?.as_deref(),
+        headers.has("Set-Cookie")?,
+    ) {
+        return Ok(None);
+    }
+    copy_for_storage(plan, response, ttl_secs, now_ms).map(Some)
+}
+
+/// The copy of `response` to store for `ttl_secs`, and how many seconds to
+/// keep it.
+fn copy_for_storage(
+    plan: &Plan,
+    response: &mut Response,
+    ttl_secs: u64,
+    now_ms: u64,
+) -> Result<(Response, u64)> {
+    let mut copy = response.cloned()?;
+    let stored_headers = Headers::new();
+    for (name, value) in copy.headers() {
+        match name.as_str() {
+            "cache-control" => stored_headers.set(UPSTREAM_CACHE_CONTROL_HEADER, &value)?,
+            _ => stored_headers.append(&name, &value)?,
+        }
+    }
+    // The Cache API evicts at max-age, so stale entries need the extra time,
+    // and entries with validators more for revalidation.
+    let revalidate_secs = if stored_headers.has("ETag")? || stored_headers.has("Last-Modified")? {
+        plan.revalidate_secs
+    } else {
+        0
+    };
+    let retain_secs = ttl_secs + plan.stale_secs + revalidate_secs;
+    stored_headers.set("Cache-Control", &format!("public, max-age={retain_secs}"))?;
+    stored_headers.set(STORED_AT_HEADER, &now_ms.to_string())?;
+    stored_headers.set(EXPIRES_AT_HEADER, &(now_ms + ttl_secs * 1000).to_string())?;
+    let body: streams::Body = Box::pin(copy.stream()?);
+    // A chunked body over the limit errors out here, which aborts the write
+    // to every tier.
+    let body: streams::Body = match plan.max_body_bytes {
+        Some(limit) => Box::pin(streams::LimitedStream::new(body, limit)),
+        None => body,
+    };
+    Ok((
+        Response::from_stream(body)?
+            .with_status(copy.status_code())
+            .with_headers(stored_headers),
+        retain_secs,
+    ))
+}
+
+async fn put(tiers: Tiers, key: &str, (mut stored, retain_secs): (Response, u64)) {
+    let length = stored
+        .headers()
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse::<u64>().ok());
+    if let (Some((bucket, min_bytes)), Some(length)) = (&tiers.r2, length) {
+        if length >= *min_bytes {
+            if let Err(e) = r2_put(bucket, key, stored, length, retain_secs).await {
+                console_error!("Caching response for {} in R2 failed: {:?}", key, e);
+            }
+            return;
+        }
+    }
+    if let Some((kv, max_bytes)) = tiers.kv {
+        let written = match stored.cloned() {
+            Ok(mut copy) => kv_put(&kv, max_bytes, key, &mut copy, retain_secs).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            console_error!("Caching response for {} in KV failed: {:?}", key, e);
+        }
+    }
+    if let Err(e) = Cache::default().put(key, stored).await {
+        console_error!("Caching response for {} failed: {:?}", key, e);
+    }
+}
+
+/// Store a copy of a fresh upstream `response` in the background, if it is
+/// cacheable, releasing the requests waiting on `leader` once it is.
+pub fn store(
+    ctx: &Context,
+    env: &Env,
+    plan: &Plan,
+    response: &mut Response,
+    now_ms: u64,
+    leader: Option<coalesce::Leader>,
+) -> Result<()> {
+    if let Some(stored) = prepare(plan, response, now_ms)? {
+        let key = plan.key.clone();
+        let tiers = tiers(env);
+        ctx.wait_until(async move {
+            put(tiers, &key, stored).await;
+            drop(leader);
+        });
+    }
+    Ok(())
+}
+
+/// After the upstream couldn't be reached: with negative caching on, the
+/// `502` to answer with, stored in the background for later requests.
+pub fn store_failure(
+    ctx: &Context,
+    env: &Env,
+    plan: &Plan,
+    leader: Option<coalesce::Leader>,
+) -> Result<Option<Response>> {
+    let Some(ttl_secs) = plan.negative_ttl else {
+        return Ok(None);
+    };
+    let mut response = responses::error(
+        502,
+        "upstream_unreachable",
+        "The upstream could not be reached",
+    )?;
+    let stored = copy_for_storage(plan, &mut response, ttl_secs, Date::now().as_millis())?;
+    let key = plan.key.clone();
+    let tiers = tiers(env);
+    ctx.wait_until(async move {
+        put(tiers, &key, stored).await;
+        drop(leader);
+    });
+    Ok(Some(response))
+}
+
+/// After a stale hit, revalidate `stale` with `request` in the background
+/// and store the result. A failed or uncacheable refresh leaves the stale
+/// entry in place.
+pub fn refresh(
+    ctx: &Context,
+    env: &Env,
+    plan: &Plan,
+    request: Request,
+    stale: &mut Response,
+) -> Result<()> {
+    let plan = plan.clone();
+    let tiers = tiers(env);
+    let conditional = condition(request.headers(), stale.headers())?;
+    let stale = if conditional {
+        Some(stale.cloned()?)
+    } else {
+        None
+    };
+    ctx.wait_until(async move {
+        let now_ms = Date::now().as_millis();
+        let stored = match (Fetch::Request(request).send().await, stale) {
+            (Ok(response), Some(stale)) if response.status_code() == 304 => {
+                revalidated(stale, &response)
+                    .and_then(|mut fresh| prepare(&plan, &mut fresh, now_ms))
+            }
+            (Ok(mut response), _) => prepare(&plan, &mut response, now_ms),
+            (Err(e), _) => Err(e),
+        };
+        match stored {
+            Ok(Some(stored)) => put(tiers, &plan.key, stored).await,
+            Ok(None) => {}
+            Err(e) => console_error!("Refreshing {} failed: {:?}", plan.key, e),
+        }
+    });
+    Ok(())
+}
+
+/// `DELETE /cache?url=<target>`
+pub async fn admin_purge(req: &Request, env: &Env) -> Result<Response> {
+    let url = req.url()?;
+    let Some(target) = url
+        .query_pairs()
+        .find(|(k, _)| k == "url")
+        .and_then(|(_, v)| target::parse(&v))
+    else {
+        return responses::error(
+            400,
+            "invalid_request",
+            "Query parameter `url` must be an http(s) URL",
+        );
+    };
+    let key = key_for(env, req.headers(), &target)?;
+    let mut purged = matches!(
+        Cache::default().delete(&key, false).await?,
+        CacheDeletionOutcome::Success
+    );
+    let tiers = tiers(env);
+    if let Some((kv, _)) = tiers.kv {
+        let kv_key = kv_key(&key);
+        if kv.get(&kv_key).bytes().await?.is_some() {
+            kv.delete(&kv_key).await?;
+            purged = true;
+        }
+    }
+    if let Some((bucket, _)) = tiers.r2 {
+        let r2_key = r2_key(&key);
+        if bucket.head(r2_key.clone()).await?.is_some() {
+            bucket.delete(r2_key).await?;
+            purged = true;
+        }
+    }
+    responses::json(200, &json!({ "url": target.as_str(), "purged": purged }))
+}
+
+/// Whether an entry that expires at `expires_at_ms` is past its TTL.
+pub fn is_stale(expires_at_ms: Option<u64>, now_ms: u64) -> bool {
+    expires_at_ms.is_some_and(|expires| now_ms >= expires)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let target = Url::parse("https://example.com/a?b=1&c=2").unwrap();
+        assert_eq!(
+            key(&target, &[]),
+            "https://cache.proxyflare.internal/?url=https%3A%2F%2Fexample.com%2Fa%3Fb%3D1%26c%3D2"
+        );
+        let with_header = key(&target, &[("accept-language".into(), "de".into())]);
+        assert!(with_header.ends_with("&h.accept-language=de"));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let ignored: Vec<String> = TRACKING_PARAMS
+            .iter()
+            .map(|p| p.to_string())
+            .chain(["session".to_string()])
+            .collect();
+        let a = Url::parse("https://Example.COM:443/p?z=1&utm_source=x&a=2&fbclid=y#top").unwrap();
+        let b = Url::parse("https://example.com/p?a=2&session=s&z=1").unwrap();
+        assert_eq!(
+            normalize(&a, &ignored).as_str(),
+            "https://example.com/p?a=2&z=1"
+        );
+        assert_eq!(normalize(&a, &ignored), normalize(&b, &ignored));
+        let bare = Url::parse("https://example.com/p?utm_medium=m").unwrap();
+        assert_eq!(normalize(&bare, &ignored).as_str(), "https://example.com/p");
+    }
+
+    #[test]
+    fn test_storable() {
+        assert!(storable(
+            Some("public, max-age=60"),
+            Some("Accept-Encoding"),
+            false
+        ));
+        assert!(storable(None, None, false));
+        assert!(!storable(Some("private, max-age=60"), None, false));
+        assert!(!storable(Some("No-Store"), None, false));
+        assert!(!storable(None, Some("*"), false));
+        assert!(!storable(None, None, true));
+    }
+
+    #[test]
+    fn test_request_cacheable() {
+        // The client sent `Authorization: Bearer <api key>`, which the proxy
+        // consumed instead of forwarding.
+        let client = ["Authorization", "Accept"];
+        let upstream = ["accept", "x-forwarded-for"];
+        assert!(!request_cacheable(&Method::Get, client));
+        assert!(request_cacheable(&Method::Get, upstream));
+        assert!(!request_cacheable(
+            &Method::Get,
+            ["accept", "authorization"]
+        ));
+        assert!(!request_cacheable(&Method::Get, ["range"]));
+        assert!(!request_cacheable(&Method::Post, upstream));
+    }
+
+    #[test]
+    fn test_client_no_cache() {
+        assert!(client_no_cache(Some("no-cache"), None));
+        assert!(client_no_cache(Some("max-age=0"), None));
+        assert!(client_no_cache(None, Some("no-cache")));
+        assert!(!client_no_cache(Some("max-age=60"), None));
+        assert!(!client_no_cache(None, None));
+    }
+
+    #[test]
+    fn test_is_stale() {
+        assert!(!is_stale(Some(2_000), 1_999));
+        assert!(is_stale(Some(2_000), 2_000));
+        assert!(!is_stale(None, 5_000));
+    }
+
+    #[test]
+    fn test_kv_entry() {
+        let entry = Entry {
+            status: 200,
+            headers: vec![("content-type".into(), "text/plain\nx".into())],
+        };
+        let value = encode(&entry, b"line 1\nline 2");
+        let (decoded, body) = decode(&value).unwrap();
+        assert_eq!(decoded, entry);
+        assert_eq!(body, b"line 1\nline 2");
+        assert!(decode(b"not json\nbody").is_none());
+        assert!(kv_key(&"k".repeat(2048)).len() < 512);
+    }
+
+    #[test]
+    fn test_r2_entry() {
+        let entry = Entry {
+            status: 200,
+            headers: vec![("content-type".into(), "video/mp4".into())],
+        };
+        let metadata = r2_metadata(&entry, 5_000).unwrap();
+        assert_eq!(r2_entry(&metadata, 4_999), Some(entry));
+        assert_eq!(r2_entry(&metadata, 5_000), None);
+        assert!(!r2_stale(&metadata, 4_999));
+        assert!(r2_stale(&metadata, 5_000));
+        assert!(r2_stale(&HashMap::new(), 0));
+        let huge = Entry {
+            status: 200,
+            headers: vec![("link".into(), "x".repeat(R2_MAX_METADATA_BYTES))],
+        };
+        assert!(r2_metadata(&huge, 5_000).is_none());
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(Status::Hit.as_str(), "HIT");
+        assert_eq!(Status::Revalidated.as_str(), "REVALIDATED");
+    }
+
+    #[test]
+    fn test_validators() {
+        assert_eq!(
+            validators(
+                Some("\"v1\"".into()),
+                Some("Sun, 06 Nov 1994 08:49:37 GMT".into())
+            ),
+            vec![
+                ("If-None-Match", "\"v1\"".to_string()),
+                (
+                    "If-Modified-Since",
+                    "Sun, 06 Nov 1994 08:49:37 GMT".to_string()
+                ),
+            ]
+        );
+        assert!(validators(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_ttl_for() {
+        let rules: Vec<TtlRule> = serde_json::from_str(
+            r#"[{"content_type": "image/*", "ttl": 86400},
+                {"content_type": "application/json", "ttl": 60},
+                {"content_type": "text/html", "ttl": 0},
+                {"status": 404, "ttl": 30}]"#,
+        )
+        .unwrap();
+        let plan = Plan {
+            default_ttl: Some(300),
+            rules,
+            ..Plan::default()
+        };
+        assert_eq!(plan.ttl_for(200, Some("image/png")), Some(86400));
+        assert_eq!(
+            plan.ttl_for(200, Some("application/json; charset=utf-8")),
+            Some(60)
+        );
+        assert_eq!(plan.ttl_for(200, Some("text/html")), None);
+        assert_eq!(plan.ttl_for(404, Some("text/plain")), Some(30));
+        assert_eq!(plan.ttl_for(200, Some("text/css")), Some(300));
+        assert_eq!(plan.ttl_for(500, None), None);
+        let forced = Plan {
+            override_ttl: Some(5),
+            ..plan
+        };
+        assert_eq!(forced.ttl_for(200, Some("text/html")), Some(5));
+        assert_eq!(forced.ttl_for(404, None), None);
+        let negative = Plan {
+            negative_ttl: Some(10),
+            ..forced
+        };
+        assert_eq!(negative.ttl_for(404, None), Some(10));
+        assert_eq!(negative.ttl_for(410, None), Some(10));
+        assert_eq!(negative.ttl_for(500, None), None);
+    }
+}
@@ -0,0 +1,550 @@
+//! Certificate expiry watch for upstream origins, run from the cron handler.
+//!
+//! `CERT_WATCH_HOSTS` lists origins (`host` or `host:port`, default port
+//! 443). Once an hour each one gets a TLS 1.2 handshake over a Workers TCP
+//! socket, just far enough to read the certificate the origin presents
+//! (`fetch()` doesn't expose it); its issuer and expiry are stored in
+//! `MONITOR_KV` (`cert:<host>`) and listed at `GET /admin/certs`. A
+//! certificate is flagged once it expires within `CERT_ALERT_DAYS` (default
+//! 14), and a JSON alert is POSTed to `MONITOR_ALERT_WEBHOOK` whenever a host
+//! changes between healthy and flagged. Origins that only speak TLS 1.3
+//! encrypt their certificate and are reported as unchecked errors.
+
+use std::future::poll_fn;
+use std::pin::{pin, Pin};
+use std::time::Duration;
+
+use futures_util::future::{join_all, select, Either};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+use crate::{config, responses, utils, xff};
+
+const RESULTS_KV: &str = "MONITOR_KV";
+const RESULT_TTL_SECS: u64 = 2 * 86_400;
+const DEFAULT_ALERT_DAYS: i64 = 14;
+/// Minute of the hour at which hosts are checked.
+const RUN_MINUTE: u64 = 45;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Give up on origins that send more than this before their certificate.
+const MAX_HANDSHAKE_BYTES: usize = 64 * 1024;
+
+const CIPHER_SUITES: &[u8] = &[
+    0xc0, 0x2b, 0xc0, 0x2f, 0xc0, 0x2c, 0xc0, 0x30, 0xcc, 0xa9, 0xcc, 0xa8, 0xc0, 0x13, 0xc0, 0x14,
+    0x00, 0x9c, 0x00, 0x9d, 0x00, 0x2f, 0x00, 0x35,
+];
+const SUPPORTED_GROUPS: &[u8] = &[0x00, 0x1d, 0x00, 0x17, 0x00, 0x18];
+const SIGNATURE_ALGORITHMS: &[u8] = &[
+    0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x04, 0x01, 0x05, 0x01,
+    0x06, 0x01, 0x02, 0x01,
+];
+const ALERT_PROTOCOL_VERSION: u8 = 70;
+
+/// X.520 attributes shown for the issuer.
+const ISSUER_ATTRIBUTES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x06], "C"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertInfo {
+    pub issuer: String,
+    /// Unix seconds.
+    pub not_after: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CertStatus {
+    pub host: String,
+    pub ok: bool,
+    pub issuer: Option<String>,
+    pub not_after: Option<String>,
+    pub days_left: Option<i64>,
+    pub error: Option<String>,
+    pub checked_at: String,
+}
+
+fn with_len16(bytes: &[u8]) -> Vec<u8> {
+    let mut out = (bytes.len() as u16).to_be_bytes().to_vec();
+    out.extend_from_slice(bytes);
+    out
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+/// A TLS 1.2 ClientHello record for `host` (with SNI unless it is an IP).
+pub fn client_hello(host: &str, random: [u8; 32]) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    let mut push = |kind: u16, data: &[u8]| {
+        extensions.extend(kind.to_be_bytes());
+        extensions.extend(with_len16(data));
+    };
+    if host.parse::<std::net::IpAddr>().is_err() {
+        let mut name = vec![0];
+        name.extend(with_len16(host.as_bytes()));
+        push(0x0000, &with_len16(&name));
+    }
+    push(0x000a, &with_len16(SUPPORTED_GROUPS));
+    push(0x000b, &[0x01, 0x00]);
+    push(0x000d, &with_len16(SIGNATURE_ALGORITHMS));
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend(random);
+    hello.push(0);
+    hello.extend(with_len16(CIPHER_SUITES));
+    hello.extend([0x01, 0x00]);
+    hello.extend(with_len16(&extensions));
+
+    let mut handshake = vec![0x01];
+    handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend(hello);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend(with_len16(&handshake));
+    record
+}
+
+/// Collects the server's handshake until its Certificate message arrives.
+#[derive(Default)]
+pub struct HandshakeReader {
+    records: Vec<u8>,
+    handshake: Vec<u8>,
+}
+
+impl HandshakeReader {
+    /// Feed bytes from the server; returns the leaf certificate (DER) once
+    /// it has arrived.
+    pub fn feed(&mut self, bytes: &[u8]) -> std::result::Result<Option<Vec<u8>>, String> {
+        self.records.extend_from_slice(bytes);
+        while self.records.len() >= 5 {
+            let len = u16::from_be_bytes([self.records[3], self.records[4]]) as usize;
+            if self.records.len() < 5 + len {
+                break;
+            }
+            let record: Vec<u8> = self.records.drain(..5 + len).collect();
+            match (record[0], record.get(6).copied()) {
+                (0x16, _) => self.handshake.extend_from_slice(&record[5..]),
+                (0x15, Some(ALERT_PROTOCOL_VERSION)) => {
+                    return Err("origin requires TLS 1.3; certificate not readable".into())
+                }
+                (0x15, alert) => return Err(format!("TLS alert {}", alert.unwrap_or_default())),
+                (kind, _) => return Err(format!("unexpected TLS record type {kind}")),
+            }
+        }
+        while self.handshake.len() >= 4 {
+            let len = u24(&self.handshake[1..4]);
+            if self.handshake.len() < 4 + len {
+                break;
+            }
+            let message: Vec<u8> = self.handshake.drain(..4 + len).collect();
+            if message[0] == 11 {
+                return first_certificate(&message[4..])
+                    .map(|cert| Some(cert.to_vec()))
+                    .ok_or_else(|| "malformed Certificate message".into());
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn first_certificate(body: &[u8]) -> Option<&[u8]> {
+    let len = u24(body.get(3..6)?);
+    body.get(6..6 + len)
+}
+
+/// One DER element: tag, contents and the bytes after it.
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        _ => {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0, |acc, &b| acc << 8 | b as usize);
+            (len, &rest[n..])
+        }
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// `UTCTime` (tag 0x17) or `GeneralizedTime` (0x18) as Unix seconds.
+pub fn parse_time(tag: u8, value: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let yy: i16 = text.get(..2)?.parse().ok()?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, text.get(2..)?)
+        }
+        0x18 => (text.get(..4)?.parse().ok()?, text.get(4..)?),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<i8> { rest.get(i..i + 2)?.parse().ok() };
+    let datetime = jiff::civil::DateTime::new(
+        year,
+        field(0)?,
+        field(2)?,
+        field(4)?,
+        field(6)?,
+        field(8)?,
+        0,
+    )
+    .ok()?;
+    let zoned = datetime.to_zoned(jiff::tz::TimeZone::UTC).ok()?;
+    Some(zoned.timestamp().as_second())
+}
+
+fn issuer_name(mut name: &[u8]) -> String {
+    let mut parts = Vec::new();
+    while let Some((0x31, set, rest)) = der(name) {
+        name = rest;
+        let Some((0x30, pair, _)) = der(set) else {
+            continue;
+        };
+        let Some((0x06, oid, value)) = der(pair) else {
+            continue;
+        };
+        let Some((_, value, _)) = der(value) else {
+            continue;
+        };
+        if let Some((_, label)) = ISSUER_ATTRIBUTES.iter().find(|(id, _)| *id == oid) {
+            parts.push(format!("{label}={}", String::from_utf8_lossy(value)));
+        }
+    }
+    parts.join(", ")
+}
+
+/// Issuer and expiry of a DER certificate.
+pub fn parse_certificate(cert: &[u8]) -> Option<CertInfo> {
+    let Some((0x30, cert, _)) = der(cert) else {
+        return None;
+    };
+    let Some((0x30, tbs, _)) = der(cert) else {
+        return None;
+    };
+    // Optional explicit version, then serial number and signature algorithm.
+    let fields = match der(tbs)? {
+        (0xa0, _, rest) => rest,
+        _ => tbs,
+    };
+    let (_, _, fields) = der(fields)?;
+    let (_, _, fields) = der(fields)?;
+    let Some((0x30, issuer, fields)) = der(fields) else {
+        return None;
+    };
+    let Some((0x30, validity, _)) = der(fields) else {
+        return None;
+    };
+    let (_, _, validity) = der(validity)?;
+    let (tag, not_after, _) = der(validity)?;
+    Some(CertInfo {
+        issuer: issuer_name(issuer),
+        not_after: parse_time(tag, not_after)?,
+    })
+}
+
+/// `host` or `host:port` to connect to.
+pub fn address(entry: &str) -> (String, u16) {
+    match entry.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (entry.to_string(), 443),
+        },
+        _ => (entry.to_string(), 443),
+    }
+}
+
+async fn write_all(socket: &mut Socket, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        let written = poll_fn(|cx| Pin::new(&mut *socket).poll_write(cx, data)).await?;
+        data = &data[written..];
+    }
+    poll_fn(|cx| Pin::new(&mut *socket).poll_flush(cx)).await
+}
+
+async fn read(socket: &mut Socket, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read_buf = ReadBuf::new(buf);
+    poll_fn(|cx| Pin::new(&mut *socket).poll_read(cx, &mut read_buf)).await?;
+    Ok(read_buf.filled().len())
+}
+
+async fn fetch_certificate(host: &str, port: u16) -> std::result::Result<Vec<u8>, String> {
+    let mut socket = ConnectionBuilder::new()
+        .connect(host, port)
+        .map_err(|e| e.to_string())?;
+    let mut rng = xff::Rng::from_entropy();
+    let mut random = [0u8; 32];
+    for chunk in random.chunks_mut(8) {
+        chunk.copy_from_slice(&rng.next_u64().to_be_bytes());
+    }
+    write_all(&mut socket, &client_hello(host, random))
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut reader = HandshakeReader::default();
+    let mut buf = [0u8; 4096];
+    let mut received = 0;
+    let result = loop {
+        let n = match read(&mut socket, &mut buf).await {
+            Ok(0) => break Err("connection closed before the certificate".into()),
+            Ok(n) => n,
+            Err(e) => break Err(e.to_string()),
+        };
+        received += n;
+        match reader.feed(&buf[..n]) {
+            Ok(Some(cert)) => break Ok(cert),
+            Ok(None) if received > MAX_HANDSHAKE_BYTES => {
+                break Err("no certificate in the first 64 KiB".into())
+            }
+            Ok(None) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = socket.close().await;
+    result
+}
+
+async fn check(entry: &str, alert_days: i64) -> CertStatus {
+    let (host, port) = address(entry);
+    let fetched = match select(
+        pin!(fetch_certificate(&host, port)),
+        pin!(Delay::from(TIMEOUT)),
+    )
+    .await
+    {
+        Either::Left((fetched, _)) => fetched,
+        Either::Right(_) => Err("timed out".into()),
+    };
+    let info = fetched.and_then(|cert| {
+        parse_certificate(&cert).ok_or_else(|| "unparsable certificate".to_string())
+    });
+    let now_secs = (Date::now().as_millis() / 1000) as i64;
+    match info {
+        Ok(info) => {
+            let days_left = (info.not_after - now_secs).div_euclid(86_400);
+            CertStatus {
+                host: entry.to_string(),
+                ok: days_left >= alert_days,
+                issuer: Some(info.issuer),
+                not_after: jiff::Timestamp::from_second(info.not_after)
+                    .ok()
+                    .map(|t| t.to_string()),
+                days_left: Some(days_left),
+                error: None,
+                checked_at: utils::now_iso(),
+            }
+        }
+        Err(error) => CertStatus {
+            host: entry.to_string(),
+            ok: false,
+            issuer: None,
+            not_after: None,
+            days_left: None,
+            error: Some(error),
+            checked_at: utils::now_iso(),
+        },
+    }
+}
+
+async fn watch(entry: String, alert_days: i64, kv: Option<&KvStore>, webhook: Option<&str>) {
+    let status = check(&entry, alert_days).await;
+    let key = format!("cert:{entry}");
+    let previous: Option<CertStatus> = match kv {
+        Some(kv) => kv.get(&key).json().await.ok().flatten(),
+        None => None,
+    };
+    if !status.ok {
+        console_warn!(
+            "Certificate check for {}: {} days left, error {:?}",
+            entry,
+            status.days_left.unwrap_or_default(),
+            status.error
+        );
+    }
+    if let Some(kv) = kv {
+        let stored = kv
+            .put(&key, &status)
+            .map(|p| p.expiration_ttl(RESULT_TTL_SECS));
+        if let Err(e) = match stored {
+            Ok(put) => put.execute().await,
+            Err(e) => Err(e),
+        } {
+            console_error!("Failed to store certificate status {}: {:?}", key, e);
+        }
+    }
+    let changed = previous
+        .as_ref()
+        .map_or(!status.ok, |prev| prev.ok != status.ok);
+    if let (true, Some(webhook)) = (changed, webhook) {
+        let body = json!({ "alert": "certificate_state_changed", "result": status });
+        match utils::json_request(webhook, Method::Post, &body) {
+            Ok(req) => {
+                if let Err(e) = Fetch::Request(req).send().await {
+                    console_error!("Certificate alert webhook failed: {:?}", e);
+                }
+            }
+            Err(e) => console_error!("Certificate alert webhook failed: {:?}", e),
+        }
+    }
+}
+
+/// Check every watched host once an hour.
+pub async fn run(env: &Env, scheduled_ms: u64) {
+    if (scheduled_ms / 60_000) % 60 != RUN_MINUTE {
+        return;
+    }
+    let hosts = config::var_list(env, "CERT_WATCH_HOSTS");
+    if hosts.is_empty() {
+        return;
+    }
+    let alert_days =
+        config::var_u64(env, "CERT_ALERT_DAYS").map_or(DEFAULT_ALERT_DAYS, |d| d as i64);
+    let kv = env.kv(RESULTS_KV).ok();
+    let webhook = config::var(env, "MONITOR_ALERT_WEBHOOK");
+    join_all(
+        hosts
+            .into_iter()
+            .map(|host| watch(host, alert_days, kv.as_ref(), webhook.as_deref())),
+    )
+    .await;
+}
+
+/// `GET /admin/certs`: latest status of every watched host.
+pub async fn admin_certs(env: &Env) -> Result<Response> {
+    let kv = env.kv(RESULTS_KV).ok();
+    let mut certs = Vec::new();
+    for host in config::var_list(env, "CERT_WATCH_HOSTS") {
+        let status: Option<CertStatus> = match &kv {
+            Some(kv) => kv.get(&format!("cert:{host}")).json().await?,
+            None => None,
+        };
+        certs.push(json!({ "host": host, "last": status }));
+    }
+    responses::json(200, &json!({ "certs": certs }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match contents.len() {
+            len @ 0..=0x7f => out.push(len as u8),
+            len => {
+                out.push(0x82);
+                out.extend((len as u16).to_be_bytes());
+            }
+        }
+        out.extend(contents);
+        out
+    }
+
+    fn certificate() -> Vec<u8> {
+        let attribute = |oid: &[u8], value: &str| {
+            let pair = [tlv(0x06, oid), tlv(0x0c, value.as_bytes())].concat();
+            tlv(0x31, &tlv(0x30, &pair))
+        };
+        let issuer = [
+            attribute(&[0x55, 0x04, 0x06], "US"),
+            attribute(&[0x55, 0x04, 0x0a], "Let's Encrypt"),
+            attribute(&[0x55, 0x04, 0x03], "R11"),
+        ]
+        .concat();
+        let validity = [tlv(0x17, b"240101000000Z"), tlv(0x18, b"20250401120000Z")].concat();
+        let tbs = [
+            tlv(0xa0, &tlv(0x02, &[2])),
+            tlv(0x02, &[0x01, 0x23]),
+            tlv(0x30, &tlv(0x06, &[0x2a, 0x86, 0x48])),
+            tlv(0x30, &issuer),
+            tlv(0x30, &validity),
+            tlv(0x30, &[0u8; 200]),
+        ]
+        .concat();
+        tlv(
+            0x30,
+            &[tlv(0x30, &tbs), tlv(0x30, &[]), tlv(0x03, &[0])].concat(),
+        )
+    }
+
+    #[test]
+    fn test_parse_certificate() {
+        let info = parse_certificate(&certificate()).unwrap();
+        assert_eq!(info.issuer, "C=US, O=Let's Encrypt, CN=R11");
+        assert_eq!(info.not_after, 1_743_508_800);
+        assert_eq!(parse_certificate(&[0x30, 0x05, 0x01]), None);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time(0x17, b"700101000000Z"), Some(0));
+        assert_eq!(parse_time(0x17, b"491231235959Z"), Some(2_524_607_999));
+        assert_eq!(parse_time(0x18, b"20250401120000Z"), Some(1_743_508_800));
+        assert_eq!(parse_time(0x17, b"not a time"), None);
+    }
+
+    #[test]
+    fn test_handshake_reader_finds_certificate_across_chunks() {
+        let cert = certificate();
+        let server_hello = [vec![0x02, 0, 0, 2], vec![0x03, 0x03]].concat();
+        let mut list = (cert.len() as u32).to_be_bytes()[1..].to_vec();
+        list.extend(&cert);
+        let mut body = (list.len() as u32).to_be_bytes()[1..].to_vec();
+        body.extend(list);
+        let mut message = vec![11];
+        message.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+        let handshake = [server_hello, message].concat();
+        // Split the handshake over two records, and the records over chunks.
+        let (first, second) = handshake.split_at(10);
+        let stream = [
+            [vec![0x16, 0x03, 0x03], with_len16(first)].concat(),
+            [vec![0x16, 0x03, 0x03], with_len16(second)].concat(),
+        ]
+        .concat();
+        let mut reader = HandshakeReader::default();
+        let mut found = None;
+        for chunk in stream.chunks(7) {
+            if let Some(cert) = reader.feed(chunk).unwrap() {
+                found = Some(cert);
+                break;
+            }
+        }
+        assert_eq!(found, Some(cert));
+    }
+
+    #[test]
+    fn test_handshake_reader_reports_alerts() {
+        let mut reader = HandshakeReader::default();
+        let alert = reader.feed(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 70]);
+        assert!(alert.unwrap_err().contains("TLS 1.3"));
+    }
+
+    #[test]
+    fn test_client_hello() {
+        let hello = client_hello("origin.example", [7; 32]);
+        assert_eq!(&hello[..3], &[0x16, 0x03, 0x01]);
+        assert_eq!(
+            u16::from_be_bytes([hello[3], hello[4]]) as usize,
+            hello.len() - 5
+        );
+        assert_eq!(u24(&hello[6..9]), hello.len() - 9);
+        assert!(hello.windows(14).any(|w| w == b"origin.example"));
+        let by_ip = client_hello("192.0.2.1", [7; 32]);
+        assert!(!by_ip.windows(9).any(|w| w == b"192.0.2.1"));
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(address("origin.example"), ("origin.example".into(), 443));
+        assert_eq!(
+            address("origin.example:8443"),
+            ("origin.example".into(), 8443)
+        );
+    }
+}
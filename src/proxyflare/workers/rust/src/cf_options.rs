@@ -0,0 +1,269 @@
+//! Cloudflare `cf` fetch options on upstream requests.
+//!
+//! `CF_FETCH_OPTIONS` sets them for every upstream fetch:
+//! `{"cacheEverything": true, "cacheTtl": 300, "cacheTtlByStatus":
+//! {"200-299": 86400, "404": 1, "500-599": 0}, "polish": "lossy",
+//! "minify": {"js": true, "css": true}, "mirage": false}`. They only take
+//! effect where the zone has the feature (Polish, Mirage...) available.
+//!
+//! With `CF_FETCH_OPTION_HEADERS=true` clients may also set them for one
+//! request, overriding the configured values:
+//! - `X-Proxyflare-Cache-Everything`: `true`/`false`;
+//! - `X-Proxyflare-Cache-TTL-By-Status`: `200-299=86400, 404=1`;
+//! - `X-Proxyflare-Polish`: `off`, `lossy` or `lossless`;
+//! - `X-Proxyflare-Minify`: the kinds to minify, `js, css, html` (`none`
+//!   for none);
+//! - `X-Proxyflare-Mirage`: `true`/`false`.
+//!
+//! (`cacheTtl` has its own, ungated header, `X-Proxyflare-Cache-TTL`.) The
+//! headers are off by default because `cacheEverything` and long TTLs let a
+//! client put responses into the zone's shared cache. Invalid values are a
+//! 400 either way.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::config;
+use crate::control::parse_bool;
+
+pub const CACHE_EVERYTHING_HEADER: &str = "X-Proxyflare-Cache-Everything";
+pub const CACHE_TTL_BY_STATUS_HEADER: &str = "X-Proxyflare-Cache-TTL-By-Status";
+pub const POLISH_HEADER: &str = "X-Proxyflare-Polish";
+pub const MINIFY_HEADER: &str = "X-Proxyflare-Minify";
+pub const MIRAGE_HEADER: &str = "X-Proxyflare-Mirage";
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Polish {
+    Off,
+    Lossy,
+    Lossless,
+}
+
+impl Polish {
+    fn parse(value: &str) -> Option<Polish> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Polish::Off),
+            "lossy" => Some(Polish::Lossy),
+            "lossless" => Some(Polish::Lossless),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Minify {
+    pub js: bool,
+    pub css: bool,
+    pub html: bool,
+}
+
+impl Minify {
+    fn parse(value: &str) -> Option<Minify> {
+        let mut minify = Minify::default();
+        if value.trim().eq_ignore_ascii_case("none") {
+            return Some(minify);
+        }
+        for kind in value.split(',') {
+            match kind.trim().to_ascii_lowercase().as_str() {
+                "js" => minify.js = true,
+                "css" => minify.css = true,
+                "html" => minify.html = true,
+                _ => return None,
+            }
+        }
+        Some(minify)
+    }
+}
+
+/// The supported subset of `RequestInit.cf`.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CfOptions {
+    pub cache_ttl: Option<i32>,
+    pub cache_everything: Option<bool>,
+    pub cache_ttl_by_status: Option<HashMap<String, i32>>,
+    pub polish: Option<Polish>,
+    pub minify: Option<Minify>,
+    pub mirage: Option<bool>,
+}
+
+impl CfOptions {
+    /// These options with the ones `other` sets taking precedence.
+    pub fn overridden_by(self, other: &CfOptions) -> CfOptions {
+        CfOptions {
+            cache_ttl: other.cache_ttl.or(self.cache_ttl),
+            cache_everything: other.cache_everything.or(self.cache_everything),
+            cache_ttl_by_status: other
+                .cache_ttl_by_status
+                .clone()
+                .or(self.cache_ttl_by_status),
+            polish: other.polish.or(self.polish),
+            minify: other.minify.or(self.minify),
+            mirage: other.mirage.or(self.mirage),
+        }
+    }
+}
+
+/// `404` or `200-299`, within the HTTP status range.
+pub fn valid_status_range(range: &str) -> bool {
+    let status = |s: &str| {
+        s.len() == 3
+            && s.parse::<u16>()
+                .is_ok_and(|status| (100..=599).contains(&status))
+    };
+    match range.split_once('-') {
+        Some((from, to)) => status(from) && status(to) && from <= to,
+        None => status(range),
+    }
+}
+
+fn parse_ttl_by_status(value: &str) -> Option<HashMap<String, i32>> {
+    value
+        .split(',')
+        .map(|pair| {
+            let (range, ttl) = pair.split_once('=')?;
+            let range = range.trim();
+            valid_status_range(range).then_some(())?;
+            Some((range.to_string(), ttl.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Parse the per-request options from `header` lookups; the error names
+/// the bad header.
+pub fn parse_headers(
+    header: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<CfOptions, String> {
+    fn parsed<T>(
+        header: &impl Fn(&str) -> Option<String>,
+        name: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> std::result::Result<Option<T>, String> {
+        match header(name) {
+            Some(value) => parse(&value)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid {name} header")),
+            None => Ok(None),
+        }
+    }
+    Ok(CfOptions {
+        cache_ttl: None,
+        cache_everything: parsed(&header, CACHE_EVERYTHING_HEADER, parse_bool)?,
+        cache_ttl_by_status: parsed(&header, CACHE_TTL_BY_STATUS_HEADER, parse_ttl_by_status)?,
+        polish: parsed(&header, POLISH_HEADER, Polish::parse)?,
+        minify: parsed(&header, MINIFY_HEADER, Minify::parse)?,
+        mirage: parsed(&header, MIRAGE_HEADER, parse_bool)?,
+    })
+}
+
+/// Set the configured options, and the request's own when allowed, on the
+/// upstream fetch.
+pub fn apply(env: &Env, requested: &CfOptions, init: &mut RequestInit) {
+    let mut options: CfOptions = config::var_json(env, "CF_FETCH_OPTIONS").unwrap_or_default();
+    options
+        .cache_ttl_by_status
+        .get_or_insert_with(HashMap::new)
+        .retain(|range, _| valid_status_range(range));
+    if config::var(env, "CF_FETCH_OPTION_HEADERS").as_deref() == Some("true") {
+        options = options.overridden_by(requested);
+    }
+    let cf = &mut init.cf;
+    if options.cache_ttl.is_some() {
+        cf.cache_ttl = options.cache_ttl;
+    }
+    if options.cache_everything.is_some() {
+        cf.cache_everything = options.cache_everything;
+    }
+    if let Some(by_status) = options.cache_ttl_by_status.filter(|m| !m.is_empty()) {
+        cf.cache_ttl_by_status = Some(by_status);
+    }
+    if let Some(polish) = options.polish {
+        cf.polish = Some(match polish {
+            Polish::Off => PolishConfig::Off,
+            Polish::Lossy => PolishConfig::Lossy,
+            Polish::Lossless => PolishConfig::Lossless,
+        });
+    }
+    if let Some(minify) = options.minify {
+        cf.minify = Some(MinifyConfig {
+            js: minify.js,
+            css: minify.css,
+            html: minify.html,
+        });
+    }
+    if options.mirage.is_some() {
+        cf.mirage = options.mirage;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_pairs(pairs: &[(&str, &str)]) -> std::result::Result<CfOptions, String> {
+        parse_headers(|name| {
+            pairs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        assert_eq!(parse_pairs(&[]), Ok(CfOptions::default()));
+        let options = parse_pairs(&[
+            ("x-proxyflare-cache-everything", "true"),
+            ("x-proxyflare-cache-ttl-by-status", "200-299=86400, 404=1"),
+            ("x-proxyflare-polish", "Lossy"),
+            ("x-proxyflare-minify", "js, css"),
+        ])
+        .unwrap();
+        assert_eq!(options.cache_everything, Some(true));
+        assert_eq!(
+            options.cache_ttl_by_status,
+            Some(HashMap::from([
+                ("200-299".into(), 86400),
+                ("404".into(), 1)
+            ]))
+        );
+        assert_eq!(options.polish, Some(Polish::Lossy));
+        assert_eq!(
+            options.minify,
+            Some(Minify {
+                js: true,
+                css: true,
+                html: false
+            })
+        );
+        assert!(parse_pairs(&[("x-proxyflare-polish", "max")])
+            .unwrap_err()
+            .contains(POLISH_HEADER));
+        assert!(parse_pairs(&[("x-proxyflare-minify", "js, png")]).is_err());
+        assert!(parse_pairs(&[("x-proxyflare-cache-ttl-by-status", "2xx=60")]).is_err());
+    }
+
+    #[test]
+    fn test_config_and_overrides() {
+        let configured: CfOptions = serde_json::from_str(
+            r#"{"cacheEverything": true, "cacheTtl": 300, "polish": "lossless",
+                "minify": {"js": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(configured.cache_ttl, Some(300));
+        assert!(serde_json::from_str::<CfOptions>(r#"{"cache_ttl": 1}"#).is_err());
+        let merged = configured.overridden_by(&CfOptions {
+            polish: Some(Polish::Off),
+            ..CfOptions::default()
+        });
+        assert_eq!(merged.polish, Some(Polish::Off));
+        assert_eq!(merged.cache_everything, Some(true));
+        assert!(valid_status_range("500-599"));
+        assert!(!valid_status_range("599-500"));
+        assert!(!valid_status_range("99"));
+    }
+}
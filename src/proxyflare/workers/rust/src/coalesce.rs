@@ -0,0 +1,117 @@
+//! Coalescing of concurrent cache misses.
+//!
+//! When a cacheable GET misses the cache, the first request for that key in
+//! the isolate leads: it fetches from the upstream as usual. Identical
+//! requests arriving while it is in flight wait until the leader's response
+//! has been stored and are then answered from the cache, so a stampede on a
+//! popular URL costs one upstream fetch per isolate instead of one per
+//! client. Followers wait at most `CACHE_COALESCE_WAIT_MS` (default 5000,
+//! `0` turns coalescing off) and never past their deadline; when the wait
+//! runs out, or the leader's response wasn't cacheable, they fetch on their
+//! own. Followers check every 25 ms whether the leader is done rather than
+//! being woken by it: a request's futures must be resumed from its own
+//! context, not from another request's.
+//!
+//! Flights are not shared between isolates: a Durable Object in front of
+//! every miss would add a round trip to each of them to save the few
+//! duplicate fetches concurrent isolates make.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use worker::{Delay, Env};
+
+use crate::config;
+
+const DEFAULT_WAIT_MS: u64 = 5000;
+const POLL_INTERVAL_MS: u64 = 25;
+
+thread_local! {
+    /// Key -> whether its leader is done.
+    static FLIGHTS: RefCell<HashMap<String, Rc<Cell<bool>>>> = RefCell::new(HashMap::new());
+}
+
+/// The request fetching for a key; dropping it releases the followers.
+pub struct Leader {
+    key: String,
+    landed: Rc<Cell<bool>>,
+}
+
+/// A follower's view of the leader for a key.
+pub struct Landing(Rc<Cell<bool>>);
+
+impl Landing {
+    pub fn landed(&self) -> bool {
+        self.0.get()
+    }
+}
+
+pub enum Joined {
+    Leader(Leader),
+    Follower(Landing),
+}
+
+/// Lead the flight for `key`, or follow the one already in the air.
+pub fn join(key: &str) -> Joined {
+    FLIGHTS.with(|flights| {
+        let mut flights = flights.borrow_mut();
+        if let Some(flight) = flights.get(key) {
+            return Joined::Follower(Landing(flight.clone()));
+        }
+        let landed = Rc::new(Cell::new(false));
+        flights.insert(key.to_string(), landed.clone());
+        Joined::Leader(Leader {
+            key: key.to_string(),
+            landed,
+        })
+    })
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        FLIGHTS.with(|flights| flights.borrow_mut().remove(&self.key));
+        self.landed.set(true);
+    }
+}
+
+/// How long a follower may wait; `0` when coalescing is off.
+pub fn wait_ms(env: &Env, remaining_ms: Option<u64>) -> u64 {
+    let wait_ms = config::var_u64(env, "CACHE_COALESCE_WAIT_MS").unwrap_or(DEFAULT_WAIT_MS);
+    remaining_ms.map_or(wait_ms, |remaining| wait_ms.min(remaining))
+}
+
+/// Wait up to `wait_ms` for the leader; whether it landed in time.
+pub async fn wait(landing: Landing, wait_ms: u64) -> bool {
+    let mut waited = 0;
+    while !landing.landed() {
+        if waited >= wait_ms {
+            return false;
+        }
+        let step = POLL_INTERVAL_MS.min(wait_ms - waited);
+        Delay::from(Duration::from_millis(step)).await;
+        waited += step;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join() {
+        let Joined::Leader(leader) = join("k") else {
+            panic!("first request should lead");
+        };
+        let Joined::Follower(landing) = join("k") else {
+            panic!("second request should follow");
+        };
+        assert!(matches!(join("other"), Joined::Leader(_)));
+        assert!(!landing.landed());
+        drop(leader);
+        assert!(landing.landed());
+        assert!(matches!(join("k"), Joined::Leader(_)));
+    }
+}
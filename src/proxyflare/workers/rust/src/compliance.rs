@@ -0,0 +1,176 @@
+//! Legal/compliance blocklist answered with `451 Unavailable For Legal Reasons`.
+//!
+//! This is deliberately separate from any operational filtering: every match is
+//! logged with its rule id so takedown handling stays auditable.
+//!
+//! Rules come from two optional sources:
+//! - `COMPLIANCE_BLOCKLIST`: JSON array of [`ComplianceRule`]s.
+//! - `COMPLIANCE_KV`: KV namespace with rules stored under `host:<host>` or
+//!   `host:*.<parent-domain>` keys.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+use worker::*;
+
+use crate::{config, responses, utils};
+
+const BLOCKLIST_VAR: &str = "COMPLIANCE_BLOCKLIST";
+pub const BLOCKLIST_KV: &str = "COMPLIANCE_KV";
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ComplianceRule {
+    /// `host[/path-prefix]` pattern, see [`utils::url_matches`]. Optional for
+    /// KV entries, whose key already scopes the rule.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Human-readable legal basis, returned to the client.
+    pub reason: String,
+    /// Stable identifier (e.g. a takedown ticket) used in audit logs.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// URL describing the block, emitted as `Link: <...>; rel="blocked-by"`.
+    #[serde(default)]
+    pub link: Option<String>,
+}
+
+impl ComplianceRule {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        self.pattern
+            .as_deref()
+            .is_some_and(|p| utils::url_matches(p, host, path))
+    }
+}
+
+/// Parse the `COMPLIANCE_BLOCKLIST` value, skipping it entirely if malformed.
+pub fn parse_rules(raw: &str) -> Vec<ComplianceRule> {
+    serde_json::from_str(raw).unwrap_or_else(|e| {
+        console_error!("Ignoring malformed {}: {}", BLOCKLIST_VAR, e);
+        Vec::new()
+    })
+}
+
+/// KV keys that may hold a rule for `host`: the exact host, then wildcard
+/// entries for each parent domain.
+pub fn kv_keys(host: &str) -> Vec<String> {
+    let host = host.to_ascii_lowercase();
+    let mut keys = vec![format!("host:{host}")];
+    let mut rest = host.as_str();
+    while let Some((_, parent)) = rest.split_once('.') {
+        if !parent.contains('.') {
+            break;
+        }
+        keys.push(format!("host:*.{parent}"));
+        rest = parent;
+    }
+    keys
+}
+
+/// Every rule stored in `COMPLIANCE_KV`, keyed by its KV key.
+pub async fn kv_rules(kv: &kv::KvStore) -> Result<BTreeMap<String, ComplianceRule>> {
+    let mut rules = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix("host:".to_string());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(rule) = kv.get(&key.name).json::<ComplianceRule>().await? {
+                rules.insert(key.name, rule);
+            }
+        }
+        match page.cursor {
+            Some(next) if !page.list_complete => cursor = Some(next),
+            _ => return Ok(rules),
+        }
+    }
+}
+
+/// Find the first compliance rule that applies to `target`.
+pub async fn find_rule(env: &Env, target: &Url) -> Option<ComplianceRule> {
+    let host = target.host_str()?;
+    let path = target.path();
+
+    if let Some(raw) = config::var(env, BLOCKLIST_VAR) {
+        if let Some(rule) = parse_rules(&raw)
+            .into_iter()
+            .find(|r| r.matches(host, path))
+        {
+            return Some(rule);
+        }
+    }
+
+    let kv = env.kv(BLOCKLIST_KV).ok()?;
+    for key in kv_keys(host) {
+        match kv.get(&key).json::<ComplianceRule>().await {
+            Ok(Some(rule)) if rule.pattern.is_none() || rule.matches(host, path) => {
+                return Some(rule)
+            }
+            Ok(_) => {}
+            Err(e) => console_error!("Compliance KV lookup failed for {}: {:?}", key, e),
+        }
+    }
+    None
+}
+
+/// Build the 451 response for a matched rule and write the audit log line.
+pub fn blocked_response(rule: &ComplianceRule, target: &Url) -> Result<Response> {
+    let rule_id = rule.id.as_deref().unwrap_or("unnamed");
+    console_log!(
+        "COMPLIANCE BLOCK rule={} host={} path={}",
+        rule_id,
+        target.host_str().unwrap_or_default(),
+        target.path()
+    );
+
+    let mut response = responses::json(
+        451,
+        &json!({
+            "error": "unavailable_for_legal_reasons",
+            "reason": rule.reason,
+            "rule": rule_id,
+            "link": rule.link,
+        }),
+    )?;
+    if let Some(link) = &rule.link {
+        response
+            .headers_mut()
+            .set("Link", &format!("<{link}>; rel=\"blocked-by\""))?;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules_and_match() {
+        let rules = parse_rules(
+            r#"[{"pattern": "*.example.com/videos/", "reason": "DMCA", "id": "T-1"}]"#,
+        );
+        assert_eq!(rules.len(), 1);
+        assert!(rules[0].matches("cdn.example.com", "/videos/a.mp4"));
+        assert!(!rules[0].matches("cdn.example.com", "/about"));
+        assert!(!rules[0].matches("example.org", "/videos/a.mp4"));
+    }
+
+    #[test]
+    fn test_rule_without_pattern_never_matches_from_env() {
+        let rules = parse_rules(r#"[{"reason": "court order"}]"#);
+        assert!(!rules[0].matches("example.com", "/"));
+    }
+
+    #[test]
+    fn test_kv_keys_walk_parent_domains() {
+        assert_eq!(
+            kv_keys("a.b.Example.com"),
+            vec!["host:a.b.example.com", "host:*.b.example.com", "host:*.example.com"]
+        );
+        assert_eq!(kv_keys("example.com"), vec!["host:example.com"]);
+    }
+}
@@ -0,0 +1,242 @@
+//! Concurrency limits per upstream host.
+//!
+//! `HOST_CONCURRENCY` caps simultaneous subrequests to a host, with `"*"` as
+//! the default: `{"*": 50, "small-origin.example": 4}`. Each host gets a
+//! `HostSemaphore` Durable Object (binding `HOST_SEMAPHORE`) handing out
+//! leases; a lease is held until the response body has been streamed to the
+//! client. When the host is saturated the request waits up to
+//! `HOST_CONCURRENCY_QUEUE_MS` (default 0) for a free slot, then gets 503
+//! `upstream_busy`. Leases expire after a minute so a crashed invocation
+//! cannot hold a slot forever. Without the binding there is no limit, and
+//! an unreachable object fails open.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::context::{Deferred, RequestCtx};
+use crate::{config, responses, spend, utils};
+
+const BINDING: &str = "HOST_SEMAPHORE";
+const STATE_KEY: &str = "leases";
+const LEASE_TTL_MS: u64 = 60_000;
+const RETRY_INTERVAL_MS: u64 = 50;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AcquireRequest {
+    pub lease: String,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AcquireResult {
+    pub granted: bool,
+    pub in_flight: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReleaseRequest {
+    pub lease: String,
+}
+
+/// Outstanding leases and when they expire (epoch ms).
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Leases(BTreeMap<String, u64>);
+
+impl Leases {
+    pub fn acquire(&mut self, now_ms: u64, req: &AcquireRequest) -> AcquireResult {
+        self.0.retain(|_, expires| *expires > now_ms);
+        let granted = (self.0.len() as u64) < req.limit;
+        if granted {
+            self.0.insert(req.lease.clone(), now_ms + LEASE_TTL_MS);
+        }
+        AcquireResult {
+            granted,
+            in_flight: self.0.len() as u64,
+        }
+    }
+
+    pub fn release(&mut self, lease: &str) {
+        self.0.remove(lease);
+    }
+}
+
+/// A held slot. Hand it back with [`Permit::release_after`]; a permit dropped
+/// on an early return queues its release on the request's [`Deferred`].
+pub struct Permit {
+    stub: Option<Stub>,
+    lease: String,
+    deferred: Deferred,
+}
+
+impl Permit {
+    fn release_future(&mut self) -> Option<impl Future<Output = ()> + 'static> {
+        let stub = self.stub.take()?;
+        let body = ReleaseRequest {
+            lease: self.lease.clone(),
+        };
+        Some(async move {
+            let result = match utils::json_request("https://semaphore/release", Method::Post, &body)
+            {
+                Ok(req) => stub.fetch_with_request(req).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                console_error!("Releasing host lease failed: {:?}", e);
+            }
+        })
+    }
+
+    /// Release once `done` resolves (e.g. the response body has been sent).
+    pub fn release_after(mut self, ctx: &Context, done: impl Future + 'static) {
+        if let Some(release) = self.release_future() {
+            ctx.wait_until(async move {
+                done.await;
+                release.await;
+            });
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(release) = self.release_future() {
+            self.deferred.push(release);
+        }
+    }
+}
+
+pub enum Admission {
+    Unlimited,
+    Admitted(Permit),
+    Busy(Response),
+}
+
+async fn acquire(stub: &Stub, req: &AcquireRequest) -> Result<AcquireResult> {
+    let request = utils::json_request("https://semaphore/acquire", Method::Post, req)?;
+    stub.fetch_with_request(request).await?.json().await
+}
+
+/// Take a slot for the request's target host, waiting if configured.
+pub async fn admit(env: &Env, rctx: &RequestCtx) -> Result<Admission> {
+    let host = rctx.target_host();
+    let limits: HashMap<String, u64> =
+        config::var_json(env, "HOST_CONCURRENCY").unwrap_or_default();
+    let Some(limit) = spend::cap_for(&limits, host) else {
+        return Ok(Admission::Unlimited);
+    };
+    let Some(stub) = env
+        .durable_object(BINDING)
+        .ok()
+        .and_then(|ns| ns.id_from_name(host).ok()?.get_stub().ok())
+    else {
+        return Ok(Admission::Unlimited);
+    };
+    let request = AcquireRequest {
+        lease: utils::random_id(),
+        limit,
+    };
+    let queue_ms = config::var_u64(env, "HOST_CONCURRENCY_QUEUE_MS").unwrap_or(0);
+    let started = Date::now().as_millis();
+    loop {
+        let result = match acquire(&stub, &request).await {
+            Ok(result) => result,
+            Err(e) => {
+                console_error!("Host semaphore failed for {}: {:?}", host, e);
+                return Ok(Admission::Unlimited);
+            }
+        };
+        if result.granted {
+            return Ok(Admission::Admitted(Permit {
+                stub: Some(stub),
+                lease: request.lease,
+                deferred: rctx.deferred.clone(),
+            }));
+        }
+        let waited = Date::now().as_millis() - started;
+        if waited + RETRY_INTERVAL_MS > queue_ms || rctx.deadline_passed() {
+            console_log!(
+                "[{}] {} is at its concurrency limit ({} in flight)",
+                rctx.id,
+                host,
+                result.in_flight
+            );
+            let mut response = responses::error(
+                503,
+                "upstream_busy",
+                "Too many concurrent requests to this upstream, retry shortly",
+            )?;
+            response.headers_mut().set("Retry-After", "1")?;
+            return Ok(Admission::Busy(response));
+        }
+        Delay::from(Duration::from_millis(RETRY_INTERVAL_MS)).await;
+    }
+}
+
+#[durable_object]
+pub struct HostSemaphore {
+    state: State,
+}
+
+impl DurableObject for HostSemaphore {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        match req.path().as_str() {
+            "/acquire" => {
+                let acquire: AcquireRequest = req.json().await?;
+                let mut leases: Leases = storage.get(STATE_KEY).await?.unwrap_or_default();
+                let result = leases.acquire(Date::now().as_millis(), &acquire);
+                storage.put(STATE_KEY, &leases).await?;
+                Response::from_json(&result)
+            }
+            "/release" => {
+                let release: ReleaseRequest = req.json().await?;
+                let mut leases: Leases = storage.get(STATE_KEY).await?.unwrap_or_default();
+                leases.release(&release.lease);
+                storage.put(STATE_KEY, &leases).await?;
+                Response::empty()
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(lease: &str) -> AcquireRequest {
+        AcquireRequest {
+            lease: lease.into(),
+            limit: 2,
+        }
+    }
+
+    #[test]
+    fn test_acquire_until_limit_and_release() {
+        let mut leases = Leases::default();
+        assert!(leases.acquire(0, &request("a")).granted);
+        assert!(leases.acquire(0, &request("b")).granted);
+        let result = leases.acquire(0, &request("c"));
+        assert!(!result.granted);
+        assert_eq!(result.in_flight, 2);
+        leases.release("a");
+        assert!(leases.acquire(0, &request("c")).granted);
+    }
+
+    #[test]
+    fn test_expired_leases_free_slots() {
+        let mut leases = Leases::default();
+        leases.acquire(0, &request("a"));
+        leases.acquire(0, &request("b"));
+        assert!(!leases.acquire(LEASE_TTL_MS - 1, &request("c")).granted);
+        assert!(leases.acquire(LEASE_TTL_MS, &request("c")).granted);
+    }
+}
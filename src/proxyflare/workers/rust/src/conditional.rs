@@ -0,0 +1,103 @@
+//! Conditional requests (`If-None-Match`, `If-Modified-Since`).
+//!
+//! Clients' validators are forwarded to the upstream like any other header
+//! and a `304 Not Modified` is relayed without a body. Responses served from
+//! the edge cache are checked here instead (RFC 9110 §13.1: `If-None-Match`
+//! with weak comparison, `If-Modified-Since` only without it), so clients
+//! with their own caches don't re-download bodies the proxy already has.
+
+use jiff::{fmt::rfc2822::DateTimeParser, Timestamp};
+use worker::*;
+
+static HTTP_DATE: DateTimeParser = DateTimeParser::new();
+
+/// Statuses whose responses never carry a body.
+pub fn is_null_body_status(status: u16) -> bool {
+    matches!(status, 101 | 204 | 205 | 304)
+}
+
+fn opaque(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+/// Weak comparison of an `If-None-Match` list against the response `ETag`.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|tag| !tag.trim().is_empty() && opaque(tag) == opaque(etag))
+}
+
+fn http_date(value: &str) -> Option<Timestamp> {
+    HTTP_DATE.parse_timestamp(value.trim()).ok()
+}
+
+/// Whether a response with `etag`/`last_modified` satisfies the client's
+/// validators, so a 304 can stand in for it.
+pub fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        return etag.is_some_and(|etag| etag_matches(if_none_match, etag));
+    }
+    match (
+        if_modified_since.and_then(http_date),
+        last_modified.and_then(http_date),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// `response`, or a bodiless 304 in its place when the `client` request
+/// headers' validators match it.
+pub fn answer(client: &Headers, response: Response) -> Result<Response> {
+    let headers = response.headers();
+    if not_modified(
+        client.get("If-None-Match")?.as_deref(),
+        client.get("If-Modified-Since")?.as_deref(),
+        headers.get("ETag")?.as_deref(),
+        headers.get("Last-Modified")?.as_deref(),
+    ) {
+        Ok(Response::empty()?
+            .with_status(304)
+            .with_headers(headers.clone()))
+    } else {
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"a\"", "\"a\""));
+        assert!(etag_matches("\"x\", W/\"a\"", "\"a\""));
+        assert!(etag_matches("*", "\"b\""));
+        assert!(!etag_matches("\"a\"", "\"b\""));
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let later = "Mon, 07 Nov 1994 08:49:37 GMT";
+        assert!(not_modified(None, Some(later), None, Some(date)));
+        assert!(not_modified(None, Some(date), None, Some(date)));
+        assert!(!not_modified(None, Some(date), None, Some(later)));
+        // If-None-Match wins over If-Modified-Since.
+        assert!(!not_modified(
+            Some("\"a\""),
+            Some(later),
+            Some("\"b\""),
+            Some(date)
+        ));
+        assert!(!not_modified(None, None, Some("\"a\""), Some(date)));
+        assert!(is_null_body_status(304));
+        assert!(!is_null_body_status(200));
+    }
+}
@@ -0,0 +1,53 @@
+//! Helpers for reading worker configuration from environment variables.
+//!
+//! Every setting is optional: a missing or empty variable means "feature off"
+//! (or the documented default), so a bare deployment behaves like the original
+//! open proxy.
+
+use serde::de::DeserializeOwned;
+use worker::{console_error, Env};
+
+/// Read a string variable or secret, treating blank values as unset.
+pub fn var(env: &Env, name: &str) -> Option<String> {
+    env.var(name)
+        .ok()
+        .map(|v| v.to_string().trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Read an unsigned integer variable, ignoring unparsable values.
+pub fn var_u64(env: &Env, name: &str) -> Option<u64> {
+    var(env, name).and_then(|v| v.parse().ok())
+}
+
+/// Read a comma-separated list variable.
+pub fn var_list(env: &Env, name: &str) -> Vec<String> {
+    var(env, name).map(|v| parse_list(&v)).unwrap_or_default()
+}
+
+/// Read a JSON variable; malformed values are logged and treated as unset.
+pub fn var_json<T: DeserializeOwned>(env: &Env, name: &str) -> Option<T> {
+    let raw = var(env, name)?;
+    serde_json::from_str(&raw)
+        .map_err(|e| console_error!("Ignoring malformed {}: {}", name, e))
+        .ok()
+}
+
+pub fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list_trims_and_skips_empty() {
+        assert_eq!(parse_list(" a, b ,,c "), vec!["a", "b", "c"]);
+        assert!(parse_list("").is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+//! Response content-type allowlist (`ALLOWED_CONTENT_TYPES`).
+//!
+//! A comma-separated list of media types, with `type/*` wildcards, e.g.
+//! `image/*,application/json`. When set, upstream responses of any other type
+//! are discarded and answered with 415.
+
+use worker::*;
+
+use crate::{config, responses};
+
+/// The bare media type of a `Content-Type` value, lowercased, without params.
+pub fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+pub fn is_allowed(allowlist: &[String], content_type: &str) -> bool {
+    let media = media_type(content_type);
+    allowlist.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix("/*") {
+            Some(major) => media.split_once('/').is_some_and(|(m, _)| m == major),
+            None => media == pattern,
+        }
+    })
+}
+
+/// Reject the upstream response if its content type is not allowlisted.
+/// Responses without a body (204/304) always pass.
+pub fn check(env: &Env, response: &Response) -> Result<Option<Response>> {
+    let allowlist = config::var_list(env, "ALLOWED_CONTENT_TYPES");
+    if allowlist.is_empty() || matches!(response.status_code(), 204 | 304) {
+        return Ok(None);
+    }
+    let content_type = response.headers().get("Content-Type")?.unwrap_or_default();
+    if is_allowed(&allowlist, &content_type) {
+        return Ok(None);
+    }
+    console_log!("Blocked upstream content type {:?}", content_type);
+    responses::error(
+        415,
+        "content_type_not_allowed",
+        &format!(
+            "Upstream content type '{}' is not allowed by this proxy",
+            media_type(&content_type)
+        ),
+    )
+    .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> Vec<String> {
+        vec!["image/*".into(), "application/json".into()]
+    }
+
+    #[test]
+    fn test_media_type_strips_parameters() {
+        assert_eq!(media_type("Text/HTML; charset=utf-8"), "text/html");
+    }
+
+    #[test]
+    fn test_is_allowed_exact_and_wildcard() {
+        assert!(is_allowed(&allowlist(), "image/png"));
+        assert!(is_allowed(&allowlist(), "application/json; charset=utf-8"));
+        assert!(!is_allowed(&allowlist(), "text/html"));
+        assert!(!is_allowed(&allowlist(), "application/octet-stream"));
+        assert!(!is_allowed(&allowlist(), ""));
+    }
+}
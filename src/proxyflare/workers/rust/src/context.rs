@@ -0,0 +1,236 @@
+//! Per-request state threaded through the proxy pipeline.
+//!
+//! [`RequestCtx`] is built once at the top of `do_main` and filled in as
+//! stages run (authentication sets the caller, target parsing the target),
+//! so later stages, logs and metrics read one source of truth instead of
+//! re-deriving values from the request.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use worker::*;
+
+use crate::{auth, control, dates, deadline, envelope, language, metadata, ranged, spend, utils};
+
+/// Per-request switches from the worker URL.
+#[derive(Debug, Clone, Default)]
+pub struct Flags {
+    pub envelope: bool,
+    pub dates: Option<dates::DateOptions>,
+    /// Number of ranges to download in parallel.
+    pub parallel: Option<u64>,
+    pub strip_metadata: bool,
+    /// Preferred languages, best first, for the 404 fallback.
+    pub languages: Vec<String>,
+    /// JSONP callback, when `JSONP_ENABLED` (see [`crate::jsonp`]).
+    pub jsonp: Option<String>,
+    /// `X-Proxyflare-*` control headers.
+    pub controls: control::Controls,
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Background work queued by guards dropped on early returns (a host
+/// concurrency lease, an idempotency claim). A detached `spawn_local` does
+/// not outlive the request, so `do_main` hands the queue to
+/// `ctx.wait_until` once the response is ready.
+#[derive(Clone, Default)]
+pub struct Deferred(Rc<RefCell<Vec<Task>>>);
+
+impl Deferred {
+    pub fn push(&self, task: impl Future<Output = ()> + 'static) {
+        self.0.borrow_mut().push(Box::pin(task));
+    }
+
+    fn take(&self) -> Vec<Task> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+
+    /// Keep the request alive until the queued work is done.
+    pub fn run(&self, ctx: &Context) {
+        for task in self.take() {
+            ctx.wait_until(task);
+        }
+    }
+}
+
+impl fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Deferred({} tasks)", self.0.borrow().len())
+    }
+}
+
+#[derive(Debug)]
+pub struct RequestCtx {
+    /// `cf-ray` when present, otherwise a generated id; echoed as `X-Request-Id`.
+    pub id: String,
+    /// The worker URL, parsed once.
+    pub url: Url,
+    pub method: Method,
+    pub client_ip: Option<String>,
+    /// ISO country code from `cf.country`.
+    pub country: Option<String>,
+    /// Cloudflare data center serving the request.
+    pub colo: Option<String>,
+    pub started_ms: u64,
+    /// Absolute deadline (Unix ms), see [`crate::deadline`].
+    pub deadline_ms: Option<u64>,
+    /// Authenticated caller id (API key, JWT subject, Access identity).
+    pub caller: Option<String>,
+    pub key_source: Option<auth::KeySource>,
+    /// The request carried a valid signed link.
+    pub signed: bool,
+    pub target: Option<Url>,
+    pub flags: Flags,
+    /// `Set-Cookie` values for the client (e.g. a Turnstile pass).
+    pub set_cookies: Vec<String>,
+    /// Other headers for the client added by pipeline stages.
+    pub extra_headers: Vec<(&'static str, String)>,
+    /// Cleanup left behind by early returns, see [`Deferred`].
+    pub deferred: Deferred,
+    timings: Vec<(&'static str, u64)>,
+}
+
+impl RequestCtx {
+    pub fn new(req: &Request, env: &Env) -> Result<Self> {
+        let id = req
+            .headers()
+            .get("cf-ray")?
+            .unwrap_or_else(utils::random_id);
+        let started_ms = Date::now().as_millis();
+        Ok(Self {
+            id,
+            url: req.url()?,
+            method: req.method(),
+            client_ip: utils::client_ip(req),
+            country: req.cf().and_then(|cf| cf.country()),
+            colo: req.cf().map(|cf| cf.colo()),
+            started_ms,
+            deadline_ms: deadline::from_request(req, env, started_ms)?,
+            caller: None,
+            key_source: None,
+            signed: false,
+            target: None,
+            flags: Flags::default(),
+            set_cookies: Vec::new(),
+            extra_headers: Vec::new(),
+            deferred: Deferred::default(),
+            timings: Vec::new(),
+        })
+    }
+
+    /// Parse the per-request switches; a bad value is a client error.
+    pub fn parse_flags(&mut self, req: &Request) -> std::result::Result<(), String> {
+        self.flags = Flags {
+            envelope: envelope::requested(
+                &self.url,
+                req.headers().get("Accept").ok().flatten().as_deref(),
+            ),
+            dates: dates::requested(&self.url)?,
+            parallel: ranged::requested(&self.url)?,
+            strip_metadata: metadata::requested(&self.url),
+            languages: language::requested(&self.url)?,
+            controls: control::from_request(req)?,
+            jsonp: None,
+        };
+        if let Some(timeout_ms) = self.flags.controls.timeout_ms {
+            let deadline_ms = self.started_ms.saturating_add(timeout_ms);
+            self.deadline_ms = Some(self.deadline_ms.map_or(deadline_ms, |d| d.min(deadline_ms)));
+        }
+        if self.flags.parallel.is_some() && (self.flags.envelope || self.flags.dates.is_some()) {
+            return Err(format!(
+                "{} cannot be combined with envelopes or date normalization",
+                ranged::PARAM
+            ));
+        }
+        Ok(())
+    }
+
+    /// Id used for per-tenant accounting.
+    pub fn tenant(&self) -> &str {
+        self.caller.as_deref().unwrap_or(spend::ANONYMOUS)
+    }
+
+    /// Neither authenticated nor holding a signed link.
+    pub fn is_anonymous(&self) -> bool {
+        self.caller.is_none() && !self.signed
+    }
+
+    pub fn target_host(&self) -> &str {
+        self.target
+            .as_ref()
+            .and_then(|t| t.host_str())
+            .unwrap_or_default()
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        Date::now().as_millis().saturating_sub(self.started_ms)
+    }
+
+    /// Time left before the deadline, if there is one.
+    pub fn remaining_ms(&self) -> Option<u64> {
+        self.deadline_ms
+            .map(|d| d.saturating_sub(Date::now().as_millis()))
+    }
+
+    pub fn deadline_passed(&self) -> bool {
+        self.remaining_ms() == Some(0)
+    }
+
+    /// Record that `stage` finished now.
+    pub fn mark(&mut self, stage: &'static str) {
+        let elapsed = self.elapsed_ms();
+        self.timings.push((stage, elapsed));
+    }
+
+    pub fn server_timing(&self) -> String {
+        server_timing(&self.timings)
+    }
+}
+
+/// `Server-Timing` value from cumulative stage marks: each entry's duration
+/// is the time since the previous mark.
+pub fn server_timing(marks: &[(&str, u64)]) -> String {
+    let mut previous = 0;
+    marks
+        .iter()
+        .map(|(stage, at)| {
+            let duration = at.saturating_sub(previous);
+            previous = *at;
+            format!("{stage};dur={duration}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_timing_durations() {
+        assert_eq!(
+            server_timing(&[("auth", 3), ("upstream", 120), ("total", 125)]),
+            "auth;dur=3, upstream;dur=117, total;dur=5"
+        );
+        assert_eq!(server_timing(&[]), "");
+    }
+
+    #[test]
+    fn test_deferred_tasks_are_taken_once() {
+        let deferred = Deferred::default();
+        let ran = Rc::new(RefCell::new(0));
+        for _ in 0..2 {
+            let ran = ran.clone();
+            deferred.clone().push(async move { *ran.borrow_mut() += 1 });
+        }
+        for task in deferred.take() {
+            futures_executor::block_on(task);
+        }
+        assert_eq!(*ran.borrow(), 2);
+        assert!(deferred.take().is_empty());
+    }
+}
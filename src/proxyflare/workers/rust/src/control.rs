@@ -0,0 +1,175 @@
+//! Per-request control headers (`X-Proxyflare-*`).
+//!
+//! Clients tune a single request with headers instead of query params, which
+//! would otherwise end up on the target URL:
+//! - `X-Proxyflare-Timeout`: upstream budget, in milliseconds (`2500`,
+//!   `2500ms`) or seconds (`2.5s`); it can only tighten the deadline.
+//! - `X-Proxyflare-Cache-TTL`: seconds Cloudflare may cache the upstream
+//!   response (`cf.cacheTtl`, and the proxy's own edge cache, see
+//!   [`crate::cache`]), `0` to skip the cache.
+//! - `X-Proxyflare-Follow-Redirects`: `false` returns upstream redirects to
+//!   the client instead of following them.
+//! - `X-Proxyflare-No-Cache`: `true` skips the edge cache lookup and fetches
+//!   from the upstream, still storing the fresh response.
+//! - `X-Proxyflare-No-Negative-Cache`: `true` ignores cached 404s and
+//!   upstream failures.
+//! - Cloudflare fetch options (`X-Proxyflare-Polish`, `-Minify`...), when
+//!   enabled, see [`crate::cf_options`].
+//!
+//! An unparsable value is a 400. Every `X-Proxyflare-*` header, known or
+//! not, is consumed by the proxy and never sent upstream.
+
+use worker::*;
+
+use crate::cf_options::{self, CfOptions};
+
+pub const PREFIX: &str = "x-proxyflare-";
+pub const TIMEOUT_HEADER: &str = "X-Proxyflare-Timeout";
+pub const CACHE_TTL_HEADER: &str = "X-Proxyflare-Cache-TTL";
+pub const FOLLOW_REDIRECTS_HEADER: &str = "X-Proxyflare-Follow-Redirects";
+pub const NO_CACHE_HEADER: &str = "X-Proxyflare-No-Cache";
+pub const NO_NEGATIVE_CACHE_HEADER: &str = "X-Proxyflare-No-Negative-Cache";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Controls {
+    pub timeout_ms: Option<u64>,
+    pub cache_ttl: Option<i32>,
+    pub follow_redirects: Option<bool>,
+    pub no_cache: bool,
+    pub no_negative_cache: bool,
+    pub cf: CfOptions,
+}
+
+pub fn is_control_header(lowercase_name: &str) -> bool {
+    lowercase_name.starts_with(PREFIX)
+}
+
+fn parse_timeout(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_lowercase();
+    if let Some(ms) = value.strip_suffix("ms") {
+        return ms.trim().parse().ok();
+    }
+    if let Some(secs) = value.strip_suffix('s') {
+        let secs: f64 = secs.trim().parse().ok()?;
+        return (secs.is_finite() && secs >= 0.0).then(|| (secs * 1000.0).ceil() as u64);
+    }
+    value.parse().ok()
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse the controls from `header` lookups; the error names the bad header.
+pub fn parse(header: impl Fn(&str) -> Option<String>) -> std::result::Result<Controls, String> {
+    let invalid = |name: &str| format!("Invalid {name} header");
+    let timeout_ms = match header(TIMEOUT_HEADER) {
+        Some(value) => Some(parse_timeout(&value).ok_or_else(|| invalid(TIMEOUT_HEADER))?),
+        None => None,
+    };
+    let cache_ttl = match header(CACHE_TTL_HEADER) {
+        Some(value) => Some(
+            value
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .and_then(|ttl| i32::try_from(ttl).ok())
+                .ok_or_else(|| invalid(CACHE_TTL_HEADER))?,
+        ),
+        None => None,
+    };
+    let follow_redirects = match header(FOLLOW_REDIRECTS_HEADER) {
+        Some(value) => Some(parse_bool(&value).ok_or_else(|| invalid(FOLLOW_REDIRECTS_HEADER))?),
+        None => None,
+    };
+    let no_cache = match header(NO_CACHE_HEADER) {
+        Some(value) => parse_bool(&value).ok_or_else(|| invalid(NO_CACHE_HEADER))?,
+        None => false,
+    };
+    let no_negative_cache = match header(NO_NEGATIVE_CACHE_HEADER) {
+        Some(value) => parse_bool(&value).ok_or_else(|| invalid(NO_NEGATIVE_CACHE_HEADER))?,
+        None => false,
+    };
+    let cf = cf_options::parse_headers(&header)?;
+    Ok(Controls {
+        timeout_ms,
+        cache_ttl,
+        follow_redirects,
+        no_cache,
+        no_negative_cache,
+        cf,
+    })
+}
+
+pub fn from_request(req: &Request) -> std::result::Result<Controls, String> {
+    let headers = req.headers();
+    parse(|name| headers.get(name).ok().flatten())
+}
+
+/// Apply the fetch-level controls to the upstream request.
+pub fn apply(controls: &Controls, init: &mut RequestInit) {
+    if let Some(ttl) = controls.cache_ttl {
+        init.cf.cache_ttl = Some(ttl);
+    }
+    if controls.follow_redirects == Some(false) {
+        init.with_redirect(RequestRedirect::Manual);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_pairs(pairs: &[(&str, &str)]) -> std::result::Result<Controls, String> {
+        parse(|name| {
+            pairs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    #[test]
+    fn test_parse_controls() {
+        assert_eq!(parse_pairs(&[]), Ok(Controls::default()));
+        let controls = parse_pairs(&[
+            ("x-proxyflare-timeout", "2.5s"),
+            ("x-proxyflare-cache-ttl", "300"),
+            ("x-proxyflare-follow-redirects", "false"),
+        ])
+        .unwrap();
+        assert_eq!(controls.timeout_ms, Some(2500));
+        assert_eq!(controls.cache_ttl, Some(300));
+        assert_eq!(controls.follow_redirects, Some(false));
+        assert_eq!(
+            parse_pairs(&[("x-proxyflare-timeout", "750ms")]).map(|c| c.timeout_ms),
+            Ok(Some(750))
+        );
+        assert!(parse_pairs(&[("x-proxyflare-timeout", "soon")])
+            .unwrap_err()
+            .contains(TIMEOUT_HEADER));
+        assert!(parse_pairs(&[("x-proxyflare-cache-ttl", "-1")]).is_err());
+        assert!(parse_pairs(&[("x-proxyflare-follow-redirects", "maybe")]).is_err());
+        assert!(
+            parse_pairs(&[("x-proxyflare-no-cache", "1")])
+                .unwrap()
+                .no_cache
+        );
+        assert!(
+            parse_pairs(&[("x-proxyflare-no-negative-cache", "true")])
+                .unwrap()
+                .no_negative_cache
+        );
+    }
+
+    #[test]
+    fn test_is_control_header() {
+        assert!(is_control_header("x-proxyflare-timeout"));
+        assert!(is_control_header("x-proxyflare-anything"));
+        assert!(!is_control_header("x-proxied-by"));
+    }
+}
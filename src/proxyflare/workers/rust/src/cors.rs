@@ -0,0 +1,559 @@
+//! CORS headers on proxied responses and preflight answers.
+//!
+//! `CORS_ALLOWED_ORIGINS` is a comma-separated list of web origins in the
+//! `ALLOWED_ORIGINS` format (`https://app.example.com, https://*.example.dev`),
+//! and falls back to `ALLOWED_ORIGINS` itself. A listed `Origin` is echoed
+//! back in `Access-Control-Allow-Origin`; any other origin gets no CORS
+//! headers, so the browser keeps the response from the page. With neither
+//! variable set (or a `*` entry) every origin is allowed with `*`.
+//!
+//! `CORS_ALLOW_CREDENTIALS=true` adds `Access-Control-Allow-Credentials:
+//! true` for cookies and `Authorization`. Browsers reject `*` in that mode,
+//! so the allowed origin is always echoed. Credentials are only granted to
+//! origins matched by an explicit list; with an allow-any policy the
+//! response keeps `*` without them.
+//!
+//! A preflight echoes `Access-Control-Request-Method` (when it is one of
+//! [`ALLOWED_METHODS`]) and `Access-Control-Request-Headers` back rather
+//! than answering with wildcards, which browsers ignore for credentialed
+//! requests; a preflight for any other method gets no CORS headers, and a
+//! bare `OPTIONS` lists every allowed method.
+//!
+//! Preflight answers carry `Access-Control-Max-Age` (`CORS_MAX_AGE` seconds,
+//! default 7200, the longest Chromium honours; `0` omits it) so browsers
+//! stop repeating them. `CORS_PREFLIGHT_CACHE=true` also keeps the answers
+//! in the Cache API for that long, keyed by URL, origin and the requested
+//! method and headers.
+//!
+//! Browser scripts can only read safelisted response headers, so allowed
+//! responses list the headers the proxy adds itself (request id,
+//! `Server-Timing`, rate limit and diagnostic headers...) in
+//! `Access-Control-Expose-Headers`, along with any upstream headers named
+//! in `CORS_EXPOSE_HEADERS`.
+//!
+//! `CORS_RULES` gives targets their own policy (first match wins,
+//! `host[/path-prefix]` patterns); fields a rule leaves out keep the
+//! deployment-wide value:
+//! `[{"pattern": "cdn.example.com", "origins": ["*"]},
+//! {"pattern": "api.example.com", "origins": ["https://app.example.com"],
+//! "credentials": true, "methods": ["GET", "POST"], "max_age": 600}]`.
+//! A preflight is matched against the target in its `url` parameter or
+//! path, since browsers don't send `X-Target-URL` values with it.
+//!
+//! `CORS_PASSTHROUGH=true` (or `"passthrough": true` in a rule) is a plain
+//! reverse proxy for upstreams that implement CORS themselves: the proxy
+//! adds no CORS headers, keeps the upstream's, and sends `OPTIONS` requests
+//! on to the upstream like any other request (so they must pass the
+//! deployment's other checks). Otherwise the proxy answers preflights and
+//! replaces any `Access-Control-*` headers the upstream sent.
+//!
+//! When the answer depends on the caller (an origin list rather than `*`)
+//! responses and preflights add `Origin` to `Vary`, keeping the upstream's
+//! own members, so caches don't hand one origin's answer to another.
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{config, freshness, origins, target, utils};
+
+pub const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS, PATCH, HEAD";
+const DEFAULT_MAX_AGE_SECS: u64 = 7200;
+
+/// Response headers scripts can read without being exposed.
+const SAFELISTED: &[&str] = &[
+    "cache-control",
+    "content-language",
+    "content-length",
+    "content-type",
+    "expires",
+    "last-modified",
+    "pragma",
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    /// Origin patterns; empty allows any origin.
+    pub origins: Vec<String>,
+    pub credentials: bool,
+    /// Allowed methods; empty allows [`ALLOWED_METHODS`].
+    pub methods: Vec<String>,
+    /// Preflight lifetime in seconds; `0` sends no `Access-Control-Max-Age`.
+    pub max_age: u64,
+    /// Leave CORS to the upstream.
+    pub passthrough: bool,
+}
+
+/// A `CORS_RULES` entry overriding parts of the policy for some targets.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct CorsRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub origins: Option<Vec<String>>,
+    #[serde(default)]
+    pub credentials: Option<bool>,
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub passthrough: Option<bool>,
+}
+
+pub fn rule_for<'a>(rules: &'a [CorsRule], target: &Url) -> Option<&'a CorsRule> {
+    let host = target.host_str().unwrap_or_default();
+    rules
+        .iter()
+        .find(|r| utils::url_matches(&r.pattern, host, target.path()))
+}
+
+impl Policy {
+    /// The policy for `target`: the deployment-wide one with the first
+    /// matching `CORS_RULES` entry applied.
+    pub fn for_target(env: &Env, target: Option<&Url>) -> Self {
+        let policy = Self::from_env(env);
+        let Some(target) = target else {
+            return policy;
+        };
+        let rules: Vec<CorsRule> = config::var_json(env, "CORS_RULES").unwrap_or_default();
+        match rule_for(&rules, target) {
+            Some(rule) => policy.with_rule(rule),
+            None => policy,
+        }
+    }
+
+    pub fn with_rule(mut self, rule: &CorsRule) -> Self {
+        if let Some(origins) = &rule.origins {
+            self.origins = origins.clone();
+        }
+        if let Some(credentials) = rule.credentials {
+            self.credentials = credentials;
+        }
+        if let Some(methods) = &rule.methods {
+            self.methods = methods.clone();
+        }
+        if let Some(max_age) = rule.max_age {
+            self.max_age = max_age;
+        }
+        if let Some(passthrough) = rule.passthrough {
+            self.passthrough = passthrough;
+        }
+        self
+    }
+
+    pub fn from_env(env: &Env) -> Self {
+        let mut origins = config::var_list(env, "CORS_ALLOWED_ORIGINS");
+        if origins.is_empty() {
+            origins = config::var_list(env, "ALLOWED_ORIGINS");
+        }
+        Policy {
+            origins,
+            credentials: config::var(env, "CORS_ALLOW_CREDENTIALS").as_deref() == Some("true"),
+            methods: Vec::new(),
+            max_age: config::var_u64(env, "CORS_MAX_AGE").unwrap_or(DEFAULT_MAX_AGE_SECS),
+            passthrough: config::var(env, "CORS_PASSTHROUGH").as_deref() == Some("true"),
+        }
+    }
+
+    fn allows_any(&self) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|p| p.trim() == "*")
+    }
+
+    fn methods(&self) -> Vec<&str> {
+        match self.methods.is_empty() {
+            true => ALLOWED_METHODS.split(',').map(str::trim).collect(),
+            false => self.methods.iter().map(|m| m.trim()).collect(),
+        }
+    }
+
+    fn method_allowed(&self, method: &str) -> bool {
+        self.methods()
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    /// Whether the CORS headers depend on the request's `Origin`.
+    pub fn varies_by_origin(&self) -> bool {
+        !self.passthrough && !self.allows_any()
+    }
+
+    fn with_credentials(&self) -> bool {
+        self.credentials && !self.allows_any()
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request from `origin`.
+    pub fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.allows_any() {
+            return Some("*".into());
+        }
+        let origin = origin.filter(|o| *o != "null")?;
+        let parsed = Url::parse(origin).ok()?;
+        self.origins
+            .iter()
+            .any(|p| origins::origin_matches(p, &parsed))
+            .then(|| origin.to_string())
+    }
+
+    /// Headers for an actual (non-preflight) response.
+    pub fn response_headers(&self, origin: Option<&str>) -> Vec<(&'static str, String)> {
+        if self.passthrough {
+            return Vec::new();
+        }
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return Vec::new();
+        };
+        let mut headers = vec![("Access-Control-Allow-Origin", allow_origin)];
+        if self.with_credentials() {
+            headers.push(("Access-Control-Allow-Credentials", "true".into()));
+        }
+        headers
+    }
+
+    /// Headers answering a preflight for `request_method` and
+    /// `request_headers` (`Access-Control-Request-Method`/`-Headers`).
+    pub fn preflight_headers(
+        &self,
+        origin: Option<&str>,
+        request_method: Option<&str>,
+        request_headers: Option<&str>,
+    ) -> Vec<(&'static str, String)> {
+        let mut headers = self.response_headers(origin);
+        if headers.is_empty() {
+            return headers;
+        }
+        match request_method.map(str::trim) {
+            Some(method) if self.method_allowed(method) => {
+                headers.push(("Access-Control-Allow-Methods", method.into()));
+            }
+            Some(_) => return Vec::new(),
+            None => headers.push(("Access-Control-Allow-Methods", self.methods().join(", "))),
+        }
+        if let Some(requested) = request_headers.filter(|h| !h.trim().is_empty()) {
+            headers.push(("Access-Control-Allow-Headers", requested.trim().into()));
+        }
+        if self.max_age > 0 {
+            headers.push(("Access-Control-Max-Age", self.max_age.to_string()));
+        }
+        headers
+    }
+}
+
+/// `Access-Control-Expose-Headers` for `names` plus `extra`, in order and
+/// without duplicates; CORS headers themselves and `Set-Cookie`, which
+/// browsers never expose, are left out.
+pub fn expose_list<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    extra: &'a [String],
+) -> Option<String> {
+    let mut exposed: Vec<String> = Vec::new();
+    for name in names.into_iter().chain(extra.iter().map(String::as_str)) {
+        let name = name.trim().to_ascii_lowercase();
+        if name.is_empty()
+            || name.starts_with("access-control-")
+            || name == "set-cookie"
+            || SAFELISTED.contains(&name.as_str())
+            || exposed.contains(&name)
+        {
+            continue;
+        }
+        exposed.push(name);
+    }
+    (!exposed.is_empty()).then(|| exposed.join(", "))
+}
+
+/// The calling `Origin`, if any.
+pub fn request_origin(req: &Request) -> Result<Option<String>> {
+    req.headers().get("Origin")
+}
+
+/// Cache API key for a preflight of `url` with the given request headers.
+pub fn preflight_cache_key(
+    url: &Url,
+    origin: Option<&str>,
+    method: Option<&str>,
+    headers: Option<&str>,
+) -> String {
+    let mut key = Url::parse("https://cors-preflight.proxyflare.internal/").expect("valid URL");
+    key.query_pairs_mut()
+        .append_pair("url", url.as_str())
+        .append_pair("origin", origin.unwrap_or_default())
+        .append_pair("method", method.unwrap_or_default())
+        .append_pair("headers", headers.unwrap_or_default());
+    key.into()
+}
+
+/// The target a preflight is for, from the `url` parameter or the path.
+pub fn preflight_target(url: &Url) -> Option<Url> {
+    let raw = url
+        .query_pairs()
+        .find(|(k, _)| k == "url")
+        .map(|(_, v)| v.into_owned())
+        .or_else(|| target::from_path(url.path()).map(str::to_string))?;
+    target::parse(&raw)
+}
+
+/// Answer a preflight request, unless its target passes CORS through.
+pub async fn preflight(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let url = req.url()?;
+    let policy = Policy::for_target(env, preflight_target(&url).as_ref());
+    if policy.passthrough {
+        return Ok(None);
+    }
+    let origin = request_origin(req)?;
+    let requested_method = req.headers().get("Access-Control-Request-Method")?;
+    let requested = req.headers().get("Access-Control-Request-Headers")?;
+    let cached =
+        policy.max_age > 0 && config::var(env, "CORS_PREFLIGHT_CACHE").as_deref() == Some("true");
+    let cache = match cached {
+        true => Some((
+            Cache::default(),
+            preflight_cache_key(
+                &url,
+                origin.as_deref(),
+                requested_method.as_deref(),
+                requested.as_deref(),
+            ),
+        )),
+        false => None,
+    };
+    if let Some((cache, key)) = &cache {
+        if let Some(cached) = cache.get(key, false).await? {
+            return Ok(Some(cached));
+        }
+    }
+
+    let headers = Headers::new();
+    if policy.varies_by_origin() {
+        headers.set("Vary", "Origin")?;
+    }
+    for (name, value) in policy.preflight_headers(
+        origin.as_deref(),
+        requested_method.as_deref(),
+        requested.as_deref(),
+    ) {
+        headers.set(name, &value)?;
+    }
+    let mut response = Response::empty()?.with_status(204).with_headers(headers);
+    if let Some((cache, key)) = &cache {
+        let mut cached = response.cloned()?;
+        cached.headers_mut().set(
+            "Cache-Control",
+            &format!("public, max-age={}", policy.max_age),
+        )?;
+        if let Err(e) = cache.put(key, cached).await {
+            console_error!("Failed to cache CORS preflight: {:?}", e);
+        }
+    }
+    Ok(Some(response))
+}
+
+/// Whether an upstream response header is kept under `policy`.
+pub fn keeps_upstream_header(policy: &Policy, lowercase_name: &str) -> bool {
+    policy.passthrough || !lowercase_name.starts_with("access-control-")
+}
+
+/// Add the CORS headers for the request's origin to `headers`.
+pub fn annotate(headers: &Headers, req: &Request, policy: &Policy) -> Result<()> {
+    let origin = request_origin(req)?;
+    for (name, value) in policy.response_headers(origin.as_deref()) {
+        headers.set(name, &value)?;
+    }
+    Ok(())
+}
+
+/// Expose the proxy's own `headers` (and `CORS_EXPOSE_HEADERS`) to
+/// scripts, on responses that allow the caller's origin.
+pub fn expose(headers: &Headers, env: &Env) -> Result<()> {
+    if !headers.has("Access-Control-Allow-Origin")? {
+        return Ok(());
+    }
+    let names: Vec<String> = headers.keys().collect();
+    let mut extra = vec![freshness::FETCHED_AT_HEADER.to_string()];
+    extra.extend(config::var_list(env, "CORS_EXPOSE_HEADERS"));
+    if let Some(list) = expose_list(names.iter().map(String::as_str), &extra) {
+        headers.set("Access-Control-Expose-Headers", &list)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str]) -> Policy {
+        Policy {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            credentials: false,
+            methods: Vec::new(),
+            max_age: 0,
+            passthrough: false,
+        }
+    }
+
+    #[test]
+    fn test_allow_origin() {
+        assert_eq!(policy(&[]).allow_origin(None).as_deref(), Some("*"));
+        assert_eq!(
+            policy(&["*"])
+                .allow_origin(Some("https://a.example"))
+                .as_deref(),
+            Some("*")
+        );
+        let listed = policy(&["https://app.example.com", "https://*.example.dev"]);
+        assert_eq!(
+            listed
+                .allow_origin(Some("https://app.example.com"))
+                .as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            listed
+                .allow_origin(Some("https://pr-1.example.dev"))
+                .as_deref(),
+            Some("https://pr-1.example.dev")
+        );
+        assert_eq!(listed.allow_origin(Some("https://evil.example")), None);
+        assert_eq!(listed.allow_origin(Some("null")), None);
+        assert_eq!(listed.allow_origin(None), None);
+        assert!(listed
+            .response_headers(Some("https://evil.example"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_preflight_cache_key() {
+        let url = Url::parse("https://proxy.example.com/https://api.example.com/x").unwrap();
+        let key = preflight_cache_key(&url, Some("https://a.example"), Some("PUT"), None);
+        assert!(key.starts_with("https://cors-preflight.proxyflare.internal/?url=https%3A%2F%2F"));
+        assert!(key.contains("&method=PUT&headers="));
+        assert_ne!(
+            key,
+            preflight_cache_key(&url, Some("https://b.example"), Some("PUT"), None)
+        );
+    }
+
+    #[test]
+    fn test_preflight_reflection() {
+        let open = policy(&[]);
+        let headers = open.preflight_headers(None, Some("PATCH"), Some("x-custom"));
+        assert!(headers.contains(&("Access-Control-Allow-Methods", "PATCH".into())));
+        assert!(headers.contains(&("Access-Control-Allow-Headers", "x-custom".into())));
+        assert!(!headers.iter().any(|(name, _)| name.ends_with("Max-Age")));
+        assert!(open.preflight_headers(None, Some("TRACE"), None).is_empty());
+        let bare = open.preflight_headers(None, None, None);
+        assert!(bare.contains(&("Access-Control-Allow-Methods", ALLOWED_METHODS.into())));
+        assert!(!bare
+            .iter()
+            .any(|(name, _)| *name == "Access-Control-Allow-Headers"));
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules: Vec<CorsRule> = serde_json::from_str(
+            r#"[{"pattern": "cdn.example.com", "origins": ["*"]},
+                {"pattern": "api.example.com/v2", "credentials": true,
+                 "methods": ["GET", "POST"], "max_age": 600}]"#,
+        )
+        .unwrap();
+        let global = policy(&["https://app.example.com"]);
+        let url = |s: &str| Url::parse(s).unwrap();
+
+        let cdn = global
+            .clone()
+            .with_rule(rule_for(&rules, &url("https://cdn.example.com/a.js")).unwrap());
+        assert_eq!(
+            cdn.allow_origin(Some("https://x.example")).as_deref(),
+            Some("*")
+        );
+
+        let api = global
+            .clone()
+            .with_rule(rule_for(&rules, &url("https://api.example.com/v2/users")).unwrap());
+        assert_eq!(api.origins, global.origins);
+        let headers = api.preflight_headers(Some("https://app.example.com"), None, None);
+        assert!(headers.contains(&("Access-Control-Allow-Methods", "GET, POST".into())));
+        assert!(headers.contains(&("Access-Control-Allow-Credentials", "true".into())));
+        assert!(headers.contains(&("Access-Control-Max-Age", "600".into())));
+        assert!(api
+            .preflight_headers(Some("https://app.example.com"), Some("DELETE"), None)
+            .is_empty());
+
+        assert_eq!(rule_for(&rules, &url("https://api.example.com/v1")), None);
+        assert_eq!(
+            preflight_target(&url("https://proxy.example/https://api.example.com/v2/x"))
+                .map(String::from)
+                .as_deref(),
+            Some("https://api.example.com/v2/x")
+        );
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut upstream = policy(&[]);
+        upstream.passthrough = true;
+        assert!(upstream
+            .response_headers(Some("https://a.example"))
+            .is_empty());
+        assert!(upstream
+            .preflight_headers(Some("https://a.example"), Some("GET"), None)
+            .is_empty());
+        assert!(keeps_upstream_header(
+            &upstream,
+            "access-control-allow-origin"
+        ));
+        assert!(!keeps_upstream_header(
+            &policy(&[]),
+            "access-control-allow-origin"
+        ));
+        assert!(keeps_upstream_header(&policy(&[]), "content-type"));
+    }
+
+    #[test]
+    fn test_expose_list() {
+        let extra = vec!["X-Upstream-Id".to_string(), "x-request-id".to_string()];
+        assert_eq!(
+            expose_list(
+                [
+                    "access-control-allow-origin",
+                    "set-cookie",
+                    "x-request-id",
+                    "server-timing",
+                    "content-type",
+                ],
+                &extra
+            )
+            .as_deref(),
+            Some("x-request-id, server-timing, x-upstream-id")
+        );
+        assert_eq!(expose_list(["content-type"], &[]), None);
+    }
+
+    #[test]
+    fn test_credentials() {
+        let app = "https://app.example.com";
+        let origin = Some(app);
+        let mut listed = policy(&["https://app.example.com"]);
+        listed.credentials = true;
+        listed.max_age = 600;
+        let headers =
+            listed.preflight_headers(origin, Some("PUT"), Some("authorization, content-type"));
+        assert!(headers.contains(&("Access-Control-Allow-Origin", app.into())));
+        assert!(headers.contains(&("Access-Control-Allow-Credentials", "true".into())));
+        assert!(headers.contains(&(
+            "Access-Control-Allow-Headers",
+            "authorization, content-type".into()
+        )));
+        assert!(headers.contains(&("Access-Control-Max-Age", "600".into())));
+        assert!(!listed
+            .response_headers(origin)
+            .iter()
+            .any(|(_, v)| v == "*"));
+
+        let mut open = policy(&[]);
+        open.credentials = true;
+        let headers = open.response_headers(origin);
+        assert!(headers.contains(&("Access-Control-Allow-Origin", "*".into())));
+        assert!(!headers
+            .iter()
+            .any(|(name, _)| *name == "Access-Control-Allow-Credentials"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Opt-in timestamp normalization for JSON responses.
+//!
+//! `?dates=iso|epoch|epoch_ms` rewrites every JSON string that is an RFC 3339
+//! timestamp (with offset): `epoch`/`epoch_ms` turn it into a number, `iso`
+//! re-renders it in `tz=<IANA zone>` (default UTC). Other values, including
+//! bare dates, are left alone. `tz` is only read (and kept from the target)
+//! alongside `dates`; on its own it belongs to the target URL.
+
+use jiff::{tz::TimeZone, Timestamp};
+use serde_json::Value;
+use worker::*;
+
+pub const TZ_PARAM: &str = "tz";
+pub const FORMAT_PARAM: &str = "dates";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateFormat {
+    Iso,
+    Epoch,
+    EpochMs,
+}
+
+#[derive(Debug, Clone)]
+pub struct DateOptions {
+    pub format: DateFormat,
+    pub tz: TimeZone,
+}
+
+/// Options from the worker URL; `Ok(None)` when not requested.
+pub fn requested(url: &Url) -> std::result::Result<Option<DateOptions>, String> {
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let Some(format) = param(FORMAT_PARAM) else {
+        return Ok(None);
+    };
+    let format = match format.as_str() {
+        "iso" => DateFormat::Iso,
+        "epoch" => DateFormat::Epoch,
+        "epoch_ms" => DateFormat::EpochMs,
+        other => return Err(format!("Unknown dates format {other:?}")),
+    };
+    let tz = match param(TZ_PARAM) {
+        Some(name) => TimeZone::get(&name).map_err(|_| format!("Unknown time zone {name:?}"))?,
+        None => TimeZone::UTC,
+    };
+    Ok(Some(DateOptions { format, tz }))
+}
+
+/// Parse an RFC 3339 timestamp; cheap shape check first since most strings
+/// aren't dates.
+fn parse_timestamp(s: &str) -> Option<Timestamp> {
+    let b = s.as_bytes();
+    let shaped = b.len() >= 20
+        && b.len() <= 40
+        && b[..4].iter().all(u8::is_ascii_digit)
+        && b[4] == b'-'
+        && matches!(b[10], b'T' | b't' | b' ');
+    if !shaped {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn convert(ts: Timestamp, options: &DateOptions) -> Value {
+    match options.format {
+        DateFormat::Epoch => Value::from(ts.as_second()),
+        DateFormat::EpochMs => Value::from(ts.as_millisecond()),
+        DateFormat::Iso => {
+            let zoned = ts.to_zoned(options.tz.clone());
+            Value::from(ts.display_with_offset(zoned.offset()).to_string())
+        }
+    }
+}
+
+/// Rewrite timestamps in place; returns how many were converted.
+pub fn normalize(value: &mut Value, options: &DateOptions) -> usize {
+    match value {
+        Value::String(s) => match parse_timestamp(s) {
+            Some(ts) => {
+                *value = convert(ts, options);
+                1
+            }
+            None => 0,
+        },
+        Value::Array(items) => items.iter_mut().map(|v| normalize(v, options)).sum(),
+        Value::Object(map) => map.values_mut().map(|v| normalize(v, options)).sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn options(query: &str) -> DateOptions {
+        let url = Url::parse(&format!("https://w.dev/?url=x&{query}")).unwrap();
+        requested(&url).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_requested() {
+        let url = Url::parse("https://w.dev/?url=x").unwrap();
+        assert!(requested(&url).unwrap().is_none());
+        assert_eq!(options("dates=epoch").format, DateFormat::Epoch);
+        assert_eq!(options("dates=iso&tz=Asia/Tokyo").format, DateFormat::Iso);
+        // A lone `tz` is the target's.
+        let url = Url::parse("https://w.dev/?url=x&tz=Asia/Tokyo").unwrap();
+        assert!(requested(&url).unwrap().is_none());
+        let url = Url::parse("https://w.dev/?dates=iso&tz=Mars/Olympus").unwrap();
+        assert!(requested(&url).is_err());
+    }
+
+    #[test]
+    fn test_normalize_to_timezone() {
+        let mut body = json!({
+            "created": "2024-03-01T12:00:00Z",
+            "items": [{"at": "2024-03-01T00:30:00+01:00"}],
+            "day": "2024-03-01",
+            "name": "not a date",
+        });
+        assert_eq!(normalize(&mut body, &options("dates=iso&tz=Asia/Tokyo")), 2);
+        assert_eq!(body["created"], "2024-03-01T21:00:00+09:00");
+        assert_eq!(body["items"][0]["at"], "2024-03-01T08:30:00+09:00");
+        assert_eq!(body["day"], "2024-03-01");
+        assert_eq!(body["name"], "not a date");
+    }
+
+    #[test]
+    fn test_normalize_to_epoch() {
+        let mut body = json!(["1970-01-01T00:01:00Z", "1970-01-01T00:00:01.5Z"]);
+        normalize(&mut body, &options("dates=epoch"));
+        assert_eq!(body, json!([60, 1]));
+        let mut body = json!("1970-01-01T00:00:01.5Z");
+        normalize(&mut body, &options("dates=epoch_ms"));
+        assert_eq!(body, json!(1500));
+    }
+}
@@ -0,0 +1,157 @@
+//! Per-request deadlines, propagated upstream.
+//!
+//! A request's deadline is the earliest of the client's `X-Deadline`
+//! (absolute Unix time in milliseconds), its `grpc-timeout` (relative, gRPC
+//! syntax such as `500m` or `5S`) and the `REQUEST_DEADLINE_MS` default. The
+//! remaining budget is forwarded upstream in both headers, upstream fetches
+//! are aborted with 504 once it runs out, and optional work (the protocol
+//! fallback retry, JSON rewriting) is skipped when nothing is left.
+
+use std::pin::pin;
+use std::time::Duration;
+
+use futures_util::future::{select, Either};
+use worker::*;
+
+use crate::{config, responses};
+
+pub const DEADLINE_HEADER: &str = "X-Deadline";
+pub const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// Largest value gRPC allows in a timeout (at most 8 digits).
+const GRPC_MAX_DIGITS: u64 = 99_999_999;
+
+/// Parse a gRPC timeout (`<digits><unit>`, unit one of `HMSmun`) into
+/// milliseconds, rounding sub-millisecond values up.
+pub fn parse_grpc_timeout(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value.len().checked_sub(1)?;
+    let (digits, unit) = value.split_at(split);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => amount * 3_600_000,
+        "M" => amount * 60_000,
+        "S" => amount * 1000,
+        "m" => amount,
+        "u" => amount.div_ceil(1000),
+        "n" => amount.div_ceil(1_000_000),
+        _ => return None,
+    })
+}
+
+/// Format milliseconds as a gRPC timeout, switching to seconds when the
+/// value doesn't fit in eight digits.
+pub fn grpc_timeout(ms: u64) -> String {
+    if ms <= GRPC_MAX_DIGITS {
+        format!("{ms}m")
+    } else {
+        format!("{}S", ms.div_ceil(1000).min(GRPC_MAX_DIGITS))
+    }
+}
+
+/// Absolute deadline (Unix ms) from the client's headers and the default
+/// budget; unparsable headers are ignored.
+pub fn resolve(
+    now_ms: u64,
+    deadline: Option<&str>,
+    timeout: Option<&str>,
+    default_ms: Option<u64>,
+) -> Option<u64> {
+    [
+        deadline.and_then(|d| d.trim().parse().ok()),
+        timeout
+            .and_then(parse_grpc_timeout)
+            .map(|t| now_ms.saturating_add(t)),
+        default_ms.map(|d| now_ms.saturating_add(d)),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+}
+
+pub fn from_request(req: &Request, env: &Env, now_ms: u64) -> Result<Option<u64>> {
+    let headers = req.headers();
+    Ok(resolve(
+        now_ms,
+        headers.get(DEADLINE_HEADER)?.as_deref(),
+        headers.get(GRPC_TIMEOUT_HEADER)?.as_deref(),
+        config::var_u64(env, "REQUEST_DEADLINE_MS"),
+    ))
+}
+
+/// Tell the upstream how long it has.
+pub fn forward(headers: &Headers, deadline_ms: u64, remaining_ms: u64) -> Result<()> {
+    headers.set(DEADLINE_HEADER, &deadline_ms.to_string())?;
+    headers.set(GRPC_TIMEOUT_HEADER, &grpc_timeout(remaining_ms))
+}
+
+/// Send `request`, aborting it after `remaining_ms`; `Ok(None)` on timeout.
+pub async fn fetch_within(request: Request, remaining_ms: Option<u64>) -> Result<Option<Response>> {
+    let Some(remaining_ms) = remaining_ms else {
+        return Fetch::Request(request).send().await.map(Some);
+    };
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let fetch = Fetch::Request(request);
+    let fetch = pin!(fetch.send_with_signal(&signal));
+    let timer = pin!(Delay::from(Duration::from_millis(remaining_ms)));
+    match select(fetch, timer).await {
+        Either::Left((response, _)) => response.map(Some),
+        Either::Right(_) => {
+            controller.abort();
+            Ok(None)
+        }
+    }
+}
+
+pub fn exceeded() -> Result<Response> {
+    responses::error(
+        504,
+        "deadline_exceeded",
+        "The request deadline passed before the upstream responded",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("500m"), Some(500));
+        assert_eq!(parse_grpc_timeout("5S"), Some(5000));
+        assert_eq!(parse_grpc_timeout("2M"), Some(120_000));
+        assert_eq!(parse_grpc_timeout("1H"), Some(3_600_000));
+        assert_eq!(parse_grpc_timeout("1500u"), Some(2));
+        assert_eq!(parse_grpc_timeout("1n"), Some(1));
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(parse_grpc_timeout("123456789m"), None);
+        assert_eq!(parse_grpc_timeout("5s"), None);
+        assert_eq!(parse_grpc_timeout("-5S"), None);
+    }
+
+    #[test]
+    fn test_grpc_timeout_format() {
+        assert_eq!(grpc_timeout(250), "250m");
+        assert_eq!(grpc_timeout(100_000_000), "100000S");
+        assert_eq!(parse_grpc_timeout(&grpc_timeout(1234)), Some(1234));
+    }
+
+    #[test]
+    fn test_resolve_takes_earliest() {
+        let now = 1_000_000;
+        assert_eq!(resolve(now, None, None, None), None);
+        assert_eq!(resolve(now, None, None, Some(30_000)), Some(1_030_000));
+        assert_eq!(
+            resolve(now, Some("1002000"), Some("5S"), Some(30_000)),
+            Some(1_002_000)
+        );
+        assert_eq!(
+            resolve(now, Some("soon"), Some("5S"), None),
+            Some(1_005_000)
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Deprecation and sunset headers for fronted APIs.
+//!
+//! `DEPRECATIONS` lists routes (first match wins, `host[/path-prefix]`
+//! patterns) and their lifecycle dates:
+//! `[{"pattern": "api.example.com/v1", "deprecation": "2024-06-01",
+//! "sunset": "2025-01-01", "link": "https://example.com/v1-migration"}]`.
+//! Dates are `YYYY-MM-DD` (midnight UTC) or RFC 3339 timestamps. Matching
+//! responses get `Deprecation: @<epoch>` (RFC 9745), `Sunset: <HTTP-date>`
+//! (RFC 8594) and `Link: <link>; rel="deprecation"`, so API owners can
+//! announce lifecycle without touching the origin.
+
+use jiff::{civil::Date, tz::TimeZone, Timestamp};
+use serde::Deserialize;
+use worker::*;
+
+use crate::{config, utils};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct DeprecationRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub deprecation: Option<String>,
+    #[serde(default)]
+    pub sunset: Option<String>,
+    #[serde(default)]
+    pub link: Option<String>,
+}
+
+/// Epoch seconds of a `YYYY-MM-DD` date or RFC 3339 timestamp.
+pub fn parse_date(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(ts) = value.parse::<Timestamp>() {
+        return Some(ts.as_second());
+    }
+    let date: Date = value.parse().ok()?;
+    Some(date.to_zoned(TimeZone::UTC).ok()?.timestamp().as_second())
+}
+
+/// Header pairs for `rule`; unparsable dates are skipped.
+pub fn headers(rule: &DeprecationRule) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if let Some(at) = rule.deprecation.as_deref().and_then(parse_date) {
+        headers.push(("Deprecation", format!("@{at}")));
+    }
+    if let Some(at) = rule.sunset.as_deref().and_then(parse_date) {
+        headers.push(("Sunset", utils::http_date(at.max(0) as u64 * 1000)));
+    }
+    if let Some(link) = &rule.link {
+        headers.push(("Link", format!("<{link}>; rel=\"deprecation\"")));
+    }
+    headers
+}
+
+pub fn rule_for<'a>(rules: &'a [DeprecationRule], target: &Url) -> Option<&'a DeprecationRule> {
+    let host = target.host_str().unwrap_or_default();
+    rules
+        .iter()
+        .find(|r| utils::url_matches(&r.pattern, host, target.path()))
+}
+
+/// Add lifecycle headers for `target` to the response headers.
+pub fn annotate(headers: &Headers, env: &Env, target: &Url) -> Result<()> {
+    let rules: Vec<DeprecationRule> = config::var_json(env, "DEPRECATIONS").unwrap_or_default();
+    if let Some(rule) = rule_for(&rules, target) {
+        for (name, value) in self::headers(rule) {
+            headers.append(name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("2024-03-01"), Some(1_709_251_200));
+        assert_eq!(parse_date("2024-03-01T00:00:00Z"), Some(1_709_251_200));
+        assert_eq!(parse_date("2024-03-01T01:00:00+01:00"), Some(1_709_251_200));
+        assert_eq!(parse_date("next year"), None);
+    }
+
+    #[test]
+    fn test_headers() {
+        let rule: DeprecationRule = serde_json::from_str(
+            r#"{"pattern": "api.example.com/v1", "deprecation": "2024-03-01",
+                "sunset": "2024-03-01", "link": "https://example.com/migrate"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            headers(&rule),
+            vec![
+                ("Deprecation", "@1709251200".to_string()),
+                ("Sunset", "Fri, 01 Mar 2024 00:00:00 GMT".to_string()),
+                (
+                    "Link",
+                    "<https://example.com/migrate>; rel=\"deprecation\"".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rule_for_first_match() {
+        let rules: Vec<DeprecationRule> = serde_json::from_str(
+            r#"[{"pattern": "api.example.com/v1", "sunset": "2025-01-01"},
+                {"pattern": "api.example.com"}]"#,
+        )
+        .unwrap();
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            rule_for(&rules, &url("https://api.example.com/v1/users")),
+            Some(&rules[0])
+        );
+        assert_eq!(
+            rule_for(&rules, &url("https://api.example.com/v2/users")),
+            Some(&rules[1])
+        );
+        assert_eq!(rule_for(&rules, &url("https://other.example/")), None);
+    }
+}
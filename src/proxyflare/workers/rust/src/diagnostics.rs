@@ -0,0 +1,105 @@
+//! Edge diagnostics on proxied responses.
+//!
+//! With `DIAGNOSTIC_HEADERS=true`, responses carry `X-Proxy-Colo` (the
+//! Cloudflare data center that served the request), `X-Proxy-Country` (the
+//! caller's country as Cloudflare sees it) and `X-Proxy-Version`, so clients
+//! can say which edge location and build they hit when reporting regional
+//! issues.
+//!
+//! `GET /version` describes the build itself: crate version, git commit,
+//! build time and enabled Cargo features, all embedded at compile time by
+//! `build.rs`. The same build id (`<version>+<sha>`) is stamped into request
+//! logs and error responses, so behavior can be tied to an exact build.
+
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, responses};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("PROXYFLARE_GIT_SHA");
+/// Unix seconds.
+const BUILT_AT: &str = env!("PROXYFLARE_BUILT_AT");
+const FEATURES: &str = env!("PROXYFLARE_FEATURES");
+pub const PATH: &str = "/version";
+
+/// `<version>+<sha>`, as stamped into logs and errors.
+pub fn build_id() -> String {
+    format!("{VERSION}+{GIT_SHA}")
+}
+
+pub fn build_metadata() -> Value {
+    let built_at = BUILT_AT
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| jiff::Timestamp::from_second(secs).ok())
+        .map(|ts| ts.to_string());
+    let features: Vec<&str> = FEATURES.split(',').filter(|f| !f.is_empty()).collect();
+    json!({
+        "version": VERSION,
+        "git_sha": GIT_SHA,
+        "built_at": built_at,
+        "features": features,
+        "build": build_id(),
+    })
+}
+
+/// `GET /version`
+pub fn handle() -> Result<Response> {
+    responses::json(200, &build_metadata())
+}
+
+pub fn is_enabled(env: &Env) -> bool {
+    config::var(env, "DIAGNOSTIC_HEADERS").as_deref() == Some("true")
+}
+
+/// Header pairs to add; unknown values are left out.
+pub fn values(colo: Option<&str>, country: Option<&str>) -> Vec<(&'static str, String)> {
+    [
+        ("X-Proxy-Colo", colo),
+        ("X-Proxy-Country", country),
+        ("X-Proxy-Version", Some(VERSION)),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|v| (name, v.to_string())))
+    .collect()
+}
+
+pub fn annotate(headers: &Headers, env: &Env, rctx: &RequestCtx) -> Result<()> {
+    if !is_enabled(env) {
+        return Ok(());
+    }
+    for (name, value) in values(rctx.colo.as_deref(), rctx.country.as_deref()) {
+        headers.set(name, &value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_metadata() {
+        let metadata = build_metadata();
+        assert_eq!(metadata["version"], VERSION);
+        assert_eq!(metadata["build"], format!("{VERSION}+{GIT_SHA}"));
+        assert!(metadata["built_at"]
+            .as_str()
+            .is_some_and(|t| t.ends_with('Z')));
+        assert!(metadata["features"].is_array());
+    }
+
+    #[test]
+    fn test_values_skip_unknown() {
+        let values = values(Some("AMS"), None);
+        assert_eq!(
+            values,
+            vec![
+                ("X-Proxy-Colo", "AMS".to_string()),
+                ("X-Proxy-Version", VERSION.to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Egress bandwidth budgets per caller or IP.
+//!
+//! `EGRESS_BUDGETS` sets how many response bytes each caller id may pull
+//! through the proxy per `EGRESS_WINDOW_SECS` (default 86400), with `"*"` as
+//! the default: `{"*": 1073741824, "team-a": 53687091200}`. Anonymous
+//! callers are metered per client IP. Bytes are counted while the body
+//! streams and added to the caller's `QUOTA_COUNTER` object once it ends, so
+//! the request that crosses the budget completes; the next one gets 429.
+//! Without the binding budgets are not enforced.
+
+use std::collections::HashMap;
+
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, quota, spend, streams};
+
+const BUCKET: &str = "egress:bytes";
+const DEFAULT_WINDOW_SECS: u64 = 86_400;
+
+#[derive(Debug, Clone)]
+pub struct Budget {
+    pub subject: String,
+    pub limit: u64,
+    pub window_secs: u64,
+}
+
+impl Budget {
+    fn request(&self, bytes: u64) -> quota::ConsumeRequest {
+        quota::ConsumeRequest {
+            bucket: BUCKET.into(),
+            amount: bytes,
+            limit: self.limit,
+            window_secs: self.window_secs,
+            sliding: false,
+            force: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EgressCheck {
+    Unmetered,
+    Metered(Budget),
+    Exceeded(Response),
+}
+
+/// Counter subject: the caller id, or the client IP for anonymous callers.
+pub fn subject(caller: Option<&str>, ip: Option<&str>) -> Option<String> {
+    match (caller, ip) {
+        (Some(caller), _) => Some(format!("egress:{caller}")),
+        (None, Some(ip)) => Some(format!("egress:ip:{ip}")),
+        (None, None) => None,
+    }
+}
+
+pub async fn check(env: &Env, rctx: &RequestCtx) -> Result<EgressCheck> {
+    let budgets: HashMap<String, u64> = config::var_json(env, "EGRESS_BUDGETS").unwrap_or_default();
+    let Some(limit) = spend::cap_for(&budgets, rctx.tenant()) else {
+        return Ok(EgressCheck::Unmetered);
+    };
+    let Some(subject) = subject(rctx.caller.as_deref(), rctx.client_ip.as_deref()) else {
+        return Ok(EgressCheck::Unmetered);
+    };
+    let budget = Budget {
+        subject,
+        limit,
+        window_secs: config::var_u64(env, "EGRESS_WINDOW_SECS").unwrap_or(DEFAULT_WINDOW_SECS),
+    };
+    // A zero-byte, non-forced request only reads the current total.
+    let peek = quota::ConsumeRequest {
+        force: false,
+        ..budget.request(0)
+    };
+    match quota::consume(env, &budget.subject, &peek).await {
+        Some(result) if result.used >= result.limit => {
+            console_log!(
+                "[{}] Egress budget exhausted for {}",
+                rctx.id,
+                budget.subject
+            );
+            quota::too_many_requests(
+                &result,
+                "egress_budget_exceeded",
+                "Response byte budget exhausted",
+            )
+            .map(EgressCheck::Exceeded)
+        }
+        Some(_) => Ok(EgressCheck::Metered(budget)),
+        None => Ok(EgressCheck::Unmetered),
+    }
+}
+
+/// Meter `body`, charging the bytes to `budget` once it has been sent.
+pub fn meter(env: &Env, ctx: &Context, budget: Budget, body: streams::Body) -> streams::Body {
+    let (body, finished) = streams::counting(body);
+    let env = env.clone();
+    ctx.wait_until(async move {
+        let bytes = finished.await;
+        if bytes > 0 {
+            quota::consume(&env, &budget.subject, &budget.request(bytes)).await;
+        }
+    });
+    Box::pin(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subject() {
+        assert_eq!(
+            subject(Some("team-a"), Some("203.0.113.7")).as_deref(),
+            Some("egress:team-a")
+        );
+        assert_eq!(
+            subject(None, Some("203.0.113.7")).as_deref(),
+            Some("egress:ip:203.0.113.7")
+        );
+        assert_eq!(subject(None, None), None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Client-negotiated response envelope (`?envelope=1`, or an `Accept`
+//! listing [`MEDIA_TYPE`]).
+//!
+//! Wraps the upstream response as JSON with a 200 transport status:
+//! `{"status": 404, "headers": {...}, "body_text": "..."}` (or `body_base64`
+//! for binary bodies). Meant for runtimes that can't read non-2xx responses
+//! or cross-origin headers. The media type is removed from the `Accept`
+//! sent upstream; there is no `?format=json` switch, since `format` is a
+//! common parameter of the upstream APIs themselves.
+
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use worker::*;
+
+pub const PARAM: &str = "envelope";
+pub const MEDIA_TYPE: &str = "application/vnd.proxyflare.envelope+json";
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Envelope {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+fn is_envelope_range(range: &str) -> bool {
+    range
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case(MEDIA_TYPE)
+}
+
+/// Whether the worker URL or the `Accept` header asked for an envelope.
+pub fn requested(url: &Url, accept: Option<&str>) -> bool {
+    url.query_pairs()
+        .any(|(k, v)| k == PARAM && matches!(v.as_ref(), "1" | "true" | "yes"))
+        || accept.is_some_and(|a| a.split(',').any(is_envelope_range))
+}
+
+/// `accept` without the envelope media type, or `None` when nothing is left.
+pub fn upstream_accept(accept: &str) -> Option<String> {
+    let ranges: Vec<&str> = accept
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty() && !is_envelope_range(r))
+        .collect();
+    (!ranges.is_empty()).then(|| ranges.join(", "))
+}
+
+pub fn is_textual(content_type: &str) -> bool {
+    let ct = content_type.to_ascii_lowercase();
+    ct.starts_with("text/")
+        || ["json", "xml", "javascript", "x-www-form-urlencoded"]
+            .iter()
+            .any(|t| ct.contains(t))
+}
+
+impl Envelope {
+    pub fn new(status: u16, headers: BTreeMap<String, String>, body: Vec<u8>) -> Self {
+        let textual = headers.get("content-type").is_some_and(|ct| is_textual(ct));
+        let (body_text, body_base64) = match textual {
+            true => match String::from_utf8(body) {
+                Ok(text) => (Some(text), None),
+                Err(e) => (None, Some(STANDARD.encode(e.into_bytes()))),
+            },
+            false => (None, Some(STANDARD.encode(body))),
+        };
+        Self {
+            status,
+            headers,
+            body_text,
+            body_base64,
+        }
+    }
+}
+
+/// Buffer the upstream body and wrap it. `headers` are the already-filtered
+/// response headers; `transport_headers` go on the 200 wrapper itself.
+pub async fn wrap(
+    response: &mut Response,
+    headers: &Headers,
+    transport_headers: Headers,
+) -> Result<Response> {
+    let body = response.bytes().await.unwrap_or_default();
+    let headers: BTreeMap<String, String> = headers.entries().collect();
+    let envelope = Envelope::new(response.status_code(), headers, body);
+    let mut wrapped = Response::from_json(&envelope)?;
+    for (k, v) in transport_headers.entries() {
+        wrapped.headers_mut().set(&k, &v)?;
+    }
+    Ok(wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(ct: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([("content-type".to_string(), ct.to_string())])
+    }
+
+    #[test]
+    fn test_requested() {
+        let url = Url::parse("https://w.dev/?url=https://a.com&envelope=1").unwrap();
+        assert!(requested(&url, None));
+        let url = Url::parse("https://w.dev/?url=https://a.com&envelope=0").unwrap();
+        assert!(!requested(&url, None));
+        assert!(requested(
+            &url,
+            Some("application/vnd.proxyflare.envelope+json, application/json;q=0.9")
+        ));
+        assert!(!requested(&url, Some("application/json")));
+    }
+
+    #[test]
+    fn test_upstream_accept() {
+        assert_eq!(
+            upstream_accept("application/vnd.proxyflare.envelope+json, application/json;q=0.9")
+                .as_deref(),
+            Some("application/json;q=0.9")
+        );
+        assert_eq!(
+            upstream_accept("application/vnd.proxyflare.envelope+json"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_text_body() {
+        let env = Envelope::new(404, headers("application/json"), b"{\"a\":1}".to_vec());
+        assert_eq!(env.status, 404);
+        assert_eq!(env.body_text.as_deref(), Some("{\"a\":1}"));
+        assert!(env.body_base64.is_none());
+    }
+
+    #[test]
+    fn test_binary_body_is_base64() {
+        let env = Envelope::new(200, headers("image/png"), vec![0x89, 0x50]);
+        assert_eq!(env.body_base64.as_deref(), Some("iVA="));
+        assert!(env.body_text.is_none());
+    }
+
+    #[test]
+    fn test_invalid_utf8_text_falls_back_to_base64() {
+        let env = Envelope::new(200, headers("text/plain"), vec![0xff]);
+        assert_eq!(env.body_base64.as_deref(), Some("/w=="));
+    }
+}
@@ -0,0 +1,125 @@
+//! Per-origin protocol fallback for fragile legacy upstreams.
+//!
+//! When a fetch fails with a connection/protocol level error, the request is
+//! retried once with a simplified header profile (no compression hints, no
+//! browser fetch metadata or client hints, no HTTP/2 priority hints). Only
+//! those are dropped: everything else, including the headers the proxy adds
+//! itself (loop guard, deadlines, `Forwarded`/`Via`, injected credentials),
+//! goes out as before. Hosts that needed the
+//! fallback are remembered for the lifetime of the isolate so later requests
+//! use the simplified profile straight away.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use worker::Headers;
+
+use crate::header_rules;
+
+/// Headers dropped from the simplified profile (a trailing `*` matches a
+/// prefix); everything else is kept.
+const DROPPED_HEADERS: &[&str] = &[
+    "accept-encoding",
+    "te",
+    "priority",
+    "sec-fetch-*",
+    "sec-ch-*",
+    "sec-gpc",
+    "upgrade-insecure-requests",
+    "dpr",
+    "device-memory",
+    "downlink",
+    "ect",
+    "rtt",
+    "save-data",
+    "viewport-width",
+];
+
+/// Error fragments that suggest a protocol/transport incompatibility rather
+/// than an application error.
+const PROTOCOL_ERROR_MARKERS: &[&str] = &[
+    "network connection lost",
+    "connection reset",
+    "connection closed",
+    "protocol error",
+    "http/2",
+    "tls",
+    "ssl",
+    "handshake",
+];
+
+thread_local! {
+    static FALLBACK_HOSTS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Whether `host` previously required the simplified profile.
+pub fn is_marked(host: &str) -> bool {
+    FALLBACK_HOSTS.with(|hosts| hosts.borrow().contains(&host.to_ascii_lowercase()))
+}
+
+/// Remember that `host` needs the simplified profile.
+pub fn mark(host: &str) {
+    FALLBACK_HOSTS.with(|hosts| hosts.borrow_mut().insert(host.to_ascii_lowercase()));
+}
+
+pub fn is_protocol_error(message: &str) -> bool {
+    let message = message.to_ascii_lowercase();
+    PROTOCOL_ERROR_MARKERS.iter().any(|m| message.contains(m))
+}
+
+pub fn is_fallback_header(name: &str) -> bool {
+    !header_rules::listed(&name.to_ascii_lowercase(), DROPPED_HEADERS)
+}
+
+/// Copy of `headers` reduced to the simplified profile.
+pub fn simplify(headers: &Headers) -> Headers {
+    headers
+        .entries()
+        .filter(|(k, _)| is_fallback_header(k))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_protocol_error() {
+        assert!(is_protocol_error("TypeError: Network connection lost."));
+        assert!(is_protocol_error("HTTP/2 stream reset"));
+        assert!(is_protocol_error("HTTP protocol error"));
+        assert!(!is_protocol_error("Invalid URL"));
+        // Runtime-internal failures and protocol-relative URLs don't say
+        // anything about the upstream's protocol support.
+        assert!(!is_protocol_error("internal error; reference = 0abc"));
+        assert!(!is_protocol_error(
+            "Fetch API cannot load: protocol-relative URL"
+        ));
+    }
+
+    #[test]
+    fn test_fallback_profile_drops_compression_and_metadata() {
+        assert!(is_fallback_header("User-Agent"));
+        assert!(is_fallback_header("x-forwarded-for"));
+        assert!(!is_fallback_header("accept-encoding"));
+        assert!(!is_fallback_header("sec-fetch-mode"));
+        assert!(!is_fallback_header("priority"));
+        assert!(!is_fallback_header("Sec-CH-UA-Platform"));
+        for kept in [
+            "x-proxied-by",
+            "x-deadline",
+            "forwarded",
+            "via",
+            "x-api-token",
+        ] {
+            assert!(is_fallback_header(kept), "{kept}");
+        }
+    }
+
+    #[test]
+    fn test_mark_is_case_insensitive() {
+        assert!(!is_marked("legacy.example.com"));
+        mark("Legacy.Example.com");
+        assert!(is_marked("legacy.example.com"));
+    }
+}
@@ -0,0 +1,242 @@
+//! `GET /favicon?domain=example.com`: resolve and serve a site's icon.
+//!
+//! Tries `https://<domain>/favicon.ico`, then the `<link rel="icon">` of the
+//! homepage, and falls back to a generic icon. Results are kept in the edge
+//! cache for a week (an hour for the fallback) and served with
+//! `Access-Control-Allow-Origin: *` so dashboards can embed them directly.
+//!
+//! Reads are bounded: icons over `MAX_ICON_BYTES` are abandoned as soon as
+//! their length is known, and only the first `MAX_PAGE_BYTES` of the homepage
+//! are searched for the `<link>`. An icon `<link>` pointing at a host on the
+//! compliance blocklist is not followed.
+
+use worker::*;
+
+use crate::{compliance, responses, streams, uploads};
+
+pub const PATH: &str = "/favicon";
+
+const FOUND_MAX_AGE_SECS: u64 = 7 * 86_400;
+const FALLBACK_MAX_AGE_SECS: u64 = 3600;
+/// Icons larger than this are treated as missing.
+const MAX_ICON_BYTES: u64 = 256 * 1024;
+/// The homepage prefix searched for the icon `<link>`.
+const MAX_PAGE_BYTES: u64 = 512 * 1024;
+
+const DEFAULT_ICON: &str = concat!(
+    r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16">"##,
+    r##"<circle cx="8" cy="8" r="7" fill="none" stroke="#8a8f98" stroke-width="1.5"/>"##,
+    r##"<path d="M1 8h14M8 1c2.5 2 2.5 12 0 14M8 1c-2.5 2-2.5 12 0 14" fill="none" "##,
+    r##"stroke="#8a8f98" stroke-width="1"/></svg>"##
+);
+
+/// Whether `domain` is a plain hostname (no scheme, port, path or userinfo).
+pub fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Value of attribute `name` in a single HTML tag (case-insensitive name).
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find(name) {
+        let start = pos + found;
+        pos = start + name.len();
+        let boundary = lower[..start]
+            .chars()
+            .last()
+            .is_some_and(|c| c.is_whitespace());
+        let Some(rest) = tag[pos..].trim_start().strip_prefix('=') else {
+            continue;
+        };
+        if !boundary {
+            continue;
+        }
+        let rest = rest.trim_start();
+        let value = match rest.chars().next() {
+            Some(q @ ('"' | '\'')) => rest[1..].split(q).next().unwrap_or_default(),
+            _ => rest
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default(),
+        };
+        return Some(value.trim().to_string());
+    }
+    None
+}
+
+/// Find the best icon declared in `<link>` tags, resolved against `base`.
+/// Plain `icon` wins over `apple-touch-icon` and friends.
+pub fn extract_icon_href(html: &str, base: &Url) -> Option<Url> {
+    let lower = html.to_ascii_lowercase();
+    let mut fallback = None;
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find("<link") {
+        let start = pos + found;
+        let end = lower[start..].find('>').map_or(lower.len(), |e| start + e);
+        pos = end;
+        let tag = &html[start..end];
+        let (Some(rel), Some(href)) = (attribute(tag, "rel"), attribute(tag, "href")) else {
+            continue;
+        };
+        let rel = rel.to_ascii_lowercase();
+        let Ok(resolved) = base.join(&href) else {
+            continue;
+        };
+        if rel.split_whitespace().any(|r| r == "icon") {
+            return Some(resolved);
+        }
+        if fallback.is_none() && rel.contains("icon") {
+            fallback = Some(resolved);
+        }
+    }
+    fallback
+}
+
+/// Fetch `url` and return its body and content type if it looks like an icon.
+async fn fetch_icon(url: &str) -> Option<(Vec<u8>, String)> {
+    let mut response = Fetch::Url(Url::parse(url).ok()?).send().await.ok()?;
+    if response.status_code() != 200 {
+        return None;
+    }
+    let content_type = response
+        .headers()
+        .get("Content-Type")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| "image/x-icon".into());
+    if !content_type.to_ascii_lowercase().starts_with("image/") {
+        return None;
+    }
+    let length = response.headers().get("Content-Length").ok().flatten();
+    if uploads::declared_over(length.as_deref(), MAX_ICON_BYTES) {
+        return None;
+    }
+    let body = streams::LimitedStream::new(response.stream().ok()?, MAX_ICON_BYTES);
+    let body = streams::collect(body).await.ok()?;
+    (!body.is_empty()).then_some((body, content_type))
+}
+
+async fn resolve(env: &Env, domain: &str) -> Option<(Vec<u8>, String)> {
+    if let Some(icon) = fetch_icon(&format!("https://{domain}/favicon.ico")).await {
+        return Some(icon);
+    }
+    let base = Url::parse(&format!("https://{domain}/")).ok()?;
+    let mut page = Fetch::Url(base.clone()).send().await.ok()?;
+    let html = streams::collect(streams::Take::new(page.stream().ok()?, MAX_PAGE_BYTES))
+        .await
+        .ok()?;
+    let href = extract_icon_href(&String::from_utf8_lossy(&html), &base)?;
+    if compliance::find_rule(env, &href).await.is_some() {
+        return None;
+    }
+    fetch_icon(href.as_str()).await
+}
+
+pub async fn handle(req: Request, env: &Env) -> Result<Response> {
+    if req.method() != Method::Get {
+        return responses::error(405, "method_not_allowed", "Use GET");
+    }
+    let domain = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "domain")
+        .map(|(_, v)| v.trim().trim_end_matches('.').to_ascii_lowercase());
+    let Some(domain) = domain.filter(|d| is_valid_domain(d)) else {
+        return responses::error(
+            400,
+            "invalid_request",
+            "Query parameter `domain` must be a hostname",
+        );
+    };
+
+    let site = Url::parse(&format!("https://{domain}/"))?;
+    if let Some(rule) = compliance::find_rule(env, &site).await {
+        return compliance::blocked_response(&rule, &site);
+    }
+
+    let cache = Cache::default();
+    let cache_key = format!("https://favicon.proxyflare.internal/{domain}");
+    if let Some(cached) = cache.get(&cache_key, false).await? {
+        return Ok(cached);
+    }
+
+    let (mut response, max_age) = match resolve(env, &domain).await {
+        Some((body, content_type)) => {
+            let mut response = Response::from_bytes(body)?;
+            response.headers_mut().set("Content-Type", &content_type)?;
+            (response, FOUND_MAX_AGE_SECS)
+        }
+        None => {
+            let mut response = Response::ok(DEFAULT_ICON)?;
+            response
+                .headers_mut()
+                .set("Content-Type", "image/svg+xml")?;
+            (response, FALLBACK_MAX_AGE_SECS)
+        }
+    };
+    let headers = response.headers_mut();
+    headers.set("Cache-Control", &format!("public, max-age={max_age}"))?;
+    headers.set("Access-Control-Allow-Origin", "*")?;
+
+    if let Err(e) = cache.put(&cache_key, response.cloned()?).await {
+        console_error!("Failed to cache favicon for {}: {:?}", domain, e);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_domain() {
+        assert!(is_valid_domain("example.com"));
+        assert!(is_valid_domain("sub-1.example.co.uk"));
+        assert!(!is_valid_domain("localhost"));
+        assert!(!is_valid_domain("example.com:8080"));
+        assert!(!is_valid_domain("example.com/path"));
+        assert!(!is_valid_domain("user@example.com"));
+        assert!(!is_valid_domain("-bad.example.com"));
+    }
+
+    #[test]
+    fn test_extract_icon_href_prefers_icon() {
+        let base = Url::parse("https://example.com/").unwrap();
+        let html = r#"
+            <link rel="stylesheet" href="/site.css">
+            <link rel="apple-touch-icon" href="/apple.png">
+            <LINK REL="shortcut icon" HREF='/static/fav.png'>
+        "#;
+        assert_eq!(
+            extract_icon_href(html, &base).unwrap().as_str(),
+            "https://example.com/static/fav.png"
+        );
+    }
+
+    #[test]
+    fn test_extract_icon_href_falls_back_to_touch_icon() {
+        let base = Url::parse("https://example.com/").unwrap();
+        let html = r#"<link href="https://cdn.example.com/t.png" rel=apple-touch-icon>"#;
+        assert_eq!(
+            extract_icon_href(html, &base).unwrap().as_str(),
+            "https://cdn.example.com/t.png"
+        );
+        assert!(extract_icon_href("<link rel=stylesheet href=a.css>", &base).is_none());
+    }
+
+    #[test]
+    fn test_attribute_ignores_substring_names() {
+        assert_eq!(
+            attribute(r#"<link data-href="x" href="y">"#, "href").as_deref(),
+            Some("y")
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Headers that tell the upstream how the client reached the proxy.
+//!
+//! With `FORWARDED_HEADER=true` the proxy adds one RFC 7239 `Forwarded`
+//! element, `for=<client>;proto=<scheme>;host=<host>`, describing the hop
+//! from the client to the worker: `for` is the same address sent in
+//! `X-Forwarded-For` (`unknown` when none is sent), `proto` and `host` come
+//! from the worker URL. A well-formed incoming `Forwarded` chain is kept and
+//! the element is appended; a malformed one is replaced. With the setting
+//! off, an incoming header passes through untouched and nothing is added.
+//!
+//! With `X_FORWARDED_HOST_PROTO=true` the worker's host and scheme are also
+//! sent as `X-Forwarded-Host` and `X-Forwarded-Proto`, replacing whatever
+//! the client sent, so upstream apps build absolute URLs and HTTPS redirects
+//! for the address the client actually used.
+
+use worker::*;
+
+use crate::config;
+
+pub fn is_enabled(env: &Env) -> bool {
+    config::var(env, "FORWARDED_HEADER").as_deref() == Some("true")
+}
+
+pub fn x_forwarded_enabled(env: &Env) -> bool {
+    config::var(env, "X_FORWARDED_HOST_PROTO").as_deref() == Some("true")
+}
+
+/// The worker's `host[:port]` as the client addressed it.
+pub fn worker_host(url: &Url) -> String {
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (host, None) => host.unwrap_or_default().to_string(),
+        (None, Some(_)) => String::new(),
+    }
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// `value` as a token, or as a quoted string when it isn't one (IPv6
+/// addresses, ports).
+fn quote(value: &str) -> String {
+    if is_token(value) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// A node identifier for `for=`: IPv6 addresses are bracketed.
+pub fn node(ip: &str) -> String {
+    match ip.contains(':') && !ip.starts_with('[') {
+        true => format!("[{ip}]"),
+        false => ip.to_string(),
+    }
+}
+
+/// The element this proxy adds.
+pub fn element(client: &str, proto: &str, host: &str) -> String {
+    format!(
+        "for={};proto={};host={}",
+        quote(&node(client)),
+        quote(proto),
+        quote(host)
+    )
+}
+
+/// Split on `sep` outside quoted strings.
+fn split_unquoted(value: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn is_valid_pair(pair: &str) -> bool {
+    let Some((name, value)) = pair.trim().split_once('=') else {
+        return false;
+    };
+    let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+    is_token(name) && (is_token(value) || quoted)
+}
+
+/// The elements of an incoming chain, or `None` if it is malformed.
+pub fn parse_chain(header: &str) -> Option<Vec<String>> {
+    split_unquoted(header, ',')
+        .into_iter()
+        .map(|element| {
+            let element = element.trim();
+            split_unquoted(element, ';')
+                .iter()
+                .all(|pair| is_valid_pair(pair))
+                .then(|| element.to_string())
+        })
+        .collect()
+}
+
+/// `existing` with `element` appended (or just `element`).
+pub fn append(existing: Option<&str>, element: &str) -> String {
+    let mut chain = existing.and_then(parse_chain).unwrap_or_default();
+    chain.push(element.to_string());
+    chain.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_host() {
+        let url = Url::parse("https://proxy.example.com/x").unwrap();
+        assert_eq!(worker_host(&url), "proxy.example.com");
+        let url = Url::parse("http://localhost:8787/x").unwrap();
+        assert_eq!(worker_host(&url), "localhost:8787");
+    }
+
+    #[test]
+    fn test_element_quotes_ipv6() {
+        assert_eq!(
+            element("203.0.113.7", "https", "proxy.example"),
+            "for=203.0.113.7;proto=https;host=proxy.example"
+        );
+        assert_eq!(
+            element("2001:db8::1", "https", "proxy.example:8443"),
+            "for=\"[2001:db8::1]\";proto=https;host=\"proxy.example:8443\""
+        );
+    }
+
+    #[test]
+    fn test_parse_chain() {
+        assert_eq!(
+            parse_chain("for=192.0.2.1;proto=http, for=\"[2001:db8::2]:80\""),
+            Some(vec![
+                "for=192.0.2.1;proto=http".to_string(),
+                "for=\"[2001:db8::2]:80\"".to_string(),
+            ])
+        );
+        assert_eq!(
+            parse_chain("for=\"a,b\";by=x"),
+            Some(vec!["for=\"a,b\";by=x".into()])
+        );
+        assert_eq!(parse_chain("192.0.2.1"), None);
+        assert_eq!(parse_chain("for=a b"), None);
+    }
+
+    #[test]
+    fn test_append() {
+        let ours = "for=198.51.100.1;proto=https;host=p.example";
+        assert_eq!(
+            append(Some("for=192.0.2.1"), ours),
+            format!("for=192.0.2.1, {ours}")
+        );
+        assert_eq!(append(Some("garbage"), ours), ours);
+        assert_eq!(append(None, ours), ours);
+    }
+}
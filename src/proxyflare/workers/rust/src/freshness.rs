@@ -0,0 +1,46 @@
+//! Content freshness annotation: `X-Proxy-Fetched-At` and `Age`.
+//!
+//! Responses replayed from storage pass the time the body was originally
+//! fetched, so clients can compute staleness. Fresh upstream responses are
+//! annotated as fetched "now", keeping any `Age` the upstream's own CDN sent.
+
+use worker::{Headers, Result};
+
+use crate::utils;
+
+pub const FETCHED_AT_HEADER: &str = "X-Proxy-Fetched-At";
+
+/// `Age` in seconds for a body fetched at `fetched_at_ms` that was already
+/// `upstream_age` seconds old when fetched.
+pub fn age_secs(fetched_at_ms: u64, now_ms: u64, upstream_age: u64) -> u64 {
+    upstream_age + now_ms.saturating_sub(fetched_at_ms) / 1000
+}
+
+pub fn annotate(headers: &Headers, fetched_at_ms: u64, now_ms: u64) -> Result<()> {
+    let upstream_age = headers
+        .get("Age")?
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0);
+    headers.set(FETCHED_AT_HEADER, &utils::http_date(fetched_at_ms))?;
+    headers.set(
+        "Age",
+        &age_secs(fetched_at_ms, now_ms, upstream_age).to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_age_accumulates_storage_time() {
+        assert_eq!(age_secs(1_000, 1_000, 0), 0);
+        assert_eq!(age_secs(1_000, 61_999, 0), 60);
+        assert_eq!(age_secs(1_000, 11_000, 30), 40);
+    }
+
+    #[test]
+    fn test_age_never_negative_with_clock_skew() {
+        assert_eq!(age_secs(10_000, 5_000, 0), 0);
+    }
+}
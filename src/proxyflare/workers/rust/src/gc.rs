@@ -0,0 +1,258 @@
+//! Daily cleanup of stored artifacts that have no expiry of their own.
+//!
+//! `RETENTION_DAYS` sets how long each kind is kept, e.g.
+//! `{"usage_reports": 90, "usage_rows": 400, "abuse_reports": 180,
+//! "schemas": 30}`; a kind that is left out is kept forever.
+//!
+//! - `usage_reports`: CSV reports in `USAGE_BUCKET`, by report date.
+//! - `usage_rows`: daily counters in `USAGE_DB`, by day.
+//! - `abuse_reports`: `report:<id>` entries in `REPORTS_KV`, by submission
+//!   time.
+//! - `schemas`: sampled route schemas in `SCHEMA_KV`, by last sample.
+//!
+//! Objects in the R2 response cache (`CACHE_BUCKET`) carry their own
+//! `retain_until` time and are pruned once it passes, whatever
+//! `RETENTION_DAYS` says. The job runs from the cron handler once a day,
+//! deletes at most 500 objects per kind per run, and records how many it
+//! reclaimed in `USAGE_DB` (see [`usage::record_reclaimed`]).
+
+use serde::Deserialize;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::{cache, config, schema, usage, utils};
+
+/// Minute of the UTC day at which the job runs (after the usage reports).
+const RUN_MINUTE: u64 = 30;
+const MS_PER_DAY: u64 = 86_400_000;
+const MAX_DELETIONS: usize = 500;
+
+const DELETE_ROWS: &str = "DELETE FROM usage WHERE day < ?1";
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+pub struct Retention {
+    pub usage_reports: Option<u64>,
+    pub usage_rows: Option<u64>,
+    pub abuse_reports: Option<u64>,
+    pub schemas: Option<u64>,
+}
+
+/// Epoch ms before which an artifact kept for `days` is stale.
+pub fn cutoff_ms(now_ms: u64, days: u64) -> u64 {
+    now_ms.saturating_sub(days * MS_PER_DAY)
+}
+
+/// The date of a usage report key (`usage/<tenant>/<YYYY-MM-DD>.csv`).
+pub fn report_day(key: &str) -> Option<&str> {
+    let day = key.strip_prefix("usage/")?.rsplit('/').next()?;
+    let day = day.strip_suffix(".csv")?;
+    (day.len() == 10 && day.as_bytes()[4] == b'-' && day.as_bytes()[7] == b'-').then_some(day)
+}
+
+/// Creation time of an id from [`utils::random_id`] (hex ms, then 8 random
+/// hex digits).
+pub fn id_created_ms(id: &str) -> Option<u64> {
+    let millis = id.get(..id.len().checked_sub(8)?)?;
+    u64::from_str_radix(millis, 16).ok()
+}
+
+async fn prune_usage_reports(env: &Env, cutoff_day: &str) -> Result<usize> {
+    let Ok(bucket) = env.bucket("USAGE_BUCKET") else {
+        return Ok(0);
+    };
+    let mut stale = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = bucket.list().prefix("usage/");
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        stale.extend(
+            page.objects()
+                .into_iter()
+                .map(|object| object.key())
+                .filter(|key| report_day(key).is_some_and(|day| day < cutoff_day)),
+        );
+        cursor = page.cursor().filter(|_| page.truncated());
+        if cursor.is_none() || stale.len() >= MAX_DELETIONS {
+            break;
+        }
+    }
+    stale.truncate(MAX_DELETIONS);
+    let reclaimed = stale.len();
+    if reclaimed > 0 {
+        bucket.delete_multiple(stale).await?;
+    }
+    Ok(reclaimed)
+}
+
+async fn prune_cache_objects(env: &Env, now_ms: u64) -> Result<usize> {
+    let Ok(bucket) = env.bucket(cache::R2_BINDING) else {
+        return Ok(0);
+    };
+    let mut stale = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = bucket
+            .list()
+            .prefix(cache::R2_PREFIX)
+            .include(vec![Include::CustomMetadata]);
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for object in page.objects() {
+            if cache::r2_stale(&object.custom_metadata()?, now_ms) {
+                stale.push(object.key());
+            }
+        }
+        cursor = page.cursor().filter(|_| page.truncated());
+        if cursor.is_none() || stale.len() >= MAX_DELETIONS {
+            break;
+        }
+    }
+    stale.truncate(MAX_DELETIONS);
+    let reclaimed = stale.len();
+    if reclaimed > 0 {
+        bucket.delete_multiple(stale).await?;
+    }
+    Ok(reclaimed)
+}
+
+async fn prune_usage_rows(env: &Env, cutoff_day: &str) -> Result<usize> {
+    let Ok(db) = env.d1("USAGE_DB") else {
+        return Ok(0);
+    };
+    let result = db
+        .prepare(DELETE_ROWS)
+        .bind(&[JsValue::from(cutoff_day)])?
+        .run()
+        .await?;
+    Ok(result.meta()?.and_then(|m| m.changes).unwrap_or(0))
+}
+
+/// Delete up to [`MAX_DELETIONS`] keys under `prefix` that `stale` flags.
+async fn prune_kv(
+    env: &Env,
+    binding: &str,
+    prefix: &str,
+    stale: impl Fn(&str, Option<&serde_json::Value>) -> bool,
+) -> Result<usize> {
+    let Ok(kv) = env.kv(binding) else {
+        return Ok(0);
+    };
+    let mut reclaimed = 0;
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.into());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if reclaimed >= MAX_DELETIONS {
+                return Ok(reclaimed);
+            }
+            if stale(&key.name, key.metadata.as_ref()) {
+                kv.delete(&key.name).await?;
+                reclaimed += 1;
+            }
+        }
+        cursor = page.cursor.filter(|_| !page.list_complete);
+        if cursor.is_none() {
+            return Ok(reclaimed);
+        }
+    }
+}
+
+/// Log and record how many `kind` artifacts a pruning pass reclaimed.
+async fn report(env: &Env, day: &str, kind: &str, outcome: Result<usize>) {
+    let label = kind.replace('_', " ");
+    match outcome {
+        Ok(0) => {}
+        Ok(reclaimed) => {
+            console_log!("Retention: removed {} stale {}", reclaimed, label);
+            if let Err(e) = usage::record_reclaimed(env, day, kind, reclaimed).await {
+                console_error!("Retention count for {} not recorded: {:?}", label, e);
+            }
+        }
+        Err(e) => console_error!("Retention cleanup of {} failed: {:?}", label, e),
+    }
+}
+
+/// Prune stale artifacts once a day from the cron handler.
+pub async fn run(env: &Env, scheduled_ms: u64) {
+    if (scheduled_ms / 60_000) % 1440 != RUN_MINUTE {
+        return;
+    }
+    let day = utils::iso_date(scheduled_ms);
+    report(
+        env,
+        &day,
+        "cache_objects",
+        prune_cache_objects(env, scheduled_ms).await,
+    )
+    .await;
+    let Some(retention) = config::var_json::<Retention>(env, "RETENTION_DAYS") else {
+        return;
+    };
+    let cutoff_day = |days| utils::iso_date(cutoff_ms(scheduled_ms, days));
+    if let Some(days) = retention.usage_reports {
+        let outcome = prune_usage_reports(env, &cutoff_day(days)).await;
+        report(env, &day, "usage_reports", outcome).await;
+    }
+    if let Some(days) = retention.usage_rows {
+        let outcome = prune_usage_rows(env, &cutoff_day(days)).await;
+        report(env, &day, "usage_rows", outcome).await;
+    }
+    if let Some(days) = retention.abuse_reports {
+        let cutoff = cutoff_ms(scheduled_ms, days);
+        let stale = |name: &str, _: Option<&serde_json::Value>| {
+            name.strip_prefix("report:")
+                .and_then(id_created_ms)
+                .is_some_and(|created| created < cutoff)
+        };
+        let outcome = prune_kv(env, "REPORTS_KV", "report:", stale).await;
+        report(env, &day, "abuse_reports", outcome).await;
+    }
+    if let Some(days) = retention.schemas {
+        let cutoff_secs = cutoff_ms(scheduled_ms, days) / 1000;
+        let stale = |_: &str, metadata: Option<&serde_json::Value>| {
+            metadata
+                .and_then(|m| m.get("updated_at")?.as_u64())
+                .is_some_and(|updated| updated < cutoff_secs)
+        };
+        let outcome = prune_kv(env, "SCHEMA_KV", schema::KV_PREFIX, stale).await;
+        report(env, &day, "schemas", outcome).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_day() {
+        assert_eq!(report_day("usage/acme/2024-03-01.csv"), Some("2024-03-01"));
+        assert_eq!(report_day("usage/a/b/2024-03-01.csv"), Some("2024-03-01"));
+        assert_eq!(report_day("usage/acme/notes.csv"), None);
+        assert_eq!(report_day("other/2024-03-01.csv"), None);
+    }
+
+    #[test]
+    fn test_id_created_ms() {
+        assert_eq!(id_created_ms("18e0c5a1f00deadbeef"), Some(0x18e0c5a1f00));
+        assert_eq!(id_created_ms("deadbeef"), None);
+        assert_eq!(id_created_ms("zz00000000"), None);
+    }
+
+    #[test]
+    fn test_cutoff() {
+        assert_eq!(cutoff_ms(10 * MS_PER_DAY, 3), 7 * MS_PER_DAY);
+        assert_eq!(cutoff_ms(MS_PER_DAY, 3), 0);
+        let retention: Retention = serde_json::from_str(r#"{"schemas": 30}"#).unwrap();
+        assert_eq!(retention.schemas, Some(30));
+        assert_eq!(retention.usage_rows, None);
+    }
+}
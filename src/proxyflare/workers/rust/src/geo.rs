@@ -0,0 +1,106 @@
+//! Requester geo-blocking by country.
+//!
+//! `BLOCKED_COUNTRIES` and `ALLOWED_COUNTRIES` are comma-separated ISO 3166-1
+//! alpha-2 codes matched against Cloudflare's `cf.country` for the caller.
+//! A blocked country is always refused; when an allowlist is set, anything
+//! not on it (including requests whose country is unknown) is refused too.
+//! Rejections are 403 with a machine-readable reason.
+
+use serde_json::json;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, responses};
+
+#[derive(Debug, Default)]
+pub struct GeoPolicy {
+    pub blocked: Vec<String>,
+    pub allowed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoDecision {
+    Allow,
+    /// The country is listed in `BLOCKED_COUNTRIES`.
+    Blocked,
+    /// An allowlist is set and the country is not on it.
+    NotAllowed,
+}
+
+impl GeoPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            blocked: config::var_list(env, "BLOCKED_COUNTRIES"),
+            allowed: config::var_list(env, "ALLOWED_COUNTRIES"),
+        }
+    }
+
+    pub fn decide(&self, country: Option<&str>) -> GeoDecision {
+        let listed = |list: &[String]| {
+            country.is_some_and(|c| list.iter().any(|l| l.eq_ignore_ascii_case(c)))
+        };
+        if listed(&self.blocked) {
+            GeoDecision::Blocked
+        } else if !self.allowed.is_empty() && !listed(&self.allowed) {
+            GeoDecision::NotAllowed
+        } else {
+            GeoDecision::Allow
+        }
+    }
+}
+
+/// Reject requests from countries the operator does not serve.
+pub fn check(env: &Env, rctx: &RequestCtx) -> Result<Option<Response>> {
+    let policy = GeoPolicy::from_env(env);
+    let country = rctx.country.as_deref();
+    let reason = match policy.decide(country) {
+        GeoDecision::Allow => return Ok(None),
+        GeoDecision::Blocked => "country_blocked",
+        GeoDecision::NotAllowed => "country_not_allowed",
+    };
+    console_log!(
+        "[{}] Geo block ({}) for country {}",
+        rctx.id,
+        reason,
+        country.unwrap_or("unknown")
+    );
+    responses::json(
+        403,
+        &json!({
+            "error": "geo_blocked",
+            "reason": reason,
+            "country": country,
+            "message": "This service is not available in your region",
+        }),
+    )
+    .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(blocked: &[&str], allowed: &[&str]) -> GeoPolicy {
+        GeoPolicy {
+            blocked: blocked.iter().map(|s| s.to_string()).collect(),
+            allowed: allowed.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let policy = policy(&["RU", "kp"], &[]);
+        assert_eq!(policy.decide(Some("KP")), GeoDecision::Blocked);
+        assert_eq!(policy.decide(Some("DE")), GeoDecision::Allow);
+        assert_eq!(policy.decide(None), GeoDecision::Allow);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let policy = policy(&["FR"], &["DE", "FR"]);
+        assert_eq!(policy.decide(Some("de")), GeoDecision::Allow);
+        assert_eq!(policy.decide(Some("FR")), GeoDecision::Blocked);
+        assert_eq!(policy.decide(Some("US")), GeoDecision::NotAllowed);
+        assert_eq!(policy.decide(None), GeoDecision::NotAllowed);
+    }
+}
@@ -0,0 +1,453 @@
+//! Request headers added toward the upstream by configuration.
+//!
+//! Rules come from two optional sources, and every matching rule applies in
+//! order (variable first, then KV from the exact host to its wildcards):
+//! - `REQUEST_HEADER_RULES`: JSON array of [`HeaderRule`]s, e.g.
+//!   `[{"pattern": "*.internal.example", "set": {"X-Internal-Token":
+//!   "$INTERNAL_TOKEN"}}]`.
+//! - `HEADER_RULES_KV`: KV namespace with one rule per `host:<host>` or
+//!   `host:*.<parent-domain>` key, like `COMPLIANCE_KV`.
+//!
+//! `set` replaces whatever the client sent; `add` appends another value. A
+//! value starting with `$` names a secret (or variable) to read it from, so
+//! tokens stay out of plain configuration; a rule whose secret is missing is
+//! skipped and logged.
+//!
+//! `STRIP_REQUEST_HEADERS` and `STRIP_RESPONSE_HEADERS` are comma-separated
+//! header names removed on the way to the upstream and on the way back, for
+//! every target (`server, x-powered-by, set-cookie`); a trailing `*` matches
+//! a prefix (`x-amz-*`). Cookies the proxy sets itself are not affected.
+//! With `STRICT_RESPONSE_HEADERS=true` only [`SAFE_RESPONSE_HEADERS`] and
+//! the names in `ALLOWED_RESPONSE_HEADERS` are kept from the upstream
+//! response; headers the proxy adds itself (CORS, request id, timing) are
+//! unaffected. `ANONYMIZE=true` adds [`FINGERPRINT_HEADERS`] (client hints, fetch
+//! metadata and other browser preference headers) to the request list.
+//!
+//! Headers Cloudflare adds on the way in ([`is_platform_header`]: every
+//! `cf-*` header, `cdn-loop`, `true-client-ip`) are never forwarded, so the
+//! upstream doesn't learn the client's real address or that a worker is
+//! relaying. `cf-access-*` is the exception, since clients use it for
+//! upstreams behind Cloudflare Access. The runtime still stamps `cf-worker`
+//! and `cdn-loop` onto the subrequest itself; a worker can't remove those.
+//!
+//! `UPSTREAM_ACCEPT_ENCODING` replaces the client's `Accept-Encoding` on
+//! every upstream request (`br, gzip`, or `identity` for an uncompressed
+//! body), independent of what the client negotiated with the worker.
+//!
+//! Client headers are checked before any of them is copied upstream: a name
+//! that isn't an RFC 9110 token or a value with control characters (CR, LF,
+//! NUL...) fails the whole request with 400 rather than being passed on to
+//! an upstream that might split or merge messages differently. Headers
+//! larger than `MAX_HEADER_BYTES` (default 16 KiB, name and value) or
+//! together larger than `MAX_REQUEST_HEADER_BYTES` (default 64 KiB) fail it
+//! with 431 before anything is copied.
+//!
+//! Hop-by-hop headers (RFC 9110 §7.6.1: [`HOP_BY_HOP`] and every header the
+//! message's `Connection` names) describe one connection, not the message,
+//! and are dropped in both directions. Upstream responses also lose
+//! [`ENDPOINT_HINTS`] (`Alt-Svc` and friends), which would otherwise invite
+//! the client to reach the upstream directly, bypassing the proxy.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{compliance, config, responses, utils};
+
+const RULES_VAR: &str = "REQUEST_HEADER_RULES";
+pub const RULES_KV: &str = "HEADER_RULES_KV";
+const DEFAULT_MAX_HEADER_BYTES: u64 = 16 * 1024;
+const DEFAULT_MAX_REQUEST_HEADER_BYTES: u64 = 64 * 1024;
+
+/// Headers that only apply to a single connection.
+pub const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Response headers that point the client at another endpoint or protocol.
+pub const ENDPOINT_HINTS: &[&str] = &["alt-svc", "alt-used", "http2-settings", "upgrade"];
+
+/// Upstream response headers kept in strict mode.
+pub const SAFE_RESPONSE_HEADERS: &[&str] = &[
+    "accept-ranges",
+    "cache-control",
+    "content-disposition",
+    "content-language",
+    "content-range",
+    "content-type",
+    "etag",
+    "expires",
+    "last-modified",
+    "location",
+    "retry-after",
+    "vary",
+];
+
+/// Request headers that describe the browser rather than the request.
+pub const FINGERPRINT_HEADERS: &[&str] = &[
+    "sec-ch-*",
+    "sec-fetch-*",
+    "sec-gpc",
+    "sec-purpose",
+    "purpose",
+    "dnt",
+    "save-data",
+    "device-memory",
+    "dpr",
+    "width",
+    "viewport-width",
+    "viewport-height",
+    "downlink",
+    "ect",
+    "rtt",
+    "x-client-data",
+];
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct HeaderRule {
+    /// `host[/path-prefix]` pattern, see [`utils::url_matches`]. Optional for
+    /// KV entries, whose key already scopes the rule.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+impl HeaderRule {
+    fn matches(&self, target: &Url) -> bool {
+        let host = target.host_str().unwrap_or_default();
+        self.pattern
+            .as_deref()
+            .is_some_and(|p| utils::url_matches(p, host, target.path()))
+    }
+}
+
+/// Parse `REQUEST_HEADER_RULES`, skipping it entirely if malformed.
+pub fn parse_rules(raw: &str) -> Vec<HeaderRule> {
+    serde_json::from_str(raw).unwrap_or_else(|e| {
+        console_error!("Ignoring malformed {}: {}", RULES_VAR, e);
+        Vec::new()
+    })
+}
+
+/// Whether lowercase header `name` was added by Cloudflare's edge.
+pub fn is_platform_header(name: &str) -> bool {
+    (name.starts_with("cf-") && !name.starts_with("cf-access-"))
+        || matches!(name, "cdn-loop" | "true-client-ip")
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Visible characters, spaces and tabs; no CR, LF, NUL or other controls.
+pub fn valid_value(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c == '\t' || (c != '\u{7f}' && !c.is_control()))
+}
+
+/// The upstream `Accept-Encoding` set by `UPSTREAM_ACCEPT_ENCODING`.
+pub fn upstream_accept_encoding(env: &Env) -> Option<String> {
+    config::var(env, "UPSTREAM_ACCEPT_ENCODING").filter(|value| {
+        let ok = valid_value(value);
+        if !ok {
+            console_error!("Ignoring invalid UPSTREAM_ACCEPT_ENCODING");
+        }
+        ok
+    })
+}
+
+/// The first client header that can't be forwarded safely.
+pub fn first_invalid(headers: &Headers) -> Option<String> {
+    headers
+        .entries()
+        .find(|(name, value)| !valid_name(name) || !valid_value(value))
+        .map(|(name, _)| name)
+}
+
+/// Why the headers exceed the per-header or combined byte limit, if they do.
+pub fn oversized(
+    headers: impl IntoIterator<Item = (String, String)>,
+    per_header: u64,
+    total: u64,
+) -> Option<String> {
+    let mut sum = 0u64;
+    for (name, value) in headers {
+        // "name: value\r\n" on the wire.
+        let size = (name.len() + value.len() + 4) as u64;
+        if size > per_header {
+            return Some(format!("Header {name:?} is larger than {per_header} bytes"));
+        }
+        sum += size;
+        if sum > total {
+            return Some(format!("Request headers are larger than {total} bytes"));
+        }
+    }
+    None
+}
+
+/// 431 when the client's headers exceed the configured size limits.
+pub fn check_size(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let per_header = config::var_u64(env, "MAX_HEADER_BYTES").unwrap_or(DEFAULT_MAX_HEADER_BYTES);
+    let total = config::var_u64(env, "MAX_REQUEST_HEADER_BYTES")
+        .unwrap_or(DEFAULT_MAX_REQUEST_HEADER_BYTES);
+    match oversized(req.headers().entries(), per_header, total) {
+        Some(reason) => responses::error(431, "headers_too_large", &reason).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The hop-by-hop headers of a message whose `Connection` is `connection`.
+pub fn hop_by_hop(connection: Option<&str>) -> Vec<String> {
+    let named = connection
+        .unwrap_or_default()
+        .split(',')
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty() && !token.ends_with('*'));
+    HOP_BY_HOP
+        .iter()
+        .map(|h| h.to_string())
+        .chain(named)
+        .collect()
+}
+
+/// Lowercased removal list from `var`.
+pub fn strip_list(env: &Env, var: &str) -> Vec<String> {
+    config::var_list(env, var)
+        .into_iter()
+        .map(|name| name.to_ascii_lowercase())
+        .collect()
+}
+
+/// Upstream response headers to keep in strict mode, or `None` to keep
+/// everything not stripped otherwise.
+pub fn response_allowlist(env: &Env) -> Option<Vec<String>> {
+    if config::var(env, "STRICT_RESPONSE_HEADERS").as_deref() != Some("true") {
+        return None;
+    }
+    let mut list = strip_list(env, "ALLOWED_RESPONSE_HEADERS");
+    list.extend(SAFE_RESPONSE_HEADERS.iter().map(|name| name.to_string()));
+    Some(list)
+}
+
+/// Request headers to remove: `STRIP_REQUEST_HEADERS`, plus the fingerprint
+/// headers with `ANONYMIZE=true`.
+pub fn request_strip_list(env: &Env) -> Vec<String> {
+    let mut list = strip_list(env, "STRIP_REQUEST_HEADERS");
+    if config::var(env, "ANONYMIZE").as_deref() == Some("true") {
+        list.extend(FINGERPRINT_HEADERS.iter().map(|name| name.to_string()));
+    }
+    list
+}
+
+/// Whether lowercase header `name` is on `list`.
+pub fn listed<S: AsRef<str>>(name: &str, list: &[S]) -> bool {
+    list.iter()
+        .any(|entry| match entry.as_ref().strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == entry.as_ref(),
+        })
+}
+
+/// `value` with a `$NAME` reference looked up through `lookup`.
+pub fn resolve(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Option<String> {
+    match value.strip_prefix('$') {
+        Some(name) => lookup(name),
+        None => Some(value.to_string()),
+    }
+}
+
+/// Header operations of `rule` with references resolved: `(name, value,
+/// replace)`. `None` if any reference is missing.
+pub fn operations(
+    rule: &HeaderRule,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Option<Vec<(String, String, bool)>> {
+    let set = rule.set.iter().map(|(name, value)| (name, value, true));
+    let add = rule.add.iter().map(|(name, value)| (name, value, false));
+    set.chain(add)
+        .map(|(name, value, replace)| Some((name.clone(), resolve(value, &lookup)?, replace)))
+        .collect()
+}
+
+async fn matching_rules(env: &Env, target: &Url) -> Vec<HeaderRule> {
+    let mut rules: Vec<HeaderRule> = config::var(env, RULES_VAR)
+        .map(|raw| parse_rules(&raw))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|rule| rule.matches(target))
+        .collect();
+    let (Ok(kv), Some(host)) = (env.kv(RULES_KV), target.host_str()) else {
+        return rules;
+    };
+    for key in compliance::kv_keys(host) {
+        match kv.get(&key).json::<HeaderRule>().await {
+            Ok(Some(rule)) if rule.pattern.is_none() || rule.matches(target) => rules.push(rule),
+            Ok(_) => {}
+            Err(e) => console_error!("Header rule KV lookup failed for {}: {:?}", key, e),
+        }
+    }
+    rules
+}
+
+/// Apply every rule for `target` to the upstream request headers.
+pub async fn apply(env: &Env, headers: &Headers, target: &Url) -> Result<()> {
+    let lookup = |name: &str| config::var(env, name);
+    for rule in matching_rules(env, target).await {
+        let Some(operations) = operations(&rule, lookup) else {
+            console_error!(
+                "Skipping header rule {:?}: a referenced secret is not set",
+                rule.pattern
+            );
+            continue;
+        };
+        for (name, value, replace) in operations {
+            match replace {
+                true => headers.set(&name, &value)?,
+                false => headers.append(&name, &value)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_matching() {
+        let rules = parse_rules(
+            r#"[{"pattern": "*.internal.example", "set": {"X-Internal-Token": "t"}},
+                {"pattern": "api.example.com/v2", "add": {"X-Feature": "beta"}}]"#,
+        );
+        let internal = Url::parse("https://db.internal.example/q").unwrap();
+        let api = Url::parse("https://api.example.com/v1/users").unwrap();
+        assert!(rules[0].matches(&internal));
+        assert!(!rules[1].matches(&api));
+        let unscoped = HeaderRule::default();
+        assert!(!unscoped.matches(&internal));
+    }
+
+    #[test]
+    fn test_is_platform_header() {
+        for name in [
+            "cf-connecting-ip",
+            "cf-connecting-ipv6",
+            "cf-worker",
+            "cf-ew-via",
+            "cf-new-header",
+            "cdn-loop",
+            "true-client-ip",
+        ] {
+            assert!(is_platform_header(name), "{name}");
+        }
+        assert!(!is_platform_header("cf-access-client-id"));
+        assert!(!is_platform_header("x-cf-thing"));
+    }
+
+    #[test]
+    fn test_header_validation() {
+        assert!(valid_name("X-Custom_Header.1"));
+        assert!(!valid_name("Bad Header"));
+        assert!(!valid_name("x-a:b"));
+        assert!(!valid_name(""));
+        assert!(valid_value("text/html; q=0.9\tlatin-1: \u{e9}"));
+        assert!(valid_value(""));
+        assert!(!valid_value("a\r\nX-Injected: 1"));
+        assert!(!valid_value("a\0b"));
+        assert!(!valid_value("a\u{7f}"));
+    }
+
+    #[test]
+    fn test_oversized() {
+        let headers = |pairs: &[(&str, usize)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, len)| (name.to_string(), "a".repeat(*len)))
+                .collect()
+        };
+        assert_eq!(
+            oversized(headers(&[("x-a", 10), ("x-b", 10)]), 20, 40),
+            None
+        );
+        assert!(oversized(headers(&[("x-a", 30)]), 20, 40)
+            .unwrap()
+            .contains("\"x-a\""));
+        assert!(
+            oversized(headers(&[("x-a", 10), ("x-b", 10), ("x-c", 10)]), 20, 40)
+                .unwrap()
+                .starts_with("Request headers")
+        );
+    }
+
+    #[test]
+    fn test_hop_by_hop() {
+        let list = hop_by_hop(Some("keep-alive, X-Session-Hint , close"));
+        for name in ["connection", "te", "upgrade", "x-session-hint", "close"] {
+            assert!(listed(name, &list), "{name}");
+        }
+        assert!(!listed("content-type", &list));
+        assert_eq!(hop_by_hop(None).len(), HOP_BY_HOP.len());
+        // A token can't turn into a prefix pattern.
+        assert!(!listed("x-anything", &hop_by_hop(Some("x-*"))));
+    }
+
+    #[test]
+    fn test_listed() {
+        let list: Vec<String> = ["server", "x-amz-*"].map(String::from).to_vec();
+        assert!(listed("server", &list));
+        assert!(listed("x-amz-request-id", &list));
+        assert!(!listed("x-server", &list));
+        assert!(!listed::<String>("content-type", &[]));
+        let fingerprint: Vec<String> = FINGERPRINT_HEADERS.iter().map(|h| h.to_string()).collect();
+        for name in [
+            "sec-ch-ua",
+            "sec-ch-ua-platform",
+            "sec-fetch-mode",
+            "dnt",
+            "save-data",
+        ] {
+            assert!(listed(name, &fingerprint), "{name}");
+        }
+        assert!(!listed("accept-language", &fingerprint));
+        let safe: Vec<String> = SAFE_RESPONSE_HEADERS
+            .iter()
+            .map(|h| h.to_string())
+            .collect();
+        assert!(listed("content-type", &safe));
+        assert!(!listed("server", &safe) && !listed("set-cookie", &safe));
+    }
+
+    #[test]
+    fn test_operations_resolve_secrets() {
+        let rule: HeaderRule = serde_json::from_str(
+            r#"{"set": {"X-Internal-Token": "$TOKEN"}, "add": {"X-Via-Rule": "yes"}}"#,
+        )
+        .unwrap();
+        let lookup = |name: &str| (name == "TOKEN").then(|| "s3cret".to_string());
+        assert_eq!(
+            operations(&rule, lookup),
+            Some(vec![
+                ("X-Internal-Token".into(), "s3cret".into(), true),
+                ("X-Via-Rule".into(), "yes".into(), false),
+            ])
+        );
+        assert_eq!(operations(&rule, |_| None), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! `GET /health`: which optional subsystems are on, off or degraded.
+//!
+//! Every subsystem treats a missing binding as "feature off" or falls back
+//! to a documented behavior; this endpoint makes that visible so a minimal
+//! deployment and a fully bound one can be told apart at a glance. The report
+//! names settings and bindings only, never their values.
+
+use serde::Serialize;
+use serde_json::json;
+use worker::*;
+
+use crate::{config, responses};
+
+pub const PATH: &str = "/health";
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Enabled,
+    Disabled,
+    Degraded,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Subsystem {
+    pub name: &'static str,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Classify a subsystem: off unless `configured`; degraded (with
+/// `fallback` describing the behavior) when a dependency is missing.
+pub fn assess(
+    name: &'static str,
+    configured: bool,
+    dependencies: &[(&str, bool)],
+    fallback: &str,
+) -> Subsystem {
+    let missing: Vec<&str> = dependencies
+        .iter()
+        .filter(|(_, present)| !present)
+        .map(|(dep, _)| *dep)
+        .collect();
+    let (status, detail) = match (configured, missing.is_empty()) {
+        (false, _) => (Status::Disabled, None),
+        (true, true) => (Status::Enabled, None),
+        (true, false) => (
+            Status::Degraded,
+            Some(format!("{} missing: {fallback}", missing.join(", "))),
+        ),
+    };
+    Subsystem {
+        name,
+        status,
+        detail,
+    }
+}
+
+pub fn subsystems(env: &Env) -> Vec<Subsystem> {
+    let var = |name: &str| config::var(env, name).is_some();
+    let kv = |name: &str| env.kv(name).is_ok();
+    let durable_object = |name: &str| env.durable_object(name).is_ok();
+    let quota = ("QUOTA_COUNTER", durable_object("QUOTA_COUNTER"));
+    let reports_store = kv("REPORTS_KV") || env.queue("REPORTS_QUEUE").is_ok();
+
+    vec![
+        assess(
+            "compliance",
+            var("COMPLIANCE_BLOCKLIST") || kv("COMPLIANCE_KV"),
+            &[],
+            "",
+        ),
+        assess(
+            "reports",
+            true,
+            &[("REPORTS_QUEUE or REPORTS_KV", reports_store)],
+            "POST /report answers 503",
+        ),
+        assess("slo", durable_object("SLO_TRACKER"), &[], ""),
+        assess(
+            "cache_kv",
+            kv("CACHE_KV"),
+            &[(
+                "CACHE_TTL or CACHE_TTL_RULES",
+                var("CACHE_TTL") || var("CACHE_TTL_RULES"),
+            )],
+            "nothing is cached",
+        ),
+        assess(
+            "cache_r2",
+            env.bucket("CACHE_BUCKET").is_ok(),
+            &[(
+                "CACHE_TTL or CACHE_TTL_RULES",
+                var("CACHE_TTL") || var("CACHE_TTL_RULES"),
+            )],
+            "nothing is cached",
+        ),
+        assess(
+            "monitor",
+            var("MONITOR_PROBES"),
+            &[("MONITOR_KV", kv("MONITOR_KV"))],
+            "results are not stored and every failed probe alerts",
+        ),
+        assess(
+            "cert_watch",
+            var("CERT_WATCH_HOSTS"),
+            &[("MONITOR_KV", kv("MONITOR_KV"))],
+            "results are not stored and every flagged certificate alerts",
+        ),
+        assess(
+            "header_rules",
+            var("REQUEST_HEADER_RULES") || kv("HEADER_RULES_KV"),
+            &[],
+            "",
+        ),
+        assess("api_keys", var("API_KEYS") || kv("API_KEYS_KV"), &[], ""),
+        assess("jwt", var("JWT_JWKS_URL"), &[], ""),
+        assess(
+            "cloudflare_access",
+            var("CF_ACCESS_TEAM_DOMAIN"),
+            &[("CF_ACCESS_AUD", var("CF_ACCESS_AUD"))],
+            "Access assertions are not checked",
+        ),
+        assess(
+            "geo_blocking",
+            var("BLOCKED_COUNTRIES") || var("ALLOWED_COUNTRIES"),
+            &[],
+            "",
+        ),
+        assess("deadlines", var("REQUEST_DEADLINE_MS"), &[], ""),
+        assess("basic_auth", var("BASIC_AUTH_CREDENTIALS"), &[], ""),
+        assess("signed_links", var("URL_SIGNING_SECRET"), &[], ""),
+        assess(
+            "turnstile",
+            var("TURNSTILE_SECRET"),
+            &[("TURNSTILE_SITE_KEY", var("TURNSTILE_SITE_KEY"))],
+            "browsers get a JSON error instead of the challenge page",
+        ),
+        assess(
+            "bot_score",
+            var("BOT_SCORE_BLOCK") || var("BOT_SCORE_THROTTLE"),
+            &[(quota.0, quota.1 || !var("BOT_SCORE_THROTTLE"))],
+            "throttling is not enforced",
+        ),
+        assess(
+            "rate_limit",
+            var("RATE_LIMIT_REQUESTS")
+                || var("RATE_LIMIT_COUNTRIES")
+                || env.rate_limiter("RATE_LIMITER").is_ok(),
+            &[
+                (
+                    "RATE_LIMITER or QUOTA_COUNTER",
+                    quota.1 || env.rate_limiter("RATE_LIMITER").is_ok(),
+                ),
+                (quota.0, quota.1 || !var("RATE_LIMIT_COUNTRIES")),
+            ],
+            "requests are not limited",
+        ),
+        assess(
+            "key_quotas",
+            var("KEY_QUOTAS") || var("ANONYMOUS_QUOTA"),
+            &[("QUOTAS_KV", kv("QUOTAS_KV"))],
+            "quotas are not enforced",
+        ),
+        assess(
+            "spend_caps",
+            var("SPEND_RULES"),
+            &[quota],
+            "caps are not enforced",
+        ),
+        assess(
+            "egress_budgets",
+            var("EGRESS_BUDGETS"),
+            &[quota],
+            "budgets are not enforced",
+        ),
+        assess(
+            "host_concurrency",
+            var("HOST_CONCURRENCY"),
+            &[("HOST_SEMAPHORE", durable_object("HOST_SEMAPHORE"))],
+            "concurrency is not limited",
+        ),
+        assess("idempotency", durable_object("IDEMPOTENCY"), &[], ""),
+        assess("session_kv", kv("SESSION_KV"), &[], ""),
+        assess("watermarks", var("WATERMARK_SECRET"), &[], ""),
+        assess(
+            "schema_sampling",
+            var("SCHEMA_SAMPLE_RATE"),
+            &[("SCHEMA_KV", kv("SCHEMA_KV"))],
+            "samples are not taken",
+        ),
+        assess(
+            "usage",
+            env.d1("USAGE_DB").is_ok(),
+            &[("USAGE_BUCKET", env.bucket("USAGE_BUCKET").is_ok())],
+            "usage is recorded but daily reports are not written",
+        ),
+    ]
+}
+
+pub fn handle(env: &Env) -> Result<Response> {
+    let subsystems = subsystems(env);
+    let degraded = subsystems.iter().any(|s| s.status == Status::Degraded);
+    responses::json(
+        200,
+        &json!({
+            "status": if degraded { "degraded" } else { "ok" },
+            "subsystems": subsystems,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assess() {
+        assert_eq!(
+            assess("x", false, &[("B", false)], "n/a").status,
+            Status::Disabled
+        );
+        assert_eq!(
+            assess("x", true, &[("B", true)], "n/a").status,
+            Status::Enabled
+        );
+        let degraded = assess("x", true, &[("A", true), ("B", false)], "falls back");
+        assert_eq!(degraded.status, Status::Degraded);
+        assert_eq!(degraded.detail.as_deref(), Some("B missing: falls back"));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let value = serde_json::to_value(assess("usage", true, &[], "")).unwrap();
+        assert_eq!(value, json!({"name": "usage", "status": "enabled"}));
+    }
+}
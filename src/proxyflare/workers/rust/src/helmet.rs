@@ -0,0 +1,95 @@
+//! Security headers on proxied responses ("helmet" mode).
+//!
+//! With `SECURITY_HEADERS=true` every proxied response carries the headers
+//! in [`DEFAULTS`], replacing whatever the upstream sent, so embedded
+//! content gets the same policy whichever site it came from.
+//! `SECURITY_HEADER_OVERRIDES` (a JSON object) changes individual values;
+//! an empty string leaves that header to the upstream instead.
+
+use std::collections::HashMap;
+
+use worker::*;
+
+use crate::config;
+
+pub const DEFAULTS: &[(&str, &str)] = &[
+    ("X-Content-Type-Options", "nosniff"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+    (
+        "Permissions-Policy",
+        "camera=(), microphone=(), geolocation=(), payment=(), usb=()",
+    ),
+    (
+        "Strict-Transport-Security",
+        "max-age=31536000; includeSubDomains",
+    ),
+];
+
+pub fn is_enabled(env: &Env) -> bool {
+    config::var(env, "SECURITY_HEADERS").as_deref() == Some("true")
+}
+
+/// The defaults with `overrides` applied; names match case-insensitively.
+pub fn headers(overrides: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = DEFAULTS
+        .iter()
+        .map(|(name, value)| {
+            let value = overrides
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map_or(*value, |(_, v)| v.as_str());
+            (name.to_string(), value.trim().to_string())
+        })
+        .collect();
+    headers.extend(
+        overrides
+            .iter()
+            .filter(|(k, _)| {
+                !DEFAULTS
+                    .iter()
+                    .any(|(name, _)| k.eq_ignore_ascii_case(name))
+            })
+            .map(|(k, v)| (k.clone(), v.trim().to_string())),
+    );
+    headers.retain(|(_, value)| !value.is_empty());
+    headers
+}
+
+/// Move the security headers onto the client-facing `transport` headers,
+/// dropping the upstream's own values from `upstream`.
+pub fn apply(env: &Env, upstream: &Headers, transport: &Headers) -> Result<()> {
+    if !is_enabled(env) {
+        return Ok(());
+    }
+    let overrides: HashMap<String, String> =
+        config::var_json(env, "SECURITY_HEADER_OVERRIDES").unwrap_or_default();
+    for (name, value) in headers(&overrides) {
+        upstream.delete(&name)?;
+        transport.set(&name, &value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let defaults = headers(&HashMap::new());
+        assert_eq!(defaults.len(), DEFAULTS.len());
+        assert!(defaults.contains(&("X-Content-Type-Options".into(), "nosniff".into())));
+
+        let overrides: HashMap<String, String> = serde_json::from_str(
+            r#"{"referrer-policy": "no-referrer", "Strict-Transport-Security": "",
+                "Cross-Origin-Opener-Policy": "same-origin"}"#,
+        )
+        .unwrap();
+        let custom = headers(&overrides);
+        assert!(custom.contains(&("Referrer-Policy".into(), "no-referrer".into())));
+        assert!(!custom
+            .iter()
+            .any(|(name, _)| name == "Strict-Transport-Security"));
+        assert!(custom.contains(&("Cross-Origin-Opener-Policy".into(), "same-origin".into())));
+    }
+}
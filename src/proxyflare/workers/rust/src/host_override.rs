@@ -0,0 +1,93 @@
+//! Upstream `Host` different from the address the proxy connects to.
+//!
+//! `HOST_OVERRIDES` lists targets (first match wins, `host[/path-prefix]`
+//! patterns) and the `Host` the upstream should see:
+//! `[{"pattern": "origin.example.com", "host": "www.example.com"},
+//! {"pattern": "staging.example.com", "preserve": true}]`. The worker can't
+//! set `Host` directly, so the fetch goes to a URL carrying the wanted host
+//! with `cf.resolveOverride` pointing at the original target, which is
+//! where the connection is made. `preserve` sends the host the client used
+//! to reach the worker. Cloudflare only honours the override when both
+//! names are in the worker's own zone; elsewhere the request goes to the
+//! rewritten host itself.
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{config, utils};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct HostOverride {
+    pub pattern: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Send the worker's own host.
+    #[serde(default)]
+    pub preserve: bool,
+}
+
+pub fn rule_for<'a>(rules: &'a [HostOverride], target: &Url) -> Option<&'a HostOverride> {
+    let host = target.host_str().unwrap_or_default();
+    rules
+        .iter()
+        .find(|r| utils::url_matches(&r.pattern, host, target.path()))
+}
+
+/// The URL to fetch and the `resolveOverride` that connects it to `target`.
+pub fn plan(rule: &HostOverride, target: &Url, worker: &Url) -> Option<(Url, String)> {
+    let host = match rule.preserve {
+        true => worker.host_str()?,
+        false => rule.host.as_deref()?.trim(),
+    };
+    let connect = target.host_str()?.to_string();
+    let mut fetch_url = target.clone();
+    fetch_url.set_host(Some(host)).ok()?;
+    Some((fetch_url, connect))
+}
+
+/// The URL to fetch for `target`, setting `resolveOverride` on `init` when
+/// a rule applies.
+pub fn apply(env: &Env, target: &Url, worker: &Url, init: &mut RequestInit) -> Url {
+    let rules: Vec<HostOverride> = config::var_json(env, "HOST_OVERRIDES").unwrap_or_default();
+    match rule_for(&rules, target).and_then(|rule| plan(rule, target, worker)) {
+        Some((fetch_url, connect)) => {
+            init.cf.resolve_override = Some(connect);
+            fetch_url
+        }
+        None => target.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let rules: Vec<HostOverride> = serde_json::from_str(
+            r#"[{"pattern": "origin.example.com", "host": "www.example.com"},
+                {"pattern": "staging.example.com", "preserve": true}]"#,
+        )
+        .unwrap();
+        let worker = Url::parse("https://proxy.example.com/").unwrap();
+
+        let target = Url::parse("https://origin.example.com:8443/a?b=1").unwrap();
+        let (fetch_url, connect) =
+            plan(rule_for(&rules, &target).unwrap(), &target, &worker).unwrap();
+        assert_eq!(fetch_url.as_str(), "https://www.example.com:8443/a?b=1");
+        assert_eq!(connect, "origin.example.com");
+
+        let target = Url::parse("https://staging.example.com/").unwrap();
+        let (fetch_url, _) = plan(rule_for(&rules, &target).unwrap(), &target, &worker).unwrap();
+        assert_eq!(fetch_url.host_str(), Some("proxy.example.com"));
+
+        let unrelated = Url::parse("https://example.org/").unwrap();
+        assert_eq!(rule_for(&rules, &unrelated), None);
+        let empty = HostOverride {
+            pattern: "*".into(),
+            host: None,
+            preserve: false,
+        };
+        assert_eq!(plan(&empty, &target, &worker), None);
+    }
+}
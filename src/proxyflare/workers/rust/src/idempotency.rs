@@ -0,0 +1,523 @@
+//! `Idempotency-Key` deduplication for non-`GET` requests.
+//!
+//! When a client sends `Idempotency-Key` on a `POST`, `PUT`, `PATCH` or
+//! `DELETE`, the first request claims the key in an `IdempotencyStore`
+//! Durable Object (binding `IDEMPOTENCY`, one object per caller and key) and
+//! its eventual status, headers and body (up to
+//! `IDEMPOTENCY_MAX_BODY_BYTES`, default 64 KiB) are kept for
+//! `IDEMPOTENCY_TTL_SECS` (default 24 hours). A retry within that window
+//! gets the stored result with `Idempotent-Replayed: true` instead of
+//! reaching the upstream again.
+//!
+//! A retry while the first request is still running gets 409
+//! `idempotency_in_progress`; reusing a key for a different method, target or
+//! body gets 422 `idempotency_key_reused`. Request bodies are compared by
+//! SHA-256 when they declare a `Content-Length` of at most
+//! `IDEMPOTENCY_MAX_REQUEST_BYTES` (default 1 MiB), and only by length
+//! otherwise, since they would have to be buffered. Upstream failures and 5xx
+//! responses release the key so the retry really is retried, as do
+//! responses whose body can't be kept (no `Content-Length`, e.g. chunked or
+//! decompressed bodies, or one over the limit): replaying their status
+//! without the body would be worse than repeating the request. Without the
+//! binding the header is forwarded untouched, and an unreachable object
+//! fails open.
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::context::{Deferred, RequestCtx};
+use crate::{config, responses, utils};
+
+const BINDING: &str = "IDEMPOTENCY";
+const STATE_KEY: &str = "record";
+pub const HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LEN: usize = 255;
+const DEFAULT_TTL_SECS: u64 = 86_400;
+const DEFAULT_MAX_BODY_BYTES: u64 = 64 * 1024;
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 1024 * 1024;
+/// How long a claim survives without completing (e.g. a crashed invocation).
+const IN_FLIGHT_TTL_MS: u64 = 60_000;
+/// Response headers that describe the original transfer, not the result.
+const SKIPPED_HEADERS: &[&str] = &[
+    "content-encoding",
+    "content-length",
+    "transfer-encoding",
+    "set-cookie",
+];
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// `None` when the body could not be kept; such a record is not replayed.
+    pub body_base64: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BeginRequest {
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompleteRequest {
+    pub response: StoredResponse,
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Proceed,
+    InProgress,
+    Mismatch,
+    Replay { response: StoredResponse },
+}
+
+/// What one key has seen so far.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Record {
+    pub fingerprint: String,
+    /// Epoch ms after which the record no longer counts.
+    pub expires_at: u64,
+    pub response: Option<StoredResponse>,
+}
+
+impl Record {
+    /// Decide what a request with `fingerprint` should do, claiming the key
+    /// when it is free.
+    pub fn begin(record: &mut Option<Record>, now_ms: u64, fingerprint: &str) -> Outcome {
+        match record {
+            Some(existing) if existing.expires_at > now_ms => {
+                if existing.fingerprint != fingerprint {
+                    return Outcome::Mismatch;
+                }
+                match &existing.response {
+                    Some(response) if response.body_base64.is_some() => Outcome::Replay {
+                        response: response.clone(),
+                    },
+                    Some(_) => Record::claim(record, now_ms, fingerprint),
+                    None => Outcome::InProgress,
+                }
+            }
+            _ => Record::claim(record, now_ms, fingerprint),
+        }
+    }
+
+    fn claim(record: &mut Option<Record>, now_ms: u64, fingerprint: &str) -> Outcome {
+        *record = Some(Record {
+            fingerprint: fingerprint.to_string(),
+            expires_at: now_ms + IN_FLIGHT_TTL_MS,
+            response: None,
+        });
+        Outcome::Proceed
+    }
+}
+
+/// How a completed response's body can be kept for replays.
+#[derive(Debug, PartialEq)]
+pub enum BodyPlan {
+    /// The status has no body.
+    Empty,
+    /// Buffer the declared-length body and store it.
+    Buffer,
+    /// Not kept; the key is released instead.
+    Unstorable,
+}
+
+pub fn body_plan(status: u16, declared: Option<u64>, max_bytes: u64) -> BodyPlan {
+    if matches!(status, 204 | 304) {
+        BodyPlan::Empty
+    } else if declared.is_some_and(|len| len <= max_bytes) {
+        BodyPlan::Buffer
+    } else {
+        BodyPlan::Unstorable
+    }
+}
+
+/// A valid key: printable ASCII, at most 255 characters.
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// What a retry must match: method, target, declared body length and, when
+/// it was buffered, the body itself.
+pub fn fingerprint(
+    method: &Method,
+    target: &Url,
+    content_length: Option<&str>,
+    body: Option<&[u8]>,
+) -> String {
+    let input = format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        target,
+        content_length.unwrap_or_default(),
+        body.map(|b| utils::hex(&Sha256::digest(b)))
+            .unwrap_or_default()
+    );
+    utils::hex(&Sha256::digest(input.as_bytes()))
+}
+
+/// Whether a request declaring `content_length` should have its body
+/// buffered for the fingerprint.
+pub fn hashable(content_length: Option<&str>, max_bytes: u64) -> bool {
+    content_length
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .is_some_and(|len| len <= max_bytes)
+}
+
+/// Whether `req` carries an `Idempotency-Key` and a body small enough to
+/// buffer and hash; pass the body to [`begin`] then.
+pub fn wants_body(env: &Env, req: &Request, method: &Method) -> bool {
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    matches!(
+        method,
+        Method::Post | Method::Put | Method::Patch | Method::Delete
+    ) && header(HEADER).is_some()
+        && env.durable_object(BINDING).is_ok()
+        && hashable(
+            header("Content-Length").as_deref(),
+            config::var_u64(env, "IDEMPOTENCY_MAX_REQUEST_BYTES")
+                .unwrap_or(DEFAULT_MAX_REQUEST_BYTES),
+        )
+}
+
+/// A claimed key. Record the result with [`Claim::complete`]; a claim
+/// dropped on an early return queues its release on the request's
+/// [`Deferred`].
+pub struct Claim {
+    stub: Option<Stub>,
+    ttl_secs: u64,
+    max_body_bytes: u64,
+    deferred: Deferred,
+}
+
+pub enum Idempotency {
+    NotRequested,
+    Claimed(Claim),
+    Done(Response),
+}
+
+async fn call(stub: &Stub, path: &str, body: &impl Serialize) -> Result<Response> {
+    let request = utils::json_request(&format!("https://idempotency{path}"), Method::Post, body)?;
+    stub.fetch_with_request(request).await
+}
+
+impl Claim {
+    /// Keep `response` for retries (releasing the key instead for 5xx and
+    /// bodies that can't be stored), and hand back an equivalent response
+    /// to send. Bodies with a declared length within the limit are buffered
+    /// so they can be stored.
+    pub async fn complete(mut self, ctx: &Context, mut response: Response) -> Result<Response> {
+        let Some(stub) = self.stub.take() else {
+            return Ok(response);
+        };
+        let status = response.status_code();
+        if status >= 500 {
+            self.stub = Some(stub);
+            return Ok(response);
+        }
+        let headers: Vec<(String, String)> = response
+            .headers()
+            .entries()
+            .filter(|(k, _)| !SKIPPED_HEADERS.contains(&k.to_ascii_lowercase().as_str()))
+            .collect();
+        let declared: Option<u64> = response
+            .headers()
+            .get("Content-Length")?
+            .and_then(|v| v.parse().ok());
+        let body = match body_plan(status, declared, self.max_body_bytes) {
+            BodyPlan::Empty => String::new(),
+            BodyPlan::Buffer => {
+                let response_headers = response.headers().clone();
+                let body = response.bytes().await?;
+                response = Response::from_bytes(body.clone())?
+                    .with_status(status)
+                    .with_headers(response_headers);
+                STANDARD.encode(body)
+            }
+            BodyPlan::Unstorable => {
+                self.stub = Some(stub);
+                return Ok(response);
+            }
+        };
+        let complete = CompleteRequest {
+            response: StoredResponse {
+                status,
+                headers,
+                body_base64: Some(body),
+            },
+            ttl_secs: self.ttl_secs,
+        };
+        ctx.wait_until(async move {
+            if let Err(e) = call(&stub, "/complete", &complete).await {
+                console_error!("Storing idempotent response failed: {:?}", e);
+            }
+        });
+        Ok(response)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(stub) = self.stub.take() {
+            self.deferred.push(async move {
+                if let Err(e) = call(&stub, "/release", &serde_json::json!({})).await {
+                    console_error!("Releasing idempotency key failed: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Build the response for a stored result.
+pub fn replay(stored: &StoredResponse) -> Result<Response> {
+    let body = stored
+        .body_base64
+        .as_deref()
+        .and_then(|b| STANDARD.decode(b).ok())
+        .unwrap_or_default();
+    let headers = Headers::new();
+    for (name, value) in &stored.headers {
+        headers.append(name, value)?;
+    }
+    headers.set("Idempotent-Replayed", "true")?;
+    Ok(Response::from_bytes(body)?
+        .with_status(stored.status)
+        .with_headers(headers))
+}
+
+/// Claim the request's `Idempotency-Key`, or answer it from a previous run.
+/// `body` is the buffered request body when [`wants_body`] asked for it.
+pub async fn begin(
+    env: &Env,
+    rctx: &RequestCtx,
+    req: &Request,
+    target: &Url,
+    body: Option<&[u8]>,
+) -> Result<Idempotency> {
+    if !matches!(
+        rctx.method,
+        Method::Post | Method::Put | Method::Patch | Method::Delete
+    ) {
+        return Ok(Idempotency::NotRequested);
+    }
+    let Some(key) = req.headers().get(HEADER)? else {
+        return Ok(Idempotency::NotRequested);
+    };
+    if !valid_key(&key) {
+        return responses::error(400, "invalid_request", "Invalid Idempotency-Key header")
+            .map(Idempotency::Done);
+    }
+    let subject = match (&rctx.caller, &rctx.client_ip) {
+        (Some(caller), _) => caller.clone(),
+        (None, Some(ip)) => format!("ip:{ip}"),
+        (None, None) => return Ok(Idempotency::NotRequested),
+    };
+    let Some(stub) = env.durable_object(BINDING).ok().and_then(|ns| {
+        ns.id_from_name(&format!("{subject}:{key}"))
+            .ok()?
+            .get_stub()
+            .ok()
+    }) else {
+        return Ok(Idempotency::NotRequested);
+    };
+    let begin = BeginRequest {
+        fingerprint: fingerprint(
+            &rctx.method,
+            target,
+            req.headers().get("Content-Length")?.as_deref(),
+            body,
+        ),
+    };
+    let outcome: Outcome = match call(&stub, "/begin", &begin).await {
+        Ok(mut response) => response.json().await?,
+        Err(e) => {
+            console_error!("Idempotency store failed: {:?}", e);
+            return Ok(Idempotency::NotRequested);
+        }
+    };
+    match outcome {
+        Outcome::Proceed => Ok(Idempotency::Claimed(Claim {
+            stub: Some(stub),
+            ttl_secs: config::var_u64(env, "IDEMPOTENCY_TTL_SECS").unwrap_or(DEFAULT_TTL_SECS),
+            max_body_bytes: config::var_u64(env, "IDEMPOTENCY_MAX_BODY_BYTES")
+                .unwrap_or(DEFAULT_MAX_BODY_BYTES),
+            deferred: rctx.deferred.clone(),
+        })),
+        Outcome::Replay { response } => {
+            console_log!("[{}] Replaying {} {}", rctx.id, HEADER, key);
+            replay(&response).map(Idempotency::Done)
+        }
+        Outcome::InProgress => {
+            let mut response = responses::error(
+                409,
+                "idempotency_in_progress",
+                "A request with this Idempotency-Key is still in progress",
+            )?;
+            response.headers_mut().set("Retry-After", "1")?;
+            Ok(Idempotency::Done(response))
+        }
+        Outcome::Mismatch => responses::error(
+            422,
+            "idempotency_key_reused",
+            "This Idempotency-Key was used for a different request",
+        )
+        .map(Idempotency::Done),
+    }
+}
+
+#[durable_object]
+pub struct IdempotencyStore {
+    state: State,
+}
+
+impl DurableObject for IdempotencyStore {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        match req.path().as_str() {
+            "/begin" => {
+                let begin: BeginRequest = req.json().await?;
+                let mut record: Option<Record> = storage.get(STATE_KEY).await?;
+                let outcome =
+                    Record::begin(&mut record, Date::now().as_millis(), &begin.fingerprint);
+                if let (Outcome::Proceed, Some(record)) = (&outcome, &record) {
+                    storage.put(STATE_KEY, record).await?;
+                    // Clean up a claim that is never completed or released;
+                    // `/complete` moves the alarm to the replay window's end.
+                    storage
+                        .set_alarm(Duration::from_millis(IN_FLIGHT_TTL_MS))
+                        .await?;
+                }
+                Response::from_json(&outcome)
+            }
+            "/complete" => {
+                let complete: CompleteRequest = req.json().await?;
+                let Some(mut record) = storage.get::<Record>(STATE_KEY).await? else {
+                    return Response::empty();
+                };
+                let expires_at = Date::now().as_millis() + complete.ttl_secs * 1000;
+                record.expires_at = expires_at;
+                record.response = Some(complete.response);
+                storage.put(STATE_KEY, &record).await?;
+                // Drop the record once nothing can replay it.
+                storage
+                    .set_alarm(Duration::from_secs(complete.ttl_secs))
+                    .await?;
+                Response::empty()
+            }
+            "/release" => {
+                storage.delete(STATE_KEY).await?;
+                Response::empty()
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored() -> StoredResponse {
+        StoredResponse {
+            status: 201,
+            headers: vec![("content-type".into(), "application/json".into())],
+            body_base64: Some(STANDARD.encode(b"{\"id\":1}")),
+        }
+    }
+
+    #[test]
+    fn test_first_request_claims_then_retry_waits() {
+        let mut record = None;
+        assert_eq!(Record::begin(&mut record, 0, "f"), Outcome::Proceed);
+        assert_eq!(Record::begin(&mut record, 10, "f"), Outcome::InProgress);
+        assert_eq!(Record::begin(&mut record, 10, "g"), Outcome::Mismatch);
+        // An abandoned claim lapses.
+        assert_eq!(
+            Record::begin(&mut record, IN_FLIGHT_TTL_MS, "f"),
+            Outcome::Proceed
+        );
+    }
+
+    #[test]
+    fn test_completed_request_replays_until_expiry() {
+        let mut record = Some(Record {
+            fingerprint: "f".into(),
+            expires_at: 1_000,
+            response: Some(stored()),
+        });
+        assert_eq!(
+            Record::begin(&mut record, 999, "f"),
+            Outcome::Replay { response: stored() }
+        );
+        assert_eq!(Record::begin(&mut record, 1_000, "f"), Outcome::Proceed);
+    }
+
+    #[test]
+    fn test_bodyless_record_is_not_replayed() {
+        assert_eq!(body_plan(201, Some(8), 1024), BodyPlan::Buffer);
+        assert_eq!(body_plan(201, None, 1024), BodyPlan::Unstorable);
+        assert_eq!(body_plan(201, Some(4096), 1024), BodyPlan::Unstorable);
+        assert_eq!(body_plan(204, None, 1024), BodyPlan::Empty);
+        let mut record = Some(Record {
+            fingerprint: "f".into(),
+            expires_at: 1_000,
+            response: Some(StoredResponse {
+                body_base64: None,
+                ..stored()
+            }),
+        });
+        assert_eq!(Record::begin(&mut record, 10, "f"), Outcome::Proceed);
+        assert_eq!(Record::begin(&mut record, 20, "f"), Outcome::InProgress);
+    }
+
+    #[test]
+    fn test_fingerprint_and_key() {
+        let target = Url::parse("https://api.example/charges").unwrap();
+        let a = fingerprint(&Method::Post, &target, Some("12"), None);
+        assert_eq!(a, fingerprint(&Method::Post, &target, Some("12"), None));
+        assert_ne!(a, fingerprint(&Method::Put, &target, Some("12"), None));
+        assert_ne!(a, fingerprint(&Method::Post, &target, Some("13"), None));
+        assert!(valid_key("order-42_retry"));
+        assert!(!valid_key(""));
+        assert!(!valid_key("has space"));
+        assert!(!valid_key(&"k".repeat(256)));
+    }
+
+    #[test]
+    fn test_same_length_different_body_is_a_mismatch() {
+        let target = Url::parse("https://api.example/charges").unwrap();
+        let first = fingerprint(
+            &Method::Post,
+            &target,
+            Some("16"),
+            Some(b"{\"amount\": 1000}"),
+        );
+        let retry = fingerprint(
+            &Method::Post,
+            &target,
+            Some("16"),
+            Some(b"{\"amount\": 9000}"),
+        );
+        let mut record = None;
+        assert_eq!(Record::begin(&mut record, 0, &first), Outcome::Proceed);
+        assert_eq!(Record::begin(&mut record, 10, &retry), Outcome::Mismatch);
+        assert!(hashable(Some("16"), 1024));
+        assert!(!hashable(Some("2048"), 1024));
+        assert!(!hashable(None, 1024));
+    }
+}
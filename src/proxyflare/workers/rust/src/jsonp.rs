@@ -0,0 +1,94 @@
+//! JSONP responses (`?callback=fn`) for legacy embeds that can't use CORS.
+//!
+//! Off unless `JSONP_ENABLED=true`: a JSONP response can be read by any page
+//! that includes it as a script, so it bypasses CORS entirely. When enabled,
+//! `callback` is consumed by the proxy instead of being sent upstream, and a
+//! JSON upstream body is streamed back as `/**/fn(<body>);` with
+//! `Content-Type: application/javascript`. Other content types pass through
+//! unwrapped. Callback names are restricted to dotted JavaScript
+//! identifiers, so the parameter can't inject script of its own.
+
+use futures_util::{stream, StreamExt};
+use worker::*;
+
+use crate::{config, streams};
+
+pub const PARAM: &str = "callback";
+const MAX_CALLBACK_LEN: usize = 128;
+
+pub fn is_enabled(env: &Env) -> bool {
+    config::var(env, "JSONP_ENABLED").as_deref() == Some("true")
+}
+
+/// `name` or `name.name...` with JavaScript identifier characters.
+pub fn valid_callback(callback: &str) -> bool {
+    callback.len() <= MAX_CALLBACK_LEN
+        && callback.split('.').all(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
+/// The callback the worker URL asked for.
+pub fn requested(url: &Url) -> std::result::Result<Option<String>, String> {
+    match url.query_pairs().find(|(k, _)| k == PARAM) {
+        Some((_, callback)) if valid_callback(&callback) => Ok(Some(callback.into_owned())),
+        Some(_) => Err(format!(
+            "Invalid {PARAM}: expected a JavaScript function name"
+        )),
+        None => Ok(None),
+    }
+}
+
+pub fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// Wrap `body` in a call to `callback`.
+pub fn wrap(body: streams::Body, callback: &str) -> streams::Body {
+    // The leading comment keeps the response from starting with
+    // caller-controlled bytes (Rosetta Flash and similar content sniffing).
+    let call = format!("/**/{callback}(").into_bytes();
+    let head = stream::once(async move { Ok(call) });
+    let tail = stream::once(async { Ok(b");".to_vec()) });
+    Box::pin(head.chain(body).chain(tail))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_callback() {
+        assert!(valid_callback("cb"));
+        assert!(valid_callback("jQuery_123.handlers.$done"));
+        assert!(!valid_callback(""));
+        assert!(!valid_callback("1cb"));
+        assert!(!valid_callback("cb."));
+        assert!(!valid_callback("alert(1);cb"));
+        assert!(!valid_callback(&"a".repeat(MAX_CALLBACK_LEN + 1)));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let body: streams::Body = Box::pin(stream::iter(vec![
+            Ok(b"{\"a\":".to_vec()),
+            Ok(b"1}".to_vec()),
+        ]));
+        let chunks: Vec<Vec<u8>> =
+            futures_executor::block_on(wrap(body, "cb").map(|chunk| chunk.unwrap()).collect());
+        assert_eq!(chunks.concat(), b"/**/cb({\"a\":1});");
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(is_json("application/problem+json"));
+        assert!(!is_json("text/html"));
+    }
+}
@@ -0,0 +1,495 @@
+//! JWT bearer tokens validated against a JWKS endpoint.
+//!
+//! Enabled by `JWT_JWKS_URL`. Tokens signed with `RS256` or `ES256` are
+//! accepted when the signature matches a key from the JWKS, `exp` is present
+//! and `exp`/`nbf` hold (with `JWT_LEEWAY_SECS`, default 60, of slack), `aud`
+//! contains one of `JWT_AUDIENCE` and, if configured, `iss` equals
+//! `JWT_ISSUER`. `JWT_AUDIENCE` is required: without it every token is
+//! rejected, since any token the identity provider issued for another
+//! application would otherwise be accepted.
+//!
+//! The JWKS is cached per isolate for `JWT_JWKS_TTL_SECS` (default 600) and
+//! refetched early when a token names an unknown `kid`, so key rotation at the
+//! identity provider is picked up without a redeploy.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use p256::ecdsa::signature::Verifier as _;
+use serde::Deserialize;
+use sha2::Sha256;
+use worker::*;
+
+use crate::config;
+
+const JWKS_URL_VAR: &str = "JWT_JWKS_URL";
+const DEFAULT_TTL_SECS: u64 = 600;
+const DEFAULT_LEEWAY_SECS: u64 = 60;
+/// Unknown `kid`s trigger a refetch at most this often.
+const MIN_REFRESH_SECS: u64 = 30;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JwtError {
+    Malformed,
+    UnsupportedAlgorithm(String),
+    UnknownKey,
+    BadSignature,
+    Expired,
+    MissingExpiry,
+    NotYetValid,
+    WrongAudience,
+    WrongIssuer,
+    JwksUnavailable,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed token"),
+            Self::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {alg}"),
+            Self::UnknownKey => write!(f, "no matching signing key"),
+            Self::BadSignature => write!(f, "signature verification failed"),
+            Self::Expired => write!(f, "token expired"),
+            Self::MissingExpiry => write!(f, "token has no expiry"),
+            Self::NotYetValid => write!(f, "token not yet valid"),
+            Self::WrongAudience => write!(f, "audience not accepted"),
+            Self::WrongIssuer => write!(f, "issuer not accepted"),
+            Self::JwksUnavailable => write!(f, "signing keys unavailable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default)]
+    pub kid: Option<String>,
+    #[serde(default)]
+    pub n: Option<String>,
+    #[serde(default)]
+    pub e: Option<String>,
+    #[serde(default)]
+    pub crv: Option<String>,
+    #[serde(default)]
+    pub x: Option<String>,
+    #[serde(default)]
+    pub y: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Header {
+    pub alg: String,
+    #[serde(default)]
+    pub kid: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Audience {
+    #[default]
+    Missing,
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default)]
+    pub iss: Option<String>,
+    #[serde(default)]
+    pub aud: Audience,
+    #[serde(default)]
+    pub exp: Option<u64>,
+    #[serde(default)]
+    pub nbf: Option<u64>,
+    /// Set by Cloudflare Access for user logins.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Set by Cloudflare Access for service tokens (the client id).
+    #[serde(default)]
+    pub common_name: Option<String>,
+}
+
+/// Claim requirements, taken from env.
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub audiences: Vec<String>,
+    pub issuer: Option<String>,
+    pub leeway_secs: u64,
+}
+
+pub struct Token {
+    pub header: Header,
+    pub claims: Claims,
+    signing_input: String,
+    signature: Vec<u8>,
+}
+
+fn b64(segment: &str) -> std::result::Result<Vec<u8>, JwtError> {
+    URL_SAFE_NO_PAD
+        .decode(segment.trim_end_matches('='))
+        .map_err(|_| JwtError::Malformed)
+}
+
+/// Cheap shape check used to tell JWTs apart from opaque API keys.
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.starts_with("eyJ") && token.split('.').count() == 3
+}
+
+pub fn decode(token: &str) -> std::result::Result<Token, JwtError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(JwtError::Malformed);
+    };
+    Ok(Token {
+        header: serde_json::from_slice(&b64(header)?).map_err(|_| JwtError::Malformed)?,
+        claims: serde_json::from_slice(&b64(payload)?).map_err(|_| JwtError::Malformed)?,
+        signing_input: format!("{header}.{payload}"),
+        signature: b64(signature)?,
+    })
+}
+
+fn jwk_bytes(value: &Option<String>) -> std::result::Result<Vec<u8>, JwtError> {
+    b64(value.as_deref().ok_or(JwtError::UnknownKey)?)
+}
+
+impl Token {
+    /// Pick the JWKS entry this token was signed with.
+    pub fn find_key<'a>(&self, jwks: &'a Jwks) -> Option<&'a Jwk> {
+        let kty = match self.header.alg.as_str() {
+            "RS256" => "RSA",
+            "ES256" => "EC",
+            _ => return None,
+        };
+        jwks.keys.iter().find(|k| {
+            k.kty == kty
+                && match &self.header.kid {
+                    Some(kid) => k.kid.as_deref() == Some(kid.as_str()),
+                    None => true,
+                }
+        })
+    }
+
+    pub fn verify_signature(&self, jwk: &Jwk) -> std::result::Result<(), JwtError> {
+        let message = self.signing_input.as_bytes();
+        match self.header.alg.as_str() {
+            "RS256" => {
+                let key = rsa::RsaPublicKey::new(
+                    rsa::BigUint::from_bytes_be(&jwk_bytes(&jwk.n)?),
+                    rsa::BigUint::from_bytes_be(&jwk_bytes(&jwk.e)?),
+                )
+                .map_err(|_| JwtError::UnknownKey)?;
+                let signature = rsa::pkcs1v15::Signature::try_from(self.signature.as_slice())
+                    .map_err(|_| JwtError::BadSignature)?;
+                rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key)
+                    .verify(message, &signature)
+                    .map_err(|_| JwtError::BadSignature)
+            }
+            "ES256" => {
+                let (x, y) = (jwk_bytes(&jwk.x)?, jwk_bytes(&jwk.y)?);
+                if jwk.crv.as_deref() != Some("P-256") || x.len() != 32 || y.len() != 32 {
+                    return Err(JwtError::UnknownKey);
+                }
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    p256::FieldBytes::from_slice(&x),
+                    p256::FieldBytes::from_slice(&y),
+                    false,
+                );
+                let key = p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map_err(|_| JwtError::UnknownKey)?;
+                let signature = p256::ecdsa::Signature::from_slice(&self.signature)
+                    .map_err(|_| JwtError::BadSignature)?;
+                key.verify(message, &signature)
+                    .map_err(|_| JwtError::BadSignature)
+            }
+            alg => Err(JwtError::UnsupportedAlgorithm(alg.to_string())),
+        }
+    }
+
+    pub fn validate_claims(
+        &self,
+        rules: &Validation,
+        now_secs: u64,
+    ) -> std::result::Result<(), JwtError> {
+        let claims = &self.claims;
+        let exp = claims.exp.ok_or(JwtError::MissingExpiry)?;
+        if now_secs > exp.saturating_add(rules.leeway_secs) {
+            return Err(JwtError::Expired);
+        }
+        if claims
+            .nbf
+            .is_some_and(|nbf| now_secs.saturating_add(rules.leeway_secs) < nbf)
+        {
+            return Err(JwtError::NotYetValid);
+        }
+        // No configured audience accepts nothing.
+        let accepted = |aud: &String| rules.audiences.contains(aud);
+        let ok = match &claims.aud {
+            Audience::Missing => false,
+            Audience::One(aud) => accepted(aud),
+            Audience::Many(auds) => auds.iter().any(accepted),
+        };
+        if !ok {
+            return Err(JwtError::WrongAudience);
+        }
+        if rules.issuer.is_some() && claims.iss != rules.issuer {
+            return Err(JwtError::WrongIssuer);
+        }
+        Ok(())
+    }
+}
+
+struct CachedJwks {
+    fetched_at_ms: u64,
+    jwks: Jwks,
+}
+
+thread_local! {
+    /// JWKS URL -> last fetched key set.
+    static JWKS_CACHE: RefCell<HashMap<String, CachedJwks>> = RefCell::new(HashMap::new());
+}
+
+async fn fetch_jwks(url: &str) -> Result<Jwks> {
+    let mut response = Fetch::Url(Url::parse(url)?).send().await?;
+    if response.status_code() != 200 {
+        return Err(Error::RustError(format!(
+            "JWKS fetch returned {}",
+            response.status_code()
+        )));
+    }
+    response.json().await
+}
+
+/// The cached JWKS, refreshed when older than `ttl_ms` (or `force`d and not
+/// fetched within the last [`MIN_REFRESH_SECS`]). A stale copy is kept if
+/// the refresh fails.
+async fn jwks(url: &str, ttl_ms: u64, force: bool) -> Option<Jwks> {
+    let now = Date::now().as_millis();
+    let cached = JWKS_CACHE.with(|c| {
+        c.borrow()
+            .get(url)
+            .map(|c| (c.fetched_at_ms, c.jwks.clone()))
+    });
+    let fresh = cached.as_ref().is_some_and(|(at, _)| {
+        let age = now.saturating_sub(*at);
+        age < ttl_ms && !(force && age >= MIN_REFRESH_SECS * 1000)
+    });
+    if fresh {
+        return cached.map(|(_, jwks)| jwks);
+    }
+    match fetch_jwks(url).await {
+        Ok(jwks) => {
+            JWKS_CACHE.with(|c| {
+                c.borrow_mut().insert(
+                    url.to_string(),
+                    CachedJwks {
+                        fetched_at_ms: now,
+                        jwks: jwks.clone(),
+                    },
+                )
+            });
+            Some(jwks)
+        }
+        Err(e) => {
+            console_error!("JWKS refresh from {} failed: {:?}", url, e);
+            cached.map(|(_, jwks)| jwks)
+        }
+    }
+}
+
+/// Whether JWT validation is configured.
+pub fn is_enabled(env: &Env) -> bool {
+    config::var(env, JWKS_URL_VAR).is_some()
+}
+
+/// Verify `token` against the key set at `jwks_url` and check its claims.
+pub async fn verify(
+    token: &str,
+    jwks_url: &str,
+    ttl_ms: u64,
+    rules: &Validation,
+) -> std::result::Result<Claims, JwtError> {
+    let token = decode(token)?;
+    if !matches!(token.header.alg.as_str(), "RS256" | "ES256") {
+        return Err(JwtError::UnsupportedAlgorithm(token.header.alg.clone()));
+    }
+    let mut keys = jwks(jwks_url, ttl_ms, false)
+        .await
+        .ok_or(JwtError::JwksUnavailable)?;
+    if token.find_key(&keys).is_none() {
+        keys = jwks(jwks_url, ttl_ms, true)
+            .await
+            .ok_or(JwtError::JwksUnavailable)?;
+    }
+    let jwk = token.find_key(&keys).ok_or(JwtError::UnknownKey)?;
+    token.verify_signature(jwk)?;
+    token.validate_claims(rules, Date::now().as_millis() / 1000)?;
+    Ok(token.claims)
+}
+
+/// Validate a bearer `token`; returns the subject (or issuer) identifying
+/// the caller.
+pub async fn validate(env: &Env, token: &str) -> std::result::Result<String, JwtError> {
+    let Some(url) = config::var(env, JWKS_URL_VAR) else {
+        return Err(JwtError::JwksUnavailable);
+    };
+    let audiences = config::var_list(env, "JWT_AUDIENCE");
+    if audiences.is_empty() {
+        console_error!("JWT_JWKS_URL is set without JWT_AUDIENCE; rejecting JWTs");
+        return Err(JwtError::WrongAudience);
+    }
+    let rules = Validation {
+        audiences,
+        issuer: config::var(env, "JWT_ISSUER"),
+        leeway_secs: leeway_secs(env),
+    };
+    let ttl_ms = config::var_u64(env, "JWT_JWKS_TTL_SECS").unwrap_or(DEFAULT_TTL_SECS) * 1000;
+    let claims = verify(token, &url, ttl_ms, &rules).await?;
+    Ok(claims
+        .sub
+        .or(claims.iss)
+        .unwrap_or_else(|| "anonymous".into()))
+}
+
+pub fn leeway_secs(env: &Env) -> u64 {
+    config::var_u64(env, "JWT_LEEWAY_SECS").unwrap_or(DEFAULT_LEEWAY_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    fn es256_fixture(claims: &str) -> (String, Jwks) {
+        let signing_key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let point = signing_key.verifying_key().to_encoded_point(false);
+        let jwks = Jwks {
+            keys: vec![Jwk {
+                kty: "EC".into(),
+                kid: Some("k1".into()),
+                n: None,
+                e: None,
+                crv: Some("P-256".into()),
+                x: Some(URL_SAFE_NO_PAD.encode(point.x().unwrap())),
+                y: Some(URL_SAFE_NO_PAD.encode(point.y().unwrap())),
+            }],
+        };
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","kid":"k1"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(claims);
+        let input = format!("{header}.{payload}");
+        let signature: p256::ecdsa::Signature = signing_key.sign(input.as_bytes());
+        let token = format!("{input}.{}", URL_SAFE_NO_PAD.encode(signature.to_bytes()));
+        (token, jwks)
+    }
+
+    #[test]
+    fn test_es256_signature_roundtrip() {
+        let (token, jwks) = es256_fixture(r#"{"sub":"alice","exp":2000}"#);
+        assert!(looks_like_jwt(&token));
+        let decoded = decode(&token).unwrap();
+        let jwk = decoded.find_key(&jwks).unwrap();
+        assert_eq!(decoded.verify_signature(jwk), Ok(()));
+        assert_eq!(decoded.claims.sub.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_tampered_payload_is_rejected() {
+        let (token, jwks) = es256_fixture(r#"{"sub":"alice"}"#);
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = format!(
+            "{}.{}.{}",
+            parts[0],
+            URL_SAFE_NO_PAD.encode(r#"{"sub":"mallory"}"#),
+            parts[2]
+        );
+        let decoded = decode(&forged).unwrap();
+        let jwk = decoded.find_key(&jwks).unwrap();
+        assert_eq!(decoded.verify_signature(jwk), Err(JwtError::BadSignature));
+    }
+
+    #[test]
+    fn test_unknown_kid_has_no_key() {
+        let (token, mut jwks) = es256_fixture("{}");
+        jwks.keys[0].kid = Some("rotated".into());
+        assert!(decode(&token).unwrap().find_key(&jwks).is_none());
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        let (token, _) =
+            es256_fixture(r#"{"exp":1000,"nbf":500,"aud":["api","web"],"iss":"https://idp"}"#);
+        let token = decode(&token).unwrap();
+        let rules = Validation {
+            audiences: vec!["web".into()],
+            issuer: Some("https://idp".into()),
+            leeway_secs: 60,
+        };
+        assert_eq!(token.validate_claims(&rules, 1050), Ok(()));
+        assert_eq!(token.validate_claims(&rules, 1061), Err(JwtError::Expired));
+        assert_eq!(
+            token.validate_claims(&rules, 400),
+            Err(JwtError::NotYetValid)
+        );
+        let other_aud = Validation {
+            audiences: vec!["admin".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            token.validate_claims(&other_aud, 900),
+            Err(JwtError::WrongAudience)
+        );
+        let other_iss = Validation {
+            audiences: vec!["api".into()],
+            issuer: Some("https://evil".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            token.validate_claims(&other_iss, 900),
+            Err(JwtError::WrongIssuer)
+        );
+    }
+
+    #[test]
+    fn test_validate_claims_requires_exp_and_audience() {
+        let rules = Validation {
+            audiences: vec!["api".into()],
+            ..Default::default()
+        };
+        let claims = |json: &str| decode(&es256_fixture(json).0).unwrap();
+        assert_eq!(
+            claims(r#"{"aud":"api"}"#).validate_claims(&rules, 900),
+            Err(JwtError::MissingExpiry)
+        );
+        let foreign = claims(r#"{"exp":1000,"aud":"other-app"}"#);
+        assert_eq!(
+            foreign.validate_claims(&rules, 900),
+            Err(JwtError::WrongAudience)
+        );
+        assert_eq!(
+            claims(r#"{"exp":1000}"#).validate_claims(&rules, 900),
+            Err(JwtError::WrongAudience)
+        );
+        assert_eq!(
+            claims(r#"{"exp":1000,"aud":"api"}"#).validate_claims(&Validation::default(), 900),
+            Err(JwtError::WrongAudience)
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_garbage() {
+        assert!(matches!(decode("a.b"), Err(JwtError::Malformed)));
+        assert!(matches!(decode("eyJ.e30.sig"), Err(JwtError::Malformed)));
+        assert!(!looks_like_jwt("plain-api-key"));
+    }
+}
@@ -0,0 +1,307 @@
+//! Request quotas counted in KV, in two tiers.
+//!
+//! `KEY_QUOTAS` sets daily and/or monthly request budgets per caller id, with
+//! `"*"` as the default: `{"*": {"daily": 10000}, "team-a": {"daily": 50000,
+//! "monthly": 1000000}}`. `ANONYMOUS_QUOTA` (e.g. `{"daily": 100}`) gives
+//! unauthenticated clients a smaller allowance per client IP, so a public
+//! deployment stays usable for casual testing while keyed callers get the
+//! real capacity; signed links are not counted. Counters live in the
+//! `QUOTAS_KV` namespace under `quota:<subject>:<YYYY-MM-DD>` and
+//! `quota:<subject>:<YYYY-MM>` (the subject is the caller id or
+//! `ip:<address>`); windows
+//! reset at 00:00 UTC and on the first of the month. KV is eventually
+//! consistent, so limits are approximate under bursts; use the spend caps
+//! (a Durable Object) where exact enforcement matters.
+//!
+//! Responses carry `X-RateLimit-Limit`, `X-RateLimit-Remaining` and
+//! `X-RateLimit-Reset` (epoch seconds) for the tightest window; once a
+//! budget is spent requests get 429 until it resets.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::json;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, responses, utils};
+
+const KV_BINDING: &str = "QUOTAS_KV";
+const DAY_SECS: u64 = 86_400;
+/// Counters outlive their window by this long before KV drops them.
+const EXPIRY_SLACK_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub struct Limits {
+    #[serde(default)]
+    pub daily: Option<u64>,
+    #[serde(default)]
+    pub monthly: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    /// Window label (used in the counter key) and its reset time in epoch seconds.
+    pub fn window(self, now_ms: u64) -> (String, u64) {
+        let date = utils::iso_date(now_ms);
+        match self {
+            Period::Daily => (date, (now_ms / 1000 / DAY_SECS + 1) * DAY_SECS),
+            Period::Monthly => (date[..7].to_string(), utils::next_month_start(now_ms)),
+        }
+    }
+}
+
+impl Limits {
+    pub fn periods(&self) -> Vec<(Period, u64)> {
+        [(Period::Daily, self.daily), (Period::Monthly, self.monthly)]
+            .into_iter()
+            .filter_map(|(period, limit)| limit.map(|l| (period, l)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub key: String,
+    pub limit: u64,
+    pub used: u64,
+    pub reset_at: u64,
+}
+
+impl Usage {
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+}
+
+pub fn limits_for(quotas: &HashMap<String, Limits>, caller: &str) -> Option<Limits> {
+    quotas.get(caller).or_else(|| quotas.get("*")).copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    Anonymous,
+    Keyed,
+}
+
+/// Counter subject, tier and limits for a request, if it is metered.
+/// `signed` requests without a caller are not.
+pub fn subject(
+    caller: Option<&str>,
+    client_ip: Option<&str>,
+    signed: bool,
+    key_quotas: &HashMap<String, Limits>,
+    anonymous: Option<Limits>,
+) -> Option<(String, Tier, Limits)> {
+    match (caller, client_ip) {
+        (Some(caller), _) => Some((
+            caller.to_string(),
+            Tier::Keyed,
+            limits_for(key_quotas, caller)?,
+        )),
+        (None, Some(ip)) if !signed => Some((format!("ip:{ip}"), Tier::Anonymous, anonymous?)),
+        _ => None,
+    }
+}
+
+/// The window closest to running out.
+pub fn tightest(usages: &[Usage]) -> Option<&Usage> {
+    usages.iter().min_by_key(|u| u.remaining())
+}
+
+pub fn headers(usage: &Usage) -> Vec<(&'static str, String)> {
+    vec![
+        ("X-RateLimit-Limit", usage.limit.to_string()),
+        ("X-RateLimit-Remaining", usage.remaining().to_string()),
+        ("X-RateLimit-Reset", usage.reset_at.to_string()),
+    ]
+}
+
+fn exceeded(usage: &Usage, tier: Tier) -> Result<Response> {
+    let hint = match tier {
+        Tier::Anonymous => "; use an API key for a larger allowance",
+        Tier::Keyed => "",
+    };
+    let mut response = responses::json(
+        429,
+        &json!({
+            "error": "quota_exceeded",
+            "message": format!(
+                "Request quota of {} reached; resets at {}{}",
+                usage.limit,
+                utils::http_date(usage.reset_at * 1000),
+                hint
+            ),
+            "limit": usage.limit,
+            "reset_at": usage.reset_at,
+        }),
+    )?;
+    let now = Date::now().as_millis() / 1000;
+    let headers = response.headers_mut();
+    for (name, value) in self::headers(usage) {
+        headers.set(name, &value)?;
+    }
+    headers.set(
+        "Retry-After",
+        &usage.reset_at.saturating_sub(now).max(1).to_string(),
+    )?;
+    Ok(response)
+}
+
+/// Enforce the caller's (or anonymous client's) quota and count the request
+/// in the background. Rate-limit headers for the client are added to `rctx`.
+pub async fn check(env: &Env, ctx: &Context, rctx: &mut RequestCtx) -> Result<Option<Response>> {
+    let quotas: HashMap<String, Limits> = config::var_json(env, "KEY_QUOTAS").unwrap_or_default();
+    let anonymous = config::var_json(env, "ANONYMOUS_QUOTA");
+    let Some((subject, tier, limits)) = subject(
+        rctx.caller.as_deref(),
+        rctx.client_ip.as_deref(),
+        rctx.signed,
+        &quotas,
+        anonymous,
+    ) else {
+        return Ok(None);
+    };
+    let Ok(kv) = env.kv(KV_BINDING) else {
+        console_warn!(
+            "Quota for {} not enforced: {} is not bound",
+            subject,
+            KV_BINDING
+        );
+        return Ok(None);
+    };
+
+    let now = Date::now().as_millis();
+    let mut usages = Vec::new();
+    for (period, limit) in limits.periods() {
+        let (label, reset_at) = period.window(now);
+        let key = format!("quota:{subject}:{label}");
+        let used = kv
+            .get(&key)
+            .text()
+            .await?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        usages.push(Usage {
+            key,
+            limit,
+            used,
+            reset_at,
+        });
+    }
+    if let Some(spent) = usages.iter().find(|u| u.remaining() == 0) {
+        console_log!("[{}] Quota exceeded for {}", rctx.id, subject);
+        return exceeded(spent, tier).map(Some);
+    }
+
+    for usage in &mut usages {
+        usage.used += 1;
+    }
+    if let Some(usage) = tightest(&usages) {
+        rctx.extra_headers.extend(headers(usage));
+    }
+    ctx.wait_until(async move {
+        for usage in usages {
+            let stored = kv
+                .put(&usage.key, usage.used.to_string())
+                .map(|p| p.expiration(usage.reset_at + EXPIRY_SLACK_SECS));
+            if let Err(e) = match stored {
+                Ok(put) => put.execute().await,
+                Err(e) => Err(e),
+            } {
+                console_error!("Quota counter update {} failed: {:?}", usage.key, e);
+            }
+        }
+    });
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(limit: u64, used: u64) -> Usage {
+        Usage {
+            key: format!("quota:x:{limit}"),
+            limit,
+            used,
+            reset_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_limits_for_falls_back_to_default() {
+        let quotas: HashMap<String, Limits> =
+            serde_json::from_str(r#"{"*": {"daily": 10}, "team-a": {"monthly": 500}}"#).unwrap();
+        assert_eq!(
+            limits_for(&quotas, "team-a").unwrap().periods(),
+            vec![(Period::Monthly, 500)]
+        );
+        assert_eq!(
+            limits_for(&quotas, "team-b").unwrap().periods(),
+            vec![(Period::Daily, 10)]
+        );
+        assert_eq!(limits_for(&HashMap::new(), "team-b"), None);
+    }
+
+    #[test]
+    fn test_subject_tiers() {
+        let quotas: HashMap<String, Limits> =
+            serde_json::from_str(r#"{"*": {"daily": 10000}}"#).unwrap();
+        let anonymous = Some(Limits {
+            daily: Some(100),
+            monthly: None,
+        });
+        let keyed = subject(
+            Some("team-a"),
+            Some("203.0.113.7"),
+            false,
+            &quotas,
+            anonymous,
+        );
+        assert_eq!(
+            keyed.map(|(s, t, _)| (s, t)),
+            Some(("team-a".into(), Tier::Keyed))
+        );
+        let (ip, tier, limits) =
+            subject(None, Some("203.0.113.7"), false, &quotas, anonymous).unwrap();
+        assert_eq!((ip.as_str(), tier), ("ip:203.0.113.7", Tier::Anonymous));
+        assert_eq!(limits.daily, Some(100));
+        assert!(subject(None, Some("203.0.113.7"), true, &quotas, anonymous).is_none());
+        assert!(subject(None, Some("203.0.113.7"), false, &quotas, None).is_none());
+    }
+
+    #[test]
+    fn test_windows() {
+        // 2024-02-29T12:00:00Z
+        let now = 1_709_208_000_000;
+        assert_eq!(
+            Period::Daily.window(now),
+            ("2024-02-29".to_string(), 1_709_251_200)
+        );
+        assert_eq!(
+            Period::Monthly.window(now),
+            ("2024-02".to_string(), 1_709_251_200)
+        );
+    }
+
+    #[test]
+    fn test_tightest_and_headers() {
+        let usages = [usage(1000, 10), usage(50, 45)];
+        let tight = tightest(&usages).unwrap();
+        assert_eq!(tight.limit, 50);
+        assert_eq!(
+            headers(tight),
+            vec![
+                ("X-RateLimit-Limit", "50".to_string()),
+                ("X-RateLimit-Remaining", "5".to_string()),
+                ("X-RateLimit-Reset", "0".to_string()),
+            ]
+        );
+    }
+}
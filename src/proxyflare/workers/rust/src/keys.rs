@@ -0,0 +1,233 @@
+//! Zero-downtime rotation of API keys stored in `API_KEYS_KV`.
+//!
+//! Every record sits in the `primary` or `secondary` slot and both are
+//! accepted, so a tenant can hold its current and its next key at once:
+//!
+//! 1. `POST /admin/keys` adds the new key (secondary unless `slot` says
+//!    otherwise);
+//! 2. `POST /admin/keys/promote` makes it primary and moves the tenant's
+//!    previous primary (same `name`) to secondary;
+//! 3. `POST /admin/keys/retire` deletes the old key once clients moved on.
+//!
+//! Keys are referenced as `{"key": "<raw key>"}` or `{"key_hash": "<sha256
+//! hex>"}`; raw keys are never stored. `GET /admin/keys` lists the records.
+//! Env-configured keys rotate the same way via `API_KEYS` and
+//! `API_KEYS_SECONDARY`.
+
+use serde::Deserialize;
+use serde_json::json;
+use worker::*;
+
+use crate::auth::{self, KeyRecord, Slot};
+use crate::responses;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct KeyCommand {
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub key_hash: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub slot: Option<Slot>,
+}
+
+impl KeyCommand {
+    /// SHA-256 hex of the referenced key, if one was given in a valid form.
+    pub fn digest(&self) -> Option<String> {
+        match (&self.key, &self.key_hash) {
+            (Some(key), _) if !key.trim().is_empty() => Some(auth::sha256_hex(key.trim())),
+            (None, Some(hash))
+                if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                Some(hash.to_ascii_lowercase())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Digests to move to the secondary slot when `promoted` becomes primary:
+/// the other primaries of the same tenant. Unnamed keys stand alone.
+pub fn demotions(records: &[(String, KeyRecord)], promoted: &str) -> Vec<String> {
+    let Some((_, target)) = records.iter().find(|(digest, _)| digest == promoted) else {
+        return Vec::new();
+    };
+    let Some(name) = &target.name else {
+        return Vec::new();
+    };
+    records
+        .iter()
+        .filter(|(digest, record)| {
+            digest != promoted && record.slot == Slot::Primary && record.name.as_ref() == Some(name)
+        })
+        .map(|(digest, _)| digest.clone())
+        .collect()
+}
+
+fn kv(env: &Env) -> Option<kv::KvStore> {
+    env.kv(auth::KEYS_KV).ok()
+}
+
+fn kv_disabled() -> Result<Response> {
+    responses::error(503, "keys_kv_disabled", "API_KEYS_KV is not bound")
+}
+
+pub async fn records(kv: &kv::KvStore) -> Result<Vec<(String, KeyRecord)>> {
+    let mut records = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(auth::KV_PREFIX.to_string());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(record) = kv.get(&key.name).json::<KeyRecord>().await? {
+                let digest = key.name.trim_start_matches(auth::KV_PREFIX).to_string();
+                records.push((digest, record));
+            }
+        }
+        match page.cursor {
+            Some(next) if !page.list_complete => cursor = Some(next),
+            _ => return Ok(records),
+        }
+    }
+}
+
+pub async fn store(kv: &kv::KvStore, digest: &str, record: &KeyRecord) -> Result<()> {
+    kv.put(&format!("{}{digest}", auth::KV_PREFIX), record)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// `GET /admin/keys`
+pub async fn admin_list(env: &Env) -> Result<Response> {
+    let Some(kv) = kv(env) else {
+        return kv_disabled();
+    };
+    let keys: Vec<_> = records(&kv)
+        .await?
+        .into_iter()
+        .map(|(digest, record)| {
+            json!({ "key_hash": digest, "name": record.name, "slot": record.slot })
+        })
+        .collect();
+    responses::json(200, &json!({ "keys": keys }))
+}
+
+/// `POST /admin/keys`, `/admin/keys/promote` and `/admin/keys/retire`.
+pub async fn admin_update(mut req: Request, env: &Env, action: &str) -> Result<Response> {
+    let Some(kv) = kv(env) else {
+        return kv_disabled();
+    };
+    let command: KeyCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => return responses::error(400, "invalid_request", "Expected a JSON body"),
+    };
+    let Some(digest) = command.digest() else {
+        return responses::error(
+            400,
+            "invalid_request",
+            "Provide `key` or a SHA-256 hex `key_hash`",
+        );
+    };
+    let name = format!("{}{digest}", auth::KV_PREFIX);
+    let existing = kv.get(&name).json::<KeyRecord>().await?;
+
+    match (action, existing) {
+        ("add", Some(_)) => responses::error(409, "key_exists", "The key is already registered"),
+        ("add", None) => {
+            let record = KeyRecord {
+                name: command.name,
+                slot: command.slot.unwrap_or(Slot::Secondary),
+            };
+            store(&kv, &digest, &record).await?;
+            console_log!("Added {:?} API key {}", record.slot, digest);
+            responses::json(
+                201,
+                &json!({ "key_hash": digest, "name": record.name, "slot": record.slot }),
+            )
+        }
+        (_, None) => responses::error(404, "key_not_found", "No such key"),
+        ("promote", Some(mut record)) => {
+            let all = records(&kv).await?;
+            let demoted = demotions(&all, &digest);
+            for other in &demoted {
+                if let Some((_, other_record)) = all.iter().find(|(d, _)| d == other) {
+                    let other_record = KeyRecord {
+                        slot: Slot::Secondary,
+                        ..other_record.clone()
+                    };
+                    store(&kv, other, &other_record).await?;
+                }
+            }
+            record.slot = Slot::Primary;
+            store(&kv, &digest, &record).await?;
+            console_log!("Promoted API key {} (demoted {:?})", digest, demoted);
+            responses::json(
+                200,
+                &json!({ "key_hash": digest, "slot": record.slot, "demoted": demoted }),
+            )
+        }
+        ("retire", Some(_)) => {
+            kv.delete(&name).await?;
+            console_log!("Retired API key {}", digest);
+            responses::json(200, &json!({ "key_hash": digest, "retired": true }))
+        }
+        _ => responses::error(404, "not_found", "Unknown admin endpoint"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: Option<&str>, slot: Slot) -> KeyRecord {
+        KeyRecord {
+            name: name.map(str::to_string),
+            slot,
+        }
+    }
+
+    #[test]
+    fn test_digest_forms() {
+        let by_key = KeyCommand {
+            key: Some("secret".into()),
+            ..Default::default()
+        };
+        assert_eq!(by_key.digest(), Some(auth::sha256_hex("secret")));
+        let by_hash = KeyCommand {
+            key_hash: Some(auth::sha256_hex("secret").to_uppercase()),
+            ..Default::default()
+        };
+        assert_eq!(by_hash.digest(), by_key.digest());
+        let bad = KeyCommand {
+            key_hash: Some("abc".into()),
+            ..Default::default()
+        };
+        assert_eq!(bad.digest(), None);
+    }
+
+    #[test]
+    fn test_demotions_same_tenant_only() {
+        let records = vec![
+            ("new".to_string(), record(Some("team-a"), Slot::Secondary)),
+            ("old".to_string(), record(Some("team-a"), Slot::Primary)),
+            ("older".to_string(), record(Some("team-a"), Slot::Secondary)),
+            ("other".to_string(), record(Some("team-b"), Slot::Primary)),
+            ("anon".to_string(), record(None, Slot::Primary)),
+        ];
+        assert_eq!(demotions(&records, "new"), vec!["old"]);
+        assert!(demotions(&records, "anon").is_empty());
+        assert!(demotions(&records, "missing").is_empty());
+    }
+
+    #[test]
+    fn test_record_defaults_to_primary() {
+        let record: KeyRecord = serde_json::from_str(r#"{"name": "team-a"}"#).unwrap();
+        assert_eq!(record.slot, Slot::Primary);
+    }
+}
@@ -0,0 +1,145 @@
+//! Language fallback for localized origins (`?languages=de,fr,en`).
+//!
+//! The first language is sent as `Accept-Language`. If the origin answers a
+//! `GET`/`HEAD` with 404, the next languages are tried in order (at most
+//! four more): each retry sends that language as `Accept-Language` and, when
+//! the target URL carries the first language as a path segment (`/de/docs`,
+//! `/de-DE/docs`) or a `lang`, `locale` or `hl` query parameter, rewrites it
+//! too. The first response that isn't a 404 is returned, and
+//! `X-Proxy-Language` names the language that was served.
+
+use worker::*;
+
+use crate::{config, deadline};
+
+pub const PARAM: &str = "languages";
+pub const HEADER: &str = "X-Proxy-Language";
+const MAX_FALLBACKS: usize = 4;
+const QUERY_KEYS: &[&str] = &["lang", "locale", "hl"];
+
+/// A BCP 47-ish tag: a 2-3 letter language and alphanumeric subtags.
+pub fn is_valid_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let primary = parts.next().unwrap_or_default();
+    (2..=3).contains(&primary.len())
+        && primary.bytes().all(|b| b.is_ascii_alphabetic())
+        && parts.all(|p| (1..=8).contains(&p.len()) && p.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+/// Preferred languages from the worker URL, best first.
+pub fn requested(url: &Url) -> std::result::Result<Vec<String>, String> {
+    let Some((_, value)) = url.query_pairs().find(|(k, _)| k == PARAM) else {
+        return Ok(Vec::new());
+    };
+    let languages = config::parse_list(&value);
+    match languages.iter().find(|l| !is_valid_tag(l)) {
+        Some(bad) => Err(format!("Invalid language tag {bad:?}")),
+        None => Ok(languages),
+    }
+}
+
+/// `target` with its locale marker switched from `from` to `to`, or `target`
+/// unchanged when it has none.
+pub fn localized(target: &Url, from: &str, to: &str) -> Url {
+    let mut url = target.clone();
+    let segments: Vec<String> = target
+        .path()
+        .split('/')
+        .map(|s| match s.eq_ignore_ascii_case(from) {
+            true => to.to_string(),
+            false => s.to_string(),
+        })
+        .collect();
+    url.set_path(&segments.join("/"));
+    if target
+        .query_pairs()
+        .any(|(k, v)| QUERY_KEYS.contains(&k.as_ref()) && v.eq_ignore_ascii_case(from))
+    {
+        let pairs: Vec<(String, String)> = target
+            .query_pairs()
+            .map(
+                |(k, v)| match QUERY_KEYS.contains(&k.as_ref()) && v.eq_ignore_ascii_case(from) {
+                    true => (k.into_owned(), to.to_string()),
+                    false => (k.into_owned(), v.into_owned()),
+                },
+            )
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url
+}
+
+/// Retry `languages[1..]` after a 404 for `languages[0]`. Returns the first
+/// non-404 response and its language, or `None` if every variant 404s.
+pub async fn fall_back(
+    target: &Url,
+    headers: &Headers,
+    method: &Method,
+    languages: &[String],
+    remaining_ms: impl Fn() -> Option<u64>,
+) -> Result<Option<(Response, String)>> {
+    let Some(preferred) = languages.first() else {
+        return Ok(None);
+    };
+    for language in languages.iter().skip(1).take(MAX_FALLBACKS) {
+        if remaining_ms() == Some(0) {
+            break;
+        }
+        let headers = headers.clone();
+        headers.set("Accept-Language", language)?;
+        let mut init = RequestInit::new();
+        init.with_method(method.clone()).with_headers(headers);
+        let url = localized(target, preferred, language);
+        let request = Request::new_with_init(url.as_str(), &init)?;
+        match deadline::fetch_within(request, remaining_ms()).await? {
+            Some(response) if response.status_code() != 404 => {
+                console_log!(
+                    "Serving {} in {} instead of {}",
+                    target,
+                    language,
+                    preferred
+                );
+                return Ok(Some((response, language.clone())));
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            requested(&url("https://p.example/?languages=de-DE,%20fr,en")),
+            Ok(vec!["de-DE".to_string(), "fr".into(), "en".into()])
+        );
+        assert_eq!(requested(&url("https://p.example/")), Ok(Vec::new()));
+        assert!(requested(&url("https://p.example/?languages=de,not_a_tag")).is_err());
+    }
+
+    #[test]
+    fn test_is_valid_tag() {
+        assert!(is_valid_tag("en"));
+        assert!(is_valid_tag("zh-Hant-TW"));
+        assert!(!is_valid_tag("e"));
+        assert!(!is_valid_tag("en-"));
+        assert!(!is_valid_tag("en;q=0.5"));
+    }
+
+    #[test]
+    fn test_localized_path_and_query() {
+        let url = Url::parse("https://docs.example/de-DE/guide?lang=de-de&page=2").unwrap();
+        assert_eq!(
+            localized(&url, "de-DE", "fr").as_str(),
+            "https://docs.example/fr/guide?lang=fr&page=2"
+        );
+        let plain = Url::parse("https://docs.example/guide").unwrap();
+        assert_eq!(localized(&plain, "de", "fr"), plain);
+    }
+}
@@ -1,34 +1,86 @@
-use url::Url;
 use worker::*;
 
+mod access;
+mod admin;
+mod auth;
+mod basic_auth;
+mod bot;
+mod bundle;
+mod cache;
+mod certs;
+mod cf_options;
+mod coalesce;
+mod compliance;
+mod concurrency;
+mod conditional;
+mod config;
+mod content_types;
+mod context;
+mod control;
+mod cors;
+mod dates;
+mod deadline;
+mod deprecation;
+mod diagnostics;
+mod egress;
+mod envelope;
+mod fallback;
+mod favicon;
+mod forwarded;
+mod freshness;
+mod gc;
+mod geo;
+mod header_rules;
+mod health;
+mod helmet;
+mod host_override;
+mod idempotency;
+mod jsonp;
+mod jwt;
+mod key_quota;
+mod keys;
+mod language;
+mod linkcheck;
+mod loops;
+mod metadata;
+mod monitor;
+mod origins;
+mod qr;
+mod quota;
+mod ranged;
+mod ratelimit;
+mod report;
+mod responses;
+mod root;
+mod schema;
+mod session;
+mod signing;
+mod slo;
+mod spend;
+mod streams;
+mod target;
+mod timing;
+mod turnstile;
+mod uploads;
+mod usage;
+mod user_agent;
 mod utils;
+mod via;
+mod watermark;
+mod xff;
 
 /// Params to filter from the proxied URL (cache-busters and routing param).
 const FILTERED_PARAMS: &[&str] = &["url", "_cb", "_t"];
 
-fn generate_random_ip() -> String {
-    let now = Date::now().as_millis();
-    let mut seed = now;
-
-    // Simple Linear Congruential Generator (LCG)
-    // Using constants from MMIX by Donald Knuth
-    let mut next_rand = || {
-        seed = seed
-            .wrapping_mul(6364136223846793005)
-            .wrapping_add(1442695040888963407);
-        // Extract 8 bits from the high-order bits
-        ((seed >> 32) & 0xFF) as u8
-    };
-
-    // Ensure first octet is not 0 (though 0 is technically valid IP, often reserved)
-    // Python implementation uses 1-255.
-    let o1 = match next_rand() {
-        0 => 1,
-        x => x,
-    };
-
-    format!("{}.{}.{}.{}", o1, next_rand(), next_rand(), next_rand())
-}
+/// Worker-level switches that are consumed by the proxy and never appended
+/// to the target URL (the target's own params are left untouched).
+const CONTROL_PARAMS: &[&str] = &[
+    envelope::PARAM,
+    language::PARAM,
+    metadata::PARAM,
+    ranged::PARAM,
+    turnstile::TOKEN_PARAM,
+];
 
 fn log_request(req: &Request) {
     let (coords, region, country) = if let Some(cf) = req.cf() {
@@ -46,46 +98,184 @@ fn log_request(req: &Request) {
     };
 
     console_log!(
-        "{} - [{:?}], located at: {:?}, within: {}",
+        "{} - [{:?}], located at: {:?}, within: {} (build {})",
         req.path(),
         coords,
         region,
-        country
+        country,
+        diagnostics::build_id()
     );
 }
 
+#[event(scheduled)]
+pub async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    utils::set_panic_hook();
+    let scheduled_ms = event.schedule() as u64;
+    monitor::run(&env, scheduled_ms).await;
+    usage::run(&env, scheduled_ms).await;
+    gc::run(&env, scheduled_ms).await;
+    certs::run(&env, scheduled_ms).await;
+}
+
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
-    match do_main(req, env).await {
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
+    match do_main(req, env, ctx).await {
         Ok(resp) => Ok(resp),
         Err(e) => {
-            console_log!("CRITICAL ERROR: {:?}", e);
-            Response::error(format!("Debug Error: {:?}", e), 500)
+            let build = diagnostics::build_id();
+            console_log!("CRITICAL ERROR (build {}): {:?}", build, e);
+            Response::error(format!("Debug Error (build {build}): {:?}", e), 500)
         }
     }
 }
 
-pub async fn do_main(req: Request, _env: Env) -> Result<Response> {
+pub async fn do_main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     log_request(&req);
     utils::set_panic_hook();
 
-    let method = req.method();
+    let mut rctx = context::RequestCtx::new(&req, &env)?;
+    let response = proxy(req, env.clone(), &ctx, &mut rctx).await;
+    // Releases left by early returns (leases, idempotency claims).
+    rctx.deferred.run(&ctx);
+    let response = response?;
+
+    // 7. Constant-time responses for sensitive routes
+    timing::apply(&env, &rctx, response).await
+}
+
+async fn proxy(
+    mut req: Request,
+    env: Env,
+    ctx: &worker::Context,
+    rctx: &mut context::RequestCtx,
+) -> Result<Response> {
+    let method = rctx.method.clone();
+
+    // 0.0 Operator endpoints, ahead of every check that spends a budget
+    if admin::is_operator_request(&method, &req.path()) {
+        if req.path() == health::PATH {
+            return health::handle(&env);
+        }
+        if req.path() == diagnostics::PATH {
+            return diagnostics::handle();
+        }
+        return admin::handle(req, &env).await;
+    }
 
-    // 0. Handle CORS preflight
+    // 0. Refuse requests looping through proxy deployments, then disallowed
+    // browser origins and countries, before anything else
+    if let Some(looped) = loops::check(&req, &env)? {
+        return Ok(looped);
+    }
+    if let Some(denied) = origins::check(&req, &env)? {
+        return Ok(denied);
+    }
+    if let Some(denied) = geo::check(&env, rctx)? {
+        return Ok(denied);
+    }
+
+    // 0.1 Handle CORS preflight
     if method == Method::Options {
-        let headers = Headers::new();
-        headers.set("Access-Control-Allow-Origin", "*")?;
-        headers.set(
-            "Access-Control-Allow-Methods",
-            "GET, POST, PUT, DELETE, OPTIONS, PATCH, HEAD",
-        )?;
-        headers.set("Access-Control-Allow-Headers", "*")?;
+        if let Some(preflight) = cors::preflight(&req, &env).await? {
+            return Ok(preflight);
+        }
+    }
+
+    // 0.1.1 Per-IP rate limit
+    if let Some(limited) = ratelimit::check(&env, rctx).await? {
+        return Ok(limited);
+    }
+
+    // 0.2 Worker endpoints
+    if method == Method::Post && req.path() == report::PATH {
+        return report::handle(req, &env).await;
+    }
+    if root::matches(&req, &rctx.url)? {
+        if let Some(front_door) = root::handle(&req, &env)? {
+            return Ok(front_door);
+        }
+    }
+
+    // 0.3 Authentication (a valid signed link stands in for an API key)
+    rctx.signed = match signing::check(&rctx.url, &env)? {
+        signing::SignatureCheck::Absent => false,
+        signing::SignatureCheck::Valid => true,
+        signing::SignatureCheck::Rejected(response) => return Ok(response),
+    };
+    let basic_gate = !rctx.signed && basic_auth::is_enabled(&env);
+    if basic_gate {
+        if let Some(denied) = basic_auth::authorize(&req, &env)? {
+            return Ok(denied);
+        }
+    }
+    if !rctx.signed {
+        match auth::authenticate(&req, &env).await? {
+            auth::AuthOutcome::Disabled => {}
+            auth::AuthOutcome::Authenticated(key, source) => {
+                console_log!("[{}] Authenticated caller {}", rctx.id, key.id);
+                rctx.key_source = Some(source);
+                rctx.caller = Some(key.id);
+            }
+            auth::AuthOutcome::Rejected(response) => return Ok(response),
+        }
+    }
 
-        return Ok(Response::empty()?.with_status(204).with_headers(headers));
+    // 0.3.1 Bot score and Turnstile gates for anonymous callers
+    if rctx.is_anonymous() {
+        if let Some(denied) = bot::check(&req, &env, rctx).await? {
+            return Ok(denied);
+        }
+        match turnstile::check(&req, &env, rctx).await? {
+            turnstile::TurnstileOutcome::Passed => {}
+            turnstile::TurnstileOutcome::Verified(cookie) => rctx.set_cookies.push(cookie),
+            turnstile::TurnstileOutcome::Challenge(response) => return Ok(response),
+        }
     }
+    rctx.mark("auth");
 
-    // 1. Parse the target URL
-    let url = req.url()?;
+    // 0.4 Utility endpoints (available to authenticated callers only)
+    if req.path() == linkcheck::PATH {
+        if let Some(denied) = key_quota::check(&env, ctx, rctx).await? {
+            return Ok(denied);
+        }
+        return linkcheck::handle(req, &env, rctx.tenant()).await;
+    }
+    if req.path() == favicon::PATH {
+        return favicon::handle(req, &env).await;
+    }
+    if req.path() == qr::PATH {
+        return qr::handle(req, &env, rctx).await;
+    }
+    if session::matches(&req.path()) {
+        return session::handle(req, &env, rctx).await;
+    }
+
+    // 1. Parse the target URL (and the per-request switches)
+    if let Err(message) = rctx.parse_flags(&req) {
+        return responses::error(400, "invalid_request", &message);
+    }
+    let jsonp_enabled = jsonp::is_enabled(&env);
+    if jsonp_enabled {
+        match jsonp::requested(&rctx.url) {
+            Ok(callback) => rctx.flags.jsonp = callback,
+            Err(message) => return responses::error(400, "invalid_request", &message),
+        }
+        if rctx.flags.jsonp.is_some() && rctx.flags.envelope {
+            return responses::error(
+                400,
+                "invalid_request",
+                "JSONP callbacks cannot be combined with envelopes",
+            );
+        }
+    }
+    let consumed = |k: &str| {
+        FILTERED_PARAMS.contains(&k)
+            || CONTROL_PARAMS.contains(&k)
+            || (jsonp_enabled && k == jsonp::PARAM)
+            || (rctx.signed && matches!(k, signing::EXP_PARAM | signing::SIG_PARAM))
+            || (rctx.flags.dates.is_some() && matches!(k, dates::TZ_PARAM | dates::FORMAT_PARAM))
+    };
+    let url = &rctx.url;
     let query_pairs = url.query_pairs();
     let mut target_url_str: Option<String> = None;
 
@@ -104,12 +294,9 @@ pub async fn do_main(req: Request, _env: Env) -> Result<Response> {
         }
     }
 
-    // 1.3 Path (e.g. /https://example.com)
-    if target_url_str.is_none() && url.path() != "/" {
-        let path = url.path().trim_start_matches('/');
-        if path.starts_with("http") {
-            target_url_str = Some(path.to_string());
-        }
+    // 1.3 Path (e.g. /https://example.com, /https://[2001:db8::1]:8443/)
+    if target_url_str.is_none() {
+        target_url_str = target::from_path(url.path()).map(str::to_string);
     }
 
     let target_url_val = match target_url_str {
@@ -121,16 +308,35 @@ pub async fn do_main(req: Request, _env: Env) -> Result<Response> {
     };
 
     // Validate URL
-    let mut target_url = match Url::parse(&target_url_val) {
-        Ok(u) => u,
-        Err(_) => return Response::error("Invalid target URL", 400),
+    let target_url = match target::parse(&target_url_val) {
+        Some(u) => u,
+        None => return Response::error("Invalid target URL", 400),
+    };
+
+    // 1.3.1 Unwrap targets that point back through a proxy deployment
+    let proxy_host = forwarded::worker_host(url);
+    let sisters = config::var_list(&env, "SISTER_DEPLOYMENTS");
+    let is_proxy = |u: &Url| {
+        let host = u.host_str().unwrap_or_default();
+        forwarded::worker_host(u).eq_ignore_ascii_case(&proxy_host)
+            || sisters.iter().any(|p| utils::host_matches(p, host))
+    };
+    let keep = |k: &str| !consumed(k);
+    let Some(mut target_url) = target::unwrap(target_url, is_proxy, keep) else {
+        return responses::error(
+            508,
+            "proxy_loop",
+            "The target URL points back through the proxy",
+        );
     };
 
     // Filter out cache-buster and routing query params
     // Collect extra params from the worker URL that aren't filtered
+    // (none for signed links: the signature covers the target as-is)
     let extra_params: Vec<(String, String)> = url
         .query_pairs()
-        .filter(|(k, _)| !FILTERED_PARAMS.contains(&k.as_ref()))
+        .filter(|_| !rctx.signed)
+        .filter(|(k, _)| !consumed(k))
         .map(|(k, v)| (k.into_owned(), v.into_owned()))
         .collect();
 
@@ -155,74 +361,619 @@ pub async fn do_main(req: Request, _env: Env) -> Result<Response> {
         }
     }
 
+    rctx.target = Some(target_url.clone());
+
+    // 1.4 Legal/compliance blocklist
+    if let Some(rule) = compliance::find_rule(&env, &target_url).await {
+        return compliance::blocked_response(&rule, &target_url);
+    }
+
+    // 1.4.1 Request body size limit (a body without Content-Length is
+    // counted while it streams, in step 3)
+    let max_request_bytes = uploads::limit(&env, &method);
+    if let Some(limit) = max_request_bytes {
+        if uploads::declared_over(req.headers().get("Content-Length")?.as_deref(), limit) {
+            return uploads::too_large(limit);
+        }
+    }
+
+    // 1.5 Per-key request quotas, spend caps and egress byte budgets
+    if let Some(denied) = key_quota::check(&env, ctx, rctx).await? {
+        return Ok(denied);
+    }
+    if let Some(denied) = spend::charge(&env, rctx.tenant(), &target_url).await? {
+        return Ok(denied);
+    }
+    let egress_budget = match egress::check(&env, rctx).await? {
+        egress::EgressCheck::Exceeded(denied) => return Ok(denied),
+        egress::EgressCheck::Metered(budget) => Some(budget),
+        egress::EgressCheck::Unmetered => None,
+    };
+    let mut sample = schema::sample(&env, &method, &target_url);
+
     // 2. Prepare headers
+    if let Some(resp) = header_rules::check_size(&req, &env)? {
+        return Ok(resp);
+    }
+    if let Some(name) = header_rules::first_invalid(req.headers()) {
+        return responses::error(
+            400,
+            "invalid_header",
+            &format!("Header {name:?} has an invalid name or value"),
+        );
+    }
+    let key_source = rctx.key_source;
     let headers = Headers::new();
-    let mut has_forwarded_for = false;
+    let mut explicit_forwarded_for = None;
+    let forwarded_chain = forwarded::is_enabled(&env);
+    let x_forwarded = forwarded::x_forwarded_enabled(&env);
+    let via_pseudonym = via::is_enabled(&env).then(|| via::pseudonym(&env));
+    let strip_request = header_rules::request_strip_list(&env);
+    let hop_by_hop = header_rules::hop_by_hop(req.headers().get("Connection")?.as_deref());
     for (key, value) in req.headers() {
         let key_lower = key.to_lowercase();
         match key_lower.as_str() {
-            "host" | "cf-connecting-ip" | "cf-ipcountry" | "cf-ray" | "cf-visitor" => continue,
-            "x-my-x-forwarded-for" => {
-                headers.set("X-Forwarded-For", &value)?;
-                has_forwarded_for = true;
+            "host" => continue,
+            name if control::is_control_header(name) => continue,
+            name if header_rules::listed(name, &hop_by_hop) => continue,
+            name if header_rules::is_platform_header(name) => continue,
+            "x-turnstile-token" => continue,
+            name if header_rules::listed(name, &strip_request) => continue,
+            // Rebuilt below with this hop appended.
+            "forwarded" if forwarded_chain => continue,
+            "x-forwarded-host" | "x-forwarded-proto" if x_forwarded => continue,
+            "via" if via_pseudonym.is_some() => continue,
+            "x-proxied-by" => continue,
+            // Replaced below with the remaining budget.
+            "x-deadline" | "grpc-timeout" => continue,
+            // Proxy credentials are never forwarded upstream.
+            "x-api-key" if key_source.is_some() => continue,
+            "authorization" if key_source == Some(auth::KeySource::Authorization) => continue,
+            "authorization" if basic_gate && basic_auth::decode(&value).is_some() => continue,
+            "cf-access-jwt-assertion" if key_source == Some(auth::KeySource::AccessAssertion) => {
+                continue
+            }
+            // Set below according to XFF_MODE.
+            "x-forwarded-for" => continue,
+            "x-my-x-forwarded-for" => explicit_forwarded_for = Some(value),
+            // The proxy's Turnstile pass is not the upstream's business.
+            "cookie" => {
+                if let Some(cookies) = turnstile::without_pass_cookie(&value) {
+                    headers.set(&key, &cookies)?;
+                }
             }
             _ => {
                 headers.set(&key, &value)?;
             }
         }
     }
-    if !has_forwarded_for {
-        headers.set("X-Forwarded-For", &generate_random_ip())?;
+    let forwarded_for = xff::for_request(
+        &env,
+        explicit_forwarded_for.as_deref(),
+        req.headers().get("X-Forwarded-For")?.as_deref(),
+        rctx.client_ip.as_deref(),
+    );
+    if let Some(forwarded_for) = &forwarded_for {
+        headers.set("X-Forwarded-For", forwarded_for)?;
+    }
+    if forwarded_chain {
+        let client = forwarded_for
+            .as_deref()
+            .and_then(|chain| chain.rsplit(',').next())
+            .map(str::trim)
+            .unwrap_or("unknown");
+        let host = forwarded::worker_host(&rctx.url);
+        let element = forwarded::element(client, rctx.url.scheme(), &host);
+        let incoming = req.headers().get("Forwarded")?;
+        headers.set(
+            "Forwarded",
+            &forwarded::append(incoming.as_deref(), &element),
+        )?;
+    }
+    if x_forwarded {
+        headers.set("X-Forwarded-Host", &forwarded::worker_host(&rctx.url))?;
+        headers.set("X-Forwarded-Proto", rctx.url.scheme())?;
+    }
+    if let Some(pseudonym) = &via_pseudonym {
+        let incoming = req.headers().get("Via")?;
+        let protocol = via::client_protocol(&req);
+        headers.set(
+            "Via",
+            &via::append(incoming.as_deref(), &protocol, pseudonym),
+        )?;
+    }
+    if let Some(preferred) = rctx.flags.languages.first() {
+        headers.set("Accept-Language", preferred)?;
+    }
+    if rctx.flags.envelope {
+        if let Some(accept) = headers.get("Accept")? {
+            match envelope::upstream_accept(&accept) {
+                Some(accept) => headers.set("Accept", &accept)?,
+                None => headers.delete("Accept")?,
+            }
+        }
+    }
+    if let Some(encoding) = header_rules::upstream_accept_encoding(&env) {
+        headers.set("Accept-Encoding", &encoding)?;
+    }
+    if let Some(user_agent) = user_agent::for_request(&env) {
+        headers.set("User-Agent", &user_agent)?;
+    }
+    if let Some(proxied_by) = loops::outgoing(&req, &env)? {
+        headers.set(loops::HEADER, &proxied_by)?;
+    }
+    header_rules::apply(&env, &headers, &target_url).await?;
+    if let (Some(deadline_ms), Some(remaining)) = (rctx.deadline_ms, rctx.remaining_ms()) {
+        if remaining == 0 {
+            return deadline::exceeded();
+        }
+        deadline::forward(&headers, deadline_ms, remaining)?;
     }
 
     // 3. Request Body & Init
+    let target_host = rctx.target_host().to_string();
+    let use_fallback = fallback::is_marked(&target_host);
     let mut init = RequestInit::new();
     init.with_method(method.clone());
-    init.with_headers(headers);
+    init.with_headers(if use_fallback {
+        fallback::simplify(&headers)
+    } else {
+        headers.clone()
+    });
 
+    let mut has_body = false;
+    let mut upload_overflow = None;
+    let mut buffered_body = None;
     if method != Method::Get && method != Method::Head {
         // req.inner() returns &web_sys::Request.
         // req.inner().body() returns Option<ReadableStream>.
         // ReadableStream implements Into<JsValue>.
-        if let Some(body_stream) = req.inner().body() {
+        let header = |name: &str| req.headers().get(name).ok().flatten();
+        let sample_body = sample.is_some()
+            && schema::body_sampleable(
+                header("Content-Type").as_deref(),
+                header("Content-Length").and_then(|v| v.parse().ok()),
+                schema::max_bytes(&env),
+            );
+        let hash_body = idempotency::wants_body(&env, &req, &method);
+        if sample_body || hash_body {
+            // Small body: buffer it so it can be sampled or fingerprinted and sent.
+            let body = req.bytes().await?;
+            if let Some(sample) = sample.as_mut().filter(|_| sample_body) {
+                sample.request = serde_json::from_slice(&body).ok();
+            }
+            init.with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+            has_body = true;
+            if hash_body {
+                buffered_body = Some(body);
+            }
+        } else if let Some(limit) = max_request_bytes.filter(|_| header("Content-Length").is_none())
+        {
+            let (body_stream, overflow) = uploads::counted(&mut req, limit)?;
+            has_body = body_stream.is_some();
+            init.with_body(body_stream);
+            upload_overflow = Some((overflow, limit));
+        } else if let Some(body_stream) = req.inner().body() {
             init.with_body(Some(body_stream.into()));
+            has_body = true;
+        }
+    }
+
+    // 3.1 Idempotency-Key: answer retries of a finished request from the store
+    let claim =
+        match idempotency::begin(&env, rctx, &req, &target_url, buffered_body.as_deref()).await? {
+            idempotency::Idempotency::Done(response) => return Ok(response),
+            idempotency::Idempotency::Claimed(claim) => Some(claim),
+            idempotency::Idempotency::NotRequested => None,
+        };
+
+    // 3.2 Answer repeated GETs from the edge cache
+    let cache_plan = cache::plan(&env, rctx, req.headers(), &headers, &target_url)?;
+    let hit = match &cache_plan {
+        Some(plan) if !plan.bypass => cache::lookup(&env, plan).await?,
+        _ => None,
+    };
+
+    // 3.2.1 Wait for an identical miss already in flight instead of fetching
+    let mut leader = None;
+    let coalesce_wait_ms = coalesce::wait_ms(&env, rctx.remaining_ms());
+    let hit = match (&cache_plan, hit) {
+        (Some(plan), None) if !plan.bypass && coalesce_wait_ms > 0 => {
+            match coalesce::join(&plan.key) {
+                coalesce::Joined::Leader(flight) => {
+                    leader = Some(flight);
+                    None
+                }
+                coalesce::Joined::Follower(landing) => {
+                    if coalesce::wait(landing, coalesce_wait_ms).await {
+                        cache::lookup(&env, plan).await?
+                    } else {
+                        None
+                    }
+                }
+            }
+        }
+        (_, hit) => hit,
+    };
+    let mut cache_status = match &cache_plan {
+        Some(plan) if !plan.bypass => Some(cache::Status::Miss),
+        Some(_) => Some(cache::Status::Bypass),
+        None => cache::is_enabled(&env).then_some(cache::Status::Bypass),
+    };
+
+    // 3.3 Entries past the stale window go upstream with their validators
+    let mut revalidating = None;
+    let hit = match hit {
+        Some(hit) if hit.expired => {
+            if cache::condition(&init.headers, hit.response.headers())? {
+                revalidating = Some(hit.response);
+            }
+            None
+        }
+        hit => hit,
+    };
+    let mut stored_at_ms = hit.as_ref().map(|hit| hit.stored_at_ms);
+
+    // 4. Fetch, holding a slot on hosts with a concurrency limit
+    let permit = match hit {
+        Some(_) => None,
+        None => match concurrency::admit(&env, rctx).await? {
+            concurrency::Admission::Busy(busy) => return Ok(busy),
+            concurrency::Admission::Admitted(permit) => Some(permit),
+            concurrency::Admission::Unlimited => None,
+        },
+    };
+    let fetch_url = host_override::apply(&env, &target_url, &rctx.url, &mut init);
+    cf_options::apply(&env, &rctx.flags.controls.cf, &mut init);
+    control::apply(&rctx.flags.controls, &mut init);
+    let mut response = match hit {
+        Some(mut hit) => {
+            if let (true, Some(plan)) = (hit.stale, &cache_plan) {
+                cache::refresh(
+                    ctx,
+                    &env,
+                    plan,
+                    Request::new_with_init(fetch_url.as_str(), &init)?,
+                    &mut hit.response,
+                )?;
+            }
+            cache_status = Some(if hit.stale {
+                cache::Status::Stale
+            } else {
+                cache::Status::Hit
+            });
+            usage::record(&env, ctx, rctx.tenant(), &target_host, true);
+            rctx.mark("cache");
+            conditional::answer(req.headers(), hit.response)?
+        }
+        None => {
+            let record_failure = || {
+                slo::record(&env, ctx, &target_host, false);
+                usage::record(&env, ctx, rctx.tenant(), &target_host, false);
+            };
+            let mut cache_failure = || match &cache_plan {
+                Some(plan) => cache::store_failure(ctx, &env, plan, leader.take()),
+                None => Ok(None),
+            };
+            let fetch_request = Request::new_with_init(fetch_url.as_str(), &init)?;
+            let mut response =
+                match deadline::fetch_within(fetch_request, rctx.remaining_ms()).await {
+                    Ok(Some(resp)) => resp,
+                    Ok(None) => {
+                        record_failure();
+                        return deadline::exceeded();
+                    }
+                    // Retry once with the simplified profile; a streamed body can't be replayed.
+                    Err(e)
+                        if !use_fallback
+                            && !has_body
+                            && !rctx.deadline_passed()
+                            && fallback::is_protocol_error(&e.to_string()) =>
+                    {
+                        console_log!("Protocol fallback for {}: {:?}", target_host, e);
+                        fallback::mark(&target_host);
+                        init.with_headers(fallback::simplify(&headers));
+                        let retry_request = Request::new_with_init(fetch_url.as_str(), &init)?;
+                        match deadline::fetch_within(retry_request, rctx.remaining_ms()).await {
+                            Ok(Some(resp)) => resp,
+                            Ok(None) => {
+                                record_failure();
+                                return deadline::exceeded();
+                            }
+                            Err(e) => {
+                                record_failure();
+                                return cache_failure()?.ok_or(e);
+                            }
+                        }
+                    }
+                    Err(_) if upload_overflow.as_ref().is_some_and(|(o, _)| o.happened()) => {
+                        let limit = upload_overflow.map_or(0, |(_, limit)| limit);
+                        return uploads::too_large(limit);
+                    }
+                    Err(e) => {
+                        record_failure();
+                        return cache_failure()?.ok_or(e);
+                    }
+                };
+            // 4.0 Try the other requested languages after a 404
+            if let Some(preferred) = rctx.flags.languages.first().cloned() {
+                let mut served = Some(preferred);
+                if response.status_code() == 404
+                    && !has_body
+                    && matches!(method, Method::Get | Method::Head)
+                {
+                    let languages = rctx.flags.languages.clone();
+                    served = None;
+                    if let Some((fallback, language)) =
+                        language::fall_back(&target_url, &headers, &method, &languages, || {
+                            rctx.remaining_ms()
+                        })
+                        .await?
+                    {
+                        response = fallback;
+                        served = Some(language);
+                    }
+                }
+                if let Some(language) = served {
+                    rctx.extra_headers.push((language::HEADER, language));
+                }
+            }
+            // 4.0.1 A 304 for the stored validators renews the cached entry
+            if let (Some(plan), Some(stale)) = (&cache_plan, revalidating) {
+                if response.status_code() == 304 {
+                    let now = Date::now().as_millis();
+                    let mut fresh = cache::revalidated(stale, &response)?;
+                    cache::store(ctx, &env, plan, &mut fresh, now, leader.take())?;
+                    stored_at_ms = Some(now);
+                    cache_status = Some(cache::Status::Revalidated);
+                    response = conditional::answer(req.headers(), fresh)?;
+                }
+            }
+            let upstream_ok = response.status_code() < 500;
+            slo::record(&env, ctx, &target_host, upstream_ok);
+            usage::record(&env, ctx, rctx.tenant(), &target_host, upstream_ok);
+            rctx.mark("upstream");
+            response
+        }
+    };
+
+    // 4.0.2 Report what the cache did
+    if let Some(status) = cache_status {
+        rctx.extra_headers
+            .push((cache::STATUS_HEADER, status.as_str().to_string()));
+        if let Some(stored_at) = stored_at_ms {
+            let age = freshness::age_secs(stored_at, Date::now().as_millis(), 0);
+            rctx.extra_headers
+                .push((cache::AGE_HEADER, age.to_string()));
+        }
+    }
+
+    // 4.1 Content-type allowlist
+    if let Some(rejected) = content_types::check(&env, &response)? {
+        return Ok(rejected);
+    }
+
+    // 4.2 Response size cap
+    let max_response_bytes = config::var_u64(&env, "MAX_RESPONSE_BYTES");
+    if let Some(limit) = max_response_bytes {
+        let declared: Option<u64> = response
+            .headers()
+            .get("Content-Length")?
+            .and_then(|v| v.parse().ok());
+        if declared.is_some_and(|len| len > limit) {
+            return responses::error(
+                502,
+                "response_too_large",
+                &format!("Upstream response exceeds the {limit} byte limit"),
+            );
+        }
+    }
+
+    // 4.2.1 Keep fresh upstream responses in the edge cache
+    if let (Some(plan), None) = (&cache_plan, stored_at_ms) {
+        cache::store(
+            ctx,
+            &env,
+            plan,
+            &mut response,
+            Date::now().as_millis(),
+            leader.take(),
+        )?;
+    }
+
+    // 4.3 Timestamp normalization and schema sampling for JSON bodies
+    // (opt-in, skipped once the deadline has passed)
+    let content_type = response.headers().get("Content-Type")?;
+    let is_json = !rctx.deadline_passed()
+        && content_type
+            .as_deref()
+            .is_some_and(|ct| ct.to_ascii_lowercase().contains("json"));
+    let sample_response = sample.is_some()
+        && response.status_code() < 300
+        && schema::body_sampleable(
+            content_type.as_deref(),
+            response
+                .headers()
+                .get("Content-Length")?
+                .and_then(|v| v.parse().ok()),
+            schema::max_bytes(&env),
+        );
+    if is_json && (rctx.flags.dates.is_some() || sample_response) {
+        let status = response.status_code();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+        response = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(mut value) => {
+                if let Some(sample) = sample.as_mut().filter(|_| sample_response) {
+                    sample.response = Some(value.clone());
+                }
+                match &rctx.flags.dates {
+                    Some(options) => {
+                        dates::normalize(&mut value, options);
+                        Response::from_bytes(serde_json::to_vec(&value)?)?
+                    }
+                    None => Response::from_bytes(body)?,
+                }
+            }
+            Err(_) => Response::from_bytes(body)?,
         }
+        .with_status(status)
+        .with_headers(headers);
+    }
+    if let Some(sample) = sample {
+        schema::record(&env, ctx, sample);
     }
 
-    // 4. Fetch
-    let fetch_request = Request::new_with_init(target_url.as_str(), &init)?;
-    let mut response = Fetch::Request(fetch_request).send().await?;
+    // 4.4 Per-caller watermark for leak tracing (opt-in)
+    response = watermark::apply(&env, rctx, response).await?;
+
+    // 4.5 Keep the result for Idempotency-Key retries
+    if let Some(claim) = claim {
+        response = claim.complete(ctx, response).await?;
+    }
 
     // 5. Process Response Headers
+    let strip_format = match metadata::enabled(&env, rctx.flags.strip_metadata) {
+        true => response
+            .headers()
+            .get("Content-Type")?
+            .and_then(|ct| metadata::Format::from_content_type(&ct)),
+        false => None,
+    };
+    let jsonp_callback = match &rctx.flags.jsonp {
+        Some(callback) => response
+            .headers()
+            .get("Content-Type")?
+            .filter(|ct| jsonp::is_json(ct))
+            .map(|_| callback.clone()),
+        None => None,
+    };
     let new_headers = Headers::new();
+    let strip_response = header_rules::strip_list(&env, "STRIP_RESPONSE_HEADERS");
+    let allow_response = header_rules::response_allowlist(&env);
+    let hop_by_hop = header_rules::hop_by_hop(response.headers().get("Connection")?.as_deref());
+    let cors_policy = cors::Policy::for_target(&env, Some(&target_url));
     for (key, value) in response.headers() {
         let key_lower = key.to_lowercase();
-        if !matches!(
-            key_lower.as_str(),
-            "content-encoding" | "content-length" | "transfer-encoding"
-        ) {
+        if !matches!(key_lower.as_str(), "content-encoding" | "content-length")
+            && !header_rules::listed(&key_lower, &hop_by_hop)
+            && !header_rules::ENDPOINT_HINTS.contains(&key_lower.as_str())
+            && cors::keeps_upstream_header(&cors_policy, &key_lower)
+            && !header_rules::listed(&key_lower, &strip_response)
+            && allow_response
+                .as_ref()
+                .is_none_or(|allowed| header_rules::listed(&key_lower, allowed))
+        {
             new_headers.set(&key, &value)?;
         }
     }
+    if strip_format.is_some() {
+        // The stripped body no longer matches the origin's validators or ranges.
+        new_headers.delete("ETag")?;
+        new_headers.delete("Accept-Ranges")?;
+    }
+    if jsonp_callback.is_some() {
+        new_headers.set("Content-Type", "application/javascript; charset=utf-8")?;
+        new_headers.set("X-Content-Type-Options", "nosniff")?;
+        new_headers.delete("ETag")?;
+        new_headers.delete("Accept-Ranges")?;
+    }
 
-    // Add CORS
-    new_headers.set("Access-Control-Allow-Origin", "*")?;
-    new_headers.set(
-        "Access-Control-Allow-Methods",
-        "GET, POST, PUT, DELETE, OPTIONS, PATCH, HEAD",
-    )?;
-    new_headers.set("Access-Control-Allow-Headers", "*")?;
+    let now = Date::now().as_millis();
+    freshness::annotate(&new_headers, stored_at_ms.unwrap_or(now), now)?;
+    deprecation::annotate(&new_headers, &env, &target_url)?;
+    if let Some(pseudonym) = &via_pseudonym {
+        let upstream = new_headers.get("Via")?;
+        new_headers.set("Via", &via::append(upstream.as_deref(), "1.1", pseudonym))?;
+    }
+    // The runtime re-encodes a decoded upstream body for each client's
+    // Accept-Encoding, and reflected CORS origins differ per caller.
+    let mut vary = Vec::new();
+    if cors_policy.varies_by_origin() {
+        vary.push("Origin");
+    }
+    if response.headers().has("Content-Encoding")? {
+        vary.push("Accept-Encoding");
+    }
+    if !vary.is_empty() {
+        let upstream = new_headers.get("Vary")?;
+        new_headers.set("Vary", &utils::merge_vary(upstream.as_deref(), &vary))?;
+    }
+
+    // Add CORS (and other headers meant for the client rather than describing
+    // the upstream response)
+    let transport_headers = Headers::new();
+    cors::annotate(&transport_headers, &req, &cors_policy)?;
+    for cookie in &rctx.set_cookies {
+        transport_headers.append("Set-Cookie", cookie)?;
+    }
+    for (name, value) in &rctx.extra_headers {
+        transport_headers.set(name, value)?;
+    }
+    transport_headers.set("X-Request-Id", &rctx.id)?;
+    rctx.mark("total");
+    transport_headers.set("Server-Timing", &rctx.server_timing())?;
+    diagnostics::annotate(&transport_headers, &env, rctx)?;
+    cors::expose(&transport_headers, &env)?;
+    helmet::apply(&env, &new_headers, &transport_headers)?;
+
+    // 5.1 JSON envelope: upstream status/headers/body inside a 200 response
+    if rctx.flags.envelope {
+        return envelope::wrap(&mut response, &new_headers, transport_headers).await;
+    }
+    for (key, value) in transport_headers.entries() {
+        new_headers.append(&key, &value)?;
+    }
 
     // 6. Return Response
+    // Large downloads may be fetched as parallel ranges (not on hosts with a
+    // concurrency limit, which the extra subrequests would exceed).
+    let accelerated = match rctx.flags.parallel {
+        Some(parts)
+            if method == Method::Get && permit.is_none() && !req.headers().has("Range")? =>
+        {
+            ranged::accelerate(&env, &target_url, &headers, &mut response, parts)?
+        }
+        _ => None,
+    };
     // We use Response::from_stream to stream the body back.
-    if let Ok(stream) = response.stream() {
-        // worker::Response::from_stream takes a stream.
-        let mut final_response = Response::from_stream(stream)?;
+    let upstream_body = match accelerated {
+        Some(body) => Some(body),
+        // A 304 (or 204) must go out without a body, not an empty stream.
+        None if conditional::is_null_body_status(response.status_code()) => None,
+        None => response.stream().ok().map(|s| Box::pin(s) as streams::Body),
+    };
+    let upstream_body = match strip_format {
+        Some(format) => upstream_body
+            .map(|body| Box::pin(metadata::StripStream::new(body, format)) as streams::Body),
+        None => upstream_body,
+    };
+    let upstream_body = match &jsonp_callback {
+        Some(callback) => upstream_body.map(|body| jsonp::wrap(body, callback)),
+        None => upstream_body,
+    };
+    if let Some(stream) = upstream_body {
+        let mut body: streams::Body = match max_response_bytes {
+            Some(limit) => Box::pin(streams::LimitedStream::new(stream, limit)),
+            None => stream,
+        };
+        if let Some(budget) = egress_budget {
+            body = egress::meter(&env, ctx, budget, body);
+        }
+        if let Some(permit) = permit {
+            let (counted, finished) = streams::counting(body);
+            permit.release_after(ctx, finished);
+            body = Box::pin(counted);
+        }
+        let mut final_response = Response::from_stream(body)?;
         final_response = final_response.with_status(response.status_code());
         *final_response.headers_mut() = new_headers;
         Ok(final_response)
     } else {
         // Fallback if no body stream (e.g. null body), sending empty.
+        if let Some(permit) = permit {
+            permit.release_after(ctx, async {});
+        }
         Ok(Response::empty()?
             .with_status(response.status_code())
             .with_headers(new_headers))
@@ -233,33 +984,6 @@ pub async fn do_main(req: Request, _env: Env) -> Result<Response> {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_generate_random_ip_format() {
-        let ip = generate_random_ip();
-        let parts: Vec<&str> = ip.split('.').collect();
-        assert_eq!(parts.len(), 4, "IP must have 4 octets: {ip}");
-
-        for part in &parts {
-            let octet: u8 = part
-                .parse()
-                .unwrap_or_else(|_| panic!("Octet '{part}' is not a valid u8 in IP: {ip}"));
-            assert!(octet >= 1, "Octet must be >= 1, got {octet} in {ip}");
-            // u8 max is 255, so no need to check upper bound explicitly
-        }
-    }
-
-    #[test]
-    fn test_generate_random_ip_nonzero_octets() {
-        // Run multiple times to increase confidence
-        for _ in 0..10 {
-            let ip = generate_random_ip();
-            for part in ip.split('.') {
-                let octet: u8 = part.parse().expect("valid octet");
-                assert!(octet >= 1, "0 is not a valid octet for X-Forwarded-For");
-            }
-        }
-    }
-
     #[test]
     fn test_filtered_params_contains_expected() {
         assert!(FILTERED_PARAMS.contains(&"url"));
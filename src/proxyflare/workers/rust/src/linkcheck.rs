@@ -0,0 +1,258 @@
+//! `/linkcheck`: HEAD-based link checker built on the proxy's fetch engine.
+//!
+//! - `POST /linkcheck` with `{"urls": [...]}` checks the given URLs.
+//! - `GET /linkcheck?page=<url>` extracts `href`s from an HTML page first.
+//!
+//! Each link is requested with `HEAD` (falling back to `GET` when the origin
+//! rejects `HEAD`) without following redirects, so redirect targets are
+//! reported. `LINKCHECK_MAX_LINKS` (default 50) and `LINKCHECK_CONCURRENCY`
+//! (default 6) bound the work per call, and only the first
+//! `MAX_PAGE_BYTES` of a page are scanned for links.
+//!
+//! The compliance blocklist applies as it does to proxied requests: a
+//! blocked page is refused, and blocked links are reported as
+//! `"error": "blocked"` without being requested. The call counts against the
+//! caller's key quota, and each link is charged to their spend cap; links
+//! past the cap are reported as `"error": "spend_cap_exceeded"`.
+
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::{compliance, config, responses, spend, streams};
+
+pub const PATH: &str = "/linkcheck";
+
+const DEFAULT_MAX_LINKS: u64 = 50;
+const DEFAULT_CONCURRENCY: u64 = 6;
+const MAX_PAGE_BYTES: u64 = 2 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct CheckInput {
+    urls: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LinkStatus {
+    pub url: String,
+    pub ok: bool,
+    pub status: Option<u16>,
+    pub method: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Extract absolute http(s) links from `href` attributes, resolved against
+/// `base` and de-duplicated in document order.
+pub fn extract_links(html: &str, base: &Url) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    let lower = html.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find("href") {
+        let start = pos + found + 4;
+        pos = start;
+        let rest = html[start..].trim_start();
+        let Some(rest) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let value = match rest.chars().next() {
+            Some(q @ ('"' | '\'')) => rest[1..].split(q).next().unwrap_or_default(),
+            _ => rest
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default(),
+        };
+        let Ok(resolved) = base.join(value.trim()) else {
+            continue;
+        };
+        if !matches!(resolved.scheme(), "http" | "https") {
+            continue;
+        }
+        let mut resolved = resolved;
+        resolved.set_fragment(None);
+        let link = resolved.to_string();
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+/// The http(s) URLs among `urls`, at most `max_links` of them, and whether
+/// any were cut off.
+pub fn select(urls: Vec<String>, max_links: u64) -> (Vec<String>, bool) {
+    let mut urls: Vec<String> = urls
+        .into_iter()
+        .filter(|u| Url::parse(u).is_ok_and(|u| matches!(u.scheme(), "http" | "https")))
+        .collect();
+    let truncated = urls.len() as u64 > max_links;
+    urls.truncate(max_links as usize);
+    (urls, truncated)
+}
+
+async fn send(url: &str, method: Method) -> Result<Response> {
+    let mut init = RequestInit::new();
+    init.with_method(method)
+        .with_redirect(RequestRedirect::Manual);
+    Fetch::Request(Request::new_with_init(url, &init)?)
+        .send()
+        .await
+}
+
+/// The first `MAX_PAGE_BYTES` of `page`'s body as text.
+async fn page_text(page: &mut Response) -> Result<String> {
+    let body = streams::collect(streams::Take::new(page.stream()?, MAX_PAGE_BYTES)).await?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+async fn refusal(env: &Env, caller: &str, url: &str) -> Option<&'static str> {
+    let target = Url::parse(url).ok()?;
+    if compliance::find_rule(env, &target).await.is_some() {
+        return Some("blocked");
+    }
+    match spend::charge(env, caller, &target).await {
+        Ok(Some(_)) => Some("spend_cap_exceeded"),
+        _ => None,
+    }
+}
+
+async fn check(env: &Env, caller: &str, url: String) -> LinkStatus {
+    if let Some(error) = refusal(env, caller, &url).await {
+        return LinkStatus {
+            url,
+            ok: false,
+            status: None,
+            method: "HEAD",
+            redirect: None,
+            error: Some(error.into()),
+        };
+    }
+    let mut method = "HEAD";
+    let mut result = send(&url, Method::Head).await;
+    if matches!(&result, Ok(r) if matches!(r.status_code(), 405 | 501)) {
+        method = "GET";
+        result = send(&url, Method::Get).await;
+    }
+    match result {
+        Ok(response) => {
+            let status = response.status_code();
+            LinkStatus {
+                url,
+                ok: status < 400,
+                status: Some(status),
+                method,
+                redirect: match status {
+                    300..=399 => response.headers().get("Location").ok().flatten(),
+                    _ => None,
+                },
+                error: None,
+            }
+        }
+        Err(e) => LinkStatus {
+            url,
+            ok: false,
+            status: None,
+            method,
+            redirect: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+pub async fn handle(mut req: Request, env: &Env, caller: &str) -> Result<Response> {
+    let max_links = config::var_u64(env, "LINKCHECK_MAX_LINKS").unwrap_or(DEFAULT_MAX_LINKS);
+    let concurrency = config::var_u64(env, "LINKCHECK_CONCURRENCY")
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .max(1);
+
+    let urls: Vec<String> = match req.method() {
+        Method::Post => match req.json::<CheckInput>().await {
+            Ok(input) => input.urls,
+            Err(_) => {
+                return responses::error(400, "invalid_request", "Expected {\"urls\": [...]}")
+            }
+        },
+        Method::Get => {
+            let page = req
+                .url()?
+                .query_pairs()
+                .find(|(k, _)| k == "page")
+                .map(|(_, v)| v.into_owned());
+            let Some(page_url) = page.and_then(|p| Url::parse(&p).ok()) else {
+                return responses::error(
+                    400,
+                    "invalid_request",
+                    "Query parameter `page` must be a URL",
+                );
+            };
+            if let Some(rule) = compliance::find_rule(env, &page_url).await {
+                return compliance::blocked_response(&rule, &page_url);
+            }
+            let mut page = send(page_url.as_str(), Method::Get).await?;
+            let html = page_text(&mut page).await.unwrap_or_default();
+            extract_links(&html, &page_url)
+        }
+        _ => return responses::error(405, "method_not_allowed", "Use GET or POST"),
+    };
+
+    let (urls, truncated) = select(urls, max_links);
+
+    let results: Vec<LinkStatus> = stream::iter(urls)
+        .map(|url| check(env, caller, url))
+        .buffered(concurrency as usize)
+        .collect()
+        .await;
+
+    responses::json(200, &json!({ "links": results, "truncated": truncated }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_links_resolves_and_dedupes() {
+        let base = Url::parse("https://example.com/docs/index.html").unwrap();
+        let html = r##"
+            <a href="/about">About</a>
+            <a HREF='guide.html#intro'>Guide</a>
+            <a href=https://other.org/x>Other</a>
+            <a href="/about">Again</a>
+            <a href="mailto:me@example.com">Mail</a>
+            <a href="#top">Top</a>
+        "##;
+        assert_eq!(
+            extract_links(html, &base),
+            vec![
+                "https://example.com/about",
+                "https://example.com/docs/guide.html",
+                "https://other.org/x",
+                "https://example.com/docs/index.html",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_counts_only_http_links() {
+        let urls = |list: &[&str]| list.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+        let (selected, truncated) = select(
+            urls(&["ftp://a/", "https://a/", "not a url", "http://b/"]),
+            2,
+        );
+        assert_eq!(selected, urls(&["https://a/", "http://b/"]));
+        assert!(!truncated);
+        let (selected, truncated) = select(urls(&["https://a/", "https://b/"]), 1);
+        assert_eq!(selected, urls(&["https://a/"]));
+        assert!(truncated);
+    }
+
+    #[test]
+    fn test_extract_links_ignores_bare_href_text() {
+        let base = Url::parse("https://example.com/").unwrap();
+        assert!(extract_links("the href attribute", &base).is_empty());
+    }
+}
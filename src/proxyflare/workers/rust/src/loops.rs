@@ -0,0 +1,108 @@
+//! Loop guard for requests that have already passed through the proxy.
+//!
+//! A request's hop count is the larger of two signals: how often this
+//! deployment's `Via` pseudonym already appears in its `Via` chain, and the
+//! count in a signed `X-Proxied-By: <pseudonym>;hops=<n>;sig=<hex>` header
+//! left by an earlier proxyflare hop. Once it reaches `LOOP_MAX_HOPS`
+//! (default 3) the request is refused with `508 Loop Detected` instead of
+//! going round again; targets that point straight back at the proxy are
+//! caught earlier, when the target URL is unwrapped.
+//!
+//! `X-Proxied-By` is only sent and trusted when `LOOP_GUARD_SECRET` is set,
+//! and deployments that proxy to each other must share it; a header with a
+//! bad signature counts for nothing, so clients can't trip the guard.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use worker::*;
+
+use crate::{config, responses, utils, via};
+
+pub const HEADER: &str = "X-Proxied-By";
+const DEFAULT_MAX_HOPS: u64 = 3;
+
+/// Hex HMAC-SHA256 over `"<pseudonym>\n<hops>"`.
+fn signature(secret: &str, pseudonym: &str, hops: u64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{pseudonym}\n{hops}").as_bytes());
+    utils::hex(&mac.finalize().into_bytes())
+}
+
+/// `X-Proxied-By` value for a request that has made `hops` hops.
+pub fn sign(secret: &str, pseudonym: &str, hops: u64) -> String {
+    format!(
+        "{pseudonym};hops={hops};sig={}",
+        signature(secret, pseudonym, hops)
+    )
+}
+
+/// The hop count of a correctly signed `X-Proxied-By` value.
+pub fn verify(secret: &str, value: &str) -> Option<u64> {
+    let mut parts = value.trim().split(';');
+    let pseudonym = parts.next()?.trim();
+    let hops: u64 = parts.next()?.trim().strip_prefix("hops=")?.parse().ok()?;
+    let sig = parts.next()?.trim().strip_prefix("sig=")?;
+    let expected = signature(secret, pseudonym, hops);
+    (parts.next().is_none()
+        && utils::constant_time_eq(expected.as_bytes(), sig.to_ascii_lowercase().as_bytes()))
+    .then_some(hops)
+}
+
+/// Hops the request has already made through proxyflare deployments.
+pub fn hops(req: &Request, env: &Env) -> Result<u64> {
+    let headers = req.headers();
+    let via_hops = match headers.get("Via")? {
+        Some(chain) => via::hops(&chain, &via::pseudonym(env)) as u64,
+        None => 0,
+    };
+    let signed_hops = match (config::var(env, "LOOP_GUARD_SECRET"), headers.get(HEADER)?) {
+        (Some(secret), Some(value)) => verify(&secret, &value).unwrap_or(0),
+        _ => 0,
+    };
+    Ok(via_hops.max(signed_hops))
+}
+
+/// 508 once the request has made `LOOP_MAX_HOPS` hops.
+pub fn check(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let max_hops = config::var_u64(env, "LOOP_MAX_HOPS").unwrap_or(DEFAULT_MAX_HOPS);
+    let hops = hops(req, env)?;
+    if hops < max_hops {
+        return Ok(None);
+    }
+    console_warn!("Refusing request after {} proxy hops", hops);
+    responses::error(
+        508,
+        "loop_detected",
+        &format!("The request has already passed through the proxy {hops} times"),
+    )
+    .map(Some)
+}
+
+/// `X-Proxied-By` value to send upstream, if signing is configured.
+pub fn outgoing(req: &Request, env: &Env) -> Result<Option<String>> {
+    let Some(secret) = config::var(env, "LOOP_GUARD_SECRET") else {
+        return Ok(None);
+    };
+    Ok(Some(sign(
+        &secret,
+        &via::pseudonym(env),
+        hops(req, env)? + 1,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let value = sign("s", "proxyflare", 2);
+        assert!(value.starts_with("proxyflare;hops=2;sig="));
+        assert_eq!(verify("s", &value), Some(2));
+        assert_eq!(verify("other", &value), None);
+        let forged = value.replace("hops=2", "hops=9");
+        assert_eq!(verify("s", &forged), None);
+        assert_eq!(verify("s", "proxyflare;hops=2"), None);
+    }
+}
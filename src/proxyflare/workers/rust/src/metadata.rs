@@ -0,0 +1,366 @@
+//! Metadata stripping for proxied images (`?strip_metadata=1`).
+//!
+//! JPEG and PNG responses have their EXIF/GPS, XMP, IPTC and comment
+//! metadata removed while they stream: JPEG `APP1`, `APP3`-`APP13`, `APP15`
+//! and `COM` segments before the image data are dropped (JFIF, ICC color
+//! profiles and Adobe color information are kept), as are PNG `eXIf`,
+//! `tEXt`, `zTXt`, `iTXt` and `tIME` chunks. Only the headers are
+//! inspected; pixel data passes through untouched. `STRIP_IMAGE_METADATA=true`
+//! applies it to every image, which suits deployments that display
+//! user-submitted image URLs. Stripping runs after watermarking, so the
+//! proxy's own watermark segment (see [`watermark`](crate::watermark)) is
+//! kept; a traced caller can't strip it.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::Stream;
+use worker::*;
+
+use crate::{config, watermark};
+
+pub const PARAM: &str = "strip_metadata";
+const JPEG_SIGNATURE: &[u8] = &[0xFF, 0xD8];
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_METADATA: &[&[u8; 4]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jpeg,
+    Png,
+}
+
+impl Format {
+    pub fn from_content_type(content_type: &str) -> Option<Format> {
+        let ct = content_type.to_ascii_lowercase();
+        match ct.split(';').next().unwrap_or_default().trim() {
+            "image/jpeg" | "image/jpg" => Some(Format::Jpeg),
+            "image/png" => Some(Format::Png),
+            _ => None,
+        }
+    }
+
+    fn signature(self) -> &'static [u8] {
+        match self {
+            Format::Jpeg => JPEG_SIGNATURE,
+            Format::Png => PNG_SIGNATURE,
+        }
+    }
+}
+
+/// Whether the worker URL asked for stripping.
+pub fn requested(url: &Url) -> bool {
+    url.query_pairs()
+        .any(|(k, v)| k == PARAM && matches!(v.as_ref(), "1" | "true" | "yes"))
+}
+
+/// Whether stripping applies to this response.
+pub fn enabled(env: &Env, requested: bool) -> bool {
+    requested || config::var(env, "STRIP_IMAGE_METADATA").as_deref() == Some("true")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Signature,
+    Header,
+    Copy(usize),
+    Skip(usize),
+    /// Image data (or an unrecognized file): everything passes through.
+    Passthrough,
+}
+
+/// What to do with a segment or chunk whose header is in `header`.
+enum Decision {
+    /// Header incomplete; wait for more bytes.
+    More,
+    Keep(usize),
+    Drop(usize),
+    /// The image data starts here.
+    Done,
+}
+
+fn jpeg_segment(header: &[u8]) -> Decision {
+    if header.len() < 2 {
+        return Decision::More;
+    }
+    let marker = header[1];
+    // Start of scan, end of image, standalone markers, or not a marker at all.
+    if header[0] != 0xFF || matches!(marker, 0xDA | 0xD0..=0xD9 | 0x01) {
+        return Decision::Done;
+    }
+    if header.len() < 4 {
+        return Decision::More;
+    }
+    let body = (u16::from_be_bytes([header[2], header[3]]) as usize).saturating_sub(2);
+    match marker {
+        0xE1 => {
+            // Look far enough into the payload to spot a watermark.
+            let probe = body.min(watermark::SEGMENT_PROBE_LEN);
+            if header.len() < 4 + probe {
+                return Decision::More;
+            }
+            let payload = &header[4..];
+            let rest = body - payload.len();
+            if watermark::is_watermark_segment(payload) {
+                Decision::Keep(rest)
+            } else {
+                Decision::Drop(rest)
+            }
+        }
+        0xE3..=0xED | 0xEF | 0xFE => Decision::Drop(body),
+        _ => Decision::Keep(body),
+    }
+}
+
+fn png_chunk(header: &[u8]) -> Decision {
+    if header.len() < 8 {
+        return Decision::More;
+    }
+    let kind = &header[4..8];
+    if kind == b"IEND" || kind == b"IDAT" {
+        return Decision::Done;
+    }
+    // Data plus CRC.
+    let body = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize + 4;
+    if PNG_METADATA.iter().any(|m| m.as_slice() == kind) {
+        Decision::Drop(body)
+    } else {
+        Decision::Keep(body)
+    }
+}
+
+/// Incremental metadata filter; feed it chunks in order.
+pub struct Stripper {
+    format: Format,
+    state: State,
+    pending: Vec<u8>,
+}
+
+impl Stripper {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            state: State::Signature,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Filter the next chunk of the body.
+    pub fn push(&mut self, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        while !input.is_empty() {
+            match self.state {
+                State::Passthrough => {
+                    out.extend_from_slice(input);
+                    break;
+                }
+                State::Copy(n) | State::Skip(n) => {
+                    let take = n.min(input.len());
+                    if matches!(self.state, State::Copy(_)) {
+                        out.extend_from_slice(&input[..take]);
+                    }
+                    input = &input[take..];
+                    self.state = match n - take {
+                        0 => State::Header,
+                        left if matches!(self.state, State::Copy(_)) => State::Copy(left),
+                        left => State::Skip(left),
+                    };
+                }
+                State::Signature => {
+                    let signature = self.format.signature();
+                    let take = (signature.len() - self.pending.len()).min(input.len());
+                    self.pending.extend_from_slice(&input[..take]);
+                    input = &input[take..];
+                    if self.pending.len() == signature.len() {
+                        self.state = if self.pending == signature {
+                            State::Header
+                        } else {
+                            State::Passthrough
+                        };
+                        out.append(&mut self.pending);
+                    }
+                }
+                State::Header => {
+                    self.pending.push(input[0]);
+                    input = &input[1..];
+                    let decision = match self.format {
+                        Format::Jpeg => jpeg_segment(&self.pending),
+                        Format::Png => png_chunk(&self.pending),
+                    };
+                    match decision {
+                        Decision::More => {}
+                        Decision::Keep(n) => {
+                            out.append(&mut self.pending);
+                            self.state = if n == 0 {
+                                State::Header
+                            } else {
+                                State::Copy(n)
+                            };
+                        }
+                        Decision::Drop(n) => {
+                            self.pending.clear();
+                            self.state = if n == 0 {
+                                State::Header
+                            } else {
+                                State::Skip(n)
+                            };
+                        }
+                        Decision::Done => {
+                            out.append(&mut self.pending);
+                            self.state = State::Passthrough;
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Bytes held back at the end of a truncated body.
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Applies a [`Stripper`] to a body stream.
+pub struct StripStream<S> {
+    inner: S,
+    stripper: Stripper,
+    finished: bool,
+}
+
+impl<S> StripStream<S> {
+    pub fn new(inner: S, format: Format) -> Self {
+        Self {
+            inner,
+            stripper: Stripper::new(format),
+            finished: false,
+        }
+    }
+}
+
+impl<S> Stream for StripStream<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.finished {
+                return Poll::Ready(None);
+            }
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let out = self.stripper.push(&chunk);
+                    // A chunk may be entirely metadata; don't yield it empty.
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(out)));
+                    }
+                }
+                Poll::Ready(None) => {
+                    self.finished = true;
+                    let rest = self.stripper.finish();
+                    if !rest.is_empty() {
+                        return Poll::Ready(Some(Ok(rest)));
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(marker: u8, body: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend(((body.len() + 2) as u16).to_be_bytes());
+        segment.extend(body);
+        segment
+    }
+
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(kind);
+        chunk.extend(data);
+        chunk.extend([0, 0, 0, 0]);
+        chunk
+    }
+
+    /// Push `body` in chunks of `size` bytes.
+    fn strip(format: Format, body: &[u8], size: usize) -> Vec<u8> {
+        let mut stripper = Stripper::new(format);
+        let mut out: Vec<u8> = body.chunks(size).flat_map(|c| stripper.push(c)).collect();
+        out.extend(stripper.finish());
+        out
+    }
+
+    fn jpeg() -> (Vec<u8>, Vec<u8>) {
+        let jfif = segment(0xE0, b"JFIF\0....");
+        let exif = segment(0xE1, b"Exif\0\0GPS here");
+        let comment = segment(0xFE, b"shot on my phone");
+        let dqt = segment(0xDB, &[1, 2, 3]);
+        let scan = [&segment(0xDA, &[9, 9])[..], &[0xFF, 0xE1, 7, 7, 0xFF, 0xD9]].concat();
+        let original = [JPEG_SIGNATURE, &jfif, &exif, &comment, &dqt, &scan].concat();
+        let stripped = [JPEG_SIGNATURE, &jfif, &dqt, &scan].concat();
+        (original, stripped)
+    }
+
+    #[test]
+    fn test_jpeg_metadata_removed() {
+        let (original, stripped) = jpeg();
+        assert_eq!(strip(Format::Jpeg, &original, original.len()), stripped);
+    }
+
+    #[test]
+    fn test_jpeg_watermark_is_kept() {
+        let (original, stripped) = jpeg();
+        let tag = "0123456789abcdef";
+        let marked = watermark::embed(watermark::Kind::Jpeg, &original, tag).unwrap();
+        for size in [1, 7, marked.len()] {
+            let out = strip(Format::Jpeg, &marked, size);
+            assert_eq!(watermark::extract(&out).as_deref(), Some(tag));
+            assert_eq!(out.len(), stripped.len() + marked.len() - original.len());
+        }
+    }
+
+    #[test]
+    fn test_jpeg_across_chunk_boundaries() {
+        let (original, stripped) = jpeg();
+        for size in [1, 3, 7] {
+            assert_eq!(strip(Format::Jpeg, &original, size), stripped);
+        }
+    }
+
+    #[test]
+    fn test_png_text_chunks_removed() {
+        let ihdr = chunk(b"IHDR", &[0; 13]);
+        let text = chunk(b"tEXt", b"Comment\0secret");
+        let exif = chunk(b"eXIf", b"MM\0*");
+        let idat = chunk(b"IDAT", &[1, 2, 3]);
+        let iend = chunk(b"IEND", &[]);
+        let original = [PNG_SIGNATURE, &ihdr, &text, &exif, &idat, &iend].concat();
+        let stripped = [PNG_SIGNATURE, &ihdr, &idat, &iend].concat();
+        assert_eq!(strip(Format::Png, &original, 5), stripped);
+    }
+
+    #[test]
+    fn test_unrecognized_body_passes_through() {
+        let body = b"<html>not an image</html>";
+        assert_eq!(strip(Format::Png, body, 4), body);
+        assert_eq!(strip(Format::Jpeg, body, 4), body);
+    }
+
+    #[test]
+    fn test_format_from_content_type() {
+        assert_eq!(Format::from_content_type("image/PNG"), Some(Format::Png));
+        assert_eq!(
+            Format::from_content_type("image/jpeg; q=1"),
+            Some(Format::Jpeg)
+        );
+        assert_eq!(Format::from_content_type("image/gif"), None);
+    }
+}
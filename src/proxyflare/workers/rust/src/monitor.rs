@@ -0,0 +1,236 @@
+//! Synthetic monitoring probes run from the cron handler.
+//!
+//! `MONITOR_PROBES` holds a JSON array of [`ProbeSpec`]s. Every cron tick runs
+//! the probes whose interval divides the current minute. The latest result of
+//! each probe is stored in the `MONITOR_KV` namespace (`probe:<name>`), and a
+//! JSON alert is POSTed to `MONITOR_ALERT_WEBHOOK` whenever a probe changes
+//! between passing and failing. Results are listed at `GET /admin/probes`.
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::{config, utils};
+
+const PROBES_VAR: &str = "MONITOR_PROBES";
+const RESULTS_KV: &str = "MONITOR_KV";
+/// Keep results around for a day after a probe is removed from config.
+const RESULT_TTL_SECS: u64 = 86_400;
+
+fn default_method() -> String {
+    "GET".into()
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ProbeSpec {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default = "default_status")]
+    pub expect_status: u16,
+    /// Substring that must appear in the response body.
+    #[serde(default)]
+    pub expect_body: Option<String>,
+    #[serde(default = "default_interval")]
+    pub interval_minutes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProbeResult {
+    pub name: String,
+    pub ok: bool,
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+    pub checked_at: String,
+    pub consecutive_failures: u64,
+}
+
+impl ProbeSpec {
+    pub fn is_due(&self, minute: u64) -> bool {
+        minute.is_multiple_of(self.interval_minutes.max(1))
+    }
+
+    /// Compare an observed response with the expectations.
+    pub fn evaluate(&self, status: u16, body: &str) -> std::result::Result<(), String> {
+        if status != self.expect_status {
+            return Err(format!(
+                "expected status {}, got {}",
+                self.expect_status, status
+            ));
+        }
+        match &self.expect_body {
+            Some(needle) if !body.contains(needle.as_str()) => {
+                Err(format!("response body does not contain {needle:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn parse_probes(raw: &str) -> Vec<ProbeSpec> {
+    serde_json::from_str(raw).unwrap_or_else(|e| {
+        console_error!("Ignoring malformed {}: {}", PROBES_VAR, e);
+        Vec::new()
+    })
+}
+
+fn probes(env: &Env) -> Vec<ProbeSpec> {
+    config::var(env, PROBES_VAR)
+        .map(|raw| parse_probes(&raw))
+        .unwrap_or_default()
+}
+
+async fn execute(spec: &ProbeSpec) -> (Option<u16>, std::result::Result<(), String>) {
+    let method = Method::from(spec.method.to_ascii_uppercase());
+    let mut init = RequestInit::new();
+    init.with_method(method);
+    let request = match Request::new_with_init(&spec.url, &init) {
+        Ok(r) => r,
+        Err(e) => return (None, Err(e.to_string())),
+    };
+    let mut response = match Fetch::Request(request).send().await {
+        Ok(r) => r,
+        Err(e) => return (None, Err(e.to_string())),
+    };
+    let status = response.status_code();
+    let body = if spec.expect_body.is_some() {
+        response.text().await.unwrap_or_default()
+    } else {
+        String::new()
+    };
+    (Some(status), spec.evaluate(status, &body))
+}
+
+async fn run_probe(spec: ProbeSpec, kv: Option<&KvStore>, webhook: Option<&str>) {
+    let started = Date::now().as_millis();
+    let (status, outcome) = execute(&spec).await;
+    let key = format!("probe:{}", spec.name);
+
+    let previous: Option<ProbeResult> = match kv {
+        Some(kv) => kv.get(&key).json().await.ok().flatten(),
+        None => None,
+    };
+    let ok = outcome.is_ok();
+    let result = ProbeResult {
+        name: spec.name.clone(),
+        ok,
+        status,
+        latency_ms: Date::now().as_millis().saturating_sub(started),
+        error: outcome.err(),
+        checked_at: utils::now_iso(),
+        consecutive_failures: if ok {
+            0
+        } else {
+            previous.as_ref().map_or(0, |p| p.consecutive_failures) + 1
+        },
+    };
+    if !result.ok {
+        console_warn!("Probe {} failed: {:?}", result.name, result.error);
+    }
+
+    if let Some(kv) = kv {
+        let stored = kv
+            .put(&key, &result)
+            .map(|p| p.expiration_ttl(RESULT_TTL_SECS));
+        if let Err(e) = match stored {
+            Ok(put) => put.execute().await,
+            Err(e) => Err(e),
+        } {
+            console_error!("Failed to store probe result {}: {:?}", key, e);
+        }
+    }
+
+    let changed = previous
+        .as_ref()
+        .map_or(!result.ok, |prev| prev.ok != result.ok);
+    if let (true, Some(webhook)) = (changed, webhook) {
+        let body = json!({ "alert": "probe_state_changed", "result": result });
+        match utils::json_request(webhook, Method::Post, &body) {
+            Ok(req) => {
+                if let Err(e) = Fetch::Request(req).send().await {
+                    console_error!("Probe alert webhook failed: {:?}", e);
+                }
+            }
+            Err(e) => console_error!("Probe alert webhook failed: {:?}", e),
+        }
+    }
+}
+
+/// Run all probes that are due at the scheduled time (epoch millis).
+pub async fn run(env: &Env, scheduled_ms: u64) {
+    let minute = scheduled_ms / 60_000;
+    let due: Vec<ProbeSpec> = probes(env)
+        .into_iter()
+        .filter(|p| p.is_due(minute))
+        .collect();
+    if due.is_empty() {
+        return;
+    }
+    let kv = env.kv(RESULTS_KV).ok();
+    let webhook = config::var(env, "MONITOR_ALERT_WEBHOOK");
+    join_all(
+        due.into_iter()
+            .map(|spec| run_probe(spec, kv.as_ref(), webhook.as_deref())),
+    )
+    .await;
+}
+
+/// `GET /admin/probes`: latest stored result for every configured probe.
+pub async fn admin_results(env: &Env) -> Result<Response> {
+    let kv = env.kv(RESULTS_KV).ok();
+    let mut results = Vec::new();
+    for spec in probes(env) {
+        let result: Option<ProbeResult> = match &kv {
+            Some(kv) => kv.get(&format!("probe:{}", spec.name)).json().await?,
+            None => None,
+        };
+        results.push(json!({ "name": spec.name, "url": spec.url, "last": result }));
+    }
+    Response::from_json(&json!({ "probes": results }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> ProbeSpec {
+        parse_probes(
+            r#"[{"name": "api", "url": "https://example.com/health", "expect_body": "ok"}]"#,
+        )
+        .remove(0)
+    }
+
+    #[test]
+    fn test_parse_probes_defaults() {
+        let spec = spec();
+        assert_eq!(spec.method, "GET");
+        assert_eq!(spec.expect_status, 200);
+        assert_eq!(spec.interval_minutes, 5);
+    }
+
+    #[test]
+    fn test_is_due() {
+        let spec = spec();
+        assert!(spec.is_due(10));
+        assert!(!spec.is_due(11));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let spec = spec();
+        assert!(spec.evaluate(200, "status: ok").is_ok());
+        assert!(spec.evaluate(503, "status: ok").is_err());
+        assert!(spec.evaluate(200, "status: down").is_err());
+    }
+}
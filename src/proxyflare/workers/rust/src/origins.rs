@@ -0,0 +1,116 @@
+//! Restrict browser callers to known web origins.
+//!
+//! `ALLOWED_ORIGINS` is a comma-separated list such as
+//! `https://app.example.com, https://*.example.dev, http://localhost:3000`.
+//! A request whose `Origin` (or, lacking one, `Referer`) is not listed is
+//! rejected with 403 before anything is fetched. Requests carrying neither
+//! header come from non-browser clients and are left to the other gates.
+
+use worker::*;
+
+use crate::{config, responses, utils};
+
+const ALLOWED_VAR: &str = "ALLOWED_ORIGINS";
+
+/// Match `scheme://host[:port]` patterns (host may be `*.suffix`; a pattern
+/// without a scheme matches http and https).
+pub fn origin_matches(pattern: &str, origin: &Url) -> bool {
+    let pattern = pattern.trim().trim_end_matches('/');
+    let (scheme, rest) = match pattern.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, pattern),
+    };
+    if scheme.is_some_and(|s| !s.eq_ignore_ascii_case(origin.scheme())) {
+        return false;
+    }
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok()),
+        None => (rest, None),
+    };
+    port == origin.port() && utils::host_matches(host, origin.host_str().unwrap_or_default())
+}
+
+/// The calling web origin from `Origin`, falling back to `Referer`.
+pub fn caller_origin(req: &Request) -> Result<Option<Url>> {
+    let headers = req.headers();
+    let raw = match headers.get("Origin")?.filter(|o| o != "null") {
+        Some(origin) => Some(origin),
+        None => headers.get("Referer")?,
+    };
+    Ok(raw.and_then(|r| Url::parse(&r).ok()))
+}
+
+/// Reject requests from web origins that aren't allowed.
+pub fn check(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let allowed = config::var_list(env, ALLOWED_VAR);
+    if allowed.is_empty() {
+        return Ok(None);
+    }
+    let headers = req.headers();
+    if headers.get("Origin")?.is_none() && headers.get("Referer")?.is_none() {
+        return Ok(None);
+    }
+    match caller_origin(req)? {
+        Some(origin) if allowed.iter().any(|p| origin_matches(p, &origin)) => Ok(None),
+        _ => responses::error(
+            403,
+            "origin_not_allowed",
+            "Requests from this origin are not allowed",
+        )
+        .map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_origin_matches_exact() {
+        assert!(origin_matches(
+            "https://app.example.com",
+            &url("https://app.example.com")
+        ));
+        assert!(origin_matches(
+            "https://app.example.com/",
+            &url("https://app.example.com/some/page")
+        ));
+        assert!(!origin_matches(
+            "https://app.example.com",
+            &url("http://app.example.com")
+        ));
+        assert!(!origin_matches(
+            "https://app.example.com",
+            &url("https://evil.com")
+        ));
+    }
+
+    #[test]
+    fn test_origin_matches_wildcard_and_port() {
+        assert!(origin_matches(
+            "https://*.example.dev",
+            &url("https://pr-1.example.dev")
+        ));
+        assert!(!origin_matches(
+            "https://*.example.dev",
+            &url("https://example.dev")
+        ));
+        assert!(origin_matches(
+            "http://localhost:3000",
+            &url("http://localhost:3000")
+        ));
+        assert!(!origin_matches(
+            "http://localhost:3000",
+            &url("http://localhost:4000")
+        ));
+        assert!(!origin_matches(
+            "http://localhost",
+            &url("http://localhost:3000")
+        ));
+        assert!(origin_matches("example.com", &url("http://example.com")));
+    }
+}
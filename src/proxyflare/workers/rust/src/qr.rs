@@ -0,0 +1,169 @@
+//! `GET /qr?url=<link>`: QR code for a link, rendered in the worker.
+//!
+//! `format=svg` (default) or `format=png`; `scale` sets pixels per module
+//! for PNGs (default 8, at most 32). With `signed=1` and `URL_SIGNING_SECRET`
+//! configured, the code points at a signed proxy link for `url` that expires
+//! after `ttl` seconds (at most `QR_SIGNED_MAX_TTL_SECS`, default one day)
+//! instead of at `url` itself. Signed links bypass API keys and quotas, so
+//! only callers authenticated with an API key may mint them; holders of a
+//! signed link and anonymous callers get 403.
+
+use qrcodegen::{QrCode, QrCodeEcc};
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, responses, signing};
+
+pub const PATH: &str = "/qr";
+
+/// Quiet zone, in modules, required around the symbol.
+const BORDER: i32 = 4;
+const DEFAULT_SCALE: u32 = 8;
+const MAX_SCALE: u32 = 32;
+const DEFAULT_SIGNED_MAX_TTL_SECS: u64 = 86_400;
+
+pub fn render_svg(qr: &QrCode) -> String {
+    let size = qr.size() + BORDER * 2;
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + BORDER, y + BORDER));
+            }
+        }
+    }
+    format!(
+        concat!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {size} {size}" "##,
+            r##"shape-rendering="crispEdges"><rect width="100%" height="100%" fill="#fff"/>"##,
+            r##"<path d="{path}" fill="#000"/></svg>"##
+        ),
+        size = size,
+        path = path
+    )
+}
+
+/// 8-bit grayscale PNG, `scale` pixels per module.
+pub fn render_png(qr: &QrCode, scale: u32) -> std::result::Result<Vec<u8>, png::EncodingError> {
+    let modules = (qr.size() + BORDER * 2) as u32;
+    let width = modules * scale;
+    let mut pixels = Vec::with_capacity((width * width) as usize);
+    for py in 0..width {
+        let y = (py / scale) as i32 - BORDER;
+        for px in 0..width {
+            let x = (px / scale) as i32 - BORDER;
+            // get_module is false outside the symbol, which yields the border.
+            pixels.push(if qr.get_module(x, y) { 0 } else { 255 });
+        }
+    }
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, width, width);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)?;
+    }
+    Ok(out)
+}
+
+pub async fn handle(req: Request, env: &Env, rctx: &RequestCtx) -> Result<Response> {
+    if req.method() != Method::Get {
+        return responses::error(405, "method_not_allowed", "Use GET");
+    }
+    let url = req.url()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let Some(mut link) = param("url").filter(|u| Url::parse(u).is_ok()) else {
+        return responses::error(
+            400,
+            "invalid_request",
+            "Query parameter `url` must be a URL",
+        );
+    };
+    let signed = matches!(param("signed").as_deref(), Some("1" | "true" | "yes"));
+    if signed {
+        if rctx.caller.is_none() {
+            return responses::error(
+                403,
+                "signing_not_allowed",
+                "Signed links can only be minted with an API key",
+            );
+        }
+        let max_ttl =
+            config::var_u64(env, "QR_SIGNED_MAX_TTL_SECS").unwrap_or(DEFAULT_SIGNED_MAX_TTL_SECS);
+        let ttl = signing::ttl_param(&url).min(max_ttl);
+        match signing::signed_link(env, &url, &link, ttl) {
+            Some((signed, _)) => link = signed.to_string(),
+            None => {
+                return responses::error(503, "signing_disabled", "URL_SIGNING_SECRET is not set")
+            }
+        }
+    }
+
+    let Ok(qr) = QrCode::encode_text(&link, QrCodeEcc::Medium) else {
+        return responses::error(414, "url_too_long", "The link does not fit in a QR code");
+    };
+    let mut response = match param("format").as_deref() {
+        None | Some("svg") => {
+            let mut response = Response::ok(render_svg(&qr))?;
+            response
+                .headers_mut()
+                .set("Content-Type", "image/svg+xml")?;
+            response
+        }
+        Some("png") => {
+            let scale = param("scale")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_SCALE)
+                .clamp(1, MAX_SCALE);
+            let png = render_png(&qr, scale).map_err(|e| Error::RustError(e.to_string()))?;
+            let mut response = Response::from_bytes(png)?;
+            response.headers_mut().set("Content-Type", "image/png")?;
+            response
+        }
+        Some(_) => return responses::error(400, "invalid_request", "`format` must be svg or png"),
+    };
+    // Signed links expire, so their codes must not outlive them in caches.
+    let cache_control = if signed {
+        "private, no-store"
+    } else {
+        "public, max-age=86400"
+    };
+    response.headers_mut().set("Cache-Control", cache_control)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code() -> QrCode {
+        QrCode::encode_text("https://example.com/", QrCodeEcc::Medium).unwrap()
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let qr = code();
+        let svg = render_svg(&qr);
+        let size = qr.size() + BORDER * 2;
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(&format!("viewBox=\"0 0 {size} {size}\"")));
+        // Finder pattern corner module sits just inside the quiet zone.
+        assert!(svg.contains(&format!("M{BORDER},{BORDER}h1v1h-1z")));
+    }
+
+    #[test]
+    fn test_render_png() {
+        let qr = code();
+        let png = render_png(&qr, 2).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let expected = ((qr.size() + BORDER * 2) * 2) as u32;
+        assert_eq!(reader.info().width, expected);
+        assert_eq!(reader.info().height, expected);
+    }
+}
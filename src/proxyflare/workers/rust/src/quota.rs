@@ -0,0 +1,220 @@
+//! Generic fixed-window counters in the `QuotaCounter` Durable Object.
+//!
+//! One object per subject (usually an API key id, binding `QUOTA_COUNTER`)
+//! holds any number of named buckets. `POST /consume` atomically adds
+//! `amount` to a bucket unless that would exceed `limit` within the current
+//! window; windows are aligned to multiples of `window_secs` since the epoch
+//! (so a daily window resets at 00:00 UTC). A `sliding` request also counts
+//! the previous window, weighted by how much of it still overlaps the last
+//! `window_secs`, which avoids bursts right after a boundary.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::{responses, utils};
+
+const BINDING: &str = "QUOTA_COUNTER";
+const STATE_KEY: &str = "buckets";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConsumeRequest {
+    pub bucket: String,
+    pub amount: u64,
+    pub limit: u64,
+    pub window_secs: u64,
+    #[serde(default)]
+    pub sliding: bool,
+    /// Count `amount` even past the limit (usage that already happened).
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ConsumeResult {
+    pub allowed: bool,
+    pub used: u64,
+    pub limit: u64,
+    /// Epoch seconds at which the current window ends.
+    pub reset_at: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct Window {
+    pub start: u64,
+    pub used: u64,
+    /// Count of the window immediately before this one.
+    #[serde(default)]
+    pub previous: u64,
+}
+
+impl Window {
+    pub fn consume(&mut self, now_secs: u64, req: &ConsumeRequest) -> ConsumeResult {
+        let length = req.window_secs.max(1);
+        let start = now_secs - now_secs % length;
+        if self.start != start {
+            let previous = if self.start + length == start {
+                self.used
+            } else {
+                0
+            };
+            *self = Window {
+                start,
+                used: 0,
+                previous,
+            };
+        }
+        let carried = if req.sliding {
+            let overlap = length - (now_secs - start);
+            (self.previous * overlap).div_ceil(length)
+        } else {
+            0
+        };
+        let allowed = (carried + self.used).saturating_add(req.amount) <= req.limit;
+        if allowed || req.force {
+            self.used += req.amount;
+        }
+        ConsumeResult {
+            allowed,
+            used: carried + self.used,
+            limit: req.limit,
+            reset_at: start + length,
+        }
+    }
+}
+
+/// Consume from `subject`'s bucket. `None` without the binding or when the
+/// object can't be reached (callers decide whether that fails open).
+pub async fn consume(env: &Env, subject: &str, req: &ConsumeRequest) -> Option<ConsumeResult> {
+    let namespace = env.durable_object(BINDING).ok()?;
+    let stub = namespace.id_from_name(subject).ok()?.get_stub().ok()?;
+    let result = async {
+        let request = utils::json_request("https://quota/consume", Method::Post, req)?;
+        stub.fetch_with_request(request).await?.json().await
+    }
+    .await;
+    match result {
+        Ok(result) => Some(result),
+        Err(e) => {
+            console_error!("Quota consume failed for {}: {:?}", subject, e);
+            None
+        }
+    }
+}
+
+/// 429 for an exhausted window, with `Retry-After` pointing at its end.
+pub fn too_many_requests(result: &ConsumeResult, error: &str, message: &str) -> Result<Response> {
+    let mut response = responses::json(
+        429,
+        &json!({ "error": error, "message": message, "limit": result.limit }),
+    )?;
+    let now = Date::now().as_millis() / 1000;
+    response.headers_mut().set(
+        "Retry-After",
+        &result.reset_at.saturating_sub(now).max(1).to_string(),
+    )?;
+    Ok(response)
+}
+
+#[durable_object]
+pub struct QuotaCounter {
+    state: State,
+}
+
+impl DurableObject for QuotaCounter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        match req.path().as_str() {
+            "/consume" => {
+                let consume: ConsumeRequest = req.json().await?;
+                let storage = self.state.storage();
+                let mut buckets: BTreeMap<String, Window> =
+                    storage.get(STATE_KEY).await?.unwrap_or_default();
+                let now = Date::now().as_millis() / 1000;
+                let result = buckets
+                    .entry(consume.bucket.clone())
+                    .or_default()
+                    .consume(now, &consume);
+                storage.put(STATE_KEY, &buckets).await?;
+                Response::from_json(&result)
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(amount: u64) -> ConsumeRequest {
+        ConsumeRequest {
+            bucket: "spend".into(),
+            amount,
+            limit: 10,
+            window_secs: 86_400,
+            sliding: false,
+            force: false,
+        }
+    }
+
+    #[test]
+    fn test_consume_until_limit() {
+        let mut window = Window::default();
+        assert!(window.consume(100, &request(6)).allowed);
+        let result = window.consume(200, &request(5));
+        assert!(!result.allowed);
+        assert_eq!(result.used, 6);
+        assert_eq!(result.reset_at, 86_400);
+        assert!(window.consume(300, &request(4)).allowed);
+    }
+
+    #[test]
+    fn test_window_resets() {
+        let mut window = Window::default();
+        window.consume(100, &request(10));
+        let result = window.consume(86_400 + 5, &request(3));
+        assert!(result.allowed);
+        assert_eq!(result.used, 3);
+        assert_eq!(result.reset_at, 2 * 86_400);
+    }
+
+    #[test]
+    fn test_forced_usage_overdraws() {
+        let mut window = Window::default();
+        let forced = ConsumeRequest {
+            force: true,
+            ..request(12)
+        };
+        let result = window.consume(100, &forced);
+        assert!(!result.allowed);
+        assert_eq!(result.used, 12);
+        assert!(!window.consume(200, &request(0)).allowed);
+    }
+
+    #[test]
+    fn test_sliding_window_carries_previous() {
+        let request = ConsumeRequest {
+            window_secs: 60,
+            sliding: true,
+            ..request(1)
+        };
+        let mut window = Window::default();
+        for _ in 0..10 {
+            assert!(window.consume(30, &request).allowed);
+        }
+        // A quarter into the next window, 3/4 of the previous 10 still count.
+        let result = window.consume(75, &request);
+        assert!(result.allowed);
+        assert_eq!(result.used, 9);
+        assert!(window.consume(75, &request).allowed);
+        assert!(!window.consume(75, &request).allowed);
+        // Two windows later nothing carries over.
+        assert_eq!(window.consume(185, &request).used, 1);
+    }
+}
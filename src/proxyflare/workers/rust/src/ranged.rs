@@ -0,0 +1,200 @@
+//! Parallel ranged downloads for large bodies.
+//!
+//! A `GET` with `?parallel=<n>` opts into splitting the download into `n`
+//! byte ranges (at most `PARALLEL_RANGES_MAX`, default 4 and never more than
+//! 6, so the request stays well inside the subrequest budget). It applies
+//! only when the origin answers 200 with `Accept-Ranges: bytes`, no
+//! `Content-Encoding` and a `Content-Length` between
+//! `PARALLEL_RANGE_MIN_BYTES` (default 8 MiB) and `PARALLEL_RANGE_MAX_BYTES`
+//! (default 64 MiB); anything else is passed through unchanged.
+//!
+//! The first range streams from the original response while the others are
+//! fetched concurrently with `If-Range` (the ETag or Last-Modified) and
+//! buffered, then everything is sent in order as one body. A range that
+//! fails or comes back as anything but the expected 206 ends the body early,
+//! so the client sees a short download rather than corrupted bytes.
+
+use worker::*;
+
+use crate::{config, streams};
+
+pub const PARAM: &str = "parallel";
+const DEFAULT_MAX_PARTS: u64 = 4;
+const HARD_MAX_PARTS: u64 = 6;
+const DEFAULT_MIN_BYTES: u64 = 8 * 1024 * 1024;
+const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Requested part count from the worker URL; `Ok(None)` when not requested.
+pub fn requested(url: &Url) -> std::result::Result<Option<u64>, String> {
+    let Some((_, value)) = url.query_pairs().find(|(k, _)| k == PARAM) else {
+        return Ok(None);
+    };
+    match value.parse::<u64>() {
+        Ok(parts) if parts >= 2 => Ok(Some(parts)),
+        Ok(_) => Ok(None),
+        Err(_) => Err(format!("Invalid {PARAM} value {value:?}")),
+    }
+}
+
+/// Split `total` bytes into `parts` contiguous inclusive ranges.
+pub fn plan(total: u64, parts: u64) -> Vec<(u64, u64)> {
+    let parts = parts.clamp(1, total.max(1));
+    let size = total.div_ceil(parts);
+    (0..parts)
+        .map(|i| (i * size, ((i + 1) * size).min(total) - 1))
+        .filter(|(start, end)| start <= end)
+        .collect()
+}
+
+/// Length of a response that can be split, given its status and headers.
+pub fn splittable_length(
+    status: u16,
+    accept_ranges: Option<&str>,
+    content_encoding: Option<&str>,
+    content_length: Option<u64>,
+    bounds: (u64, u64),
+) -> Option<u64> {
+    let ranged = accept_ranges.is_some_and(|v| v.eq_ignore_ascii_case("bytes"));
+    let identity = content_encoding.is_none_or(|v| v.eq_ignore_ascii_case("identity"));
+    (status == 200 && ranged && identity)
+        .then_some(content_length?)
+        .filter(|len| (bounds.0..=bounds.1).contains(len))
+}
+
+async fn fetch_part(
+    url: String,
+    headers: Headers,
+    (start, end): (u64, u64),
+    validator: Option<String>,
+) -> Result<Vec<u8>> {
+    headers.set("Range", &format!("bytes={start}-{end}"))?;
+    if let Some(validator) = &validator {
+        headers.set("If-Range", validator)?;
+    }
+    let mut init = RequestInit::new();
+    init.with_method(Method::Get).with_headers(headers);
+    let mut response = Fetch::Request(Request::new_with_init(&url, &init)?)
+        .send()
+        .await?;
+    if response.status_code() != 206 {
+        return Err(Error::RustError(format!(
+            "range {start}-{end} answered {}",
+            response.status_code()
+        )));
+    }
+    let body = response.bytes().await?;
+    if body.len() as u64 != end - start + 1 {
+        return Err(Error::RustError(format!(
+            "range {start}-{end} returned {} bytes",
+            body.len()
+        )));
+    }
+    Ok(body)
+}
+
+/// Replace `response`'s body with a parallel ranged download when it
+/// qualifies; `Ok(None)` leaves the response to be streamed as usual.
+pub fn accelerate(
+    env: &Env,
+    target: &Url,
+    request_headers: &Headers,
+    response: &mut Response,
+    requested_parts: u64,
+) -> Result<Option<streams::Body>> {
+    let header = |name: &str| response.headers().get(name).ok().flatten();
+    let bounds = (
+        config::var_u64(env, "PARALLEL_RANGE_MIN_BYTES").unwrap_or(DEFAULT_MIN_BYTES),
+        config::var_u64(env, "PARALLEL_RANGE_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES),
+    );
+    let Some(total) = splittable_length(
+        response.status_code(),
+        header("Accept-Ranges").as_deref(),
+        header("Content-Encoding").as_deref(),
+        header("Content-Length").and_then(|v| v.parse().ok()),
+        bounds,
+    ) else {
+        return Ok(None);
+    };
+    let max_parts = config::var_u64(env, "PARALLEL_RANGES_MAX")
+        .unwrap_or(DEFAULT_MAX_PARTS)
+        .min(HARD_MAX_PARTS);
+    let ranges = plan(total, requested_parts.min(max_parts));
+    if ranges.len() < 2 {
+        return Ok(None);
+    }
+    let validator = header("ETag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header("Last-Modified"));
+    console_log!(
+        "Fetching {} bytes from {} in {} ranges",
+        total,
+        target.host_str().unwrap_or_default(),
+        ranges.len()
+    );
+    let head = streams::Take::new(response.stream()?, ranges[0].1 + 1);
+    let parts: Vec<streams::Part> = ranges[1..]
+        .iter()
+        .map(|range| -> streams::Part {
+            Box::pin(fetch_part(
+                target.to_string(),
+                request_headers.clone(),
+                *range,
+                validator.clone(),
+            ))
+        })
+        .collect();
+    Ok(Some(Box::pin(streams::Stitched::new(head, parts))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_covers_every_byte() {
+        assert_eq!(plan(10, 3), vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(plan(8, 4), vec![(0, 1), (2, 3), (4, 5), (6, 7)]);
+        assert_eq!(plan(2, 4), vec![(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn test_requested() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            requested(&url("https://p.example/?parallel=4")),
+            Ok(Some(4))
+        );
+        assert_eq!(requested(&url("https://p.example/?parallel=1")), Ok(None));
+        assert_eq!(requested(&url("https://p.example/")), Ok(None));
+        assert!(requested(&url("https://p.example/?parallel=many")).is_err());
+    }
+
+    #[test]
+    fn test_splittable_length() {
+        let bounds = (10, 100);
+        assert_eq!(
+            splittable_length(200, Some("bytes"), None, Some(50), bounds),
+            Some(50)
+        );
+        assert_eq!(
+            splittable_length(200, Some("none"), None, Some(50), bounds),
+            None
+        );
+        assert_eq!(
+            splittable_length(200, Some("bytes"), Some("gzip"), Some(50), bounds),
+            None
+        );
+        assert_eq!(
+            splittable_length(206, Some("bytes"), None, Some(50), bounds),
+            None
+        );
+        assert_eq!(
+            splittable_length(200, Some("bytes"), None, Some(5), bounds),
+            None
+        );
+        assert_eq!(
+            splittable_length(200, Some("bytes"), None, None, bounds),
+            None
+        );
+    }
+}
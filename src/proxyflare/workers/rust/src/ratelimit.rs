@@ -0,0 +1,231 @@
+//! Per-IP rate limiting.
+//!
+//! With `RATE_LIMIT_REQUESTS` set, each client IP (`cf-connecting-ip`) may
+//! make that many requests in any `RATE_LIMIT_WINDOW_SECS` (default 60,
+//! sliding); the count lives in the `QUOTA_COUNTER` Durable Object, one
+//! object per IP. Responses carry `RateLimit-Limit`, `RateLimit-Remaining`
+//! and `RateLimit-Reset` (seconds) so clients can pace themselves; excess
+//! requests get 429 with `Retry-After`. Without the binding the limit is
+//! not enforced.
+//!
+//! When a native Workers Rate Limiting binding is bound as `RATE_LIMITER`
+//! it is used instead (limit and period come from its wrangler config),
+//! which is cheaper and faster than a Durable Object round trip. Set
+//! `RATE_LIMIT_BACKEND=durable_object` to keep using the object anyway.
+//! The native binding reports no counts, so it sends no `RateLimit-*` headers.
+//!
+//! `RATE_LIMIT_COUNTRIES` adjusts the limit by requester country (`cf.country`):
+//! `{"XX": {"multiplier": 0.25}, "YY": {"limit": 10}}` scales
+//! `RATE_LIMIT_REQUESTS` or replaces it with a hard limit (which applies
+//! even when no global limit is set). Requests from a country with a rule
+//! are always counted in the Durable Object, whose limit can vary per
+//! request; use `BLOCKED_COUNTRIES` to refuse a country outright.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, quota, responses};
+
+const DEFAULT_WINDOW_SECS: u64 = 60;
+const NATIVE_BINDING: &str = "RATE_LIMITER";
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub struct CountryRule {
+    #[serde(default)]
+    pub multiplier: Option<f64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+pub fn country_rule<'a>(
+    rules: &'a HashMap<String, CountryRule>,
+    country: Option<&str>,
+) -> Option<&'a CountryRule> {
+    let country = country?;
+    rules
+        .iter()
+        .find(|(code, _)| code.eq_ignore_ascii_case(country))
+        .map(|(_, rule)| rule)
+}
+
+/// The per-IP limit after applying a country rule (never below 1).
+pub fn effective_limit(base: Option<u64>, rule: Option<&CountryRule>) -> Option<u64> {
+    let limit = match rule {
+        Some(CountryRule {
+            limit: Some(limit), ..
+        }) => *limit,
+        Some(CountryRule {
+            multiplier: Some(multiplier),
+            ..
+        }) => (base? as f64 * multiplier).floor() as u64,
+        _ => base?,
+    };
+    Some(limit.max(1))
+}
+
+/// Whether the native binding should be used when it is bound.
+pub fn prefers_native(backend: Option<&str>) -> bool {
+    !matches!(backend, Some("durable_object"))
+}
+
+/// Counter request for one proxied call; `None` when limiting is off.
+pub fn consume_request(
+    limit: Option<u64>,
+    window_secs: Option<u64>,
+) -> Option<quota::ConsumeRequest> {
+    Some(quota::ConsumeRequest {
+        bucket: "requests".into(),
+        amount: 1,
+        limit: limit.filter(|l| *l > 0)?,
+        window_secs: window_secs
+            .filter(|w| *w > 0)
+            .unwrap_or(DEFAULT_WINDOW_SECS),
+        sliding: true,
+        force: false,
+    })
+}
+
+pub fn headers(result: &quota::ConsumeResult, now_secs: u64) -> Vec<(&'static str, String)> {
+    vec![
+        ("RateLimit-Limit", result.limit.to_string()),
+        (
+            "RateLimit-Remaining",
+            result.limit.saturating_sub(result.used).to_string(),
+        ),
+        (
+            "RateLimit-Reset",
+            result.reset_at.saturating_sub(now_secs).to_string(),
+        ),
+    ]
+}
+
+/// Limit through the native binding; fails open if the binding errors.
+async fn check_native(
+    limiter: RateLimiter,
+    env: &Env,
+    rctx: &RequestCtx,
+    ip: &str,
+) -> Result<Option<Response>> {
+    match limiter.limit(format!("ip:{ip}")).await {
+        Ok(outcome) if !outcome.success => {
+            console_log!("[{}] Rate limited {}", rctx.id, ip);
+            let window =
+                config::var_u64(env, "RATE_LIMIT_WINDOW_SECS").unwrap_or(DEFAULT_WINDOW_SECS);
+            let mut response =
+                responses::error(429, "rate_limited", "Too many requests, slow down")?;
+            response
+                .headers_mut()
+                .set("Retry-After", &window.to_string())?;
+            Ok(Some(response))
+        }
+        Ok(_) => Ok(None),
+        Err(e) => {
+            console_error!("Native rate limiter failed: {:?}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Count the request; rate-limit headers for the client are added to `rctx`.
+pub async fn check(env: &Env, rctx: &mut RequestCtx) -> Result<Option<Response>> {
+    let rules: HashMap<String, CountryRule> =
+        config::var_json(env, "RATE_LIMIT_COUNTRIES").unwrap_or_default();
+    let rule = country_rule(&rules, rctx.country.as_deref());
+    if rule.is_none() && prefers_native(config::var(env, "RATE_LIMIT_BACKEND").as_deref()) {
+        if let (Ok(limiter), Some(ip)) = (env.rate_limiter(NATIVE_BINDING), &rctx.client_ip) {
+            return check_native(limiter, env, rctx, ip).await;
+        }
+    }
+    let Some(request) = consume_request(
+        effective_limit(config::var_u64(env, "RATE_LIMIT_REQUESTS"), rule),
+        config::var_u64(env, "RATE_LIMIT_WINDOW_SECS"),
+    ) else {
+        return Ok(None);
+    };
+    let Some(ip) = rctx.client_ip.clone() else {
+        return Ok(None);
+    };
+    let Some(result) = quota::consume(env, &format!("ip:{ip}"), &request).await else {
+        return Ok(None);
+    };
+    let headers = headers(&result, Date::now().as_millis() / 1000);
+    if result.allowed {
+        rctx.extra_headers.extend(headers);
+        return Ok(None);
+    }
+    console_log!("[{}] Rate limited {}", rctx.id, ip);
+    let mut response =
+        quota::too_many_requests(&result, "rate_limited", "Too many requests, slow down")?;
+    for (name, value) in headers {
+        response.headers_mut().set(name, &value)?;
+    }
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let result = quota::ConsumeResult {
+            allowed: true,
+            used: 7,
+            limit: 10,
+            reset_at: 1_060,
+        };
+        assert_eq!(
+            headers(&result, 1_015),
+            vec![
+                ("RateLimit-Limit", "10".to_string()),
+                ("RateLimit-Remaining", "3".to_string()),
+                ("RateLimit-Reset", "45".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_country_rules() {
+        let rules: HashMap<String, CountryRule> = serde_json::from_str(
+            r#"{"XX": {"multiplier": 0.25}, "yy": {"limit": 10}, "ZZ": {"multiplier": 0}}"#,
+        )
+        .unwrap();
+        let limit = |country| effective_limit(Some(100), country_rule(&rules, country));
+        assert_eq!(limit(Some("XX")), Some(25));
+        assert_eq!(limit(Some("YY")), Some(10));
+        assert_eq!(limit(Some("ZZ")), Some(1));
+        assert_eq!(limit(Some("FR")), Some(100));
+        assert_eq!(limit(None), Some(100));
+        // A hard limit applies without a global one; a multiplier does not.
+        assert_eq!(
+            effective_limit(None, country_rule(&rules, Some("YY"))),
+            Some(10)
+        );
+        assert_eq!(
+            effective_limit(None, country_rule(&rules, Some("XX"))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_prefers_native() {
+        assert!(prefers_native(None));
+        assert!(prefers_native(Some("native")));
+        assert!(!prefers_native(Some("durable_object")));
+    }
+
+    #[test]
+    fn test_consume_request() {
+        assert!(consume_request(None, Some(10)).is_none());
+        assert!(consume_request(Some(0), None).is_none());
+        let request = consume_request(Some(100), None).unwrap();
+        assert_eq!(
+            (request.limit, request.window_secs),
+            (100, DEFAULT_WINDOW_SECS)
+        );
+        assert_eq!(consume_request(Some(5), Some(1)).unwrap().window_secs, 1);
+    }
+}
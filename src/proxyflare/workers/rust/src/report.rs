@@ -0,0 +1,168 @@
+//! `POST /report` abuse reporting endpoint.
+//!
+//! Reports are pushed to the `REPORTS_QUEUE` queue when bound, otherwise
+//! stored in the `REPORTS_KV` namespace under `report:<id>` for operator
+//! review. Submissions are limited per client IP (`REPORT_RATE_LIMIT` per
+//! hour, default 5), tracked in `REPORTS_KV`; the endpoint answers 503
+//! without that namespace, even with the queue bound, rather than accept
+//! unlimited reports.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::{config, responses, utils};
+
+pub const PATH: &str = "/report";
+
+const REPORTS_KV: &str = "REPORTS_KV";
+const REPORTS_QUEUE: &str = "REPORTS_QUEUE";
+const DEFAULT_RATE_LIMIT: u64 = 5;
+const RATE_WINDOW_SECS: u64 = 3600;
+
+const MAX_URL_LEN: usize = 2048;
+const MAX_REASON_LEN: usize = 2000;
+const MAX_CONTACT_LEN: usize = 256;
+
+#[derive(Debug, Deserialize)]
+pub struct ReportInput {
+    pub url: String,
+    pub reason: String,
+    #[serde(default)]
+    pub contact: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbuseReport {
+    pub id: String,
+    pub url: String,
+    pub reason: String,
+    pub contact: Option<String>,
+    pub reporter_ip: Option<String>,
+    pub received_at: String,
+}
+
+/// Check a submission, returning a description of the first problem found.
+pub fn validate(input: &ReportInput) -> std::result::Result<(), &'static str> {
+    if input.url.trim().is_empty() || input.url.len() > MAX_URL_LEN {
+        return Err("`url` is required and must be at most 2048 characters");
+    }
+    if url::Url::parse(input.url.trim()).is_err() {
+        return Err("`url` must be an absolute URL");
+    }
+    if input.reason.trim().is_empty() || input.reason.len() > MAX_REASON_LEN {
+        return Err("`reason` is required and must be at most 2000 characters");
+    }
+    if input
+        .contact
+        .as_ref()
+        .is_some_and(|c| c.len() > MAX_CONTACT_LEN)
+    {
+        return Err("`contact` must be at most 256 characters");
+    }
+    Ok(())
+}
+
+/// Count this submission against the client's hourly allowance.
+async fn within_rate_limit(kv: &KvStore, ip: &str, limit: u64) -> Result<bool> {
+    let key = format!("ratelimit:{ip}");
+    let count: u64 = kv
+        .get(&key)
+        .text()
+        .await?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    if count >= limit {
+        return Ok(false);
+    }
+    kv.put(&key, (count + 1).to_string())?
+        .expiration_ttl(RATE_WINDOW_SECS)
+        .execute()
+        .await?;
+    Ok(true)
+}
+
+pub async fn handle(mut req: Request, env: &Env) -> Result<Response> {
+    let Ok(kv) = env.kv(REPORTS_KV) else {
+        return responses::error(
+            503,
+            "reporting_unavailable",
+            "Abuse reporting is not configured",
+        );
+    };
+    let queue = env.queue(REPORTS_QUEUE).ok();
+
+    let input: ReportInput = match req.json().await {
+        Ok(input) => input,
+        Err(_) => return responses::error(400, "invalid_report", "Expected a JSON body"),
+    };
+    if let Err(message) = validate(&input) {
+        return responses::error(400, "invalid_report", message);
+    }
+
+    let reporter_ip = utils::client_ip(&req);
+    // Clients without a known IP share one allowance.
+    let limiter_key = reporter_ip.as_deref().unwrap_or("unknown");
+    let limit = config::var_u64(env, "REPORT_RATE_LIMIT").unwrap_or(DEFAULT_RATE_LIMIT);
+    if !within_rate_limit(&kv, limiter_key, limit).await? {
+        let mut response =
+            responses::error(429, "rate_limited", "Too many reports, try again later")?;
+        response
+            .headers_mut()
+            .set("Retry-After", &RATE_WINDOW_SECS.to_string())?;
+        return Ok(response);
+    }
+
+    let report = AbuseReport {
+        id: utils::random_id(),
+        url: input.url.trim().to_string(),
+        reason: input.reason.trim().to_string(),
+        contact: input.contact.map(|c| c.trim().to_string()),
+        reporter_ip,
+        received_at: utils::now_iso(),
+    };
+
+    match &queue {
+        Some(queue) => queue.send(&report).await?,
+        None => {
+            kv.put(&format!("report:{}", report.id), &report)?
+                .execute()
+                .await?
+        }
+    }
+    console_log!("Abuse report {} received for {}", report.id, report.url);
+
+    responses::json(202, &json!({ "status": "received", "id": report.id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(url: &str, reason: &str) -> ReportInput {
+        ReportInput {
+            url: url.into(),
+            reason: reason.into(),
+            contact: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_complete_report() {
+        assert!(validate(&input("https://example.com/x", "phishing")).is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_missing_fields() {
+        assert!(validate(&input("", "phishing")).is_err());
+        assert!(validate(&input("not a url", "phishing")).is_err());
+        assert!(validate(&input("https://example.com", "  ")).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_oversized_contact() {
+        let mut report = input("https://example.com", "spam");
+        report.contact = Some("a".repeat(MAX_CONTACT_LEN + 1));
+        assert!(validate(&report).is_err());
+    }
+}
@@ -0,0 +1,20 @@
+//! Structured (JSON) responses generated by the worker itself.
+
+use serde_json::{json, Value};
+use worker::{Response, Result};
+
+use crate::diagnostics;
+
+/// Build a JSON response with the given status.
+pub fn json(status: u16, body: &Value) -> Result<Response> {
+    Ok(Response::from_json(body)?.with_status(status))
+}
+
+/// Build a machine-readable error: `{"error": code, "message": message}`,
+/// plus the `build` that produced it.
+pub fn error(status: u16, code: &str, message: &str) -> Result<Response> {
+    json(
+        status,
+        &json!({ "error": code, "message": message, "build": diagnostics::build_id() }),
+    )
+}
@@ -0,0 +1,108 @@
+//! The front door: `GET /` without a target.
+//!
+//! Browsers (an `Accept` that includes `text/html`) are redirected to
+//! `ROOT_REDIRECT_URL`, e.g. a docs or landing page. API clients (an
+//! `Accept` that includes JSON) get service metadata when `ROOT_INFO` is
+//! set: a JSON object merged over `{"service", "version", "usage"}`, so
+//! `{}` serves the defaults and `{"docs": "https://..."}` adds a field.
+//! Anything else, or an unset setting, keeps the plain "Missing target URL"
+//! 400. Runs before authentication so the front door is public.
+
+use serde_json::{json, Map, Value};
+use worker::*;
+
+use crate::{config, diagnostics, responses};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Client {
+    Browser,
+    Api,
+    Other,
+}
+
+/// Who is asking, judged by `Accept`.
+pub fn classify(accept: Option<&str>) -> Client {
+    let accept = accept.unwrap_or_default().to_ascii_lowercase();
+    let types: Vec<&str> = accept
+        .split(',')
+        .map(|t| t.split(';').next().unwrap_or_default().trim())
+        .collect();
+    if types.contains(&"text/html") {
+        Client::Browser
+    } else if types
+        .iter()
+        .any(|t| *t == "application/json" || t.ends_with("+json"))
+    {
+        Client::Api
+    } else {
+        Client::Other
+    }
+}
+
+/// Default metadata with `extra`'s fields laid over it.
+pub fn metadata(extra: Map<String, Value>) -> Value {
+    let mut info = json!({
+        "service": "proxyflare",
+        "version": diagnostics::VERSION,
+        "usage": "GET /?url=<target URL>, /<target URL> or an X-Target-URL header",
+    });
+    if let Some(fields) = info.as_object_mut() {
+        fields.extend(extra);
+    }
+    info
+}
+
+/// Whether `req` is for the root route rather than a proxied target.
+pub fn matches(req: &Request, url: &Url) -> Result<bool> {
+    Ok(matches!(req.method(), Method::Get | Method::Head)
+        && url.path() == "/"
+        && !url.query_pairs().any(|(k, _)| k == "url")
+        && !req.headers().has("X-Target-URL")?)
+}
+
+/// The configured front door for this client, or `None` for the fallback.
+pub fn handle(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let accept = req.headers().get("Accept")?;
+    match classify(accept.as_deref()) {
+        Client::Browser => match config::var(env, "ROOT_REDIRECT_URL")
+            .and_then(|target| Url::parse(&target).ok())
+        {
+            Some(target) => Response::redirect_with_status(target, 302).map(Some),
+            None => Ok(None),
+        },
+        Client::Api => match config::var_json::<Map<String, Value>>(env, "ROOT_INFO") {
+            Some(extra) => responses::json(200, &metadata(extra)).map(Some),
+            None => Ok(None),
+        },
+        Client::Other => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify(Some("text/html,application/xhtml+xml,*/*;q=0.8")),
+            Client::Browser
+        );
+        assert_eq!(classify(Some("application/json")), Client::Api);
+        assert_eq!(
+            classify(Some("application/problem+json; q=0.9")),
+            Client::Api
+        );
+        assert_eq!(classify(Some("*/*")), Client::Other);
+        assert_eq!(classify(None), Client::Other);
+    }
+
+    #[test]
+    fn test_metadata_overrides() {
+        let extra = json!({"docs": "https://docs.example", "service": "acme-proxy"});
+        let info = metadata(extra.as_object().cloned().unwrap());
+        assert_eq!(info["service"], "acme-proxy");
+        assert_eq!(info["docs"], "https://docs.example");
+        assert_eq!(info["version"], diagnostics::VERSION);
+    }
+}
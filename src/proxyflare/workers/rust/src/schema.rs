@@ -0,0 +1,375 @@
+//! Schema inference from sampled JSON traffic.
+//!
+//! With `SCHEMA_SAMPLE_RATE` set (a fraction, e.g. `0.01`) and a `SCHEMA_KV`
+//! namespace bound, that share of proxied calls has its JSON request and
+//! successful JSON response bodies folded into an inferred schema for the
+//! route: method, host and path with id-like segments replaced by `{id}`.
+//! Bodies are only sampled when they declare a `Content-Length` of at most
+//! `SCHEMA_SAMPLE_MAX_BYTES` (default 64 KiB).
+//!
+//! Only the shape is kept — types, object properties, which of them were
+//! always present, and one short example per scalar. Examples under a field
+//! named in `SCHEMA_REDACT_FIELDS` (default: common credential and personal
+//! fields) are replaced by `"[redacted]"`. Schemas are merged with a KV
+//! read-modify-write, so concurrent samples may occasionally be lost.
+//!
+//! `GET /admin/schemas` lists the observed routes; `?route=<route>` returns
+//! one route's schema.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::{auth, config, responses};
+
+const KV_BINDING: &str = "SCHEMA_KV";
+pub const KV_PREFIX: &str = "schema:";
+const DEFAULT_MAX_BYTES: u64 = 64 * 1024;
+const DEFAULT_REDACT_FIELDS: &[&str] = &[
+    "password",
+    "secret",
+    "token",
+    "access_token",
+    "refresh_token",
+    "api_key",
+    "authorization",
+    "email",
+    "phone",
+    "ssn",
+];
+const REDACTED: &str = "[redacted]";
+/// Nesting below this depth is not described.
+const MAX_DEPTH: usize = 16;
+/// Objects with more keys than this are treated as maps and not expanded.
+const MAX_PROPERTIES: usize = 200;
+const MAX_EXAMPLE_CHARS: usize = 64;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Schema {
+    #[serde(rename = "type")]
+    pub types: BTreeSet<String>,
+    /// Number of values observed at this position.
+    pub seen: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, Schema>,
+    /// Properties present in every object observed here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required: Option<BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<Schema>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub example: Option<Value>,
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn example(value: &Value) -> Value {
+    match value {
+        Value::String(s) if s.chars().count() > MAX_EXAMPLE_CHARS => {
+            Value::String(s.chars().take(MAX_EXAMPLE_CHARS).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+impl Schema {
+    /// Fold `value` into the schema. `redact` marks values under a redacted
+    /// field; `redact_fields` are matched case-insensitively.
+    pub fn observe(&mut self, value: &Value, redact: bool, redact_fields: &[String]) {
+        self.observe_at(value, redact, redact_fields, 0);
+    }
+
+    fn observe_at(&mut self, value: &Value, redact: bool, redact_fields: &[String], depth: usize) {
+        self.seen += 1;
+        self.types.insert(type_name(value).to_string());
+        if depth >= MAX_DEPTH {
+            return;
+        }
+        match value {
+            Value::Object(map) if map.len() <= MAX_PROPERTIES => {
+                let present: BTreeSet<String> = map.keys().cloned().collect();
+                self.required = Some(match self.required.take() {
+                    Some(required) => required.intersection(&present).cloned().collect(),
+                    None => present,
+                });
+                for (key, child) in map {
+                    let redact =
+                        redact || redact_fields.iter().any(|f| f.eq_ignore_ascii_case(key));
+                    self.properties.entry(key.clone()).or_default().observe_at(
+                        child,
+                        redact,
+                        redact_fields,
+                        depth + 1,
+                    );
+                }
+            }
+            Value::Object(_) => {}
+            Value::Array(items) => {
+                let schema = self.items.get_or_insert_with(Default::default);
+                for item in items {
+                    schema.observe_at(item, redact, redact_fields, depth + 1);
+                }
+            }
+            Value::Null => {}
+            scalar if self.example.is_none() => {
+                self.example = Some(if redact {
+                    Value::String(REDACTED.into())
+                } else {
+                    example(scalar)
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteSchema {
+    pub route: String,
+    pub samples: u64,
+    pub updated_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<Schema>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Schema>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SchemaMetadata {
+    route: String,
+    samples: u64,
+    updated_at: u64,
+}
+
+impl RouteSchema {
+    pub fn new(route: &str) -> Self {
+        RouteSchema {
+            route: route.to_string(),
+            samples: 0,
+            updated_at: 0,
+            request: None,
+            response: None,
+        }
+    }
+
+    pub fn merge(&mut self, sample: &Sample, redact_fields: &[String], now_secs: u64) {
+        self.samples += 1;
+        self.updated_at = now_secs;
+        if let Some(body) = &sample.request {
+            self.request
+                .get_or_insert_with(Default::default)
+                .observe(body, false, redact_fields);
+        }
+        if let Some(body) = &sample.response {
+            self.response
+                .get_or_insert_with(Default::default)
+                .observe(body, false, redact_fields);
+        }
+    }
+}
+
+/// Bodies captured from one sampled call.
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    pub route: String,
+    pub request: Option<Value>,
+    pub response: Option<Value>,
+}
+
+/// Whether a path segment looks like an identifier rather than a resource name.
+pub fn is_id_segment(segment: &str) -> bool {
+    let hex_or_dash = |c: char| c.is_ascii_hexdigit() || c == '-';
+    (!segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()))
+        || (segment.len() == 36 && segment.chars().all(hex_or_dash))
+        || (segment.len() >= 16 && segment.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Route name for `method` on `target`, e.g. `GET api.example.com/v1/users/{id}`.
+pub fn route(method: &Method, target: &Url) -> String {
+    let path: Vec<&str> = target
+        .path()
+        .split('/')
+        .map(|s| if is_id_segment(s) { "{id}" } else { s })
+        .collect();
+    format!(
+        "{} {}{}",
+        method,
+        target.host_str().unwrap_or_default(),
+        path.join("/")
+    )
+}
+
+fn storage_key(route: &str) -> String {
+    format!("{KV_PREFIX}{}", &auth::sha256_hex(route)[..32])
+}
+
+/// Whether a body with these headers should be captured.
+pub fn body_sampleable(content_type: Option<&str>, content_length: Option<u64>, max: u64) -> bool {
+    content_type.is_some_and(|ct| ct.to_ascii_lowercase().contains("json"))
+        && content_length.is_some_and(|len| len > 0 && len <= max)
+}
+
+/// Largest body that is sampled.
+pub fn max_bytes(env: &Env) -> u64 {
+    config::var_u64(env, "SCHEMA_SAMPLE_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES)
+}
+
+/// Start a sample for this call if it is picked.
+pub fn sample(env: &Env, method: &Method, target: &Url) -> Option<Sample> {
+    let rate: f64 = config::var(env, "SCHEMA_SAMPLE_RATE")?.parse().ok()?;
+    if env.kv(KV_BINDING).is_err() || js_sys::Math::random() >= rate {
+        return None;
+    }
+    Some(Sample {
+        route: route(method, target),
+        ..Default::default()
+    })
+}
+
+fn redact_fields(env: &Env) -> Vec<String> {
+    match config::var_list(env, "SCHEMA_REDACT_FIELDS") {
+        fields if fields.is_empty() => DEFAULT_REDACT_FIELDS
+            .iter()
+            .map(|f| f.to_string())
+            .collect(),
+        fields => fields,
+    }
+}
+
+/// Merge `sample` into its route's stored schema after the response is sent.
+pub fn record(env: &Env, ctx: &Context, sample: Sample) {
+    if sample.request.is_none() && sample.response.is_none() {
+        return;
+    }
+    let Ok(kv) = env.kv(KV_BINDING) else {
+        return;
+    };
+    let redact_fields = redact_fields(env);
+    ctx.wait_until(async move {
+        let key = storage_key(&sample.route);
+        let mut schema = match kv.get(&key).json::<RouteSchema>().await {
+            Ok(Some(schema)) => schema,
+            _ => RouteSchema::new(&sample.route),
+        };
+        schema.merge(&sample, &redact_fields, Date::now().as_millis() / 1000);
+        let metadata = SchemaMetadata {
+            route: schema.route.clone(),
+            samples: schema.samples,
+            updated_at: schema.updated_at,
+        };
+        let stored = kv.put(&key, &schema).and_then(|p| p.metadata(metadata));
+        if let Err(e) = match stored {
+            Ok(put) => put.execute().await,
+            Err(e) => Err(e),
+        } {
+            console_error!("Schema update for {} failed: {:?}", sample.route, e);
+        }
+    });
+}
+
+/// `GET /admin/schemas[?route=<route>]`
+pub async fn admin_schemas(req: &Request, env: &Env) -> Result<Response> {
+    let Ok(kv) = env.kv(KV_BINDING) else {
+        return responses::error(503, "schemas_disabled", "SCHEMA_KV is not bound");
+    };
+    let url = req.url()?;
+    if let Some((_, route)) = url.query_pairs().find(|(k, _)| k == "route") {
+        return match kv.get(&storage_key(&route)).json::<RouteSchema>().await? {
+            Some(schema) => responses::json(200, &serde_json::to_value(&schema)?),
+            None => responses::error(404, "not_found", "No samples for this route"),
+        };
+    }
+    let page = kv.list().prefix(KV_PREFIX.into()).execute().await?;
+    let routes: Vec<_> = page
+        .keys
+        .into_iter()
+        .filter_map(|k| serde_json::from_value::<SchemaMetadata>(k.metadata?).ok())
+        .collect();
+    responses::json(200, &json!({ "routes": routes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe_all(values: &[Value]) -> Schema {
+        let redact: Vec<String> = vec!["password".into()];
+        let mut schema = Schema::default();
+        for value in values {
+            schema.observe(value, false, &redact);
+        }
+        schema
+    }
+
+    #[test]
+    fn test_observe_infers_types_and_required() {
+        let schema = observe_all(&[
+            json!({"id": 1, "name": "a", "tags": ["x"]}),
+            json!({"id": 2, "score": 0.5}),
+        ]);
+        assert_eq!(schema.seen, 2);
+        assert_eq!(schema.required, Some(BTreeSet::from(["id".to_string()])));
+        assert_eq!(
+            schema.properties["id"].types,
+            BTreeSet::from(["integer".to_string()])
+        );
+        assert_eq!(
+            schema.properties["score"].types,
+            BTreeSet::from(["number".to_string()])
+        );
+        let tags = schema.properties["tags"].items.as_ref().unwrap();
+        assert_eq!(tags.example, Some(json!("x")));
+    }
+
+    #[test]
+    fn test_observe_redacts_examples() {
+        let schema = observe_all(&[json!({"user": {"Password": "hunter2", "name": "bob"}})]);
+        let user = &schema.properties["user"];
+        assert_eq!(user.properties["Password"].example, Some(json!(REDACTED)));
+        assert_eq!(user.properties["name"].example, Some(json!("bob")));
+    }
+
+    #[test]
+    fn test_observe_mixed_types() {
+        let schema = observe_all(&[json!({"v": null}), json!({"v": "x"})]);
+        assert_eq!(
+            schema.properties["v"].types,
+            BTreeSet::from(["null".to_string(), "string".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_route_normalizes_ids() {
+        let url = Url::parse(
+            "https://api.example.com/v1/users/42/orders/123e4567-e89b-12d3-a456-426614174000?x=1",
+        )
+        .unwrap();
+        assert_eq!(
+            route(&Method::Get, &url),
+            "GET api.example.com/v1/users/{id}/orders/{id}"
+        );
+        assert!(!is_id_segment("v1"));
+        assert!(!is_id_segment(""));
+        assert!(is_id_segment("5f2b9c0e8a1d4b3c"));
+    }
+
+    #[test]
+    fn test_body_sampleable() {
+        assert!(body_sampleable(Some("application/json"), Some(10), 100));
+        assert!(!body_sampleable(Some("application/json"), None, 100));
+        assert!(!body_sampleable(Some("application/json"), Some(101), 100));
+        assert!(!body_sampleable(Some("text/html"), Some(10), 100));
+    }
+}
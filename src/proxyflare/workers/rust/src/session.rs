@@ -0,0 +1,190 @@
+//! Small, expiring key/value scratch space per authenticated caller.
+//!
+//! With a `SESSION_KV` namespace bound, authenticated callers get
+//! `/session/kv/<key>` for stashing tokens, cursors and the like without a
+//! backend of their own:
+//!
+//! - `PUT` stores the request body (at most `SESSION_KV_MAX_BYTES`, default
+//!   8 KiB) for `?ttl=<secs>` (default 3600, capped at
+//!   `SESSION_KV_MAX_TTL_SECS`, default 86400);
+//! - `GET` returns it with the content type it was stored with;
+//! - `DELETE` removes it; `GET /session/kv` lists the caller's keys.
+//!
+//! Entries are namespaced by a hash of the caller id, so one caller can never
+//! see another's keys.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{auth, config, responses};
+
+pub const PREFIX: &str = "/session/kv";
+const KV_BINDING: &str = "SESSION_KV";
+const DEFAULT_MAX_BYTES: u64 = 8 * 1024;
+const DEFAULT_TTL_SECS: u64 = 3600;
+const DEFAULT_MAX_TTL_SECS: u64 = 86_400;
+/// Shortest expiration KV accepts.
+const MIN_TTL_SECS: u64 = 60;
+const MAX_KEY_LEN: usize = 128;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryMetadata {
+    content_type: String,
+}
+
+pub fn matches(path: &str) -> bool {
+    path == PREFIX || path.starts_with(&format!("{PREFIX}/"))
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+/// KV prefix for a caller's entries.
+pub fn namespace(caller: &str) -> String {
+    format!("session:{}:", &auth::sha256_hex(caller)[..16])
+}
+
+pub fn ttl(requested: Option<u64>, max: u64) -> u64 {
+    requested
+        .unwrap_or(DEFAULT_TTL_SECS)
+        .clamp(MIN_TTL_SECS, max.max(MIN_TTL_SECS))
+}
+
+pub async fn handle(mut req: Request, env: &Env, rctx: &RequestCtx) -> Result<Response> {
+    let Some(caller) = &rctx.caller else {
+        return responses::error(
+            401,
+            "unauthorized",
+            "Session storage requires an authenticated caller",
+        );
+    };
+    let Ok(kv) = env.kv(KV_BINDING) else {
+        return responses::error(503, "session_kv_disabled", "SESSION_KV is not bound");
+    };
+    let namespace = namespace(caller);
+    let path = rctx.url.path();
+    let key = path
+        .strip_prefix(PREFIX)
+        .unwrap_or_default()
+        .trim_start_matches('/');
+
+    if key.is_empty() {
+        if rctx.method != Method::Get {
+            return responses::error(405, "method_not_allowed", "Use GET to list keys");
+        }
+        let page = kv.list().prefix(namespace.clone()).execute().await?;
+        let keys: Vec<_> = page
+            .keys
+            .iter()
+            .map(|k| {
+                json!({
+                    "key": k.name.trim_start_matches(&namespace),
+                    "expires_at": k.expiration,
+                })
+            })
+            .collect();
+        return responses::json(200, &json!({ "keys": keys }));
+    }
+    if !is_valid_key(key) {
+        return responses::error(
+            400,
+            "invalid_key",
+            "Keys are 1-128 characters of A-Z, a-z, 0-9, '.', '_' and '-'",
+        );
+    }
+    let name = format!("{namespace}{key}");
+
+    match rctx.method {
+        Method::Get => {
+            let (value, metadata) = kv.get(&name).bytes_with_metadata::<EntryMetadata>().await?;
+            let Some(value) = value else {
+                return responses::error(404, "not_found", "No such key");
+            };
+            let mut response = Response::from_bytes(value)?;
+            let headers = response.headers_mut();
+            headers.set(
+                "Content-Type",
+                &metadata.map_or("application/octet-stream".into(), |m| m.content_type),
+            )?;
+            headers.set("Cache-Control", "no-store")?;
+            Ok(response)
+        }
+        Method::Put => {
+            let max_bytes =
+                config::var_u64(env, "SESSION_KV_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES);
+            let body = req.bytes().await?;
+            if body.len() as u64 > max_bytes {
+                return responses::error(
+                    413,
+                    "value_too_large",
+                    &format!("Values are limited to {max_bytes} bytes"),
+                );
+            }
+            let requested = rctx
+                .url
+                .query_pairs()
+                .find(|(k, _)| k == "ttl")
+                .and_then(|(_, v)| v.parse().ok());
+            let max_ttl =
+                config::var_u64(env, "SESSION_KV_MAX_TTL_SECS").unwrap_or(DEFAULT_MAX_TTL_SECS);
+            let ttl = ttl(requested, max_ttl);
+            let content_type = req
+                .headers()
+                .get("Content-Type")?
+                .unwrap_or_else(|| "application/octet-stream".into());
+            kv.put_bytes(&name, &body)?
+                .expiration_ttl(ttl)
+                .metadata(EntryMetadata { content_type })?
+                .execute()
+                .await?;
+            responses::json(200, &json!({ "key": key, "ttl": ttl }))
+        }
+        Method::Delete => {
+            kv.delete(&name).await?;
+            Ok(Response::empty()?.with_status(204))
+        }
+        _ => responses::error(405, "method_not_allowed", "Use GET, PUT or DELETE"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("/session/kv"));
+        assert!(matches("/session/kv/cursor"));
+        assert!(!matches("/session/kvx"));
+    }
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(is_valid_key("cursor.page-2_a"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("a/b"));
+        assert!(!is_valid_key(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn test_namespace_separates_callers() {
+        assert_ne!(namespace("team-a"), namespace("team-b"));
+        assert!(namespace("team-a").starts_with("session:"));
+        assert!(!namespace("team-a").contains("team-a"));
+    }
+
+    #[test]
+    fn test_ttl_clamped() {
+        assert_eq!(ttl(None, 86_400), DEFAULT_TTL_SECS);
+        assert_eq!(ttl(Some(5), 86_400), MIN_TTL_SECS);
+        assert_eq!(ttl(Some(1_000_000), 86_400), 86_400);
+        assert_eq!(ttl(Some(600), 10), MIN_TTL_SECS);
+    }
+}
@@ -0,0 +1,194 @@
+//! HMAC-signed, expiring proxy links.
+//!
+//! With `URL_SIGNING_SECRET` set, `?url=<target>&exp=<unix secs>&sig=<hex>`
+//! is accepted in place of an API key, where `sig` is
+//! `HMAC-SHA256(secret, "<target>\n<exp>")`. Links stay valid for
+//! `URL_SIGNATURE_SKEW_SECS` (default 30) past `exp` to absorb clock skew.
+//! A `sig` that does not verify is not treated as a signed link at all, so
+//! a target URL that carries its own `exp`/`sig` params still goes through
+//! the usual authentication; `exp` and `sig` are only consumed (not
+//! forwarded) on requests that are valid signed links.
+//! Operators mint links with `GET /admin/sign?url=<target>&ttl=<secs>`.
+
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use worker::*;
+
+use crate::{config, responses, utils};
+
+pub const EXP_PARAM: &str = "exp";
+pub const SIG_PARAM: &str = "sig";
+
+const SECRET_VAR: &str = "URL_SIGNING_SECRET";
+const DEFAULT_SKEW_SECS: u64 = 30;
+const DEFAULT_TTL_SECS: u64 = 3600;
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Malformed,
+    Expired,
+    Mismatch,
+}
+
+#[derive(Debug)]
+pub enum SignatureCheck {
+    /// Signing is not configured or the request carries no (valid) signature.
+    Absent,
+    Valid,
+    Rejected(Response),
+}
+
+/// Hex HMAC-SHA256 over `"<target>\n<exp>"`.
+pub fn sign(secret: &str, target: &str, exp: u64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{target}\n{exp}").as_bytes());
+    utils::hex(&mac.finalize().into_bytes())
+}
+
+pub fn verify(
+    secret: &str,
+    target: &str,
+    exp: &str,
+    sig: &str,
+    now_secs: u64,
+    skew_secs: u64,
+) -> std::result::Result<(), SignatureError> {
+    let exp: u64 = exp.parse().map_err(|_| SignatureError::Malformed)?;
+    let expected = sign(secret, target, exp);
+    if !utils::constant_time_eq(expected.as_bytes(), sig.to_ascii_lowercase().as_bytes()) {
+        return Err(SignatureError::Mismatch);
+    }
+    if now_secs > exp.saturating_add(skew_secs) {
+        return Err(SignatureError::Expired);
+    }
+    Ok(())
+}
+
+fn now_secs() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+/// Check the worker URL for a signed link.
+pub fn check(url: &Url, env: &Env) -> Result<SignatureCheck> {
+    let Some(secret) = config::var(env, SECRET_VAR) else {
+        return Ok(SignatureCheck::Absent);
+    };
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let Some(sig) = param(SIG_PARAM) else {
+        return Ok(SignatureCheck::Absent);
+    };
+    let (Some(target), Some(exp)) = (param("url"), param(EXP_PARAM)) else {
+        return Ok(SignatureCheck::Absent);
+    };
+    let skew = config::var_u64(env, "URL_SIGNATURE_SKEW_SECS").unwrap_or(DEFAULT_SKEW_SECS);
+    match verify(&secret, &target, &exp, &sig, now_secs(), skew) {
+        Ok(()) => Ok(SignatureCheck::Valid),
+        Err(SignatureError::Expired) => {
+            responses::error(403, "link_expired", "This signed link has expired")
+                .map(SignatureCheck::Rejected)
+        }
+        // Not our signature: maybe the target's own params.
+        Err(_) => Ok(SignatureCheck::Absent),
+    }
+}
+
+/// A signed proxy link on the worker at `origin` for `target`, valid for
+/// `ttl_secs`. `None` when signing is not configured.
+pub fn signed_link(env: &Env, origin: &Url, target: &str, ttl_secs: u64) -> Option<(Url, u64)> {
+    let secret = config::var(env, SECRET_VAR)?;
+    let exp = now_secs().saturating_add(ttl_secs);
+    let mut link = origin.clone();
+    link.set_path("/");
+    link.set_fragment(None);
+    link.query_pairs_mut()
+        .clear()
+        .append_pair("url", target)
+        .append_pair(EXP_PARAM, &exp.to_string())
+        .append_pair(SIG_PARAM, &sign(&secret, target, exp));
+    Some((link, exp))
+}
+
+pub fn ttl_param(url: &Url) -> u64 {
+    url.query_pairs()
+        .find(|(k, _)| k == "ttl")
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECS)
+}
+
+/// `GET /admin/sign?url=<target>&ttl=<secs>`
+pub fn admin_sign(req: &Request, env: &Env) -> Result<Response> {
+    let url = req.url()?;
+    let Some(target) = url
+        .query_pairs()
+        .find(|(k, _)| k == "url")
+        .map(|(_, v)| v.into_owned())
+        .filter(|t| Url::parse(t).is_ok())
+    else {
+        return responses::error(
+            400,
+            "invalid_request",
+            "Query parameter `url` must be a URL",
+        );
+    };
+    let Some((link, exp)) = signed_link(env, &url, &target, ttl_param(&url)) else {
+        return responses::error(503, "signing_disabled", "URL_SIGNING_SECRET is not set");
+    };
+    responses::json(
+        200,
+        &json!({ "url": link.as_str(), "target": target, "exp": exp }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "s3cret";
+    const TARGET: &str = "https://example.com/video.mp4";
+
+    #[test]
+    fn test_sign_is_deterministic_hex() {
+        let sig = sign(SECRET, TARGET, 1_700_000_000);
+        assert_eq!(sig, sign(SECRET, TARGET, 1_700_000_000));
+        assert_eq!(sig.len(), 64);
+        assert_ne!(sig, sign(SECRET, TARGET, 1_700_000_001));
+    }
+
+    #[test]
+    fn test_verify_valid_and_tampered() {
+        let sig = sign(SECRET, TARGET, 1000);
+        assert_eq!(verify(SECRET, TARGET, "1000", &sig, 900, 30), Ok(()));
+        assert_eq!(
+            verify(SECRET, "https://example.com/other", "1000", &sig, 900, 30),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify(SECRET, TARGET, "2000", &sig, 900, 30),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify(SECRET, TARGET, "soon", &sig, 900, 30),
+            Err(SignatureError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_verify_clock_skew() {
+        let sig = sign(SECRET, TARGET, 1000);
+        assert_eq!(verify(SECRET, TARGET, "1000", &sig, 1030, 30), Ok(()));
+        assert_eq!(
+            verify(SECRET, TARGET, "1000", &sig, 1031, 30),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            verify(SECRET, TARGET, "1000", &sig, 1001, 0),
+            Err(SignatureError::Expired)
+        );
+    }
+}
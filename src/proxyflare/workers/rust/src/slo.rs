@@ -0,0 +1,299 @@
+//! Availability SLO and error-budget tracking per upstream host.
+//!
+//! Each target host gets its own `SloTracker` Durable Object (binding
+//! `SLO_TRACKER`) holding hourly success/error buckets for a rolling 28-day
+//! window. Upstream 5xx responses and failed fetches count as errors.
+//!
+//! Settings: `SLO_TARGET` (default `0.999`), `SLO_BURN_ALERT` (burn-rate
+//! threshold over the last hour, default `14.4`), `SLO_MIN_REQUESTS` (requests
+//! the last hour needs before its burn rate is trusted, default 100, so a
+//! single failure on a quiet host does not page) and `SLO_ALERT_WEBHOOK`
+//! (URL receiving a JSON POST when the threshold is crossed, at most hourly).
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::{config, responses, utils};
+
+const BINDING: &str = "SLO_TRACKER";
+const STATE_KEY: &str = "state";
+const WINDOW_HOURS: u64 = 28 * 24;
+const DEFAULT_TARGET: f64 = 0.999;
+const DEFAULT_BURN_ALERT: f64 = 14.4;
+const DEFAULT_MIN_REQUESTS: u64 = 100;
+const MS_PER_HOUR: u64 = 3_600_000;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct Bucket {
+    pub ok: u64,
+    pub err: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SloState {
+    pub host: String,
+    /// Hour number since the epoch -> counts.
+    pub buckets: BTreeMap<u64, Bucket>,
+    pub last_alert_hour: Option<u64>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SloSummary {
+    pub host: String,
+    pub target: f64,
+    pub window_days: u64,
+    pub total: u64,
+    pub errors: u64,
+    pub availability: f64,
+    /// Share of the window's error budget still unspent (negative when blown).
+    pub budget_remaining: f64,
+    /// Error rate over the last hour relative to the budgeted error rate.
+    pub burn_rate_1h: f64,
+    /// Requests seen in the last hour.
+    pub requests_1h: u64,
+}
+
+impl SloSummary {
+    /// Whether the last hour burned the budget at `threshold` or faster, over
+    /// at least `min_requests` requests.
+    pub fn burning(&self, threshold: f64, min_requests: u64) -> bool {
+        self.requests_1h >= min_requests && self.burn_rate_1h >= threshold
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Outcome {
+    host: String,
+    ok: bool,
+}
+
+impl SloState {
+    pub fn record(&mut self, hour: u64, ok: bool) {
+        let bucket = self.buckets.entry(hour).or_default();
+        if ok {
+            bucket.ok += 1;
+        } else {
+            bucket.err += 1;
+        }
+        let oldest = hour.saturating_sub(WINDOW_HOURS - 1);
+        self.buckets.retain(|h, _| *h >= oldest);
+    }
+
+    pub fn summarize(&self, now_hour: u64, target: f64) -> SloSummary {
+        let oldest = now_hour.saturating_sub(WINDOW_HOURS - 1);
+        let (total, errors) = self
+            .buckets
+            .range(oldest..=now_hour)
+            .fold((0, 0), |(t, e), (_, b)| (t + b.ok + b.err, e + b.err));
+        let allowed_error_rate = (1.0 - target).max(f64::EPSILON);
+
+        let availability = if total == 0 {
+            1.0
+        } else {
+            1.0 - errors as f64 / total as f64
+        };
+        let budget_remaining = if total == 0 {
+            1.0
+        } else {
+            1.0 - (errors as f64 / total as f64) / allowed_error_rate
+        };
+        let last = self.buckets.get(&now_hour).copied().unwrap_or_default();
+        let burn_rate_1h = match last.ok + last.err {
+            0 => 0.0,
+            n => (last.err as f64 / n as f64) / allowed_error_rate,
+        };
+
+        SloSummary {
+            host: self.host.clone(),
+            target,
+            window_days: WINDOW_HOURS / 24,
+            total,
+            errors,
+            availability,
+            budget_remaining,
+            burn_rate_1h,
+            requests_1h: last.ok + last.err,
+        }
+    }
+}
+
+fn current_hour() -> u64 {
+    Date::now().as_millis() / MS_PER_HOUR
+}
+
+fn target(env: &Env) -> f64 {
+    config::var(env, "SLO_TARGET")
+        .and_then(|v| v.parse().ok())
+        .filter(|t: &f64| (0.0..1.0).contains(t))
+        .unwrap_or(DEFAULT_TARGET)
+}
+
+fn stub_for(env: &Env, host: &str) -> Option<Stub> {
+    let namespace = env.durable_object(BINDING).ok()?;
+    namespace.id_from_name(host).ok()?.get_stub().ok()
+}
+
+/// Record an upstream outcome in the background. No-op without the binding.
+pub fn record(env: &Env, ctx: &Context, host: &str, ok: bool) {
+    let Some(stub) = stub_for(env, host) else {
+        return;
+    };
+    let outcome = Outcome {
+        host: host.to_string(),
+        ok,
+    };
+    ctx.wait_until(async move {
+        let result = match utils::json_request("https://slo/record", Method::Post, &outcome) {
+            Ok(req) => stub.fetch_with_request(req).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            console_error!("SLO record failed for {}: {:?}", outcome.host, e);
+        }
+    });
+}
+
+/// `GET /admin/slo?host=<host>`
+pub async fn admin_summary(req: &Request, env: &Env) -> Result<Response> {
+    let url = req.url()?;
+    let Some(host) = url
+        .query_pairs()
+        .find(|(k, _)| k == "host")
+        .map(|(_, v)| v.to_ascii_lowercase())
+    else {
+        return responses::error(400, "missing_host", "Query parameter `host` is required");
+    };
+    let Some(stub) = stub_for(env, &host) else {
+        return responses::error(503, "slo_unavailable", "SLO tracking is not configured");
+    };
+    stub.fetch_with_str("https://slo/summary").await
+}
+
+#[durable_object]
+pub struct SloTracker {
+    state: State,
+    env: Env,
+}
+
+impl SloTracker {
+    async fn load(&self) -> Result<SloState> {
+        Ok(self
+            .state
+            .storage()
+            .get(STATE_KEY)
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn maybe_alert(&self, slo: &mut SloState, hour: u64) -> Result<()> {
+        let threshold = config::var(&self.env, "SLO_BURN_ALERT")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_BURN_ALERT);
+        let min_requests =
+            config::var_u64(&self.env, "SLO_MIN_REQUESTS").unwrap_or(DEFAULT_MIN_REQUESTS);
+        let summary = slo.summarize(hour, target(&self.env));
+        if !summary.burning(threshold, min_requests) || slo.last_alert_hour == Some(hour) {
+            return Ok(());
+        }
+        slo.last_alert_hour = Some(hour);
+        console_warn!(
+            "SLO burn alert for {}: burn rate {:.1} (threshold {:.1})",
+            summary.host,
+            summary.burn_rate_1h,
+            threshold
+        );
+        if let Some(webhook) = config::var(&self.env, "SLO_ALERT_WEBHOOK") {
+            let body = json!({ "alert": "slo_burn_rate", "summary": summary });
+            let req = utils::json_request(&webhook, Method::Post, &body)?;
+            if let Err(e) = Fetch::Request(req).send().await {
+                console_error!("SLO alert webhook failed: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DurableObject for SloTracker {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let hour = current_hour();
+        match req.path().as_str() {
+            "/record" => {
+                let outcome: Outcome = req.json().await?;
+                let mut slo = self.load().await?;
+                slo.host = outcome.host;
+                slo.record(hour, outcome.ok);
+                if !outcome.ok {
+                    self.maybe_alert(&mut slo, hour).await?;
+                }
+                self.state.storage().put(STATE_KEY, &slo).await?;
+                Response::empty()
+            }
+            "/summary" => {
+                let slo = self.load().await?;
+                Response::from_json(&slo.summarize(hour, target(&self.env)))
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_of_empty_state_is_healthy() {
+        let summary = SloState::default().summarize(1000, 0.99);
+        assert_eq!(summary.total, 0);
+        assert_eq!(summary.availability, 1.0);
+        assert_eq!(summary.budget_remaining, 1.0);
+        assert_eq!(summary.burn_rate_1h, 0.0);
+    }
+
+    #[test]
+    fn test_budget_and_burn_rate() {
+        let mut state = SloState::default();
+        for _ in 0..98 {
+            state.record(1000, true);
+        }
+        state.record(1000, false);
+        state.record(1000, false);
+        let summary = state.summarize(1000, 0.99);
+        assert_eq!(summary.total, 100);
+        assert_eq!(summary.errors, 2);
+        assert!((summary.availability - 0.98).abs() < 1e-9);
+        // 2% errors against a 1% budget: budget overspent by 100%, burning at 2x.
+        assert!((summary.budget_remaining + 1.0).abs() < 1e-9);
+        assert!((summary.burn_rate_1h - 2.0).abs() < 1e-9);
+        assert_eq!(summary.requests_1h, 100);
+        assert!(summary.burning(1.9, 100));
+        assert!(!summary.burning(2.1, 100));
+    }
+
+    #[test]
+    fn test_low_traffic_does_not_alert() {
+        let mut state = SloState::default();
+        state.record(1000, false);
+        let summary = state.summarize(1000, 0.999);
+        // One failed request is a burn rate of 1000, but proves little.
+        assert!(summary.burn_rate_1h > DEFAULT_BURN_ALERT);
+        assert!(!summary.burning(DEFAULT_BURN_ALERT, DEFAULT_MIN_REQUESTS));
+        assert!(summary.burning(DEFAULT_BURN_ALERT, 1));
+    }
+
+    #[test]
+    fn test_old_buckets_leave_the_window() {
+        let mut state = SloState::default();
+        state.record(0, false);
+        state.record(WINDOW_HOURS, true);
+        assert!(!state.buckets.contains_key(&0));
+        assert_eq!(state.summarize(WINDOW_HOURS, 0.999).errors, 0);
+    }
+}
@@ -0,0 +1,122 @@
+//! Daily spend caps for metered upstreams.
+//!
+//! `SPEND_RULES` assigns a cost to routes, first match wins:
+//! `[{"pattern": "api.openai.com/v1/chat", "cost": 20}, {"pattern": "*.mapbox.com", "cost": 1}]`
+//! (patterns are `host[/path-prefix]`). `SPEND_CAPS` sets the daily budget
+//! per caller id, with `"*"` as the default: `{"*": 500, "team-a": 10000}`.
+//!
+//! Each matching request is charged up front against the caller's
+//! [`QuotaCounter`](crate::quota::QuotaCounter); once the day's budget is
+//! spent requests get a 402 until 00:00 UTC. Without the `QUOTA_COUNTER`
+//! binding caps are not enforced.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::json;
+use worker::*;
+
+use crate::{config, quota, responses, utils};
+
+const BUCKET: &str = "spend:daily";
+const DAY_SECS: u64 = 86_400;
+/// Caller id used when authentication is disabled.
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SpendRule {
+    pub pattern: String,
+    pub cost: u64,
+}
+
+/// Cost of a request to `target` under `rules`.
+pub fn cost_for(rules: &[SpendRule], target: &Url) -> Option<u64> {
+    let host = target.host_str().unwrap_or_default();
+    rules
+        .iter()
+        .find(|r| utils::url_matches(&r.pattern, host, target.path()))
+        .map(|r| r.cost)
+}
+
+pub fn cap_for(caps: &HashMap<String, u64>, caller: &str) -> Option<u64> {
+    caps.get(caller).or_else(|| caps.get("*")).copied()
+}
+
+/// Charge the request; returns a 402 once the caller's daily cap is spent.
+pub async fn charge(env: &Env, caller: &str, target: &Url) -> Result<Option<Response>> {
+    let rules: Vec<SpendRule> = config::var_json(env, "SPEND_RULES").unwrap_or_default();
+    let Some(cost) = cost_for(&rules, target).filter(|c| *c > 0) else {
+        return Ok(None);
+    };
+    let caps: HashMap<String, u64> = config::var_json(env, "SPEND_CAPS").unwrap_or_default();
+    let Some(cap) = cap_for(&caps, caller) else {
+        return Ok(None);
+    };
+
+    let request = quota::ConsumeRequest {
+        bucket: BUCKET.into(),
+        amount: cost,
+        limit: cap,
+        window_secs: DAY_SECS,
+        sliding: false,
+        force: false,
+    };
+    let Some(result) = quota::consume(env, caller, &request).await else {
+        console_warn!(
+            "Spend cap for {} not enforced: quota store unavailable",
+            caller
+        );
+        return Ok(None);
+    };
+    if result.allowed {
+        return Ok(None);
+    }
+    let body = json!({
+        "error": "spend_cap_exceeded",
+        "message": format!(
+            "Daily spend cap of {} units reached ({} used, this request costs {}); resets at {}",
+            result.limit,
+            result.used,
+            cost,
+            utils::http_date(result.reset_at * 1000)
+        ),
+        "cap": result.limit,
+        "used": result.used,
+        "cost": cost,
+        "reset_at": result.reset_at,
+    });
+    responses::json(402, &body).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_for_first_match() {
+        let rules: Vec<SpendRule> = serde_json::from_str(
+            r#"[{"pattern": "api.openai.com/v1/chat", "cost": 20},
+                {"pattern": "api.openai.com", "cost": 2}]"#,
+        )
+        .unwrap();
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            cost_for(&rules, &url("https://api.openai.com/v1/chat/completions")),
+            Some(20)
+        );
+        assert_eq!(
+            cost_for(&rules, &url("https://api.openai.com/v1/models")),
+            Some(2)
+        );
+        assert_eq!(cost_for(&rules, &url("https://example.com/")), None);
+    }
+
+    #[test]
+    fn test_cap_for_falls_back_to_default() {
+        let caps: HashMap<String, u64> =
+            serde_json::from_str(r#"{"*": 500, "team-a": 10000}"#).unwrap();
+        assert_eq!(cap_for(&caps, "team-a"), Some(10000));
+        assert_eq!(cap_for(&caps, "team-b"), Some(500));
+        assert_eq!(cap_for(&HashMap::new(), "team-b"), None);
+    }
+}
@@ -0,0 +1,341 @@
+//! Body stream adapters applied while proxying.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures_util::{Stream, StreamExt};
+use worker::{Error, Result};
+
+/// A response body of any of the adapted shapes.
+pub type Body = Pin<Box<dyn Stream<Item = Result<Vec<u8>>>>>;
+
+/// A body fetched and buffered as a whole, e.g. one range of a download.
+pub type Part = Pin<Box<dyn Future<Output = Result<Vec<u8>>>>>;
+
+/// Passes chunks through until more than `limit` bytes have been seen, then
+/// yields a single error and stops polling (and so cancels) the inner stream.
+/// The client sees a truncated body.
+pub struct LimitedStream<S> {
+    inner: Option<S>,
+    seen: u64,
+    limit: u64,
+}
+
+impl<S> LimitedStream<S> {
+    pub fn new(inner: S, limit: u64) -> Self {
+        Self {
+            inner: Some(inner),
+            seen: 0,
+            limit,
+        }
+    }
+}
+
+impl<S> Stream for LimitedStream<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match Pin::new(inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.seen += chunk.len() as u64;
+                if self.seen > self.limit {
+                    // Dropping the inner stream cancels the upstream body.
+                    self.inner = None;
+                    return Poll::Ready(Some(Err(Error::RustError(format!(
+                        "response body exceeded the {} byte limit",
+                        self.limit
+                    )))));
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            other => other,
+        }
+    }
+}
+
+/// Buffer a whole stream; bound it with [`LimitedStream`] or [`Take`] first.
+pub async fn collect<S>(stream: S) -> Result<Vec<u8>>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    let mut out = Vec::new();
+    let mut stream = stream;
+    while let Some(chunk) = stream.next().await {
+        out.extend(chunk?);
+    }
+    Ok(out)
+}
+
+/// Yields the first `len` bytes of the inner stream, then drops (and so
+/// cancels) it.
+pub struct Take<S> {
+    inner: Option<S>,
+    remaining: u64,
+}
+
+impl<S> Take<S> {
+    pub fn new(inner: S, len: u64) -> Self {
+        Self {
+            inner: Some(inner),
+            remaining: len,
+        }
+    }
+}
+
+impl<S> Stream for Take<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.remaining == 0 {
+            self.inner = None;
+        }
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match Pin::new(inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(mut chunk))) => {
+                if chunk.len() as u64 > self.remaining {
+                    chunk.truncate(self.remaining as usize);
+                }
+                self.remaining -= chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            other => other,
+        }
+    }
+}
+
+enum Slot {
+    Pending(Part),
+    Ready(Result<Vec<u8>>),
+}
+
+/// Streams `head`, then each of `parts` in order. All parts are driven
+/// whenever the stream is polled, so they download concurrently with the
+/// head and with each other; an error ends the stream.
+pub struct Stitched<S> {
+    head: Option<S>,
+    parts: VecDeque<Slot>,
+}
+
+impl<S> Stitched<S> {
+    pub fn new(head: S, parts: Vec<Part>) -> Self {
+        Self {
+            head: Some(head),
+            parts: parts.into_iter().map(Slot::Pending).collect(),
+        }
+    }
+}
+
+impl<S> Stream for Stitched<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        for slot in this.parts.iter_mut() {
+            if let Slot::Pending(part) = slot {
+                if let Poll::Ready(result) = part.as_mut().poll(cx) {
+                    *slot = Slot::Ready(result);
+                }
+            }
+        }
+        if let Some(head) = this.head.as_mut() {
+            match Pin::new(head).poll_next(cx) {
+                Poll::Ready(None) => this.head = None,
+                Poll::Ready(Some(Err(e))) => {
+                    this.head = None;
+                    this.parts.clear();
+                    return Poll::Ready(Some(Err(e)));
+                }
+                other => return other,
+            }
+        }
+        match this.parts.front() {
+            None => Poll::Ready(None),
+            Some(Slot::Pending(_)) => Poll::Pending,
+            Some(Slot::Ready(_)) => match this.parts.pop_front() {
+                Some(Slot::Ready(Ok(bytes))) => Poll::Ready(Some(Ok(bytes))),
+                Some(Slot::Ready(Err(e))) => {
+                    this.parts.clear();
+                    Poll::Ready(Some(Err(e)))
+                }
+                _ => unreachable!("front slot is ready"),
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    bytes: u64,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Counts the bytes passing through; see [`counting`].
+pub struct CountingStream<S> {
+    inner: S,
+    tally: Rc<RefCell<Tally>>,
+}
+
+/// Resolves to the byte count once the paired [`CountingStream`] ends or is
+/// dropped (e.g. the client went away mid-body).
+pub struct Finished {
+    tally: Rc<RefCell<Tally>>,
+}
+
+pub fn counting<S>(inner: S) -> (CountingStream<S>, Finished) {
+    let tally = Rc::new(RefCell::new(Tally::default()));
+    (
+        CountingStream {
+            inner,
+            tally: tally.clone(),
+        },
+        Finished { tally },
+    )
+}
+
+impl<S> CountingStream<S> {
+    fn finish(&self) {
+        let mut tally = self.tally.borrow_mut();
+        tally.done = true;
+        if let Some(waker) = tally.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<S> Stream for CountingStream<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.tally.borrow_mut().bytes += chunk.len() as u64,
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        polled
+    }
+}
+
+impl<S> Drop for CountingStream<S> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Future for Finished {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+        let mut tally = self.tally.borrow_mut();
+        if tally.done {
+            Poll::Ready(tally.bytes)
+        } else {
+            tally.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_executor::block_on;
+    use futures_util::{stream, StreamExt};
+
+    fn chunks(sizes: &[usize]) -> impl Stream<Item = Result<Vec<u8>>> + Unpin {
+        stream::iter(sizes.iter().map(|n| Ok(vec![0u8; *n])).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_limited_stream_passes_small_bodies() {
+        let out: Vec<_> = block_on(LimitedStream::new(chunks(&[4, 4]), 8).collect());
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|c| c.is_ok()));
+    }
+
+    #[test]
+    fn test_limited_stream_aborts_after_limit() {
+        let out: Vec<_> = block_on(LimitedStream::new(chunks(&[4, 4, 4, 4]), 10).collect());
+        assert_eq!(out.len(), 3);
+        assert!(out[0].is_ok() && out[1].is_ok());
+        assert!(out[2].is_err());
+    }
+
+    #[test]
+    fn test_collect_bounded_body() {
+        assert_eq!(block_on(collect(chunks(&[4, 4]))).unwrap().len(), 8);
+        assert!(block_on(collect(LimitedStream::new(chunks(&[4, 4]), 6))).is_err());
+        assert_eq!(
+            block_on(collect(Take::new(chunks(&[4, 4]), 6)))
+                .unwrap()
+                .len(),
+            6
+        );
+    }
+
+    #[test]
+    fn test_take_truncates() {
+        let out: Vec<_> = block_on(Take::new(chunks(&[4, 4, 4]), 6).collect());
+        let sizes: Vec<usize> = out.into_iter().map(|c| c.unwrap().len()).collect();
+        assert_eq!(sizes, vec![4, 2]);
+    }
+
+    #[test]
+    fn test_stitched_keeps_order() {
+        let part = |byte: u8| -> Part { Box::pin(async move { Ok(vec![byte; 2]) }) };
+        let head = stream::iter(vec![Ok(vec![0u8; 2])]);
+        let out: Vec<u8> =
+            block_on(Stitched::new(head, vec![part(1), part(2)]).collect::<Vec<_>>())
+                .into_iter()
+                .flat_map(|c| c.unwrap())
+                .collect();
+        assert_eq!(out, vec![0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_stitched_stops_on_error() {
+        let failed: Part = Box::pin(async { Err(Error::RustError("range failed".into())) });
+        let ok: Part = Box::pin(async { Ok(vec![9]) });
+        let out: Vec<_> = block_on(Stitched::new(chunks(&[1]), vec![failed, ok]).collect());
+        assert_eq!(out.len(), 2);
+        assert!(out[1].is_err());
+    }
+
+    #[test]
+    fn test_counting_stream_reports_total() {
+        let (stream, finished) = counting(chunks(&[3, 5, 7]));
+        let out: Vec<_> = block_on(stream.collect());
+        assert_eq!(out.len(), 3);
+        assert_eq!(block_on(finished), 15);
+    }
+
+    #[test]
+    fn test_counting_stream_reports_on_drop() {
+        let (mut stream, finished) = counting(chunks(&[3, 5, 7]));
+        assert!(block_on(stream.next()).is_some());
+        drop(stream);
+        assert_eq!(block_on(finished), 3);
+    }
+}
@@ -0,0 +1,171 @@
+//! Target URL forms that need more than `Url::parse`.
+//!
+//! The path form (`/https://example.com/...`) is recognised by its scheme,
+//! case-insensitively, including the single-slash `https:/host` that some
+//! clients and intermediaries collapse `//` into, and a fully
+//! percent-encoded target (`/https%3A%2F%2Fexample.com%2F`). Bracketed IPv6
+//! literals, explicit ports and internationalized hosts (sent raw or
+//! percent-encoded) parse as they would in the query or header form; IDN
+//! hosts are converted to their punycode (`xn--`) form.
+//!
+//! A target that is itself a proxy URL (this deployment's host, or one of
+//! the `SISTER_DEPLOYMENTS` host patterns) is unwrapped to the target it
+//! carries, up to [`MAX_NESTING`] levels, instead of looping through the
+//! proxy again; the nested URL's own extra query params go along, as they
+//! would have on the extra hop.
+
+use percent_encoding::percent_decode_str;
+use worker::Url;
+
+const SCHEMES: &[&str] = &["https:", "http:", "https%3a", "http%3a"];
+
+/// Proxy URLs unwrapped before the target is refused.
+pub const MAX_NESTING: usize = 4;
+
+/// The target in a path-form worker URL, if the path carries one.
+pub fn from_path(path: &str) -> Option<&str> {
+    let candidate = path.trim_start_matches('/');
+    let lower = candidate.get(..8).unwrap_or(candidate).to_ascii_lowercase();
+    SCHEMES
+        .iter()
+        .any(|scheme| lower.starts_with(scheme))
+        .then_some(candidate)
+}
+
+/// Parse a target, decoding a percent-encoded one first. Only `http` and
+/// `https` URLs with a host are accepted.
+pub fn parse(raw: &str) -> Option<Url> {
+    let raw = raw.trim();
+    let url = match raw.get(..8).map(str::to_ascii_lowercase) {
+        Some(prefix) if prefix.starts_with("http%3a") || prefix.starts_with("https%3a") => {
+            Url::parse(&percent_decode_str(raw).decode_utf8().ok()?)
+        }
+        _ => Url::parse(raw),
+    }
+    .ok()?;
+    (matches!(url.scheme(), "http" | "https") && url.host().is_some()).then_some(url)
+}
+
+/// The target carried by proxy URL `url` (`?url=` or path form), with the
+/// params `keep` accepts appended.
+fn inner_target(url: &Url, keep: &impl Fn(&str) -> bool) -> Option<Url> {
+    let raw = url
+        .query_pairs()
+        .find(|(k, _)| k == "url")
+        .map(|(_, v)| v.into_owned())
+        .or_else(|| from_path(url.path()).map(str::to_string))?;
+    let mut inner = parse(&raw)?;
+    let extra: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| keep(k))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if !extra.is_empty() {
+        inner.query_pairs_mut().extend_pairs(extra);
+    }
+    Some(inner)
+}
+
+/// Follow nested proxy URLs to the innermost target. `None` when the chain
+/// is deeper than [`MAX_NESTING`] or ends at the proxy itself.
+pub fn unwrap(
+    mut target: Url,
+    is_proxy: impl Fn(&Url) -> bool,
+    keep: impl Fn(&str) -> bool,
+) -> Option<Url> {
+    for _ in 0..=MAX_NESTING {
+        if !is_proxy(&target) {
+            return Some(target);
+        }
+        target = inner_target(&target, &keep)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The target as it arrives in a path-form worker URL.
+    fn via_path(worker_url: &str) -> Option<Url> {
+        let worker = Url::parse(worker_url).unwrap();
+        parse(from_path(worker.path())?)
+    }
+
+    #[test]
+    fn test_path_form_ipv6_and_ports() {
+        let target = via_path("https://proxy.dev/https://[2001:db8::1]:8443/a/b").unwrap();
+        assert_eq!(target.host_str(), Some("[2001:db8::1]"));
+        assert_eq!(target.port(), Some(8443));
+        assert_eq!(target.as_str(), "https://[2001:db8::1]:8443/a/b");
+        let target = via_path("https://proxy.dev/http://[::ffff:192.0.2.1]/").unwrap();
+        assert_eq!(target.host_str(), Some("[::ffff:c000:201]"));
+        let target = via_path("https://proxy.dev/http://example.com:8080/x").unwrap();
+        assert_eq!(target.as_str(), "http://example.com:8080/x");
+    }
+
+    #[test]
+    fn test_path_form_idn_hosts() {
+        let target = via_path("https://proxy.dev/https://bücher.example/straße").unwrap();
+        assert_eq!(target.host_str(), Some("xn--bcher-kva.example"));
+        assert_eq!(target.path(), "/stra%C3%9Fe");
+        let target = parse("https://xn--bcher-kva.example/").unwrap();
+        assert_eq!(target.host_str(), Some("xn--bcher-kva.example"));
+    }
+
+    #[test]
+    fn test_path_form_variants() {
+        let target = via_path("https://proxy.dev/HTTPS://Example.com/a").unwrap();
+        assert_eq!(target.as_str(), "https://example.com/a");
+        let target = via_path("https://proxy.dev/https:/example.com/a").unwrap();
+        assert_eq!(target.as_str(), "https://example.com/a");
+        let target =
+            via_path("https://proxy.dev/https%3A%2F%2F%5B2001%3Adb8%3A%3A1%5D%3A8443%2Fa").unwrap();
+        assert_eq!(target.as_str(), "https://[2001:db8::1]:8443/a");
+    }
+
+    #[test]
+    fn test_path_without_target() {
+        assert_eq!(from_path("/httpbin/get"), None);
+        assert_eq!(from_path("/"), None);
+        assert_eq!(from_path("/favicon.ico"), None);
+        assert_eq!(parse("ftp://example.com/"), None);
+        assert_eq!(parse("https://"), None);
+    }
+
+    fn unwrap_at(target: &str) -> Option<String> {
+        let is_proxy = |url: &Url| matches!(url.host_str(), Some("proxy.dev" | "eu.proxy.dev"));
+        let keep = |k: &str| k != "url" && k != "_cb";
+        unwrap(parse(target)?, is_proxy, keep).map(String::from)
+    }
+
+    #[test]
+    fn test_unwrap_nested_proxy_urls() {
+        assert_eq!(
+            unwrap_at("https://example.com/a").as_deref(),
+            Some("https://example.com/a")
+        );
+        assert_eq!(
+            unwrap_at("https://proxy.dev/https://example.com/a?x=1").as_deref(),
+            Some("https://example.com/a?x=1")
+        );
+        assert_eq!(
+            unwrap_at("https://eu.proxy.dev/?url=https%3A%2F%2Fproxy.dev%2Fhttps%3A%2F%2Fexample.com%2F&_cb=1")
+                .as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(unwrap_at("https://proxy.dev/health"), None);
+        let mut deep = "https://example.com/".to_string();
+        for _ in 0..=MAX_NESTING {
+            deep = format!("https://proxy.dev/{deep}");
+        }
+        assert_eq!(unwrap_at(&deep), None);
+    }
+
+    #[test]
+    fn test_query_rebuild_keeps_host() {
+        let mut target = parse("https://[2001:db8::1]:8443/p?a=1").unwrap();
+        target.query_pairs_mut().append_pair("q", "ü");
+        assert_eq!(target.as_str(), "https://[2001:db8::1]:8443/p?a=1&q=%C3%BC");
+    }
+}
@@ -0,0 +1,124 @@
+//! Constant-time responses for sensitive routes.
+//!
+//! `TIMING_FLOORS` lists routes (first match wins, `host[/path-prefix]`
+//! patterns) whose responses should not reveal cache hits or upstream
+//! behaviour through their timing or size:
+//! `[{"pattern": "private.example.com/docs", "floor_ms": 400,
+//! "size_bucket": 4096}]`.
+//!
+//! Every response for a matching target, errors included, is held until
+//! `floor_ms` after the request arrived, and with `size_bucket` the body is
+//! buffered and an `X-Padding` header tops its size up to the next multiple
+//! of the bucket (at most 8 KiB). `Server-Timing` and `Age` are dropped since
+//! they give the same information away. Pick a floor above the route's slow
+//! path: a response that is already later than the floor goes out as is.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{config, context::RequestCtx, utils};
+
+pub const PADDING_HEADER: &str = "X-Padding";
+const MAX_BUCKET: u64 = 8 * 1024;
+/// Headers that reveal timing or cache state on their own.
+const REVEALING_HEADERS: &[&str] = &["Server-Timing", "Age"];
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TimingRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub floor_ms: u64,
+    #[serde(default)]
+    pub size_bucket: Option<u64>,
+}
+
+pub fn rule_for<'a>(rules: &'a [TimingRule], target: &Url) -> Option<&'a TimingRule> {
+    let host = target.host_str().unwrap_or_default();
+    rules
+        .iter()
+        .find(|r| utils::url_matches(&r.pattern, host, target.path()))
+}
+
+/// Padding that brings `len` to the next multiple of `bucket`. Never zero,
+/// so the header is always present and only its length varies.
+pub fn padding_len(len: u64, bucket: u64) -> u64 {
+    let bucket = bucket.clamp(1, MAX_BUCKET);
+    bucket - len % bucket
+}
+
+/// How much longer to hold a response that is `elapsed_ms` old.
+pub fn wait_ms(floor_ms: u64, elapsed_ms: u64) -> Option<u64> {
+    Some(floor_ms.saturating_sub(elapsed_ms)).filter(|&wait| wait > 0)
+}
+
+/// Pad and delay `response` if its target matches a `TIMING_FLOORS` rule.
+pub async fn apply(env: &Env, rctx: &RequestCtx, mut response: Response) -> Result<Response> {
+    let Some(target) = &rctx.target else {
+        return Ok(response);
+    };
+    let rules: Vec<TimingRule> = config::var_json(env, "TIMING_FLOORS").unwrap_or_default();
+    let Some(rule) = rule_for(&rules, target) else {
+        return Ok(response);
+    };
+    let headers = response.headers().clone();
+    for name in REVEALING_HEADERS {
+        headers.delete(name)?;
+    }
+    if let Some(bucket) = rule.size_bucket.filter(|&b| b > 0) {
+        let declared = headers
+            .get("Content-Length")?
+            .and_then(|v| v.parse::<u64>().ok());
+        let len = match declared {
+            Some(len) => len,
+            None => {
+                let status = response.status_code();
+                let body = response.bytes().await?;
+                let len = body.len() as u64;
+                response = Response::from_bytes(body)?.with_status(status);
+                len
+            }
+        };
+        headers.set(
+            PADDING_HEADER,
+            &"0".repeat(padding_len(len, bucket) as usize),
+        )?;
+    }
+    response = response.with_headers(headers);
+    if let Some(wait) = wait_ms(rule.floor_ms, rctx.elapsed_ms()) {
+        Delay::from(Duration::from_millis(wait)).await;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_for() {
+        let rules: Vec<TimingRule> = serde_json::from_str(
+            r#"[{"pattern": "private.example.com/docs", "floor_ms": 400, "size_bucket": 4096},
+                {"pattern": "*.example.com", "floor_ms": 100}]"#,
+        )
+        .unwrap();
+        let docs = Url::parse("https://private.example.com/docs/a").unwrap();
+        assert_eq!(rule_for(&rules, &docs).unwrap().size_bucket, Some(4096));
+        let other = Url::parse("https://cdn.example.com/x").unwrap();
+        assert_eq!(rule_for(&rules, &other).unwrap().floor_ms, 100);
+        let unrelated = Url::parse("https://example.org/").unwrap();
+        assert_eq!(rule_for(&rules, &unrelated), None);
+    }
+
+    #[test]
+    fn test_padding_and_wait() {
+        assert_eq!(padding_len(0, 4096), 4096);
+        assert_eq!(padding_len(100, 4096), 3996);
+        assert_eq!(padding_len(4096, 4096), 4096);
+        assert_eq!(padding_len(100, 1 << 20), MAX_BUCKET - 100);
+        assert_eq!(wait_ms(400, 150), Some(250));
+        assert_eq!(wait_ms(400, 400), None);
+        assert_eq!(wait_ms(0, 10), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! Cloudflare Turnstile challenge for anonymous callers.
+//!
+//! Enabled by the `TURNSTILE_SECRET` secret (plus `TURNSTILE_SITE_KEY` for
+//! the widget). Requests that are neither authenticated nor signed must carry
+//! a Turnstile token in `X-Turnstile-Token` or the `cf-turnstile-response`
+//! query parameter; it is checked with the siteverify API. A verified caller
+//! gets a pass cookie valid for `TURNSTILE_PASS_TTL_SECS` (default 1800) so
+//! follow-up requests skip the challenge. Browsers without a token get a
+//! challenge page, other clients a 403 JSON error carrying the site key.
+
+use serde::Deserialize;
+use serde_json::json;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{config, responses, signing};
+
+pub const TOKEN_PARAM: &str = "cf-turnstile-response";
+const TOKEN_HEADER: &str = "X-Turnstile-Token";
+const COOKIE_NAME: &str = "pf_turnstile";
+const SECRET_VAR: &str = "TURNSTILE_SECRET";
+const SITEVERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+const DEFAULT_PASS_TTL_SECS: u64 = 1800;
+
+#[derive(Debug)]
+pub enum TurnstileOutcome {
+    /// Not configured, or the caller already holds a valid pass.
+    Passed,
+    /// Freshly verified; the `Set-Cookie` value must reach the client.
+    Verified(String),
+    Challenge(Response),
+}
+
+#[derive(Debug, Deserialize)]
+struct SiteverifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+fn pass_subject(ip: &str) -> String {
+    format!("turnstile-pass:{ip}")
+}
+
+/// Cookie value `<exp>.<sig>` binding the pass to the client IP.
+pub fn pass_value(secret: &str, ip: &str, exp: u64) -> String {
+    format!("{exp}.{}", signing::sign(secret, &pass_subject(ip), exp))
+}
+
+pub fn pass_is_valid(secret: &str, ip: &str, value: &str, now_secs: u64) -> bool {
+    value.split_once('.').is_some_and(|(exp, sig)| {
+        signing::verify(secret, &pass_subject(ip), exp, sig, now_secs, 0).is_ok()
+    })
+}
+
+/// Value of cookie `name` from a `Cookie` header.
+pub fn cookie(header: &str, name: &str) -> Option<String> {
+    header.split(';').find_map(|pair| {
+        let (k, v) = pair.trim().split_once('=')?;
+        (k == name).then(|| v.to_string())
+    })
+}
+
+/// A `Cookie` header without the proxy's pass cookie, or `None` if nothing
+/// else is left to forward.
+pub fn without_pass_cookie(header: &str) -> Option<String> {
+    let rest: Vec<&str> = header
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| pair.split_once('=').map_or(*pair, |(k, _)| k) != COOKIE_NAME)
+        .collect();
+    (!rest.is_empty()).then(|| rest.join("; "))
+}
+
+async fn siteverify(secret: &str, token: &str, ip: &str) -> Result<SiteverifyResponse> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("secret", secret)
+        .append_pair("response", token)
+        .append_pair("remoteip", ip)
+        .finish();
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.into()));
+    Fetch::Request(Request::new_with_init(SITEVERIFY_URL, &init)?)
+        .send()
+        .await?
+        .json()
+        .await
+}
+
+fn challenge_page(site_key: &str) -> String {
+    format!(
+        r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>Verifying…</title>
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<script src="https://challenges.cloudflare.com/turnstile/v0/api.js" async defer></script>
+<script>
+function onPass(token) {{
+  const url = new URL(location.href);
+  url.searchParams.set("{TOKEN_PARAM}", token);
+  location.replace(url);
+}}
+</script></head>
+<body style="font-family: system-ui, sans-serif; display: grid; place-items: center; min-height: 90vh">
+<div class="cf-turnstile" data-sitekey="{site_key}" data-callback="onPass"></div>
+</body></html>"#
+    )
+}
+
+fn challenge(req: &Request, site_key: &str, message: &str) -> Result<TurnstileOutcome> {
+    let wants_html = req
+        .headers()
+        .get("Accept")?
+        .is_some_and(|a| a.contains("text/html"));
+    let response = if wants_html && !site_key.is_empty() {
+        let mut page = Response::from_html(challenge_page(site_key))?.with_status(403);
+        page.headers_mut().set("Cache-Control", "no-store")?;
+        page
+    } else {
+        responses::json(
+            403,
+            &json!({
+                "error": "turnstile_required",
+                "message": message,
+                "site_key": site_key,
+            }),
+        )?
+    };
+    Ok(TurnstileOutcome::Challenge(response))
+}
+
+pub async fn check(req: &Request, env: &Env, rctx: &RequestCtx) -> Result<TurnstileOutcome> {
+    let Some(secret) = config::var(env, SECRET_VAR) else {
+        return Ok(TurnstileOutcome::Passed);
+    };
+    let site_key = config::var(env, "TURNSTILE_SITE_KEY").unwrap_or_default();
+    let ip = rctx.client_ip.as_deref().unwrap_or_default();
+    let now = Date::now().as_millis() / 1000;
+
+    let pass = req
+        .headers()
+        .get("Cookie")?
+        .and_then(|c| cookie(&c, COOKIE_NAME));
+    if pass.is_some_and(|p| pass_is_valid(&secret, ip, &p, now)) {
+        return Ok(TurnstileOutcome::Passed);
+    }
+
+    let token = match req.headers().get(TOKEN_HEADER)? {
+        Some(token) => Some(token),
+        None => rctx
+            .url
+            .query_pairs()
+            .find(|(k, _)| k == TOKEN_PARAM)
+            .map(|(_, v)| v.into_owned()),
+    };
+    let Some(token) = token.filter(|t| !t.is_empty()) else {
+        return challenge(
+            req,
+            &site_key,
+            "Complete the Turnstile challenge to continue",
+        );
+    };
+    let verdict = siteverify(&secret, &token, ip).await?;
+    if !verdict.success {
+        console_warn!("Turnstile verification failed: {:?}", verdict.error_codes);
+        return challenge(req, &site_key, "Turnstile verification failed");
+    }
+
+    let ttl = config::var_u64(env, "TURNSTILE_PASS_TTL_SECS").unwrap_or(DEFAULT_PASS_TTL_SECS);
+    let value = pass_value(&secret, ip, now + ttl);
+    Ok(TurnstileOutcome::Verified(format!(
+        "{COOKIE_NAME}={value}; Max-Age={ttl}; Path=/; HttpOnly; Secure; SameSite=Lax"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass_roundtrip() {
+        let value = pass_value("s", "203.0.113.7", 1000);
+        assert!(pass_is_valid("s", "203.0.113.7", &value, 999));
+        assert!(!pass_is_valid("s", "203.0.113.7", &value, 1001));
+        assert!(!pass_is_valid("s", "198.51.100.1", &value, 999));
+        assert!(!pass_is_valid("other", "203.0.113.7", &value, 999));
+        assert!(!pass_is_valid("s", "203.0.113.7", "garbage", 999));
+    }
+
+    #[test]
+    fn test_cookie() {
+        let header = "a=1; pf_turnstile=123.abc; b=2";
+        assert_eq!(cookie(header, COOKIE_NAME).as_deref(), Some("123.abc"));
+        assert_eq!(cookie(header, "missing"), None);
+    }
+
+    #[test]
+    fn test_without_pass_cookie() {
+        assert_eq!(
+            without_pass_cookie("a=1; pf_turnstile=123.abc; b=2").as_deref(),
+            Some("a=1; b=2")
+        );
+        assert_eq!(without_pass_cookie("pf_turnstile=123.abc"), None);
+        assert_eq!(without_pass_cookie("a=1").as_deref(), Some("a=1"));
+    }
+}
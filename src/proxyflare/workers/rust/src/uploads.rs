@@ -0,0 +1,109 @@
+//! Request body size limit (`MAX_REQUEST_BYTES`).
+//!
+//! `POST`, `PUT` and `PATCH` bodies larger than the limit are refused with
+//! 413 `request_too_large`. A declared `Content-Length` is checked before
+//! quotas are charged; a body without one (a chunked upload) is counted as
+//! it streams upstream, and the upload is aborted as soon as it passes the
+//! limit. Unset means no limit.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use futures_util::{Stream, StreamExt};
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::{config, responses};
+
+/// The limit for a request with this method, if any.
+pub fn limit(env: &Env, method: &Method) -> Option<u64> {
+    match method {
+        Method::Post | Method::Put | Method::Patch => config::var_u64(env, "MAX_REQUEST_BYTES"),
+        _ => None,
+    }
+}
+
+/// Whether a declared `Content-Length` is over the limit.
+pub fn declared_over(content_length: Option<&str>, limit: u64) -> bool {
+    content_length
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .is_some_and(|len| len > limit)
+}
+
+pub fn too_large(limit: u64) -> Result<Response> {
+    responses::error(
+        413,
+        "request_too_large",
+        &format!("Request body exceeds the {limit} byte limit"),
+    )
+}
+
+/// Set once a counted body has gone over the limit, so the failed upstream
+/// fetch can be reported as a 413.
+#[derive(Clone, Default)]
+pub struct Overflow(Rc<Cell<bool>>);
+
+impl Overflow {
+    pub fn happened(&self) -> bool {
+        self.0.get()
+    }
+}
+
+/// `inner` ending in an error after more than `limit` bytes.
+pub fn capped<S>(inner: S, limit: u64, overflow: Overflow) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    S: Stream<Item = Result<Vec<u8>>>,
+{
+    let mut seen = 0u64;
+    inner.map(move |chunk| {
+        let chunk = chunk?;
+        seen += chunk.len() as u64;
+        if seen > limit {
+            overflow.0.set(true);
+            return Err(Error::RustError(format!(
+                "request body exceeded the {limit} byte limit"
+            )));
+        }
+        Ok(chunk)
+    })
+}
+
+/// The request body as a counted stream to hand to `RequestInit::with_body`.
+pub fn counted(req: &mut Request, limit: u64) -> Result<(Option<JsValue>, Overflow)> {
+    let overflow = Overflow::default();
+    let body = capped(req.stream()?, limit, overflow.clone());
+    // Round-trip through a Response to get a JS ReadableStream.
+    let stream = web_sys::Response::from(Response::from_stream(body)?).body();
+    Ok((stream.map(Into::into), overflow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_executor::block_on;
+    use futures_util::stream;
+
+    #[test]
+    fn test_declared_over() {
+        assert!(declared_over(Some("11"), 10));
+        assert!(!declared_over(Some("10"), 10));
+        assert!(!declared_over(Some("lots"), 10));
+        assert!(!declared_over(None, 10));
+    }
+
+    #[test]
+    fn test_capped_stream_aborts_after_limit() {
+        let chunks = stream::iter([4, 4, 4].map(|n| Ok(vec![0u8; n])));
+        let overflow = Overflow::default();
+        let out: Vec<_> = block_on(capped(chunks, 10, overflow.clone()).collect());
+        assert!(out[0].is_ok() && out[1].is_ok());
+        assert!(out[2].is_err());
+        assert!(overflow.happened());
+
+        let chunks = stream::iter([4, 4].map(|n| Ok(vec![0u8; n])));
+        let overflow = Overflow::default();
+        let out: Vec<_> = block_on(capped(chunks, 8, overflow.clone()).collect());
+        assert!(out.iter().all(|c| c.is_ok()));
+        assert!(!overflow.happened());
+    }
+}
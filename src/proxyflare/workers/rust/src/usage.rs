@@ -0,0 +1,202 @@
+//! Per-tenant usage accounting and daily CSV reports.
+//!
+//! With a D1 database bound as `USAGE_DB` (schema in
+//! `migrations/0001_usage.sql`), every proxied request bumps a
+//! `(day, tenant, host)` counter; the tenant is the authenticated caller id.
+//! With an R2 bucket bound as `USAGE_BUCKET`, the cron handler writes the
+//! previous UTC day's totals shortly after midnight to
+//! `usage/<tenant>/<YYYY-MM-DD>.csv`, one file per tenant. The retention job
+//! records what it reclaims under the `_retention` tenant, with the kind of
+//! artifact as the host and the count as requests.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::utils;
+
+const DB_BINDING: &str = "USAGE_DB";
+const BUCKET_BINDING: &str = "USAGE_BUCKET";
+/// Minute of the UTC day at which the previous day's report is written.
+const REPORT_MINUTE: u64 = 5;
+const MS_PER_DAY: u64 = 86_400_000;
+
+const UPSERT: &str = "INSERT INTO usage (day, tenant, host, requests, errors) \
+     VALUES (?1, ?2, ?3, 1, ?4) \
+     ON CONFLICT (day, tenant, host) DO UPDATE SET \
+     requests = requests + 1, errors = errors + excluded.errors";
+const ADD_RECLAIMED: &str = "INSERT INTO usage (day, tenant, host, requests, errors) \
+     VALUES (?1, ?2, ?3, ?4, 0) \
+     ON CONFLICT (day, tenant, host) DO UPDATE SET \
+     requests = requests + excluded.requests";
+const SELECT_DAY: &str = "SELECT day, tenant, host, requests, errors FROM usage \
+     WHERE day = ?1 ORDER BY tenant, host";
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct UsageRow {
+    pub day: String,
+    pub tenant: String,
+    pub host: String,
+    pub requests: u64,
+    pub errors: u64,
+}
+
+/// Count one upstream request in the background. No-op without `USAGE_DB`.
+pub fn record(env: &Env, ctx: &Context, tenant: &str, host: &str, ok: bool) {
+    let Ok(db) = env.d1(DB_BINDING) else {
+        return;
+    };
+    let values = [
+        JsValue::from(utils::iso_date(Date::now().as_millis())),
+        JsValue::from(tenant),
+        JsValue::from(host),
+        JsValue::from(u32::from(!ok)),
+    ];
+    let tenant = tenant.to_string();
+    ctx.wait_until(async move {
+        let result = match db.prepare(UPSERT).bind(&values) {
+            Ok(statement) => statement.run().await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            console_error!("Usage record failed for {}: {:?}", tenant, e);
+        }
+    });
+}
+
+/// Tenant the retention job's counts are recorded under.
+pub const RETENTION_TENANT: &str = "_retention";
+
+/// Add `count` reclaimed `kind` artifacts to `day`'s retention row. No-op
+/// without `USAGE_DB`.
+pub async fn record_reclaimed(env: &Env, day: &str, kind: &str, count: usize) -> Result<()> {
+    let Ok(db) = env.d1(DB_BINDING) else {
+        return Ok(());
+    };
+    let values = [
+        JsValue::from(day),
+        JsValue::from(RETENTION_TENANT),
+        JsValue::from(kind),
+        JsValue::from(count as f64),
+    ];
+    db.prepare(ADD_RECLAIMED).bind(&values)?.run().await?;
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn to_csv(rows: &[UsageRow]) -> String {
+    let mut csv = String::from("date,tenant,host,requests,errors\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            row.day,
+            csv_field(&row.tenant),
+            csv_field(&row.host),
+            row.requests,
+            row.errors
+        ));
+    }
+    csv
+}
+
+/// R2 key for a tenant's report; keeps tenant ids from escaping the prefix.
+pub fn report_key(tenant: &str, day: &str) -> String {
+    let safe: String = tenant
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '@' | ':' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("usage/{safe}/{day}.csv")
+}
+
+/// Write yesterday's per-tenant reports once a day from the cron handler.
+pub async fn run(env: &Env, scheduled_ms: u64) {
+    if (scheduled_ms / 60_000) % 1440 != REPORT_MINUTE {
+        return;
+    }
+    let (Ok(db), Ok(bucket)) = (env.d1(DB_BINDING), env.bucket(BUCKET_BINDING)) else {
+        return;
+    };
+    let day = utils::iso_date(scheduled_ms.saturating_sub(MS_PER_DAY));
+    let rows: Vec<UsageRow> = match db.prepare(SELECT_DAY).bind(&[JsValue::from(&day)]) {
+        Ok(statement) => match statement.all().await.and_then(|r| r.results()) {
+            Ok(rows) => rows,
+            Err(e) => {
+                console_error!("Usage report query for {} failed: {:?}", day, e);
+                return;
+            }
+        },
+        Err(e) => {
+            console_error!("Usage report query for {} failed: {:?}", day, e);
+            return;
+        }
+    };
+
+    let mut by_tenant: BTreeMap<&str, Vec<UsageRow>> = BTreeMap::new();
+    for row in &rows {
+        by_tenant.entry(&row.tenant).or_default().push(row.clone());
+    }
+    for (tenant, rows) in by_tenant {
+        let key = report_key(tenant, &day);
+        let written = bucket
+            .put(&key, to_csv(&rows))
+            .http_metadata(HttpMetadata {
+                content_type: Some("text/csv; charset=utf-8".into()),
+                ..Default::default()
+            })
+            .execute()
+            .await;
+        if let Err(e) = written {
+            console_error!("Usage report upload {} failed: {:?}", key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(tenant: &str, host: &str) -> UsageRow {
+        UsageRow {
+            day: "2024-03-01".into(),
+            tenant: tenant.into(),
+            host: host.into(),
+            requests: 12,
+            errors: 1,
+        }
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let csv = to_csv(&[row("team-a", "api.example.com"), row("a,\"b\"", "x.org")]);
+        assert_eq!(
+            csv,
+            "date,tenant,host,requests,errors\n\
+             2024-03-01,team-a,api.example.com,12,1\n\
+             2024-03-01,\"a,\"\"b\"\"\",x.org,12,1\n"
+        );
+    }
+
+    #[test]
+    fn test_report_key_is_sanitized() {
+        assert_eq!(
+            report_key("access:a@example.com", "2024-03-01"),
+            "usage/access:a@example.com/2024-03-01.csv"
+        );
+        assert_eq!(
+            report_key("../evil/x", "2024-03-01"),
+            "usage/.._evil_x/2024-03-01.csv"
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Upstream `User-Agent` chosen by the deployment instead of the client.
+//!
+//! `USER_AGENT` sends one fixed value; otherwise `USER_AGENT_POOL` (a JSON
+//! array, since user agents contain commas) picks one entry at random for
+//! each request. With neither set the client's own `User-Agent` passes
+//! through unchanged.
+
+use worker::*;
+
+use crate::{config, xff};
+
+/// The user agent for this request from the fixed value or the pool.
+pub fn choose(fixed: Option<String>, pool: &[String], rng: &mut xff::Rng) -> Option<String> {
+    fixed.or_else(|| {
+        let pool: Vec<&String> = pool.iter().filter(|ua| !ua.trim().is_empty()).collect();
+        match pool.len() {
+            0 => None,
+            len => Some(
+                pool[(rng.next_u64() % len as u64) as usize]
+                    .trim()
+                    .to_string(),
+            ),
+        }
+    })
+}
+
+pub fn for_request(env: &Env) -> Option<String> {
+    let fixed = config::var(env, "USER_AGENT");
+    let pool: Vec<String> = match fixed {
+        Some(_) => Vec::new(),
+        None => config::var_json(env, "USER_AGENT_POOL").unwrap_or_default(),
+    };
+    choose(fixed, &pool, &mut xff::Rng::from_entropy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        let pool = vec![
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko)".to_string(),
+            "curl/8.5.0".to_string(),
+            " ".to_string(),
+        ];
+        let mut rng = xff::Rng::new(1);
+        assert_eq!(
+            choose(Some("bot/1.0".into()), &pool, &mut rng).as_deref(),
+            Some("bot/1.0")
+        );
+        let mut seen = std::collections::BTreeSet::new();
+        for _ in 0..50 {
+            seen.insert(choose(None, &pool, &mut rng).unwrap());
+        }
+        assert_eq!(seen.len(), 2);
+        assert_eq!(choose(None, &[], &mut rng), None);
+    }
+}
@@ -1,4 +1,6 @@
 use cfg_if::cfg_if;
+use serde::Serialize;
+use worker::{js_sys, Date, Headers, Method, Request, RequestInit, Result};
 
 cfg_if! {
     // https://github.com/rustwasm/console_error_panic_hook#readme
@@ -10,3 +12,250 @@ cfg_if! {
         pub fn set_panic_hook() {}
     }
 }
+
+/// The connecting client's IP as reported by Cloudflare.
+pub fn client_ip(req: &Request) -> Option<String> {
+    req.headers().get("cf-connecting-ip").ok().flatten()
+}
+
+/// Short, non-cryptographic unique id (time-ordered) for logs and storage keys.
+pub fn random_id() -> String {
+    let rand = (js_sys::Math::random() * u32::MAX as f64) as u32;
+    format!("{:x}{:08x}", Date::now().as_millis(), rand)
+}
+
+/// Current time as an RFC 3339 / ISO-8601 string.
+pub fn now_iso() -> String {
+    js_sys::Date::new_0().to_iso_string().into()
+}
+
+/// Build a request with a JSON body (Durable Object calls, webhooks).
+pub fn json_request(url: &str, method: Method, body: &impl Serialize) -> Result<Request> {
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.with_method(method)
+        .with_headers(headers)
+        .with_body(Some(serde_json::to_string(body)?.into()));
+    Request::new_with_init(url, &init)
+}
+
+/// Civil `(year, month, day)` for a day count since the epoch (Howard
+/// Hinnant's algorithm, valid for dates after 1970).
+fn civil_from_days(days: u64) -> (i64, u64, u64) {
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month as u64, day as u64)
+}
+
+/// Days since the epoch of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe - 719_468) as u64
+}
+
+/// Epoch seconds of the first instant of the UTC month after `epoch_ms`.
+pub fn next_month_start(epoch_ms: u64) -> u64 {
+    let (year, month, _) = civil_from_days(epoch_ms / 86_400_000);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    days_from_civil(year, month, 1) * 86_400
+}
+
+/// Format epoch milliseconds as an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`).
+pub fn http_date(epoch_ms: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = epoch_ms / 1000;
+    let days = secs / 86_400;
+    let rem = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Format epoch milliseconds as a UTC calendar date (`1994-11-06`).
+pub fn iso_date(epoch_ms: u64) -> String {
+    let (year, month, day) = civil_from_days(epoch_ms / 86_400_000);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Lowercase hex encoding.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Compare two secrets without short-circuiting on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `existing` `Vary` members (kept in order) plus any of `add` not already
+/// listed, compared case-insensitively; `*` already varies on everything.
+pub fn merge_vary(existing: Option<&str>, add: &[&str]) -> String {
+    let mut members: Vec<String> = existing
+        .unwrap_or_default()
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+        .collect();
+    if members.iter().any(|m| m == "*") {
+        return "*".into();
+    }
+    for member in add {
+        if !members.iter().any(|m| m.eq_ignore_ascii_case(member)) {
+            members.push(member.to_string());
+        }
+    }
+    members.join(", ")
+}
+
+/// Lowercase ASCII form of a host or pattern: IPv6 brackets dropped and
+/// internationalized names in punycode, as `Url::host_str` reports them.
+fn normalize_host(host: &str) -> String {
+    let host = host.trim().trim_end_matches('.');
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    match host.is_ascii() {
+        true => host.to_ascii_lowercase(),
+        false => url::Host::parse(host)
+            .map(|h| h.to_string())
+            .unwrap_or_else(|_| host.to_lowercase()),
+    }
+}
+
+/// Match a host against a pattern: `example.com` (exact), `*.example.com`
+/// (any subdomain, not the apex) or `*` (anything). Case-insensitive; IPv6
+/// literals match with or without brackets and IDN hosts in either form.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = normalize_host(host);
+    if pattern.trim() == "*" {
+        return true;
+    }
+    match pattern.trim().strip_prefix("*.") {
+        Some(suffix) => {
+            let suffix = normalize_host(suffix);
+            host.len() > suffix.len() && host.ends_with(&format!(".{suffix}"))
+        }
+        None => host == normalize_host(pattern),
+    }
+}
+
+/// Match a `host[/path-prefix]` pattern against a URL's host and path.
+pub fn url_matches(pattern: &str, host: &str, path: &str) -> bool {
+    let (host_pattern, path_prefix) = match pattern.find('/') {
+        Some(idx) => (&pattern[..idx], &pattern[idx..]),
+        None => (pattern, "/"),
+    };
+    host_matches(host_pattern, host) && path.starts_with(path_prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(784_111_777_000), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            http_date(1_709_251_200_000),
+            "Fri, 01 Mar 2024 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn test_iso_date() {
+        assert_eq!(iso_date(0), "1970-01-01");
+        assert_eq!(iso_date(784_111_777_000), "1994-11-06");
+        assert_eq!(iso_date(1_709_251_199_999), "2024-02-29");
+    }
+
+    #[test]
+    fn test_next_month_start() {
+        assert_eq!(next_month_start(0), 2_678_400);
+        // 2024-02-29 -> 2024-03-01, 2023-12-31 -> 2024-01-01
+        assert_eq!(next_month_start(1_709_251_199_999), 1_709_251_200);
+        assert_eq!(next_month_start(1_704_067_199_000), 1_704_067_200);
+    }
+
+    #[test]
+    fn test_merge_vary() {
+        assert_eq!(merge_vary(None, &["Origin"]), "Origin");
+        assert_eq!(
+            merge_vary(
+                Some("accept-encoding, Cookie"),
+                &["Origin", "Accept-Encoding"]
+            ),
+            "accept-encoding, Cookie, Origin"
+        );
+        assert_eq!(merge_vary(Some("*"), &["Origin"]), "*");
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+
+    #[test]
+    fn test_host_matches_exact_and_wildcard() {
+        assert!(host_matches("example.com", "EXAMPLE.com"));
+        assert!(!host_matches("example.com", "a.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+        assert!(host_matches("*", "anything.test"));
+    }
+
+    #[test]
+    fn test_host_matches_ipv6_and_idn() {
+        assert!(host_matches("2001:db8::1", "[2001:db8::1]"));
+        assert!(host_matches("[2001:DB8::1]", "[2001:db8::1]"));
+        assert!(host_matches("bücher.example", "xn--bcher-kva.example"));
+        assert!(host_matches(
+            "*.bücher.example",
+            "shop.xn--bcher-kva.example"
+        ));
+        assert!(!host_matches("bücher.example", "buecher.example"));
+    }
+
+    #[test]
+    fn test_url_matches_path_prefix() {
+        assert!(url_matches("example.com/videos/", "example.com", "/videos/1.mp4"));
+        assert!(!url_matches("example.com/videos/", "example.com", "/images/1.png"));
+        assert!(url_matches("*.example.com", "cdn.example.com", "/x"));
+    }
+}
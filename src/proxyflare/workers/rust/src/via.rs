@@ -0,0 +1,147 @@
+//! RFC 9110 `Via` on proxied requests and responses.
+//!
+//! The proxy appends `<version> <pseudonym>` to the incoming chain in both
+//! directions: toward the upstream with the version the client spoke to the
+//! worker (`2` for HTTP/2), toward the client with `1.1`, the version of the
+//! worker's own subrequest. The pseudonym is `VIA_PSEUDONYM` (default
+//! `proxyflare`); `VIA_HEADER=false` leaves `Via` untouched. Elements that
+//! can't be parsed are dropped from the chain rather than passed on, and
+//! [`hops`] counts this proxy's earlier appearances for the loop guard.
+
+use worker::*;
+
+use crate::config;
+
+pub const DEFAULT_PSEUDONYM: &str = "proxyflare";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hop {
+    /// `1.1`, `2`, or `NAME/version` for non-HTTP protocols.
+    pub protocol: String,
+    /// Host or pseudonym of the intermediary.
+    pub received_by: String,
+}
+
+pub fn is_enabled(env: &Env) -> bool {
+    config::var(env, "VIA_HEADER").as_deref() != Some("false")
+}
+
+pub fn pseudonym(env: &Env) -> String {
+    config::var(env, "VIA_PSEUDONYM")
+        .filter(|p| is_token(p))
+        .unwrap_or_else(|| DEFAULT_PSEUDONYM.to_string())
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~:/[]".contains(&b))
+}
+
+/// Drop `(comments)`, which may contain commas, then split on commas.
+fn elements(value: &str) -> Vec<String> {
+    let mut elements = vec![String::new()];
+    let mut depth = 0usize;
+    for c in value.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => elements.push(String::new()),
+            _ if depth == 0 => elements.last_mut().into_iter().for_each(|e| e.push(c)),
+            _ => {}
+        }
+    }
+    elements
+}
+
+/// The well-formed hops of a `Via` value, oldest first.
+pub fn parse(value: &str) -> Vec<Hop> {
+    elements(value)
+        .iter()
+        .filter_map(|element| {
+            let mut parts = element.split_ascii_whitespace();
+            let protocol = parts.next()?;
+            let received_by = parts.next()?;
+            let protocol = protocol.strip_prefix("HTTP/").unwrap_or(protocol);
+            (parts.next().is_none() && is_token(protocol) && is_token(received_by)).then(|| Hop {
+                protocol: protocol.to_string(),
+                received_by: received_by.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// `existing` with this proxy's hop appended.
+pub fn append(existing: Option<&str>, protocol: &str, pseudonym: &str) -> String {
+    let mut chain: Vec<String> = existing
+        .map(parse)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|hop| format!("{} {}", hop.protocol, hop.received_by))
+        .collect();
+    chain.push(format!("{protocol} {pseudonym}"));
+    chain.join(", ")
+}
+
+/// How many times `pseudonym` already appears in a `Via` value.
+pub fn hops(value: &str, pseudonym: &str) -> usize {
+    parse(value)
+        .iter()
+        .filter(|hop| hop.received_by.eq_ignore_ascii_case(pseudonym))
+        .count()
+}
+
+/// The received-protocol for the client's request (`HTTP/2` becomes `2`).
+pub fn client_protocol(req: &Request) -> String {
+    req.cf()
+        .map(|cf| cf.http_protocol())
+        .and_then(|p| p.strip_prefix("HTTP/").map(str::to_string))
+        .filter(|p| is_token(p))
+        .unwrap_or_else(|| "1.1".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let hops = parse("1.0 fred, HTTP/1.1 p.example.net (Apache/1.1, x), 2 edge:8080");
+        assert_eq!(
+            hops,
+            vec![
+                Hop {
+                    protocol: "1.0".into(),
+                    received_by: "fred".into()
+                },
+                Hop {
+                    protocol: "1.1".into(),
+                    received_by: "p.example.net".into()
+                },
+                Hop {
+                    protocol: "2".into(),
+                    received_by: "edge:8080".into()
+                },
+            ]
+        );
+        assert_eq!(parse("garbage, 1.1"), vec![]);
+    }
+
+    #[test]
+    fn test_append() {
+        assert_eq!(append(None, "2", "proxyflare"), "2 proxyflare");
+        assert_eq!(
+            append(Some("1.0 fred (comment, here)"), "1.1", "proxyflare"),
+            "1.0 fred, 1.1 proxyflare"
+        );
+        assert_eq!(append(Some(" , bad value here"), "1.1", "p"), "1.1 p");
+    }
+
+    #[test]
+    fn test_hops() {
+        let chain = append(Some("1.1 proxyflare, 1.1 cdn"), "1.1", "proxyflare");
+        assert_eq!(hops(&chain, "proxyflare"), 2);
+        assert_eq!(hops(&chain, "other"), 0);
+    }
+}
@@ -0,0 +1,323 @@
+//! Invisible per-caller watermarks for leak tracing.
+//!
+//! With `WATERMARK_SECRET` set, successful responses to authenticated
+//! callers carry a tag derived from the caller id
+//! (`HMAC-SHA256(secret, caller)`, first 8 bytes as hex):
+//!
+//! - HTML gets `<!-- proxyflare-wm:<tag> -->` before `</body>`;
+//! - plain text gets the tag as zero-width characters after its first line;
+//! - JPEG images get an extra EXIF segment whose `ImageDescription` holds
+//!   the tag (after any existing EXIF, which is left intact).
+//!
+//! Only bodies declaring a `Content-Length` of at most `WATERMARK_MAX_BYTES`
+//! (default 5 MiB) are marked. `POST /admin/watermark/trace` takes leaked
+//! content as its body and names the caller whose tag it carries, checking
+//! the API keys from `API_KEYS*` and `API_KEYS_KV` plus any ids passed as
+//! `?callers=a,b` (JWT and Access callers can't be enumerated).
+
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use worker::*;
+
+use crate::context::RequestCtx;
+use crate::{auth, config, keys, responses, utils};
+
+const SECRET_VAR: &str = "WATERMARK_SECRET";
+const MARKER: &str = "proxyflare-wm:";
+const TAG_LEN: usize = 16;
+const DEFAULT_MAX_BYTES: u64 = 5 * 1024 * 1024;
+const ZERO: char = '\u{200B}';
+const ONE: char = '\u{200C}';
+/// Brackets the zero-width bits so they can be found again.
+const FENCE: char = '\u{2060}';
+/// EXIF `ImageDescription`.
+const EXIF_TAG: u16 = 0x010E;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Html,
+    Text,
+    Jpeg,
+}
+
+impl Kind {
+    pub fn from_content_type(content_type: &str) -> Option<Kind> {
+        let ct = content_type.to_ascii_lowercase();
+        let essence = ct.split(';').next().unwrap_or_default().trim();
+        match essence {
+            "text/html" => Some(Kind::Html),
+            "text/plain" => Some(Kind::Text),
+            "image/jpeg" | "image/jpg" => Some(Kind::Jpeg),
+            _ => None,
+        }
+    }
+}
+
+pub fn tag(secret: &str, caller: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(caller.as_bytes());
+    utils::hex(&mac.finalize().into_bytes()[..TAG_LEN / 2])
+}
+
+pub fn zero_width(tag: &str) -> String {
+    let bits = tag
+        .bytes()
+        .flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1));
+    std::iter::once(FENCE)
+        .chain(bits.map(|bit| if bit == 1 { ONE } else { ZERO }))
+        .chain(std::iter::once(FENCE))
+        .collect()
+}
+
+fn decode_zero_width(text: &str) -> Option<String> {
+    let start = text.find(FENCE)? + FENCE.len_utf8();
+    let end = start + text[start..].find(FENCE)?;
+    let bits: Vec<u8> = text[start..end]
+        .chars()
+        .map(|c| match c {
+            ZERO => Some(0),
+            ONE => Some(1),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if bits.len() != TAG_LEN * 8 {
+        return None;
+    }
+    let bytes: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | bit))
+        .collect();
+    String::from_utf8(bytes).ok()
+}
+
+fn insert_html(body: &[u8], tag: &str) -> Vec<u8> {
+    let comment = format!("<!-- {MARKER}{tag} -->");
+    let lower = body.to_ascii_lowercase();
+    let at = lower
+        .windows(7)
+        .rposition(|w| w == b"</body>")
+        .unwrap_or(body.len());
+    [&body[..at], comment.as_bytes(), &body[at..]].concat()
+}
+
+fn insert_text(body: &[u8], tag: &str) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(body).ok()?;
+    let at = text.find('\n').unwrap_or(text.len());
+    Some([&body[..at], zero_width(tag).as_bytes(), &body[at..]].concat())
+}
+
+/// Bytes of an APP1 payload needed to tell a watermark segment apart.
+pub const SEGMENT_PROBE_LEN: usize = EXIF_VALUE_OFFSET + MARKER.len();
+/// Where [`exif_segment`] puts its text: after `Exif\0\0`, the TIFF header
+/// and the one-entry IFD.
+const EXIF_VALUE_OFFSET: usize = 6 + 26;
+
+/// Whether an APP1 payload (after the length field) is a watermark written
+/// by [`exif_segment`]; needs [`SEGMENT_PROBE_LEN`] bytes.
+pub fn is_watermark_segment(payload: &[u8]) -> bool {
+    payload.starts_with(b"Exif\x00\x00")
+        && payload
+            .get(EXIF_VALUE_OFFSET..)
+            .is_some_and(|value| value.starts_with(MARKER.as_bytes()))
+}
+
+/// APP1 segment holding a one-entry EXIF IFD with `text`.
+fn exif_segment(text: &str) -> Vec<u8> {
+    let value: Vec<u8> = text.bytes().chain(std::iter::once(0)).collect();
+    let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+    tiff.extend(1u16.to_be_bytes());
+    tiff.extend(EXIF_TAG.to_be_bytes());
+    tiff.extend(2u16.to_be_bytes()); // ASCII
+    tiff.extend((value.len() as u32).to_be_bytes());
+    tiff.extend(26u32.to_be_bytes()); // value offset: header + IFD
+    tiff.extend(0u32.to_be_bytes()); // no next IFD
+    tiff.extend(value);
+    let payload = [b"Exif\x00\x00".as_slice(), &tiff].concat();
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend(((payload.len() + 2) as u16).to_be_bytes());
+    segment.extend(payload);
+    segment
+}
+
+fn insert_jpeg(body: &[u8], tag: &str) -> Option<Vec<u8>> {
+    if !body.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    // Keep JFIF (APP0) and an existing EXIF segment first so readers still
+    // see the original metadata.
+    let mut at = 2;
+    while body.len() >= at + 4 && body[at] == 0xFF {
+        let marker = body[at + 1];
+        let len = u16::from_be_bytes([body[at + 2], body[at + 3]]) as usize;
+        let exif = marker == 0xE1 && body[at + 4..].starts_with(b"Exif\x00\x00");
+        if !(marker == 0xE0 || exif) || body.len() < at + 2 + len {
+            break;
+        }
+        at += 2 + len;
+    }
+    let segment = exif_segment(&format!("{MARKER}{tag}"));
+    Some([&body[..at], &segment, &body[at..]].concat())
+}
+
+/// `body` with `tag` embedded, or `None` if it can't carry one.
+pub fn embed(kind: Kind, body: &[u8], tag: &str) -> Option<Vec<u8>> {
+    match kind {
+        Kind::Html => Some(insert_html(body, tag)),
+        Kind::Text => insert_text(body, tag),
+        Kind::Jpeg => insert_jpeg(body, tag),
+    }
+}
+
+/// The tag carried by `content`, in any of the embedded forms.
+pub fn extract(content: &[u8]) -> Option<String> {
+    let marker = MARKER.as_bytes();
+    if let Some(at) = content.windows(marker.len()).position(|w| w == marker) {
+        let start = at + marker.len();
+        let tag = content.get(start..start + TAG_LEN)?;
+        if tag.iter().all(u8::is_ascii_hexdigit) {
+            return String::from_utf8(tag.to_vec()).ok();
+        }
+    }
+    decode_zero_width(&String::from_utf8_lossy(content))
+}
+
+/// Watermark the response for the caller when enabled and applicable.
+pub async fn apply(env: &Env, rctx: &RequestCtx, mut response: Response) -> Result<Response> {
+    let (Some(secret), Some(caller)) = (config::var(env, SECRET_VAR), &rctx.caller) else {
+        return Ok(response);
+    };
+    let headers = response.headers();
+    let kind = headers
+        .get("Content-Type")?
+        .and_then(|ct| Kind::from_content_type(&ct));
+    let length: Option<u64> = headers.get("Content-Length")?.and_then(|v| v.parse().ok());
+    let max_bytes = config::var_u64(env, "WATERMARK_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES);
+    let Some(kind) = kind.filter(|_| response.status_code() == 200) else {
+        return Ok(response);
+    };
+    if length.is_none_or(|len| len > max_bytes) || headers.has("Content-Encoding")? {
+        return Ok(response);
+    }
+    let headers = headers.clone();
+    let body = response.bytes().await?;
+    let Some(marked) = embed(kind, &body, &tag(&secret, caller)) else {
+        return Ok(Response::from_bytes(body)?
+            .with_status(200)
+            .with_headers(headers));
+    };
+    // The body no longer matches the origin's validators or byte ranges.
+    headers.delete("ETag")?;
+    headers.delete("Accept-Ranges")?;
+    Ok(Response::from_bytes(marked)?
+        .with_status(200)
+        .with_headers(headers))
+}
+
+/// Caller ids known from configured API keys.
+async fn known_callers(env: &Env) -> Result<Vec<String>> {
+    let mut callers: Vec<String> = ["API_KEYS", "API_KEYS_SECONDARY"]
+        .iter()
+        .flat_map(|name| config::var_list(env, name))
+        .map(|key| auth::key_id(&key))
+        .collect();
+    if let Ok(kv) = env.kv(auth::KEYS_KV) {
+        for (digest, record) in keys::records(&kv).await? {
+            callers.push(
+                record
+                    .name
+                    .unwrap_or_else(|| format!("key-{}", &digest[..12])),
+            );
+        }
+    }
+    Ok(callers)
+}
+
+/// `POST /admin/watermark/trace[?callers=a,b]`
+pub async fn admin_trace(mut req: Request, env: &Env) -> Result<Response> {
+    let Some(secret) = config::var(env, SECRET_VAR) else {
+        return responses::error(503, "watermarks_disabled", "WATERMARK_SECRET is not set");
+    };
+    let extra = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "callers")
+        .map(|(_, v)| config::parse_list(&v))
+        .unwrap_or_default();
+    let Some(found) = extract(&req.bytes().await?) else {
+        return responses::error(404, "no_watermark", "No watermark found in the content");
+    };
+    let mut candidates = known_callers(env).await?;
+    candidates.extend(extra);
+    let caller = candidates.into_iter().find(|c| tag(&secret, c) == found);
+    responses::json(200, &json!({ "tag": found, "caller": caller }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAG: &str = "0123456789abcdef";
+
+    #[test]
+    fn test_tag_is_stable_per_caller() {
+        assert_eq!(tag("s", "team-a"), tag("s", "team-a"));
+        assert_ne!(tag("s", "team-a"), tag("s", "team-b"));
+        assert_ne!(tag("s", "team-a"), tag("t", "team-a"));
+        assert_eq!(tag("s", "team-a").len(), TAG_LEN);
+    }
+
+    #[test]
+    fn test_html_comment_before_body_end() {
+        let marked = embed(Kind::Html, b"<html><BODY>hi</BODY></html>", TAG).unwrap();
+        let marked = String::from_utf8(marked).unwrap();
+        assert_eq!(
+            marked,
+            format!("<html><BODY>hi<!-- {MARKER}{TAG} --></BODY></html>")
+        );
+        assert_eq!(extract(marked.as_bytes()).as_deref(), Some(TAG));
+    }
+
+    #[test]
+    fn test_zero_width_text_roundtrip() {
+        let marked = embed(Kind::Text, b"line one\nline two", TAG).unwrap();
+        let text = String::from_utf8(marked).unwrap();
+        assert!(text.starts_with("line one"));
+        assert!(text.ends_with("\nline two"));
+        assert_eq!(
+            text.chars()
+                .filter(|c| !matches!(*c, ZERO | ONE | FENCE))
+                .collect::<String>(),
+            "line one\nline two"
+        );
+        assert_eq!(extract(text.as_bytes()).as_deref(), Some(TAG));
+    }
+
+    #[test]
+    fn test_jpeg_exif_segment_after_jfif() {
+        let jfif = [0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46];
+        let body = [&[0xFF, 0xD8][..], &jfif, &[0xFF, 0xDA, 0x00, 0x02]].concat();
+        let marked = embed(Kind::Jpeg, &body, TAG).unwrap();
+        assert_eq!(&marked[..8], &body[..8]);
+        assert_eq!(&marked[8..10], &[0xFF, 0xE1]);
+        assert!(marked.ends_with(&[0xFF, 0xDA, 0x00, 0x02]));
+        assert_eq!(extract(&marked).as_deref(), Some(TAG));
+        assert!(embed(Kind::Jpeg, b"not a jpeg", TAG).is_none());
+    }
+
+    #[test]
+    fn test_kind_from_content_type() {
+        assert_eq!(
+            Kind::from_content_type("text/html; charset=utf-8"),
+            Some(Kind::Html)
+        );
+        assert_eq!(Kind::from_content_type("image/jpeg"), Some(Kind::Jpeg));
+        assert_eq!(Kind::from_content_type("application/json"), None);
+    }
+
+    #[test]
+    fn test_extract_without_watermark() {
+        assert_eq!(extract(b"plain content"), None);
+    }
+}
@@ -0,0 +1,382 @@
+//! What the upstream sees in `X-Forwarded-For` (`XFF_MODE`).
+//!
+//! - `random` (default): a fresh random address per request.
+//! - `sticky-random`: a random-looking address derived from the client IP
+//!   (and `XFF_STICKY_SALT`, if set), so one client keeps one address.
+//! - `pseudonymous`: an address keyed on the client IP with the
+//!   `XFF_PSEUDONYM_SECRET` secret (HMAC-SHA256), in the client's own address
+//!   family. Upstreams that pin sessions or rate-limit per IP see one stable
+//!   address per client, and without the secret it can't be traced back.
+//!   If the secret is unset every request gets a fresh random address.
+//! - `real`: the client IP from `cf-connecting-ip`.
+//! - `append`: the incoming `X-Forwarded-For` chain with the client IP
+//!   appended.
+//! - `omit`: no `X-Forwarded-For` at all.
+//!
+//! Random addresses are plausible public ones: private, loopback,
+//! link-local, shared, documentation, benchmarking, multicast and reserved
+//! ranges are skipped, as are octets of 0. `XFF_RANDOM_IPV6=true` makes them
+//! global unicast IPv6 addresses instead. An explicit `X-My-X-Forwarded-For`
+//! from the client still wins in every mode but `omit`.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::config;
+
+/// IPv4 ranges that never appear as a real client address.
+const RESERVED_V4: &[([u8; 4], u32)] = &[
+    ([0, 0, 0, 0], 8),
+    ([10, 0, 0, 0], 8),
+    ([100, 64, 0, 0], 10),
+    ([127, 0, 0, 0], 8),
+    ([169, 254, 0, 0], 16),
+    ([172, 16, 0, 0], 12),
+    ([192, 0, 0, 0], 24),
+    ([192, 0, 2, 0], 24),
+    ([192, 88, 99, 0], 24),
+    ([192, 168, 0, 0], 16),
+    ([198, 18, 0, 0], 15),
+    ([198, 51, 100, 0], 24),
+    ([203, 0, 113, 0], 24),
+    ([224, 0, 0, 0], 4),
+    ([240, 0, 0, 0], 4),
+];
+
+/// Special-purpose blocks inside the global unicast range `2000::/3`.
+const RESERVED_V6: &[(u128, u32)] = &[
+    (0x2001_0000 << 96, 23),
+    (0x2001_0db8 << 96, 32),
+    (0x2002 << 112, 16),
+    (0x3fff << 112, 20),
+];
+
+/// Small seedable generator (SplitMix64); not for anything secret.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Seeded from the clock and `Math.random`.
+    pub fn from_entropy() -> Self {
+        let random = (js_sys::Math::random() * (1u64 << 53) as f64) as u64;
+        Self(Date::now().as_millis() ^ random.rotate_left(11))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+pub fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let bits = u32::from(ip);
+    !RESERVED_V4.iter().any(|(network, prefix)| {
+        let mask = u32::MAX << (32 - prefix);
+        bits & mask == u32::from_be_bytes(*network)
+    })
+}
+
+pub fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let bits = u128::from(ip);
+    let global_unicast = bits >> 125 == 0b001;
+    global_unicast
+        && !RESERVED_V6.iter().any(|(network, prefix)| {
+            let mask = u128::MAX << (128 - prefix);
+            bits & mask == *network
+        })
+}
+
+pub fn random_ipv4(rng: &mut Rng) -> Ipv4Addr {
+    loop {
+        let ip = Ipv4Addr::from(rng.next_u64() as u32);
+        if is_public_ipv4(ip) && !ip.octets().contains(&0) {
+            return ip;
+        }
+    }
+}
+
+pub fn random_ipv6(rng: &mut Rng) -> Ipv6Addr {
+    loop {
+        let bits = (u128::from(rng.next_u64()) << 64 | u128::from(rng.next_u64())) >> 3;
+        let ip = Ipv6Addr::from(bits | (0b001 << 125));
+        if is_public_ipv6(ip) {
+            return ip;
+        }
+    }
+}
+
+pub fn random_ip(rng: &mut Rng, ipv6: bool) -> String {
+    match ipv6 {
+        true => random_ipv6(rng).to_string(),
+        false => random_ipv4(rng).to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Random,
+    StickyRandom,
+    Pseudonymous,
+    Real,
+    Append,
+    Omit,
+}
+
+impl Mode {
+    pub fn parse(value: &str) -> Option<Mode> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "random" => Some(Mode::Random),
+            "sticky-random" => Some(Mode::StickyRandom),
+            "pseudonymous" => Some(Mode::Pseudonymous),
+            "real" => Some(Mode::Real),
+            "append" => Some(Mode::Append),
+            "omit" => Some(Mode::Omit),
+            _ => None,
+        }
+    }
+
+    pub fn from_env(env: &Env) -> Mode {
+        match config::var(env, "XFF_MODE") {
+            Some(raw) => Mode::parse(&raw).unwrap_or_else(|| {
+                console_error!("Ignoring unknown XFF_MODE {:?}", raw);
+                Mode::Random
+            }),
+            None => Mode::Random,
+        }
+    }
+}
+
+/// Seed for `client`'s stable address.
+pub fn sticky_seed(client: &str, salt: &str) -> u64 {
+    let digest = Sha256::digest(format!("{salt}\n{client}").as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
+
+/// Seed for `client`'s pseudonym under `secret`.
+pub fn pseudonym_seed(client: &str, secret: &str) -> u64 {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(client.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
+
+/// The `X-Forwarded-For` value to send, or `None` to send none. `key` is the
+/// sticky salt or the pseudonym secret; `rng` is only used by the random
+/// modes.
+pub fn value(
+    mode: Mode,
+    incoming: Option<&str>,
+    client: Option<&str>,
+    key: &str,
+    ipv6: bool,
+    rng: &mut Rng,
+) -> Option<String> {
+    let incoming = incoming.map(str::trim).filter(|v| !v.is_empty());
+    match mode {
+        Mode::Random => Some(random_ip(rng, ipv6)),
+        Mode::StickyRandom => Some(match client {
+            Some(client) => random_ip(&mut Rng::new(sticky_seed(client, key)), ipv6),
+            None => random_ip(rng, ipv6),
+        }),
+        Mode::Pseudonymous => Some(match client {
+            Some(client) if !key.is_empty() => {
+                let ipv6 = client.parse::<Ipv6Addr>().is_ok();
+                random_ip(&mut Rng::new(pseudonym_seed(client, key)), ipv6)
+            }
+            _ => random_ip(rng, ipv6),
+        }),
+        Mode::Real => client.map(str::to_string),
+        Mode::Append => match (incoming, client) {
+            (Some(chain), Some(client)) => Some(format!("{chain}, {client}")),
+            (chain, client) => chain.or(client).map(str::to_string),
+        },
+        Mode::Omit => None,
+    }
+}
+
+/// The value for this request and deployment; `explicit` is the client's
+/// `X-My-X-Forwarded-For`.
+pub fn for_request(
+    env: &Env,
+    explicit: Option<&str>,
+    incoming: Option<&str>,
+    client: Option<&str>,
+) -> Option<String> {
+    let mode = Mode::from_env(env);
+    match explicit {
+        Some(explicit) if mode != Mode::Omit => Some(explicit.to_string()),
+        _ => {
+            let key = match mode {
+                Mode::StickyRandom => config::var(env, "XFF_STICKY_SALT"),
+                Mode::Pseudonymous => config::var(env, "XFF_PSEUDONYM_SECRET").or_else(|| {
+                    console_error!("XFF_MODE=pseudonymous needs XFF_PSEUDONYM_SECRET");
+                    None
+                }),
+                _ => None,
+            };
+            let ipv6 = config::var(env, "XFF_RANDOM_IPV6").as_deref() == Some("true");
+            let mut rng = match mode {
+                Mode::Random | Mode::StickyRandom | Mode::Pseudonymous => Rng::from_entropy(),
+                _ => Rng::new(0),
+            };
+            value(
+                mode,
+                incoming,
+                client,
+                &key.unwrap_or_default(),
+                ipv6,
+                &mut rng,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(
+        mode: Mode,
+        incoming: Option<&str>,
+        client: Option<&str>,
+        salt: &str,
+    ) -> Option<String> {
+        super::value(mode, incoming, client, salt, false, &mut Rng::new(7))
+    }
+
+    #[test]
+    fn test_modes() {
+        let client = Some("203.0.113.7");
+        let incoming = Some("192.0.2.1");
+        let random = random_ipv4(&mut Rng::new(7)).to_string();
+        assert_eq!(value(Mode::Random, incoming, client, ""), Some(random));
+        assert_eq!(
+            value(Mode::Real, incoming, client, "").as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(
+            value(Mode::Append, incoming, client, "").as_deref(),
+            Some("192.0.2.1, 203.0.113.7")
+        );
+        assert_eq!(value(Mode::Omit, incoming, client, ""), None);
+        assert_eq!(value(Mode::Append, None, client, "").as_deref(), client);
+    }
+
+    #[test]
+    fn test_sticky_random_is_stable_per_client() {
+        let a = value(Mode::StickyRandom, None, Some("203.0.113.7"), "s");
+        assert_eq!(a, value(Mode::StickyRandom, None, Some("203.0.113.7"), "s"));
+        assert_ne!(a, value(Mode::StickyRandom, None, Some("203.0.113.8"), "s"));
+        assert_ne!(a, value(Mode::StickyRandom, None, Some("203.0.113.7"), "t"));
+    }
+
+    #[test]
+    fn test_pseudonymous_is_keyed_and_keeps_family() {
+        let a = value(Mode::Pseudonymous, None, Some("203.0.113.7"), "k").unwrap();
+        assert_eq!(
+            Some(&a),
+            value(Mode::Pseudonymous, None, Some("203.0.113.7"), "k").as_ref()
+        );
+        assert_ne!(
+            Some(&a),
+            value(Mode::Pseudonymous, None, Some("203.0.113.7"), "j").as_ref()
+        );
+        assert!(is_public_ipv4(a.parse().unwrap()));
+        let v6 = value(Mode::Pseudonymous, None, Some("2606:4700::1"), "k").unwrap();
+        assert!(is_public_ipv6(v6.parse().unwrap()));
+        // Without a secret the client gets a per-request random address.
+        let random = random_ipv4(&mut Rng::new(7)).to_string();
+        assert_eq!(
+            value(Mode::Pseudonymous, None, Some("203.0.113.7"), ""),
+            Some(random)
+        );
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(Mode::parse("Sticky-Random"), Some(Mode::StickyRandom));
+        assert_eq!(Mode::parse("spoof"), None);
+    }
+
+    #[test]
+    fn test_generate_random_ip_format() {
+        let ip = random_ip(&mut Rng::new(42), false);
+        let parts: Vec<&str> = ip.split('.').collect();
+        assert_eq!(parts.len(), 4, "IP must have 4 octets: {ip}");
+
+        for part in &parts {
+            let octet: u8 = part
+                .parse()
+                .unwrap_or_else(|_| panic!("Octet '{part}' is not a valid u8 in IP: {ip}"));
+            assert!(octet >= 1, "Octet must be >= 1, got {octet} in {ip}");
+            // u8 max is 255, so no need to check upper bound explicitly
+        }
+    }
+
+    #[test]
+    fn test_generate_random_ip_nonzero_octets() {
+        // Run multiple times to increase confidence
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            let ip = random_ip(&mut rng, false);
+            for part in ip.split('.') {
+                let octet: u8 = part.parse().expect("valid octet");
+                assert!(octet >= 1, "0 is not a valid octet for X-Forwarded-For");
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_ips_are_public() {
+        let mut rng = Rng::new(3);
+        for _ in 0..1000 {
+            let v4 = random_ipv4(&mut rng);
+            assert!(
+                !v4.is_private() && !v4.is_loopback() && !v4.is_multicast(),
+                "{v4}"
+            );
+            assert!(!v4.is_link_local() && !v4.is_documentation() && !v4.is_broadcast());
+            let v6 = random_ipv6(&mut rng);
+            assert!(is_public_ipv6(v6), "{v6}");
+            assert!(matches!(v6.segments()[0], 0x2000..=0x3fff));
+        }
+    }
+
+    #[test]
+    fn test_reserved_ranges() {
+        for reserved in [
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+            "172.31.255.1",
+            "198.19.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_public_ipv4(reserved.parse().unwrap()), "{reserved}");
+        }
+        assert!(is_public_ipv4("8.8.8.8".parse().unwrap()));
+        assert!(is_public_ipv4("172.32.0.1".parse().unwrap()));
+        for reserved in [
+            "2001:db8::1",
+            "2001:1::1",
+            "2002::1",
+            "fe80::1",
+            "::1",
+            "fc00::1",
+        ] {
+            assert!(!is_public_ipv6(reserved.parse().unwrap()), "{reserved}");
+        }
+        assert!(is_public_ipv6("2606:4700::1111".parse().unwrap()));
+    }
+}